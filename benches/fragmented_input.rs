@@ -0,0 +1,136 @@
+//! Throughput of reading objects whose bytes arrive in small fragments, and
+//! the heap allocations made per object read.
+//!
+//! Run with `cargo bench --bench fragmented_input`.
+
+extern crate object_system;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cmp;
+use std::io::{Cursor, Read, Write};
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use object_system::{BusinessObject, Payload};
+use object_system::io::{BusinessObjectStream, NextObject, ReadBusinessObject};
+
+
+const TOTAL_BYTES: usize = 32 * 1024 * 1024;
+
+
+/// Counts allocations, including reallocations, on the way to the system
+/// allocator.
+struct Counting;
+
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+
+struct Fragmented {
+    data: Cursor<Vec<u8>>,
+    chunk: usize,
+}
+
+
+impl Read for Fragmented {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = cmp::min(self.chunk, buf.len());
+        self.data.read(&mut buf[.. n])
+    }
+}
+
+
+impl Write for Fragmented {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> { Ok(buf.len()) }
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+
+fn wire_bytes(payload_size: usize) -> (Vec<u8>, usize) {
+    let object = BusinessObject {
+        _type: Some("application/octet-stream".to_string()),
+        payload: Some(Payload::Bytes(vec![42; payload_size].into())),
+        size: Some(payload_size),
+        event: Some("bench".to_string()),
+        metadata: Default::default(),
+    };
+
+    let mut bytes = Vec::new();
+    let mut count = 0;
+    while bytes.len() < TOTAL_BYTES {
+        object.write_to(&mut bytes).unwrap();
+        count += 1;
+    }
+    (bytes, count)
+}
+
+
+fn report(name: &str, payload_size: usize, chunk: usize, bytes: usize, count: usize,
+          started: Instant, allocations: usize) {
+    let seconds = started.elapsed().as_secs_f64();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+    println!("{:<22} payload {:>7} B, fragments {:>5} B: {:>8.1} MB/s, {:>5.1} allocations/object",
+             name, payload_size, chunk, bytes as f64 / seconds / 1e6, allocations as f64 / count as f64);
+}
+
+
+fn bench_read_business_objects(bytes: &[u8], count: usize, payload_size: usize, chunk: usize) {
+    let mut stream = BusinessObjectStream::new(Fragmented { data: Cursor::new(bytes.to_vec()), chunk });
+
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let started = Instant::now();
+    let mut read = 0;
+    while read < count {
+        read += stream.read_business_objects().unwrap().len();
+    }
+    report("read_business_objects", payload_size, chunk, bytes.len(), count, started, allocations);
+}
+
+
+fn bench_next_object(bytes: &[u8], count: usize, payload_size: usize, chunk: usize) {
+    let mut stream = BusinessObjectStream::new(Fragmented { data: Cursor::new(bytes.to_vec()), chunk });
+
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let started = Instant::now();
+    for _ in 0 .. count {
+        match stream.next_object().unwrap() {
+            NextObject::Object(_) => {},
+            NextObject::Streamed(_, _) => panic!("Streaming is disabled")
+        }
+    }
+    report("next_object", payload_size, chunk, bytes.len(), count, started, allocations);
+}
+
+
+fn main() {
+    for &payload_size in &[16, 1024, 1024 * 1024] {
+        let (bytes, count) = wire_bytes(payload_size);
+
+        for &chunk in &[64, 1460, 65536] {
+            bench_read_business_objects(&bytes, count, payload_size, chunk);
+            bench_next_object(&bytes, count, payload_size, chunk);
+        }
+    }
+}
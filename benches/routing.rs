@@ -0,0 +1,120 @@
+//! Routing throughput of rabboe with a single worker compared to several.
+//! Publishers broadcast objects that every subscriber receives.
+//!
+//! Run with `cargo bench --bench routing`.
+
+extern crate object_system;
+
+use std::net::{SocketAddr, TcpListener};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use object_system::{BusinessObject, Client, Payload};
+
+
+const PUBLISHERS: usize = 4;
+const SUBSCRIBERS: usize = 8;
+const OBJECTS_PER_PUBLISHER: usize = 5_000;
+const PAYLOAD_SIZE: usize = 128;
+
+
+struct Router(Child);
+
+
+impl Drop for Router {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+
+fn start_router(workers: usize) -> (Router, SocketAddr) {
+    let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let child = Command::new(env!("CARGO_BIN_EXE_rabboe"))
+        .args(["--listen", &addr.to_string(), "--workers", &workers.to_string(),
+                "--max-clients", "1024", "--max-queue-length", "1000000", "--idle-timeout", "3600",
+                "--log-level", "error"])
+        .stdout(Stdio::null())
+        .spawn()
+        .expect("Failed to start rabboe");
+    let router = Router(child);
+
+    for _ in 0 .. 100 {
+        if Client::connect(addr).is_ok() {
+            return (router, addr);
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    panic!("rabboe didn't start listening on {}", addr);
+}
+
+
+fn subscribed_client(addr: SocketAddr, rules: &[&str]) -> Client {
+    let mut client = Client::connect(addr).unwrap();
+    client.subscribe(rules).unwrap();
+    client
+}
+
+
+fn bench_object() -> BusinessObject {
+    BusinessObject {
+        _type: Some("application/octet-stream".to_string()),
+        payload: Some(Payload::Bytes(vec![42; PAYLOAD_SIZE].into())),
+        size: Some(PAYLOAD_SIZE),
+        event: Some("bench".to_string()),
+        metadata: Default::default(),
+    }
+}
+
+
+/// Returns routed objects per second, counting each delivery separately.
+fn measure(workers: usize) -> f64 {
+    let (_router, addr) = start_router(workers);
+    let expected = PUBLISHERS * OBJECTS_PER_PUBLISHER;
+    let start = Arc::new(Barrier::new(PUBLISHERS + SUBSCRIBERS + 1));
+
+    let subscribers: Vec<_> = (0 .. SUBSCRIBERS).map(|_| {
+        let mut client = subscribed_client(addr, &["@bench"]);
+        let start = start.clone();
+        thread::spawn(move || {
+            start.wait();
+            for _ in 0 .. expected {
+                client.receive().unwrap();
+            }
+        })
+    }).collect();
+
+    let publishers: Vec<_> = (0 .. PUBLISHERS).map(|_| {
+        let mut client = subscribed_client(addr, &["@nothing"]);
+        let start = start.clone();
+        thread::spawn(move || {
+            let object = bench_object();
+            start.wait();
+            for _ in 0 .. OBJECTS_PER_PUBLISHER {
+                client.send(&object).unwrap();
+            }
+        })
+    }).collect();
+
+    start.wait();
+    let started = Instant::now();
+    for thread in publishers.into_iter().chain(subscribers) {
+        thread.join().unwrap();
+    }
+
+    (expected * SUBSCRIBERS) as f64 / started.elapsed().as_secs_f64()
+}
+
+
+fn main() {
+    let baseline = measure(1);
+    println!("{} worker(s): {:>10.0} deliveries/s", 1, baseline);
+
+    for &workers in &[2, 4] {
+        let throughput = measure(workers);
+        println!("{} worker(s): {:>10.0} deliveries/s ({:.2}x)", workers, throughput, throughput / baseline);
+    }
+}
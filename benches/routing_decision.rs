@@ -0,0 +1,88 @@
+//! Time taken by `routing_decision` as subscriptions grow, and by the
+//! compiled `SubscriptionMatcher` with and without its cache.
+//!
+//! Run with `cargo bench --bench routing_decision`.
+
+extern crate object_system;
+extern crate rustc_serialize;
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use rustc_serialize::json::ToJson;
+
+use object_system::subscription::{parse_subscription, routing_decision, BusinessSubscription, RoutingKey,
+                                  SubscriptionMatcher};
+
+
+const MEASUREMENT_TIME: Duration = Duration::from_millis(500);
+
+
+/// Runs `f` repeatedly for `MEASUREMENT_TIME` and reports the mean time
+/// per run.
+fn bench<T, F: FnMut() -> T>(name: &str, mut f: F) {
+    // Warm up caches and the allocator
+    let started = Instant::now();
+    while started.elapsed() < MEASUREMENT_TIME / 5 {
+        black_box(f());
+    }
+
+    let started = Instant::now();
+    let mut iterations = 0u64;
+    while started.elapsed() < MEASUREMENT_TIME {
+        for _ in 0 .. 100 {
+            black_box(f());
+        }
+        iterations += 100;
+    }
+    let nanos = started.elapsed().as_nanos() as f64 / iterations as f64;
+    println!("{:<50} {:>10.0} ns/iter", name, nanos);
+}
+
+
+/// `rules` rules of the given kind, none of which match the benchmarked
+/// object, followed by one that does.
+fn subscription(rules: usize, kind: &str) -> BusinessSubscription {
+    let mut list: Vec<String> = (0 .. rules).map(|i| match kind {
+        "event" => format!("@sensor/{}/*", i),
+        "nature" => format!("#nature-{}", i),
+        "type" => format!("image/format-{}", i),
+        "regex" => format!("r:^sensor/{}/.*$", i),
+        _ => unreachable!()
+    }).collect();
+    list.push("@chat/*".to_string());
+
+    parse_subscription(&list.to_json()).unwrap()
+}
+
+
+fn main() {
+    let natures = vec!["message", "bench"];
+
+    for &rules in &[1, 10, 100, 1000] {
+        for kind in &["event", "nature", "type", "regex"] {
+            let subscription = subscription(rules, kind);
+            bench(&format!("routing_decision ({} {} rules)", rules, kind), || {
+                routing_decision(Some(natures.clone()), Some("chat/message"), Some("text/plain"), &subscription)
+            });
+
+            let key = RoutingKey::new(&natures, Some("chat/message"), Some("text/plain"));
+            let matcher = SubscriptionMatcher::new(subscription.clone());
+            bench(&format!("matcher, cached ({} {} rules)", rules, kind), || matcher.matches(&key));
+            // More distinct keys than the cache holds, so that every lookup misses
+            let keys: Vec<RoutingKey> = (0 .. 4096).map(|i| {
+                RoutingKey::new(&natures, Some(&format!("chat/message/{}", i)), Some("text/plain"))
+            }).collect();
+            let mut next = 0;
+            bench(&format!("matcher, uncached ({} {} rules)", rules, kind), || {
+                next = (next + 1) % keys.len();
+                matcher.matches(&keys[next])
+            });
+        }
+    }
+
+    let negated = parse_subscription(&vec!["*".to_string(), "!@chat/*".to_string()].to_json()).unwrap();
+    bench("routing_decision (negated)", || {
+        routing_decision(Some(natures.clone()), Some("chat/message"), Some("text/plain"), &negated)
+    });
+}
@@ -0,0 +1,86 @@
+//! Time taken to serialize objects and to parse their headers.
+//!
+//! Run with `cargo bench --bench serialization`.
+
+extern crate object_system;
+extern crate rustc_serialize;
+
+use std::collections::BTreeMap;
+use std::hint::black_box;
+use std::io::Cursor;
+use std::time::{Duration, Instant};
+
+use rustc_serialize::json::{Json, ToJson};
+
+use object_system::{BusinessObject, Payload};
+use object_system::io::{BusinessObjectStream, CBOR, JSON};
+
+
+const MEASUREMENT_TIME: Duration = Duration::from_millis(500);
+
+
+/// Runs `f` repeatedly for `MEASUREMENT_TIME` and reports the mean time
+/// per run.
+fn bench<T, F: FnMut() -> T>(name: &str, mut f: F) {
+    // Warm up caches and the allocator
+    let started = Instant::now();
+    while started.elapsed() < MEASUREMENT_TIME / 5 {
+        black_box(f());
+    }
+
+    let started = Instant::now();
+    let mut iterations = 0u64;
+    while started.elapsed() < MEASUREMENT_TIME {
+        for _ in 0 .. 100 {
+            black_box(f());
+        }
+        iterations += 100;
+    }
+    let nanos = started.elapsed().as_nanos() as f64 / iterations as f64;
+    println!("{:<48} {:>10.0} ns/iter", name, nanos);
+}
+
+
+fn object(metadata_keys: usize, payload_size: usize) -> BusinessObject {
+    let mut metadata = BTreeMap::new();
+    for i in 0 .. metadata_keys {
+        metadata.insert(format!("key-{}", i), format!("value {}", i).to_json());
+    }
+    metadata.insert("natures".to_string(), vec!["bench".to_string(), "message".to_string()].to_json());
+
+    BusinessObject {
+        _type: Some("text/plain; charset=utf-8".to_string()),
+        payload: Some(Payload::Text("x".repeat(payload_size), None)),
+        size: Some(payload_size),
+        event: Some("chat/message".to_string()),
+        metadata: metadata.into(),
+    }
+}
+
+
+fn main() {
+    for &(metadata_keys, payload_size) in &[(0, 16), (8, 1024), (64, 64 * 1024)] {
+        let object = object(metadata_keys, payload_size);
+        let label = format!("{} keys, payload {} B", metadata_keys, payload_size);
+
+        bench(&format!("to_bytes ({})", label), || object.to_bytes());
+
+        let header = object.to_json().to_string();
+        bench(&format!("from_json ({})", label), || {
+            BusinessObject::from_json(&Json::from_str(&header).unwrap()).unwrap()
+        });
+
+        for encoding in &[&JSON as &_, &CBOR as &_] {
+            let mut stream = BusinessObjectStream::new(Cursor::new(Vec::new()));
+            stream.set_encoding(*encoding);
+            let bytes = stream.encode(&object);
+            bench(&format!("encode {} ({})", encoding.name(), label), || stream.encode(&object));
+
+            let mut reader = BusinessObjectStream::new(Cursor::new(Vec::new()));
+            bench(&format!("next_object {} ({})", encoding.name(), label), || {
+                reader.socket = Cursor::new(bytes.clone());
+                reader.next_object().is_ok()
+            });
+        }
+    }
+}
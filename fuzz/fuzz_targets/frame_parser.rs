@@ -0,0 +1,62 @@
+//! Feeds arbitrary bytes to the frame parser, which must fail cleanly
+//! rather than panic or hang however malformed they are. The first byte
+//! picks the size of the fragments the rest arrives in.
+//!
+//! Run with `cargo +nightly fuzz run frame_parser` in this directory.
+
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate object_system;
+
+use std::cmp;
+use std::io::{Cursor, Read, Write};
+use std::io;
+
+use object_system::io::{BusinessObjectStream, NextObject, ReadBusinessObject};
+
+
+struct Fragmented {
+    data: Cursor<Vec<u8>>,
+    chunk: usize,
+}
+
+
+impl Read for Fragmented {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = cmp::min(self.chunk, buf.len());
+        self.data.read(&mut buf[.. n])
+    }
+}
+
+
+impl Write for Fragmented {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> { Ok(buf.len()) }
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+
+fuzz_target!(|data: &[u8]| {
+    let (chunk, bytes) = match data.split_first() {
+        Some((&chunk, bytes)) => (chunk as usize + 1, bytes.to_vec()),
+        None => { return; }
+    };
+
+    // As the router reads: non-blocking, with limits
+    let mut stream = BusinessObjectStream::new(Fragmented { data: Cursor::new(bytes.clone()), chunk });
+    stream.set_max_header_size(Some(64 * 1024));
+    stream.set_max_payload_size(Some(1024 * 1024));
+    while !stream.at_eof() {
+        if stream.read_business_objects().is_err() {
+            break;
+        }
+    }
+
+    // As clients read: blocking, streaming large payloads
+    let mut stream = BusinessObjectStream::new(Fragmented { data: Cursor::new(bytes), chunk });
+    stream.set_streaming_threshold(Some(256));
+    while let Ok(next) = stream.next_object() {
+        if let NextObject::Streamed(_, mut reader) = next {
+            let _ = reader.read_to_end(&mut Vec::new());
+        }
+    }
+});
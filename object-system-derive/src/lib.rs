@@ -0,0 +1,75 @@
+//! `#[derive(BusinessObject)]`, implementing `ToBusinessObject` and
+//! `FromBusinessObject` of `object_system::mapping` for a struct with named
+//! fields, each of them `ToJson` and `Decodable`. An optional
+//! `#[business_object(event = "...")]` gives the event of its objects.
+
+extern crate proc_macro;
+extern crate proc_macro2;
+#[macro_use] extern crate quote;
+extern crate syn;
+
+use proc_macro::TokenStream;
+use syn::{parse_macro_input, Data, DeriveInput, Fields, LitStr};
+
+
+#[proc_macro_derive(BusinessObject, attributes(business_object))]
+pub fn derive_business_object(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+
+fn expand(input: &DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let mut event: Option<LitStr> = None;
+    for attribute in input.attrs.iter().filter(|attribute| attribute.path().is_ident("business_object")) {
+        attribute.parse_nested_meta(|meta| {
+            if meta.path.is_ident("event") {
+                event = Some(meta.value()?.parse()?);
+                Ok(())
+            } else {
+                Err(meta.error("expected `event = \"...\"`"))
+            }
+        })?;
+    }
+    let event = match event {
+        Some(event) => quote!(Some(#event)),
+        None => quote!(None),
+    };
+
+    let fields = match input.data {
+        Data::Struct(ref data) => match data.fields {
+            Fields::Named(ref fields) => &fields.named,
+            _ => { return Err(syn::Error::new_spanned(&input.ident, "BusinessObject needs named fields")); }
+        },
+        _ => { return Err(syn::Error::new_spanned(&input.ident, "BusinessObject can only be derived for structs")); }
+    };
+    let idents: Vec<&syn::Ident> = fields.iter().map(|field| field.ident.as_ref().unwrap()).collect();
+    let names: Vec<String> = idents.iter().map(|ident| ident.to_string()).collect();
+
+    let name = &input.ident;
+    let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::object_system::mapping::ToBusinessObject for #name #type_generics #where_clause {
+            fn to_business_object(&self) -> ::object_system::BusinessObject {
+                let mut fields = ::std::collections::BTreeMap::new();
+                #(
+                    fields.insert(#names.to_string(), ::object_system::mapping::__ToJson::to_json(&self.#idents));
+                )*
+                ::object_system::mapping::to_object(#event, ::object_system::mapping::__Json::Object(fields))
+            }
+        }
+
+        impl #impl_generics ::object_system::mapping::FromBusinessObject for #name #type_generics #where_clause {
+            fn from_business_object(object: &::object_system::BusinessObject)
+                                    -> ::std::result::Result<Self, ::object_system::mapping::MappingError> {
+                let fields = ::object_system::mapping::payload_fields(#event, object)?;
+                ::std::result::Result::Ok(#name {
+                    #( #idents: ::object_system::mapping::field(&fields, #names)?, )*
+                })
+            }
+        }
+    })
+}
@@ -0,0 +1,212 @@
+//! Access control lists deciding which objects a client may publish and
+//! receive, by the identity it logged in as and the address it connects
+//! from.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use ::subscription::{BusinessSubscription, RoutingKey, SubscriptionMatcher};
+
+
+/// A range of addresses such as `10.0.0.0/8` or `fd00::/8`. A plain address
+/// is a range of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+
+fn address_bits(address: &IpAddr) -> (u128, u8) {
+    match *address {
+        IpAddr::V4(address) => (u32::from(address) as u128, 32),
+        IpAddr::V6(address) => (u128::from(address), 128),
+    }
+}
+
+
+impl Cidr {
+    pub fn contains(&self, address: &IpAddr) -> bool {
+        if self.network.is_ipv4() != address.is_ipv4() {
+            return false;
+        }
+
+        let (network, width) = address_bits(&self.network);
+        let (address, _) = address_bits(address);
+        let shift = width - self.prefix;
+        shift == width || (network >> shift) == (address >> shift)
+    }
+}
+
+
+impl FromStr for Cidr {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Cidr, ()> {
+        let (network, prefix) = match s.split_once('/') {
+            Some((network, prefix)) => (network, Some(prefix)),
+            None => (s, None)
+        };
+        let network = IpAddr::from_str(network).map_err(|_| ())?;
+        let width = address_bits(&network).1;
+        let prefix = match prefix {
+            Some(prefix) => u8::from_str(prefix).map_err(|_| ())?,
+            None => width
+        };
+        if prefix > width {
+            return Err(());
+        }
+
+        Ok(Cidr { network, prefix })
+    }
+}
+
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+
+/// Grants the clients it applies to publishing and receiving the objects
+/// matching the subscription rules in `publish` and `subscribe`. A rule
+/// without `identity` or `peer` applies regardless of it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AclRule {
+    pub identity: Option<String>,
+    pub peer: Option<Cidr>,
+    pub publish: BusinessSubscription,
+    pub subscribe: BusinessSubscription,
+}
+
+
+impl AclRule {
+    pub fn applies_to(&self, identity: Option<&str>, peer: &IpAddr) -> bool {
+        self.identity.as_ref().is_none_or(|expected| Some(expected.as_ref()) == identity) &&
+            self.peer.as_ref().is_none_or(|range| range.contains(peer))
+    }
+}
+
+
+/// What the rules applying to one client allow it. Anything no rule
+/// allows is denied.
+#[derive(Debug, Clone)]
+pub struct Permissions {
+    publish: Vec<SubscriptionMatcher>,
+    subscribe: Vec<SubscriptionMatcher>,
+}
+
+
+impl Permissions {
+    /// The permissions of a client, or `None` if there are no rules and
+    /// everything is allowed.
+    pub fn new(rules: &[AclRule], identity: Option<&str>, peer: &IpAddr) -> Option<Permissions> {
+        if rules.is_empty() {
+            return None;
+        }
+
+        let applying: Vec<&AclRule> = rules.iter().filter(|rule| rule.applies_to(identity, peer)).collect();
+        Some(Permissions {
+            publish: applying.iter().map(|rule| SubscriptionMatcher::new(rule.publish.clone())).collect(),
+            subscribe: applying.iter().map(|rule| SubscriptionMatcher::new(rule.subscribe.clone())).collect(),
+        })
+    }
+
+    pub fn may_publish(&self, key: &RoutingKey) -> bool {
+        self.publish.iter().any(|matcher| matcher.matches(key))
+    }
+
+    pub fn may_receive(&self, key: &RoutingKey) -> bool {
+        self.subscribe.iter().any(|matcher| matcher.matches(key))
+    }
+
+    /// Whether any object at all may be received, which is required for
+    /// subscribing.
+    pub fn may_subscribe(&self) -> bool {
+        self.subscribe.iter().any(|matcher| match *matcher.subscription() {
+            BusinessSubscription::List(ref rules) => !rules.is_empty(),
+            _ => true
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use std::str::FromStr;
+
+    use rustc_serialize::json::ToJson;
+
+    use super::{AclRule, Cidr, Permissions};
+    use ::object::BusinessObject;
+    use ::subscription::{BusinessSubscription, RoutingKey, parse_subscription};
+
+
+    fn ip(address: &str) -> IpAddr {
+        IpAddr::from_str(address).unwrap()
+    }
+
+    fn rules(rules: &[&str]) -> BusinessSubscription {
+        let rules: Vec<String> = rules.iter().map(|rule| rule.to_string()).collect();
+        parse_subscription(&rules.to_json()).unwrap()
+    }
+
+    fn rule(identity: Option<&str>, peer: Option<&str>, publish: &[&str], subscribe: &[&str]) -> AclRule {
+        AclRule {
+            identity: identity.map(|identity| identity.to_string()),
+            peer: peer.map(|peer| Cidr::from_str(peer).unwrap()),
+            publish: rules(publish),
+            subscribe: rules(subscribe),
+        }
+    }
+
+    fn event(event: &str) -> RoutingKey {
+        RoutingKey::of(&BusinessObject::event(event))
+    }
+
+    #[test]
+    fn cidr_should_contain_addresses_in_range() {
+        let range = Cidr::from_str("10.1.0.0/16").unwrap();
+        assert!(range.contains(&ip("10.1.2.3")));
+        assert!(!range.contains(&ip("10.2.0.1")));
+        assert!(!range.contains(&ip("::1")));
+
+        assert!(Cidr::from_str("0.0.0.0/0").unwrap().contains(&ip("192.168.1.1")));
+        assert!(Cidr::from_str("fd00::/8").unwrap().contains(&ip("fd12::1")));
+        assert!(Cidr::from_str("127.0.0.1").unwrap().contains(&ip("127.0.0.1")));
+        assert!(!Cidr::from_str("127.0.0.1").unwrap().contains(&ip("127.0.0.2")));
+
+        for invalid in &["10.0.0.0/33", "::/129", "10.0.0/8", "10.0.0.0/x", "localhost"] {
+            assert_eq!(Err(()), Cidr::from_str(invalid));
+        }
+    }
+
+    #[test]
+    fn permissions_should_combine_applying_rules() {
+        let rules = vec![rule(None, Some("10.0.0.0/8"), &["@chat/*"], &["@chat/*"]),
+                         rule(Some("admin"), None, &["*"], &["*"])];
+
+        assert!(Permissions::new(&[], None, &ip("10.0.0.1")).is_none());
+
+        let local = Permissions::new(&rules, None, &ip("10.0.0.1")).unwrap();
+        assert!(local.may_publish(&event("chat/message")));
+        assert!(!local.may_publish(&event("admin/shutdown")));
+        assert!(local.may_receive(&event("chat/message")));
+        assert!(local.may_subscribe());
+
+        let outsider = Permissions::new(&rules, None, &ip("192.168.0.1")).unwrap();
+        assert!(!outsider.may_publish(&event("chat/message")));
+        assert!(!outsider.may_subscribe());
+
+        let admin = Permissions::new(&rules, Some("admin"), &ip("192.168.0.1")).unwrap();
+        assert!(admin.may_publish(&event("admin/shutdown")));
+        assert!(admin.may_receive(&event("admin/shutdown")));
+
+        let publisher = Permissions::new(&[rule(None, None, &["@sensor/*"], &[])], None, &ip("::1")).unwrap();
+        assert!(publisher.may_publish(&event("sensor/reading")));
+        assert!(!publisher.may_subscribe());
+    }
+}
@@ -0,0 +1,202 @@
+//! An archive of routed objects in an SQLite database, for operators to
+//! run ad-hoc SQL over what has passed through a bus. Each object is a row
+//! of `objects`:
+//!
+//! - `routed`: when it was archived, in milliseconds since the epoch
+//! - `event`, `type`: those of the object
+//! - `natures`: its natures as a JSON array
+//! - `metadata`: its whole header as JSON, for `json_extract`
+//! - `payload`: its payload as it was sent
+//!
+//! For example, `SELECT event, count(*) FROM objects, json_each(natures)
+//! WHERE json_each.value = 'image' GROUP BY event`.
+//!
+//! The router archives what its clients publish with `archive` set, and
+//! `rabboe-archive` what it subscribes to as a client.
+
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use rusqlite::{self, Connection};
+use rustc_serialize::json::{Json, ToJson};
+
+use ::filter::Filter;
+use ::middleware::{Context, Middleware, Verdict};
+use ::object::{BusinessObject, Payload};
+use ::storage::{Records, Storage};
+use ::timestamp::Timestamp;
+
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS objects (
+        id INTEGER PRIMARY KEY,
+        routed INTEGER NOT NULL,
+        event TEXT,
+        type TEXT,
+        natures TEXT,
+        metadata TEXT NOT NULL,
+        payload BLOB
+    );
+    CREATE INDEX IF NOT EXISTS objects_routed ON objects (routed);
+    CREATE INDEX IF NOT EXISTS objects_event ON objects (event);
+";
+
+
+fn to_io_error(e: rusqlite::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+
+pub struct Archive {
+    connection: Connection,
+}
+
+
+impl Archive {
+    /// Opens the archive in the database at `path`, creating it if needed.
+    pub fn open<P: AsRef<Path>>(path: P) -> rusqlite::Result<Archive> {
+        Archive::with_connection(Connection::open(path)?)
+    }
+
+    /// An archive kept in memory, gone once dropped.
+    pub fn in_memory() -> rusqlite::Result<Archive> {
+        Archive::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(connection: Connection) -> rusqlite::Result<Archive> {
+        connection.execute_batch(SCHEMA)?;
+        Ok(Archive { connection })
+    }
+
+    /// The database, for queries of one's own.
+    pub fn connection(&self) -> &Connection {
+        &self.connection
+    }
+
+    /// Archives `object` as routed at `routed`.
+    pub fn insert(&self, object: &BusinessObject, routed: Timestamp) -> rusqlite::Result<()> {
+        let natures = object.metadata.natures.as_ref().map(|natures| natures.to_json().to_string());
+        let payload = object.payload.as_ref().map(|payload| payload.to_bytes(object.content_type().as_ref()));
+        self.connection.execute(
+            "INSERT INTO objects (routed, event, type, natures, metadata, payload) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            rusqlite::params![routed.as_millis(), object.event, object._type, natures, object.to_json().to_string(),
+                              payload.as_deref()])?;
+        Ok(())
+    }
+
+    /// The objects archived after `since`, or all of them, oldest first.
+    pub fn select(&self, since: Option<Timestamp>) -> rusqlite::Result<Vec<(Timestamp, BusinessObject)>> {
+        let mut statement = self.connection.prepare(
+            "SELECT routed, metadata, payload FROM objects WHERE routed > ?1 ORDER BY routed, id")?;
+        let rows = statement.query_map([since.map_or(i64::MIN, |since| since.as_millis())], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, Option<Vec<u8>>>(2)?))
+        })?;
+
+        let mut objects = Vec::new();
+        for row in rows {
+            let (routed, metadata, payload) = row?;
+            match object_from_row(&metadata, payload) {
+                Some(object) => objects.push((Timestamp::from_millis(routed), object)),
+                None => { warn!("Skipping unreadable archived object: {}", metadata); }
+            }
+        }
+        Ok(objects)
+    }
+
+    /// The objects `select` returns that `filter` matches.
+    pub fn select_matching(&self, since: Option<Timestamp>, filter: &Filter)
+                           -> rusqlite::Result<Vec<(Timestamp, BusinessObject)>> {
+        let mut objects = self.select(since)?;
+        objects.retain(|(_, object)| filter.matches(object));
+        Ok(objects)
+    }
+}
+
+
+fn object_from_row(metadata: &str, payload: Option<Vec<u8>>) -> Option<BusinessObject> {
+    let mut object = Json::from_str(metadata).ok().and_then(|json| BusinessObject::from_json(&json).ok())?;
+    object.payload = payload.map(|payload| Payload::decode(object.content_type().as_ref(), payload.into()));
+    Some(object)
+}
+
+
+impl Storage for Archive {
+    fn append(&mut self, object: Arc<BusinessObject>, routed: Timestamp) -> io::Result<()> {
+        self.insert(&object, routed).map_err(to_io_error)
+    }
+
+    fn scan(&mut self, since: Option<Timestamp>, _now: Timestamp) -> io::Result<Records> {
+        Ok(self.select(since).map_err(to_io_error)?.into_iter()
+           .map(|(routed, object)| (routed, Arc::new(object)))
+           .collect())
+    }
+}
+
+
+/// Archives the objects clients publish as they reach it, so it is best
+/// added after the middlewares that might drop them.
+pub struct Archiver {
+    archive: Mutex<Archive>,
+}
+
+
+impl Archiver {
+    pub fn new(archive: Archive) -> Archiver {
+        Archiver { archive: Mutex::new(archive) }
+    }
+}
+
+
+impl Middleware for Archiver {
+    fn inbound(&self, _context: &Context, object: Arc<BusinessObject>) -> Verdict {
+        if let Err(e) = self.archive.lock().unwrap().insert(&object, Timestamp::now()) {
+            warn!("Failed to archive object: {}", e);
+        }
+        Verdict::Pass(object)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::Archive;
+    use ::content_type::ContentType;
+    use ::filter::Filter;
+    use ::nature::Nature;
+    use ::object::{BusinessObject, Payload};
+    use ::timestamp::Timestamp;
+
+
+    fn object(event: &str, text: &str) -> BusinessObject {
+        BusinessObject {
+            _type: Some("text/plain".to_string()),
+            payload: Some(Payload::decode(ContentType::parse("text/plain").as_ref(), text.as_bytes().to_vec().into())),
+            size: Some(text.len()),
+            event: Some(event.to_string()),
+            metadata: Default::default(),
+        }
+    }
+
+    #[test]
+    fn archived_objects_should_be_queryable_and_read_back() {
+        let archive = Archive::in_memory().unwrap();
+        let mut image = object("files/upload", "not really a png");
+        image.add_nature(Nature::IMAGE);
+        image.set_meta("name", "cat.png");
+        archive.insert(&object("chat/message", "hello"), Timestamp::from_secs(1)).unwrap();
+        archive.insert(&image, Timestamp::from_secs(2)).unwrap();
+
+        let name: String = archive.connection().query_row(
+            "SELECT json_extract(metadata, '$.name') FROM objects, json_each(natures) WHERE json_each.value = 'image'",
+            [], |row| row.get(0)).unwrap();
+        assert_eq!("cat.png", name);
+
+        assert_eq!(vec![(Timestamp::from_secs(1), object("chat/message", "hello")), (Timestamp::from_secs(2), image)],
+                   archive.select(None).unwrap());
+        assert_eq!(1, archive.select(Some(Timestamp::from_secs(1))).unwrap().len());
+        let chat = Filter::parse(r#""chat" in event"#).unwrap();
+        assert_eq!(vec![(Timestamp::from_secs(1), object("chat/message", "hello"))],
+                   archive.select_matching(None, &chat).unwrap());
+    }
+}
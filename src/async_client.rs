@@ -0,0 +1,270 @@
+//! A client for async code: a `Stream` of the objects the router sends and
+//! a `Sink` for those to publish, so that they work with the combinators of
+//! `futures`:
+//!
+//! ```ignore
+//! let mut client = AsyncClient::connect("127.0.0.1:7890").await?;
+//! client.subscribe(&["@chat/*"]).await?;
+//! while let Some(object) = client.next().await {
+//!     println!("{:?}", object.event);
+//! }
+//! ```
+//!
+//! Objects are published with the `send` and `send_all` of `SinkExt`. The
+//! stream ends when the connection does. If it ended on an error,
+//! `take_error` tells which.
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_core::Stream;
+use futures_sink::Sink;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+use ::async_io::AsyncBusinessObjectStream;
+use ::error::ReadBusinessObjectError;
+use ::events::Event;
+use ::object::BusinessObject;
+
+
+// Queued bytes past which the sink waits for them to be written
+const MAX_PENDING_BYTES: usize = 64 * 1024;
+
+
+pub struct AsyncClient<S: AsyncRead + AsyncWrite + Unpin> {
+    stream: AsyncBusinessObjectStream<S>,
+    // Objects that arrived while waiting for a reply, returned first
+    inbox: VecDeque<BusinessObject>,
+    // Why the stream ended, if it was an error
+    error: Option<ReadBusinessObjectError>,
+    ended: bool,
+}
+
+
+/// Resolves to a client connected to the router.
+pub struct Connect {
+    connecting: Pin<Box<dyn Future<Output = io::Result<TcpStream>> + Send>>,
+}
+
+
+/// Resolves to the router's reply to a subscription.
+pub struct Subscribe<'a, S: 'a + AsyncRead + AsyncWrite + Unpin> {
+    client: &'a mut AsyncClient<S>,
+    sent: bool,
+}
+
+
+impl AsyncClient<TcpStream> {
+    pub fn connect<A: 'static + ToSocketAddrs + Send>(address: A) -> Connect {
+        Connect { connecting: Box::pin(TcpStream::connect(address)) }
+    }
+}
+
+
+impl <S: AsyncRead + AsyncWrite + Unpin> AsyncClient<S> {
+    /// A client talking to a router over `stream`.
+    pub fn new(stream: S) -> AsyncClient<S> {
+        AsyncClient {
+            stream: AsyncBusinessObjectStream::new(stream),
+            inbox: VecDeque::new(),
+            error: None,
+            ended: false,
+        }
+    }
+
+    pub fn get_ref(&self) -> &AsyncBusinessObjectStream<S> {
+        &self.stream
+    }
+
+    pub fn get_mut(&mut self) -> &mut AsyncBusinessObjectStream<S> {
+        &mut self.stream
+    }
+
+    /// Sends a `routing/subscribe` with the given rules, resolving to the
+    /// router's reply. Objects arriving before it are kept for the stream.
+    pub fn subscribe(&mut self, rules: &[&str]) -> Subscribe<'_, S> {
+        let mut request = BusinessObject::event(Event::RoutingSubscribe).with_new_id();
+        let rules: Vec<String> = rules.iter().map(|rule| rule.to_string()).collect();
+        request.set_meta("subscriptions", &rules);
+        self.stream.queue_object(&request);
+        Subscribe { client: self, sent: false }
+    }
+
+    /// The error the stream ended on, if any.
+    pub fn take_error(&mut self) -> Option<ReadBusinessObjectError> {
+        self.error.take()
+    }
+
+    /// The next object from the router, answering its pings on the way.
+    fn poll_receive(&mut self, cx: &mut Context<'_>) -> Poll<Result<Option<BusinessObject>, ReadBusinessObjectError>> {
+        loop {
+            let object = match self.stream.poll_read_object(cx) {
+                Poll::Ready(Ok(Some(object))) => object,
+                other => { return other; }
+            };
+            if !object.is_event(Event::Ping) {
+                return Poll::Ready(Ok(Some(object)));
+            }
+
+            let mut pong = BusinessObject::reply_to(&object);
+            pong.event = Some(Event::Pong.to_string());
+            self.stream.queue_object(&pong);
+            // Whatever is left is written with the next flush
+            if let Poll::Ready(Err(e)) = self.stream.poll_flush(cx) {
+                return Poll::Ready(Err(ReadBusinessObjectError::ReadError(e)));
+            }
+        }
+    }
+}
+
+
+impl Future for Connect {
+    type Output = io::Result<AsyncClient<TcpStream>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.connecting.as_mut().poll(cx) {
+            Poll::Ready(Ok(socket)) => {
+                socket.set_nodelay(true)?;
+                Poll::Ready(Ok(AsyncClient::new(socket)))
+            },
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending
+        }
+    }
+}
+
+
+impl <'a, S: AsyncRead + AsyncWrite + Unpin> Future for Subscribe<'a, S> {
+    type Output = Result<BusinessObject, ReadBusinessObjectError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if !self.sent {
+            match self.client.stream.poll_flush(cx) {
+                Poll::Ready(Ok(())) => { self.sent = true; },
+                Poll::Ready(Err(e)) => { return Poll::Ready(Err(ReadBusinessObjectError::ReadError(e))); },
+                Poll::Pending => { return Poll::Pending; }
+            }
+        }
+
+        loop {
+            let object = match self.client.poll_receive(cx) {
+                Poll::Ready(Ok(Some(object))) => object,
+                Poll::Ready(Ok(None)) => {
+                    return Poll::Ready(Err(ReadBusinessObjectError::ReadError(
+                        io::Error::new(io::ErrorKind::UnexpectedEof, "Disconnected before subscribing"))));
+                },
+                Poll::Ready(Err(e)) => { return Poll::Ready(Err(e)); },
+                Poll::Pending => { return Poll::Pending; }
+            };
+            if object.is_event(Event::RoutingSubscribeReply) {
+                return Poll::Ready(Ok(object));
+            }
+            self.client.inbox.push_back(object);
+        }
+    }
+}
+
+
+impl <S: AsyncRead + AsyncWrite + Unpin> Stream for AsyncClient<S> {
+    type Item = BusinessObject;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<BusinessObject>> {
+        let client = self.get_mut();
+        if let Some(object) = client.inbox.pop_front() {
+            return Poll::Ready(Some(object));
+        }
+        if client.ended {
+            return Poll::Ready(None);
+        }
+
+        match client.poll_receive(cx) {
+            Poll::Ready(Ok(Some(object))) => Poll::Ready(Some(object)),
+            Poll::Ready(Ok(None)) => {
+                client.ended = true;
+                Poll::Ready(None)
+            },
+            Poll::Ready(Err(e)) => {
+                debug!("Connection to router failed: {}", e);
+                client.error = Some(e);
+                client.ended = true;
+                Poll::Ready(None)
+            },
+            Poll::Pending => Poll::Pending
+        }
+    }
+}
+
+
+impl <S: AsyncRead + AsyncWrite + Unpin> Sink<BusinessObject> for AsyncClient<S> {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let client = self.get_mut();
+        if client.stream.pending_bytes() < MAX_PENDING_BYTES {
+            return Poll::Ready(Ok(()));
+        }
+        client.stream.poll_flush(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, object: BusinessObject) -> io::Result<()> {
+        self.get_mut().stream.queue_object(&object);
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().stream.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let client = self.get_mut();
+        match client.stream.poll_flush(cx) {
+            Poll::Ready(Ok(())) => Pin::new(client.stream.get_mut()).poll_shutdown(cx),
+            other => other
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use futures::{SinkExt, StreamExt, stream};
+    use tokio::io::duplex;
+
+    use super::AsyncClient;
+    use ::async_io::AsyncBusinessObjectStream;
+    use ::events::Event;
+    use ::object::BusinessObject;
+
+
+    #[test]
+    fn should_stream_objects_and_take_them_as_a_sink() {
+        let (a, b) = duplex(1024);
+        let mut client = AsyncClient::new(a);
+        let mut router = AsyncBusinessObjectStream::new(b);
+
+        // The router answers the subscription after an object and a ping
+        router.queue_object(&BusinessObject::event("chat/early"));
+        router.queue_object(&BusinessObject::event(Event::Ping).with_new_id());
+        router.queue_object(&BusinessObject::event(Event::RoutingSubscribeReply));
+        block_on(router.flush()).unwrap();
+        block_on(client.subscribe(&["@chat/*"])).unwrap();
+        assert!(block_on(router.read_object()).unwrap().unwrap().is_event(Event::RoutingSubscribe));
+        assert!(block_on(router.read_object()).unwrap().unwrap().is_event(Event::Pong));
+
+        let mut objects = stream::iter(vec![BusinessObject::event("chat/a"), BusinessObject::event("chat/b")]).map(Ok);
+        block_on(client.send_all(&mut objects)).unwrap();
+        assert_eq!(Some("chat/a".to_string()), block_on(router.read_object()).unwrap().unwrap().event);
+        assert_eq!(Some("chat/b".to_string()), block_on(router.read_object()).unwrap().unwrap().event);
+
+        block_on(router.write_object(&BusinessObject::event("chat/late"))).unwrap();
+        drop(router);
+        let received: Vec<BusinessObject> = block_on(client.by_ref().collect());
+        assert_eq!(vec![BusinessObject::event("chat/early"), BusinessObject::event("chat/late")], received);
+        assert!(client.take_error().is_none());
+    }
+}
@@ -0,0 +1,307 @@
+//! Objects over tokio's `AsyncRead` and `AsyncWrite`, so that TCP, TLS,
+//! Unix sockets and in-memory duplex streams all get the same framing. The
+//! framing is `BusinessObjectStream`'s: bytes read from the async stream
+//! are handed to it through an in-memory pipe, and objects it writes are
+//! collected there until the async stream takes them.
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io::{Read, Write};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use ::compression::Compression;
+use ::error::ReadBusinessObjectError;
+use ::io::{BusinessObjectStream, Encoding, HeaderDecoding, ReadBusinessObject};
+use ::object::BusinessObject;
+
+
+const READ_BUF_SIZE: usize = 64 * 1024;
+
+
+/// What the framing reads from and writes to in place of a socket.
+struct Pipe {
+    incoming: Vec<u8>,
+    // Start of the part of `incoming` not yet read
+    read_position: usize,
+    // The async stream has ended, so reads past `incoming` find EOF
+    closed: bool,
+    outgoing: Vec<u8>,
+}
+
+
+impl Read for Pipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = &self.incoming[self.read_position ..];
+        if available.is_empty() {
+            return if self.closed { Ok(0) } else { Err(io::ErrorKind::WouldBlock.into()) };
+        }
+
+        let n = cmp::min(buf.len(), available.len());
+        buf[.. n].copy_from_slice(&available[.. n]);
+        self.read_position += n;
+        if self.read_position == self.incoming.len() {
+            self.incoming.clear();
+            self.read_position = 0;
+        }
+        Ok(n)
+    }
+}
+
+
+impl Write for Pipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outgoing.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+
+/// The async counterpart of `BusinessObjectStream`.
+pub struct AsyncBusinessObjectStream<S: AsyncRead + AsyncWrite + Unpin> {
+    stream: S,
+    framing: BusinessObjectStream<Pipe>,
+    // Objects read but not yet returned
+    ready: VecDeque<BusinessObject>,
+    // Start of the part of the pipe's outgoing bytes not yet written
+    written: usize,
+    read_buffer: Vec<u8>,
+}
+
+
+/// Resolves to the next object, or `None` once the stream has ended.
+pub struct ReadObject<'a, S: 'a + AsyncRead + AsyncWrite + Unpin> {
+    stream: &'a mut AsyncBusinessObjectStream<S>,
+}
+
+
+/// Resolves once everything queued has been written and flushed.
+pub struct Flush<'a, S: 'a + AsyncRead + AsyncWrite + Unpin> {
+    stream: &'a mut AsyncBusinessObjectStream<S>,
+}
+
+
+impl <S: AsyncRead + AsyncWrite + Unpin> AsyncBusinessObjectStream<S> {
+    pub fn new(stream: S) -> AsyncBusinessObjectStream<S> {
+        let pipe = Pipe { incoming: Vec::new(), read_position: 0, closed: false, outgoing: Vec::new() };
+        AsyncBusinessObjectStream {
+            stream,
+            framing: BusinessObjectStream::new(pipe),
+            ready: VecDeque::new(),
+            written: 0,
+            read_buffer: vec![0; READ_BUF_SIZE],
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    /// See `BusinessObjectStream::set_max_header_size`.
+    pub fn set_max_header_size(&mut self, limit: Option<usize>) {
+        self.framing.set_max_header_size(limit);
+    }
+
+    /// See `BusinessObjectStream::set_max_payload_size`.
+    pub fn set_max_payload_size(&mut self, limit: Option<usize>) {
+        self.framing.set_max_payload_size(limit);
+    }
+
+    /// See `BusinessObjectStream::set_verify_checksums`.
+    pub fn set_verify_checksums(&mut self, verify: bool) {
+        self.framing.set_verify_checksums(verify);
+    }
+
+    /// See `BusinessObjectStream::set_header_decoding`.
+    pub fn set_header_decoding(&mut self, decoding: HeaderDecoding) {
+        self.framing.set_header_decoding(decoding);
+    }
+
+    /// See `BusinessObjectStream::set_compression`.
+    pub fn set_compression(&mut self, compression: Option<Compression>) {
+        self.framing.set_compression(compression);
+    }
+
+    /// See `BusinessObjectStream::set_encoding`.
+    pub fn set_encoding(&mut self, encoding: &'static dyn Encoding) {
+        self.framing.set_encoding(encoding);
+    }
+
+    pub fn bytes_read(&self) -> u64 {
+        self.framing.bytes_read()
+    }
+
+    /// Bytes of queued objects not yet written.
+    pub fn pending_bytes(&self) -> usize {
+        self.framing.socket.outgoing.len() - self.written
+    }
+
+    pub fn read_object(&mut self) -> ReadObject<'_, S> {
+        ReadObject { stream: self }
+    }
+
+    /// Queues `object` to be written by the next flush, which the returned
+    /// future is.
+    pub fn write_object(&mut self, object: &BusinessObject) -> Flush<'_, S> {
+        self.queue_object(object);
+        self.flush()
+    }
+
+    pub fn queue_object(&mut self, object: &BusinessObject) {
+        let bytes = self.framing.encode(object);
+        self.framing.socket.outgoing.extend_from_slice(&bytes);
+    }
+
+    pub fn flush(&mut self) -> Flush<'_, S> {
+        Flush { stream: self }
+    }
+
+    pub fn poll_read_object(&mut self, cx: &mut Context<'_>) -> Poll<Result<Option<BusinessObject>, ReadBusinessObjectError>> {
+        loop {
+            if let Some(object) = self.ready.pop_front() {
+                return Poll::Ready(Ok(Some(object)));
+            }
+            if let Some(e) = self.framing.take_frame_error() {
+                return Poll::Ready(Err(e));
+            }
+            if self.framing.at_eof() {
+                return Poll::Ready(Ok(None));
+            }
+
+            let n = {
+                let mut buf = ReadBuf::new(&mut self.read_buffer);
+                match Pin::new(&mut self.stream).poll_read(cx, &mut buf) {
+                    Poll::Ready(Ok(())) => buf.filled().len(),
+                    Poll::Ready(Err(e)) => { return Poll::Ready(Err(ReadBusinessObjectError::ReadError(e))); },
+                    Poll::Pending => { return Poll::Pending; }
+                }
+            };
+            if n == 0 {
+                self.framing.socket.closed = true;
+            } else {
+                self.framing.socket.incoming.extend_from_slice(&self.read_buffer[.. n]);
+            }
+
+            // The framing reads at most a buffer's worth at a time
+            loop {
+                let objects = self.framing.read_business_objects()?;
+                self.ready.extend(objects);
+                let pipe = &self.framing.socket;
+                if pipe.incoming.is_empty() && (!pipe.closed || self.framing.at_eof()) {
+                    break;
+                }
+            }
+        }
+    }
+
+    pub fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.framing.socket.outgoing.len() {
+            let pending = &self.framing.socket.outgoing[self.written ..];
+            match Pin::new(&mut self.stream).poll_write(cx, pending) {
+                Poll::Ready(Ok(0)) => { return Poll::Ready(Err(io::ErrorKind::WriteZero.into())); },
+                Poll::Ready(Ok(n)) => { self.written += n; },
+                Poll::Ready(Err(e)) => { return Poll::Ready(Err(e)); },
+                Poll::Pending => { return Poll::Pending; }
+            }
+        }
+        self.framing.socket.outgoing.clear();
+        self.written = 0;
+
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+}
+
+
+impl <'a, S: AsyncRead + AsyncWrite + Unpin> Future for ReadObject<'a, S> {
+    type Output = Result<Option<BusinessObject>, ReadBusinessObjectError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.stream.poll_read_object(cx)
+    }
+}
+
+
+impl <'a, S: AsyncRead + AsyncWrite + Unpin> Future for Flush<'a, S> {
+    type Output = io::Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.stream.poll_flush(cx)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::task::{Context, Poll, Waker};
+
+    use tokio::io::duplex;
+
+    use super::AsyncBusinessObjectStream;
+    use ::io::CBOR;
+    use ::object::{BusinessObject, Payload};
+
+
+    fn object(n: usize) -> BusinessObject {
+        BusinessObject {
+            _type: Some("text/plain".to_string()),
+            payload: Some(Payload::Text("x".repeat(n * 100), None)),
+            size: Some(n * 100),
+            event: Some(format!("test/{}", n)),
+            metadata: Default::default(),
+        }
+    }
+
+    #[test]
+    fn should_read_what_is_written_through_a_narrow_duplex() {
+        // Small enough that every object takes many reads and writes
+        let (a, b) = duplex(64);
+        let mut writer = AsyncBusinessObjectStream::new(a);
+        let mut reader = AsyncBusinessObjectStream::new(b);
+        writer.set_encoding(&CBOR);
+
+        let objects: Vec<BusinessObject> = (1 .. 6).map(object).collect();
+        for object in &objects {
+            writer.queue_object(object);
+        }
+
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut flushed = false;
+        let mut read = Vec::new();
+        while read.len() < objects.len() {
+            if !flushed {
+                match Pin::new(&mut writer.flush()).poll(&mut cx) {
+                    Poll::Ready(result) => { result.unwrap(); flushed = true; },
+                    Poll::Pending => {}
+                }
+            }
+            if let Poll::Ready(object) = Pin::new(&mut reader.read_object()).poll(&mut cx) {
+                read.push(object.unwrap().unwrap());
+            }
+        }
+        assert_eq!(objects, read);
+
+        drop(writer);
+        match Pin::new(&mut reader.read_object()).poll(&mut cx) {
+            Poll::Ready(Ok(None)) => {},
+            _ => panic!("Expected the end of the stream")
+        }
+    }
+}
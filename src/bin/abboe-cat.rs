@@ -0,0 +1,198 @@
+//! Publishes objects to a router and prints the objects it routes, as JSON
+//! lines. Each line is an object's metadata, with the payload under
+//! `payload` (text or JSON) or `payload-base64` (anything else).
+
+use std::env;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::io;
+use std::process;
+
+extern crate getopts;
+use getopts::Options;
+
+#[macro_use]
+extern crate log;
+extern crate env_logger;
+
+extern crate rustc_serialize;
+use rustc_serialize::base64::{FromBase64, ToBase64, STANDARD};
+use rustc_serialize::json::{Json, ToJson};
+
+extern crate object_system;
+use object_system::{BusinessObject, Client, Payload};
+use object_system::config;
+use object_system::filter::Filter;
+use object_system::tls;
+
+
+/// Builds an object from one input line.
+fn object_from_line(line: &str) -> Result<BusinessObject, String> {
+    let json = Json::from_str(line).map_err(|e| e.to_string())?;
+    let mut object = BusinessObject::from_json(&json).map_err(|e| e.to_string())?;
+
+    let (payload, default_type) = match (object.metadata.remove("payload"), object.metadata.remove("payload-base64")) {
+        (Some(_), Some(_)) => { return Err("both payload and payload-base64 given".to_string()); },
+        (Some(Json::String(text)), None) => (Payload::Text(text, None), "text/plain"),
+        (Some(json), None) => (Payload::Json(json, None), "application/json"),
+        (None, Some(Json::String(encoded))) => {
+            let bytes = encoded.from_base64().map_err(|e| format!("payload-base64: {}", e))?;
+            (Payload::Bytes(bytes.into()), "application/octet-stream")
+        },
+        (None, Some(_)) => { return Err("payload-base64 must be a string".to_string()); },
+        (None, None) => { return Ok(object); }
+    };
+
+    if object._type.is_none() {
+        object._type = Some(default_type.to_string());
+    }
+    object.size = Some(payload.to_bytes(object.content_type().as_ref()).len());
+    object.payload = Some(payload);
+    Ok(object)
+}
+
+
+/// The metadata of `object` with its payload, as printed by `tail`.
+fn object_to_line(object: &BusinessObject) -> String {
+    let mut json = object.to_json();
+    if let (Json::Object(ref mut fields), Some(payload)) = (&mut json, object.payload.as_ref()) {
+        match *payload {
+            Payload::Text(ref text, _) => { fields.insert("payload".to_string(), text.to_json()); },
+            Payload::Json(ref payload, _) => { fields.insert("payload".to_string(), payload.clone()); },
+            Payload::Bytes(ref bytes) => {
+                fields.insert("payload-base64".to_string(), bytes.to_base64(STANDARD).to_json());
+            },
+        }
+    }
+
+    json.to_string()
+}
+
+
+fn send_lines<R: BufRead>(client: &mut Client, input: R, name: &str) -> Result<usize, String> {
+    let mut sent = 0;
+    for (number, line) in input.lines().enumerate() {
+        let line = line.map_err(|e| format!("{}: {}", name, e))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let object = object_from_line(&line).map_err(|e| format!("{}:{}: {}", name, number + 1, e))?;
+        client.send(&object).map_err(|e| format!("Failed to send: {}", e))?;
+        sent += 1;
+    }
+
+    Ok(sent)
+}
+
+
+/// Publishes the objects in `files`, or on standard input if there are
+/// none.
+fn send(client: &mut Client, files: &[String]) -> Result<(), String> {
+    // Routers only route objects from subscribed clients
+    client.subscribe(&[]).map_err(|e| format!("Failed to subscribe: {}", e))?;
+
+    let mut sent = 0;
+    if files.is_empty() {
+        let stdin = io::stdin();
+        sent += send_lines(client, stdin.lock(), "<stdin>")?;
+    }
+    for name in files {
+        let file = File::open(name).map_err(|e| format!("{}: {}", name, e))?;
+        sent += send_lines(client, BufReader::new(file), name)?;
+    }
+
+    debug!("Sent {} object(s)", sent);
+    Ok(())
+}
+
+
+/// Prints the objects matching `rules`, and `filter` if given, until the
+/// router disconnects or `count` objects have been printed.
+fn tail(client: &mut Client, rules: &[&str], filter: Option<&Filter>, count: Option<usize>) -> Result<(), String> {
+    client.subscribe(rules).map_err(|e| format!("Failed to subscribe: {}", e))?;
+
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    let mut printed = 0;
+    while count.is_none_or(|count| printed < count) {
+        let object = client.receive().map_err(|e| format!("Failed to receive: {}", e))?;
+        if filter.is_some_and(|filter| !filter.matches(&object)) {
+            continue;
+        }
+        writeln!(stdout, "{}", object_to_line(&object))
+            .and_then(|_| stdout.flush())
+            .map_err(|e| e.to_string())?;
+        printed += 1;
+    }
+
+    Ok(())
+}
+
+
+fn print_usage(program: &str, opts: &Options) {
+    let brief = format!("Usage: {} [options] send [FILE...]\n       {} [options] tail", program, program);
+    eprint!("{}", opts.usage(&brief));
+}
+
+
+fn run() -> Result<(), String> {
+    let args: Vec<String> = env::args().collect();
+    let program = args[0].clone();
+
+    let mut opts = Options::new();
+    opts.optopt("a", "address", &format!("router to connect to (default {})", config::DEFAULT_LISTEN_ADDRESS),
+                "HOST:PORT");
+    opts.optopt("", "tls-ca", "connect over TLS, trusting the PEM certificates in FILE", "FILE");
+    opts.optopt("", "tls-server-name", "name the router's certificate must be valid for (default localhost)",
+                "NAME");
+    opts.optmulti("s", "subscribe", "rule to subscribe to when tailing; may be given several times (default *)",
+                  "RULE");
+    opts.optopt("f", "filter", "print only the objects EXPR matches when tailing, like 'size > 1024'", "EXPR");
+    opts.optopt("n", "count", "exit after printing N objects", "N");
+    opts.optflag("h", "help", "print this help");
+
+    let matches = opts.parse(&args[1..]).map_err(|e| e.to_string())?;
+    if matches.opt_present("h") || matches.free.is_empty() {
+        print_usage(&program, &opts);
+        process::exit(if matches.opt_present("h") { 0 } else { 2 });
+    }
+
+    let address = matches.opt_str("address").unwrap_or_else(|| config::DEFAULT_LISTEN_ADDRESS.to_string());
+    let mut client = match matches.opt_str("tls-ca") {
+        Some(ca) => {
+            let tls_config = tls::client_config(&ca).map_err(|e| format!("{}: {}", ca, e))?;
+            let server_name = matches.opt_str("tls-server-name").unwrap_or_else(|| "localhost".to_string());
+            Client::connect_tls(&*address, &server_name, tls_config)
+        },
+        None => Client::connect(&*address)
+    }.map_err(|e| format!("Failed to connect to {}: {}", address, e))?;
+
+    match matches.free[0].as_ref() {
+        "send" => send(&mut client, &matches.free[1..]),
+        "tail" => {
+            let rules = matches.opt_strs("subscribe");
+            let rules: Vec<&str> = if rules.is_empty() { vec!["*"] } else { rules.iter().map(|r| r.as_ref()).collect() };
+            let count = match matches.opt_str("count") {
+                Some(n) => Some(config::parse_count("count", &n).map_err(|e| e.to_string())?),
+                None => None
+            };
+            let filter = match matches.opt_str("filter") {
+                Some(expression) => Some(Filter::parse(&expression).map_err(|e| e.to_string())?),
+                None => None
+            };
+            tail(&mut client, &rules, filter.as_ref(), count)
+        },
+        command => Err(format!("Unknown command {}", command))
+    }
+}
+
+
+fn main() {
+    env_logger::init().unwrap();
+
+    if let Err(e) = run() {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
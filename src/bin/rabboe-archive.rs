@@ -0,0 +1,71 @@
+//! Subscribes to a router and archives the objects it routes in an SQLite
+//! database, for querying them with SQL later. See `object_system::archive`
+//! for the table.
+
+use std::env;
+use std::process;
+
+extern crate getopts;
+use getopts::Options;
+
+#[macro_use]
+extern crate log;
+extern crate env_logger;
+
+extern crate object_system;
+use object_system::Client;
+use object_system::archive::Archive;
+use object_system::config;
+use object_system::timestamp::Timestamp;
+
+
+fn print_usage(program: &str, opts: &Options) {
+    let brief = format!("Usage: {} [options] DATABASE", program);
+    eprint!("{}", opts.usage(&brief));
+}
+
+
+fn run() -> Result<(), String> {
+    let args: Vec<String> = env::args().collect();
+    let program = args[0].clone();
+
+    let mut opts = Options::new();
+    opts.optopt("a", "address", &format!("router to connect to (default {})", config::DEFAULT_LISTEN_ADDRESS),
+                "HOST:PORT");
+    opts.optmulti("s", "subscribe", "rule to subscribe to; may be given several times (default *)", "RULE");
+    opts.optflag("h", "help", "print this help");
+
+    let matches = opts.parse(&args[1..]).map_err(|e| e.to_string())?;
+    if matches.opt_present("h") || matches.free.len() != 1 {
+        print_usage(&program, &opts);
+        process::exit(if matches.opt_present("h") { 0 } else { 2 });
+    }
+
+    let path = &matches.free[0];
+    let archive = Archive::open(path).map_err(|e| format!("{}: {}", path, e))?;
+
+    let address = matches.opt_str("address").unwrap_or_else(|| config::DEFAULT_LISTEN_ADDRESS.to_string());
+    let mut client = Client::connect(&*address).map_err(|e| format!("Failed to connect to {}: {}", address, e))?;
+    let rules = matches.opt_strs("subscribe");
+    let rules: Vec<&str> = if rules.is_empty() { vec!["*"] } else { rules.iter().map(|r| r.as_ref()).collect() };
+    client.subscribe(&rules).map_err(|e| format!("Failed to subscribe: {}", e))?;
+    info!("Archiving objects from {} to {}", address, path);
+
+    let mut archived = 0;
+    loop {
+        let object = client.receive().map_err(|e| format!("Failed to receive: {}", e))?;
+        archive.insert(&object, Timestamp::now()).map_err(|e| format!("{}: {}", path, e))?;
+        archived += 1;
+        debug!("Archived {} object(s)", archived);
+    }
+}
+
+
+fn main() {
+    env_logger::init().unwrap();
+
+    if let Err(e) = run() {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,258 @@
+//! Load generator for a running router. Publishers send objects at a given
+//! rate and size, every subscriber receives all of them, and the routing
+//! throughput and end-to-end latency are reported at the end.
+
+use std::env;
+use std::process;
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+extern crate getopts;
+use getopts::Options;
+
+extern crate env_logger;
+
+extern crate object_system;
+use object_system::{BusinessObject, Client, Payload};
+use object_system::config;
+
+
+const DEFAULT_PUBLISHERS: usize = 4;
+const DEFAULT_SUBSCRIBERS: usize = 8;
+const DEFAULT_SIZE: usize = 128;
+const DEFAULT_DURATION: u64 = 10;
+
+const BENCH_EVENT: &str = "bench";
+// Sent by each publisher after its last object
+const DONE_EVENT: &str = "bench/done";
+const SENT_AT_KEY: &str = "bench-sent-at";
+
+
+struct Settings {
+    address: String,
+    publishers: usize,
+    subscribers: usize,
+    /// Objects per second per publisher, or unlimited.
+    rate: Option<usize>,
+    size: usize,
+    duration: Duration,
+}
+
+
+/// What one subscriber received.
+#[derive(Default)]
+struct Received {
+    /// Microseconds from publishing to receiving each object.
+    latencies: Vec<u64>,
+    error: Option<String>,
+}
+
+
+fn now_micros() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros() as u64
+}
+
+
+fn bench_object(event: &str, size: usize) -> BusinessObject {
+    BusinessObject {
+        _type: Some("application/octet-stream".to_string()),
+        payload: Some(Payload::Bytes(vec![42; size].into())),
+        size: Some(size),
+        event: Some(event.to_string()),
+        metadata: Default::default(),
+    }
+}
+
+
+fn connect(settings: &Settings, rules: &[&str]) -> Client {
+    let mut client = Client::connect(&*settings.address).unwrap_or_else(|e| {
+        eprintln!("Failed to connect to {}: {}", settings.address, e);
+        process::exit(1);
+    });
+    client.subscribe(rules).unwrap_or_else(|e| {
+        eprintln!("Failed to subscribe: {}", e);
+        process::exit(1);
+    });
+    client
+}
+
+
+/// Publishes objects until the duration has passed and returns how many.
+fn publish(mut client: Client, settings: &Settings, start: &Barrier) -> usize {
+    let mut object = bench_object(BENCH_EVENT, settings.size);
+    start.wait();
+
+    let started = Instant::now();
+    let mut sent = 0;
+    while started.elapsed() < settings.duration {
+        if let Some(rate) = settings.rate {
+            let due = started + Duration::from_secs(sent as u64) / rate as u32;
+            let now = Instant::now();
+            if due > now {
+                thread::sleep(due - now);
+            }
+        }
+
+        object.set_meta(SENT_AT_KEY, &now_micros());
+        if let Err(e) = client.send(&object) {
+            eprintln!("Publisher failed to send: {}", e);
+            return sent;
+        }
+        sent += 1;
+    }
+
+    if let Err(e) = client.send(&bench_object(DONE_EVENT, 0)) {
+        eprintln!("Publisher failed to send: {}", e);
+    }
+    sent
+}
+
+
+/// Receives objects until every publisher is done.
+fn subscribe(mut client: Client, publishers: usize, start: &Barrier) -> Received {
+    let mut received = Received::default();
+    start.wait();
+
+    let mut done = 0;
+    while done < publishers {
+        let object = match client.receive() {
+            Ok(object) => object,
+            Err(e) => {
+                received.error = Some(e.to_string());
+                break;
+            }
+        };
+
+        match object.event.as_ref().map(|event| event.as_ref()) {
+            Some(BENCH_EVENT) => {
+                if let Some(sent_at) = object.meta_u64(SENT_AT_KEY) {
+                    received.latencies.push(now_micros().saturating_sub(sent_at));
+                }
+            },
+            Some(DONE_EVENT) => { done += 1; },
+            _ => {}
+        }
+    }
+
+    received
+}
+
+
+/// The `p`th percentile of sorted `values`.
+fn percentile(values: &[u64], p: usize) -> u64 {
+    values[(values.len() - 1) * p / 100]
+}
+
+
+fn millis(micros: u64) -> f64 {
+    micros as f64 / 1000.0
+}
+
+
+fn run(settings: Settings) {
+    let settings = Arc::new(settings);
+    let start = Arc::new(Barrier::new(settings.publishers + settings.subscribers + 1));
+
+    let subscribers: Vec<_> = (0 .. settings.subscribers).map(|_| {
+        let client = connect(&settings, &[&format!("@{}", BENCH_EVENT), &format!("@{}", DONE_EVENT)]);
+        let (settings, start) = (settings.clone(), start.clone());
+        thread::spawn(move || subscribe(client, settings.publishers, &start))
+    }).collect();
+
+    let publishers: Vec<_> = (0 .. settings.publishers).map(|_| {
+        let client = connect(&settings, &[]);
+        let (settings, start) = (settings.clone(), start.clone());
+        thread::spawn(move || publish(client, &settings, &start))
+    }).collect();
+
+    start.wait();
+    let started = Instant::now();
+    let published: usize = publishers.into_iter().map(|thread| thread.join().unwrap()).sum();
+    let publish_time = started.elapsed().as_secs_f64();
+
+    let mut latencies = Vec::new();
+    let mut errors = 0;
+    for thread in subscribers {
+        let mut received = thread.join().unwrap();
+        if let Some(error) = received.error {
+            eprintln!("Subscriber failed to receive: {}", error);
+            errors += 1;
+        }
+        latencies.append(&mut received.latencies);
+    }
+    let total_time = started.elapsed().as_secs_f64();
+
+    println!("published: {} objects of {} bytes in {:.2} s ({:.0} objects/s)",
+             published, settings.size, publish_time, published as f64 / publish_time);
+    println!("delivered: {} of {} ({:.0} deliveries/s), {} subscriber(s) failed",
+             latencies.len(), published * settings.subscribers, latencies.len() as f64 / total_time, errors);
+
+    if latencies.is_empty() {
+        return;
+    }
+    latencies.sort_unstable();
+    println!("latency:   p50 {:.3} ms, p90 {:.3} ms, p99 {:.3} ms, max {:.3} ms",
+             millis(percentile(&latencies, 50)), millis(percentile(&latencies, 90)),
+             millis(percentile(&latencies, 99)), millis(percentile(&latencies, 100)));
+}
+
+
+fn print_usage(program: &str, opts: &Options) {
+    let brief = format!("Usage: {} [options]", program);
+    print!("{}", opts.usage(&brief));
+}
+
+
+fn parse_args() -> Result<Settings, String> {
+    let args: Vec<String> = env::args().collect();
+    let program = args[0].clone();
+
+    let mut opts = Options::new();
+    opts.optopt("a", "address", &format!("router to connect to (default {})", config::DEFAULT_LISTEN_ADDRESS),
+                "HOST:PORT");
+    opts.optopt("p", "publishers", &format!("publishing connections (default {})", DEFAULT_PUBLISHERS), "M");
+    opts.optopt("s", "subscribers", &format!("subscribing connections (default {})", DEFAULT_SUBSCRIBERS), "N");
+    opts.optopt("r", "rate", "objects per second per publisher (default unlimited)", "N");
+    opts.optopt("", "size", &format!("payload bytes per object (default {})", DEFAULT_SIZE), "BYTES");
+    opts.optopt("d", "duration", &format!("seconds to publish for (default {})", DEFAULT_DURATION), "SECS");
+    opts.optflag("h", "help", "print this help");
+
+    let matches = opts.parse(&args[1..]).map_err(|e| e.to_string())?;
+    if matches.opt_present("h") {
+        print_usage(&program, &opts);
+        process::exit(0);
+    }
+
+    let count = |name: &str, default: usize| -> Result<usize, String> {
+        match matches.opt_str(name) {
+            Some(n) => config::parse_count(name, &n).map_err(|e| e.to_string()),
+            None => Ok(default)
+        }
+    };
+
+    Ok(Settings {
+        address: matches.opt_str("address").unwrap_or_else(|| config::DEFAULT_LISTEN_ADDRESS.to_string()),
+        publishers: count("publishers", DEFAULT_PUBLISHERS)?,
+        subscribers: count("subscribers", DEFAULT_SUBSCRIBERS)?,
+        rate: match matches.opt_str("rate") {
+            Some(n) => Some(config::parse_count("rate", &n).map_err(|e| e.to_string())?),
+            None => None
+        },
+        size: count("size", DEFAULT_SIZE)?,
+        duration: Duration::from_secs(count("duration", DEFAULT_DURATION as usize)? as u64),
+    })
+}
+
+
+fn main() {
+    env_logger::init().unwrap();
+
+    match parse_args() {
+        Ok(settings) => run(settings),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,152 @@
+//! Publishes the objects recorded in a journal, or an archive, to a router
+//! again, for load testing and debugging with traffic like that of
+//! production. Objects are sent as fast as possible or spaced out like they
+//! were originally routed, sped up or slowed down by a factor.
+
+use std::env;
+use std::path::Path;
+use std::process;
+use std::str::FromStr;
+use std::thread;
+use std::time::Instant;
+
+extern crate getopts;
+use getopts::Options;
+
+#[macro_use]
+extern crate log;
+extern crate env_logger;
+
+extern crate object_system;
+use object_system::{BusinessObject, Client};
+#[cfg(feature = "rusqlite")] use object_system::archive::Archive;
+use object_system::clock;
+use object_system::config;
+use object_system::filter::Filter;
+use object_system::journal;
+use object_system::metadata;
+use object_system::timestamp::Timestamp;
+
+
+/// The recorded objects, oldest first.
+fn read_journal(directory: &str) -> Result<Vec<(Timestamp, BusinessObject)>, String> {
+    journal::read_all(Path::new(directory)).map_err(|e| format!("{}: {}", directory, e))
+}
+
+
+#[cfg(feature = "rusqlite")]
+fn read_archive(path: &str) -> Result<Vec<(Timestamp, BusinessObject)>, String> {
+    Archive::open(path).and_then(|archive| archive.select(None)).map_err(|e| format!("{}: {}", path, e))
+}
+
+
+#[cfg(not(feature = "rusqlite"))]
+fn read_archive(_path: &str) -> Result<Vec<(Timestamp, BusinessObject)>, String> {
+    Err("Reading archives requires building with the rusqlite feature".to_string())
+}
+
+
+/// `object` without what the router stamped on it, so that the router
+/// takes it like one published for the first time.
+fn unstamped(mut object: BusinessObject) -> BusinessObject {
+    object.metadata.remove(metadata::ROUTE);
+    object.metadata.remove(clock::ROUTED_AT_KEY);
+    object.metadata.remove(clock::RECEIVED_AT_KEY);
+    object
+}
+
+
+/// Sends `objects`, each `speed` times sooner after the first than it was
+/// originally routed, or right away without a speed.
+fn replay(client: &mut Client, objects: Vec<(Timestamp, BusinessObject)>, speed: Option<f64>) -> Result<(), String> {
+    // Routers only route objects from subscribed clients
+    client.subscribe(&[]).map_err(|e| format!("Failed to subscribe: {}", e))?;
+
+    let started = Instant::now();
+    let first = objects.first().map(|&(routed, _)| routed);
+    let count = objects.len();
+    for (routed, object) in objects {
+        if let (Some(speed), Some(first)) = (speed, first) {
+            let due = started + routed.duration_since(first).div_f64(speed);
+            let now = Instant::now();
+            if due > now {
+                thread::sleep(due - now);
+            }
+        }
+        client.send(&unstamped(object)).map_err(|e| format!("Failed to send: {}", e))?;
+    }
+
+    info!("Replayed {} object(s) in {:.1} s", count, started.elapsed().as_secs_f64());
+    Ok(())
+}
+
+
+fn print_usage(program: &str, opts: &Options) {
+    let brief = format!("Usage: {} [options] --journal DIR\n       {} [options] --archive FILE", program, program);
+    eprint!("{}", opts.usage(&brief));
+}
+
+
+fn run() -> Result<(), String> {
+    let args: Vec<String> = env::args().collect();
+    let program = args[0].clone();
+
+    let mut opts = Options::new();
+    opts.optopt("a", "address", &format!("router to connect to (default {})", config::DEFAULT_LISTEN_ADDRESS),
+                "HOST:PORT");
+    opts.optopt("", "journal", "replay the objects journaled in DIR", "DIR");
+    opts.optopt("", "archive", "replay the objects archived in the SQLite database FILE", "FILE");
+    opts.optopt("", "speed", "send objects FACTOR times as fast as they were routed (default 1)", "FACTOR");
+    opts.optflag("", "fast", "send objects as fast as possible");
+    opts.optopt("f", "filter", "replay only the objects EXPR matches, like 'event == \"chat/message\"'", "EXPR");
+    opts.optflag("h", "help", "print this help");
+
+    let matches = opts.parse(&args[1..]).map_err(|e| e.to_string())?;
+    let sources = matches.opt_count("journal") + matches.opt_count("archive");
+    if matches.opt_present("h") || sources != 1 || !matches.free.is_empty() {
+        print_usage(&program, &opts);
+        process::exit(if matches.opt_present("h") { 0 } else { 2 });
+    }
+
+    let speed = match (matches.opt_present("fast"), matches.opt_str("speed")) {
+        (true, Some(_)) => { return Err("--fast and --speed are exclusive".to_string()); },
+        (true, None) => None,
+        (false, Some(speed)) => match f64::from_str(&speed) {
+            Ok(speed) if speed > 0.0 && speed.is_finite() => Some(speed),
+            _ => { return Err("speed: expected a positive number".to_string()); }
+        },
+        (false, None) => Some(1.0)
+    };
+
+    let filter = match matches.opt_str("filter") {
+        Some(expression) => Some(Filter::parse(&expression).map_err(|e| e.to_string())?),
+        None => None
+    };
+
+    let mut objects = match (matches.opt_str("journal"), matches.opt_str("archive")) {
+        (Some(directory), _) => read_journal(&directory)?,
+        (None, Some(path)) => read_archive(&path)?,
+        (None, None) => unreachable!()
+    };
+    if let Some(ref filter) = filter {
+        objects.retain(|(_, object)| filter.matches(object));
+    }
+    if let (Some(&(first, _)), Some(&(last, _))) = (objects.first(), objects.last()) {
+        info!("Replaying {} object(s) routed over {:.1} s", objects.len(),
+              last.duration_since(first).as_secs_f64());
+    }
+
+    let address = matches.opt_str("address").unwrap_or_else(|| config::DEFAULT_LISTEN_ADDRESS.to_string());
+    let mut client = Client::connect(&*address).map_err(|e| format!("Failed to connect to {}: {}", address, e))?;
+    replay(&mut client, objects, speed)
+}
+
+
+fn main() {
+    env_logger::init().unwrap();
+
+    if let Err(e) = run() {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
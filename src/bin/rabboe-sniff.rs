@@ -0,0 +1,292 @@
+//! Sits between clients and a router as a TCP proxy and prints every frame
+//! passing through it in either direction: headers as JSON, payloads as
+//! text, JSON or a hexdump. Frames that don't parse are hexdumped as they
+//! are. The bytes are passed on unchanged, so clients and the router can't
+//! tell the proxy is there.
+
+use std::cmp;
+use std::env;
+use std::fmt::Write as FmtWrite;
+use std::io::{Read, Write};
+use std::io;
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+extern crate getopts;
+use getopts::Options;
+
+#[macro_use]
+extern crate log;
+extern crate env_logger;
+
+extern crate rustc_serialize;
+use rustc_serialize::json::ToJson;
+
+extern crate object_system;
+use object_system::{BusinessObject, Payload};
+use object_system::compression::CONTENT_ENCODING_KEY;
+use object_system::config;
+use object_system::io::{Encoding, FrameScan, HeaderDecoding, ENCODINGS, JSON};
+
+
+const DEFAULT_LISTEN_ADDRESS: &str = "127.0.0.1:7891";
+const DEFAULT_MAX_PAYLOAD: usize = 256;
+const READ_BUF_SIZE: usize = 64 * 1024;
+
+
+/// Which way a connection's bytes are going.
+#[derive(Clone, Copy)]
+enum Direction {
+    ToRouter,
+    ToClient,
+}
+
+
+impl Direction {
+    fn arrow(self) -> &'static str {
+        match self {
+            Direction::ToRouter => "client -> router",
+            Direction::ToClient => "router -> client",
+        }
+    }
+}
+
+
+/// Splits the bytes going one way into frames and prints them.
+struct Sniffer {
+    connection: usize,
+    direction: Direction,
+    max_payload: usize,
+    buffer: Vec<u8>,
+    // Header whose payload hasn't arrived in full
+    pending: Option<BusinessObject>,
+}
+
+
+fn encoding_of_frame(first_byte: u8) -> &'static dyn Encoding {
+    ENCODINGS.iter().find(|encoding| encoding.starts_frame(first_byte)).cloned().unwrap_or(&JSON)
+}
+
+
+/// `bytes` as lines of offset, hex and printable ASCII.
+fn hexdump(bytes: &[u8]) -> String {
+    let mut output = String::new();
+    for (line, chunk) in bytes.chunks(16).enumerate() {
+        let _ = write!(output, "  {:08x}  ", line * 16);
+        for i in 0 .. 16 {
+            match chunk.get(i) {
+                Some(byte) => { let _ = write!(output, "{:02x} ", byte); },
+                None => { output.push_str("   "); }
+            }
+            if i == 7 {
+                output.push(' ');
+            }
+        }
+        output.push_str(" |");
+        output.extend(chunk.iter().map(|&byte| if (0x20 .. 0x7f).contains(&byte) { byte as char } else { '.' }));
+        output.push_str("|\n");
+    }
+    output
+}
+
+
+/// Describes at most `max` bytes of the payload of `header`.
+fn describe_payload(header: &BusinessObject, payload: &[u8], max: usize) -> String {
+    let shown = &payload[.. cmp::min(payload.len(), max)];
+    let truncated = if shown.len() < payload.len() {
+        format!("  ... {} more bytes\n", payload.len() - shown.len())
+    } else {
+        String::new()
+    };
+
+    let decoded = match header.metadata.extra.get(CONTENT_ENCODING_KEY) {
+        // Compressed, so there is nothing to read but the bytes
+        Some(_) => Payload::Bytes(shown.to_vec().into()),
+        None => Payload::decode(header.content_type().as_ref(), shown.to_vec().into())
+    };
+    let body = match decoded {
+        Payload::Text(ref text, _) => format!("  {}\n", text.replace('\n', "\n  ")),
+        Payload::Json(ref json, _) => format!("  {}\n", json.pretty().to_string().replace('\n', "\n  ")),
+        Payload::Bytes(ref bytes) => hexdump(bytes),
+    };
+    body + &truncated
+}
+
+
+impl Sniffer {
+    fn new(connection: usize, direction: Direction, max_payload: usize) -> Sniffer {
+        Sniffer { connection, direction, max_payload, buffer: Vec::new(), pending: None }
+    }
+
+    fn print(&self, title: &str, body: &str) {
+        let stdout = io::stdout();
+        let mut stdout = stdout.lock();
+        let _ = write!(stdout, "[{} {}] {}\n{}", self.connection, self.direction.arrow(), title, body);
+        let _ = stdout.flush();
+    }
+
+    fn consume(&mut self, n: usize) -> Vec<u8> {
+        self.buffer.drain(.. n).collect()
+    }
+
+    /// Takes in bytes read and prints the frames they complete.
+    fn feed(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+
+        loop {
+            if let Some(header) = self.pending.take() {
+                let size = header.size.unwrap_or(0);
+                if self.buffer.len() < size {
+                    self.pending = Some(header);
+                    return;
+                }
+                let payload = self.consume(size);
+                self.print(&format!("payload, {} bytes", size), &describe_payload(&header, &payload, self.max_payload));
+                continue;
+            }
+
+            let encoding = match self.buffer.first() {
+                Some(&byte) => encoding_of_frame(byte),
+                None => { return; }
+            };
+            let (header_len, frame_len) = match encoding.find_frame(&self.buffer, 0) {
+                Ok(FrameScan::Complete { header_len, frame_len }) => (header_len, frame_len),
+                Ok(FrameScan::Incomplete { .. }) => { return; },
+                Err(e) => {
+                    // Where the next frame starts can't be told any more
+                    let bytes = self.consume(self.buffer.len());
+                    self.print(&format!("unreadable {} frame: {}", encoding.name(), e), &hexdump(&bytes));
+                    return;
+                }
+            };
+
+            let frame = self.consume(frame_len);
+            if header_len == 0 {
+                continue;
+            }
+            match encoding.parse_header(&frame[.. header_len], HeaderDecoding::Strict) {
+                Ok(header) => {
+                    let title = format!("{} header, {} bytes: {}", encoding.name(), header_len,
+                                        header.event.as_deref().unwrap_or("(no event)"));
+                    let body = format!("  {}\n", header.to_json().pretty().to_string().replace('\n', "\n  "));
+                    self.print(&title, &body);
+                    if header.has_payload() {
+                        self.pending = Some(header);
+                    }
+                },
+                Err(e) => {
+                    self.print(&format!("malformed {} header: {}", encoding.name(), e), &hexdump(&frame));
+                }
+            }
+        }
+    }
+}
+
+
+/// Copies bytes from `from` to `to` until either closes, printing the frames
+/// they make up.
+fn relay(mut from: TcpStream, mut to: TcpStream, mut sniffer: Sniffer) {
+    let mut buf = vec![0; READ_BUF_SIZE];
+    loop {
+        let n = match from.read(&mut buf) {
+            Ok(0) => { break; },
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => { continue; },
+            Err(e) => {
+                debug!("Connection {}: read failed: {}", sniffer.connection, e);
+                break;
+            }
+        };
+        if let Err(e) = to.write_all(&buf[.. n]) {
+            debug!("Connection {}: write failed: {}", sniffer.connection, e);
+            break;
+        }
+        sniffer.feed(&buf[.. n]);
+    }
+
+    if !sniffer.buffer.is_empty() {
+        let bytes = sniffer.consume(sniffer.buffer.len());
+        sniffer.print("incomplete frame at close", &hexdump(&bytes));
+    }
+    sniffer.print("closed", "");
+    let _ = to.shutdown(Shutdown::Write);
+    let _ = from.shutdown(Shutdown::Read);
+}
+
+
+fn proxy(client: TcpStream, router_address: &str, connection: usize, max_payload: usize) -> io::Result<()> {
+    let router = TcpStream::connect(router_address)?;
+    let peer = client.peer_addr()?;
+    println!("[{}] {} connected", connection, peer);
+
+    let (client_reader, router_reader) = (client.try_clone()?, router.try_clone()?);
+    thread::spawn(move || relay(client_reader, router, Sniffer::new(connection, Direction::ToRouter, max_payload)));
+    thread::spawn(move || relay(router_reader, client, Sniffer::new(connection, Direction::ToClient, max_payload)));
+    Ok(())
+}
+
+
+fn print_usage(program: &str, opts: &Options) {
+    let brief = format!("Usage: {} [options]", program);
+    eprint!("{}", opts.usage(&brief));
+}
+
+
+fn run() -> Result<(), String> {
+    let args: Vec<String> = env::args().collect();
+    let program = args[0].clone();
+
+    let mut opts = Options::new();
+    opts.optopt("l", "listen", &format!("address to accept clients on (default {})", DEFAULT_LISTEN_ADDRESS),
+                "HOST:PORT");
+    opts.optopt("r", "router", &format!("router to pass connections on to (default {})",
+                                        config::DEFAULT_LISTEN_ADDRESS), "HOST:PORT");
+    opts.optopt("", "max-payload", &format!("print at most N bytes of each payload (default {})",
+                                            DEFAULT_MAX_PAYLOAD), "N");
+    opts.optflag("h", "help", "print this help");
+
+    let matches = opts.parse(&args[1..]).map_err(|e| e.to_string())?;
+    if matches.opt_present("h") || !matches.free.is_empty() {
+        print_usage(&program, &opts);
+        process::exit(if matches.opt_present("h") { 0 } else { 2 });
+    }
+
+    let listen = matches.opt_str("listen").unwrap_or_else(|| DEFAULT_LISTEN_ADDRESS.to_string());
+    let router = matches.opt_str("router").unwrap_or_else(|| config::DEFAULT_LISTEN_ADDRESS.to_string());
+    let max_payload = match matches.opt_str("max-payload") {
+        Some(n) => config::parse_count("max-payload", &n).map_err(|e| e.to_string())?,
+        None => DEFAULT_MAX_PAYLOAD
+    };
+
+    let listener = TcpListener::bind(&*listen).map_err(|e| format!("Failed to listen on {}: {}", listen, e))?;
+    println!("Passing connections to {} on to {}", listen, router);
+
+    let connections = AtomicUsize::new(1);
+    for client in listener.incoming() {
+        let client = match client {
+            Ok(client) => client,
+            Err(e) => {
+                warn!("Failed to accept connection: {}", e);
+                continue;
+            }
+        };
+        let connection = connections.fetch_add(1, Ordering::Relaxed);
+        if let Err(e) = proxy(client, &router, connection, max_payload) {
+            eprintln!("[{}] Failed to connect to {}: {}", connection, router, e);
+        }
+    }
+
+    Ok(())
+}
+
+
+fn main() {
+    env_logger::init().unwrap();
+
+    if let Err(e) = run() {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
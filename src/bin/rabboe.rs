@@ -1,512 +1,369 @@
-use std::collections::BTreeMap;
-use std::fmt;
-use std::io::{Write,Error, ErrorKind};
-use std::io;
-use std::net::SocketAddr;
-use std::rc::Rc;
-use std::str::FromStr;
+use std::env;
+use std::fs;
+use std::mem;
+use std::path::PathBuf;
+use std::process;
+use std::ptr;
+use std::thread;
+
+extern crate getopts;
+use getopts::Options;
 
 #[macro_use]
 extern crate log;
 extern crate env_logger;
 
-extern crate rustc_serialize;
-use rustc_serialize::json::ToJson;
-
-extern crate mio;
-use mio::*;
-use mio::buf::ByteBuf;
-use mio::tcp::*;
-use mio::util::Slab;
-
-extern crate time;
-use time::{Timespec, get_time};
+extern crate libc;
 
 extern crate object_system;
-use object_system::BusinessObject;
-use object_system::io::*;
-use object_system::subscription;
-use object_system::subscription::{BusinessSubscription, BusinessSubscriptionError, routing_decision};
-
-
-fn parse_subscription(obj: &BusinessObject) -> Result<BusinessSubscription, BusinessSubscriptionError> {
-    // trace!("Parsing subscription: {:?}", &obj.to_json());
-    match obj.event {
-        Some(ref event) => {
-            if event == "routing/subscribe" {
-                match obj.metadata.get("subscriptions") {
-                    Some(subscriptions) => {
-                        match subscription::parse_subscription(subscriptions) {
-                            Ok(subs) => Ok(subs),
-                            Err(e) => Err(e)
-                        }
-                    },
-                    // TODO: default subscription
-                    None => Err(BusinessSubscriptionError::NoSubscriptionMetadataKey)
-                }
-            } else {
-                Err(BusinessSubscriptionError::UnknownSubscriptionEvent)
-            }
-        },
-        None => Err(BusinessSubscriptionError::SubscriptionNotEvent)
-    }
-}
-
+use object_system::Config;
+use object_system::config;
+use object_system::config::Upstream;
+use object_system::server::{ServerBuilder, ServerHandle};
 
-fn subscription_reply(subscriptions: &BusinessSubscription, request: &BusinessObject) -> Rc<BusinessObject> {
-    let mut metadata = BTreeMap::new();
-    metadata.insert("subscriptions".to_string(), subscriptions.to_json());
-
-    match request.metadata.get("id") {
-        Some(id) => {
-            if id.is_string() {
-                metadata.insert("in-reply-to".to_string(), id.as_string().unwrap().to_json());
-            }
-        },
-        None => {}
-    }
 
-    Rc::new(BusinessObject {
-        _type: None,
-        payload: None,
-        size: None,
-        event: Some("routing/subscribe/reply".to_string()),
-        metadata: metadata,
-    })
+fn print_usage(program: &str, opts: &Options) {
+    let brief = format!("Usage: {} [options]", program);
+    print!("{}", opts.usage(&brief));
 }
 
 
-fn ping_reply(request: &BusinessObject) -> Rc<BusinessObject> {
-    let mut metadata = BTreeMap::new();
-
-    match request.metadata.get("id") {
-        Some(id) => {
-            if id.is_string() {
-                metadata.insert("in-reply-to".to_string(), id.as_string().unwrap().to_json());
-            }
-        },
-        None => {}
+fn parse_args() -> Result<Config, String> {
+    let args: Vec<String> = env::args().collect();
+    let program = args[0].clone();
+
+    let mut opts = Options::new();
+    opts.optopt("c", "config", "read configuration from a TOML file", "FILE");
+    opts.optmulti("l", "listen", &format!("address to listen on, IPv4, IPv6 or a host name; may be given several times (default {})",
+                                          config::DEFAULT_LISTEN_ADDRESS), "HOST:PORT");
+    opts.optmulti("", "bus", "serve a bus isolated from the others on an address of its own; may be given several times",
+                  "NAME=HOST:PORT");
+    opts.optopt("", "websocket-listen", "address to accept WebSocket clients on", "HOST:PORT");
+    opts.optopt("", "tls-listen", "address to accept TLS clients on", "HOST:PORT");
+    opts.optopt("", "tls-certificate", "PEM certificate chain for TLS", "FILE");
+    opts.optopt("", "tls-private-key", "PEM private key for TLS", "FILE");
+    opts.optopt("", "metrics-listen", "address to serve Prometheus metrics on", "HOST:PORT");
+    opts.optmulti("", "upstream", "router to exchange all objects with; may be given several times",
+                  "HOST:PORT");
+    opts.optopt("", "workers", &format!("number of routing threads (default {})",
+                                        config::DEFAULT_WORKERS), "N");
+    opts.optopt("", "max-clients", &format!("maximum number of connected clients (default {})",
+                                            config::DEFAULT_MAX_CLIENTS), "N");
+    opts.optflag("", "pause-accepting", "stop accepting connections while full instead of refusing them");
+    opts.optopt("", "max-queue-length", &format!("maximum objects queued per client (default {})",
+                                                 config::DEFAULT_MAX_QUEUE_LENGTH), "N");
+    opts.optopt("", "max-queue-age", "seconds an object may wait to be sent before it is dropped (default unlimited)",
+                "SECS");
+    opts.optopt("", "max-header-size", &format!("maximum bytes of metadata per object (default {})",
+                                                config::DEFAULT_MAX_HEADER_SIZE), "BYTES");
+    opts.optopt("", "max-payload-size", &format!("maximum bytes of payload per object (default {})",
+                                                 config::DEFAULT_MAX_PAYLOAD_SIZE), "BYTES");
+    opts.optflag("", "nodelay", "send small objects right away instead of coalescing them (TCP_NODELAY)");
+    opts.optflag("", "validate-objects", "answer objects breaking the protocol with routing/error instead of routing them");
+    opts.optflag("", "dead-letters", "route objects no client got again as routing/dead-letter");
+    opts.optmulti("", "default-subscription", "rule to subscribe clients to when they give no subscriptions; \
+                                              may be given several times (default: reject such subscriptions)",
+                  "RULE");
+    opts.optflag("", "verify-checksums", "answer objects whose payload doesn't match their sha1 with routing/error");
+    opts.optopt("", "header-decoding", "what to do with headers that aren't UTF-8: strict (default) or lossy",
+                "MODE");
+    opts.optopt("", "schema-violations", "what to do with objects violating their schema: reject (default) or tag",
+                "POLICY");
+    opts.optflag("", "publish-before-subscribing", "route objects from clients that haven't subscribed yet \
+                                                    instead of rejecting them");
+    opts.optopt("", "auth-tokens-file", "require clients to log in with one of the tokens in FILE, one per line",
+                "FILE");
+    opts.optopt("", "admin-token-file", "serve admin/* requests carrying the token in FILE", "FILE");
+    opts.optopt("", "rate-limit-objects", "objects per second a client may publish (default unlimited)", "N");
+    opts.optopt("", "rate-limit-bytes", "bytes per second a client may publish (default unlimited)", "BYTES");
+    opts.optopt("", "rate-limit-policy", "what to do with clients over the rate limit: throttle (default), warn or disconnect",
+                "POLICY");
+    opts.optopt("", "history-max-objects", &format!("most recent objects kept for replay (default {})",
+                                                    config::DEFAULT_HISTORY_MAX_OBJECTS), "N");
+    opts.optopt("", "history-max-bytes", &format!("most payload bytes kept for replay (default {})",
+                                                  config::DEFAULT_HISTORY_MAX_BYTES), "BYTES");
+    opts.optopt("", "history-max-age", &format!("seconds objects are kept for replay (default {})",
+                                                config::DEFAULT_HISTORY_MAX_AGE), "SECS");
+    opts.optopt("", "journal", "record every routed object in a journal in DIR", "DIR");
+    opts.optopt("", "journal-segment-bytes", &format!("bytes after which a new journal segment is started (default {})",
+                                                      config::DEFAULT_JOURNAL_SEGMENT_BYTES), "BYTES");
+    opts.optopt("", "journal-segment-age", &format!("seconds after which a new journal segment is started (default {})",
+                                                    config::DEFAULT_JOURNAL_SEGMENT_AGE), "SECS");
+    opts.optflag("", "journal-replay", "fill the history from the end of the journal at startup");
+    opts.optopt("", "archive", "archive every published object in the SQLite database FILE", "FILE");
+    opts.optopt("", "idle-timeout", &format!("seconds of inactivity before pinging a client (default {})",
+                                             config::DEFAULT_IDLE_TIMEOUT), "SECS");
+    opts.optopt("", "pong-timeout", &format!("seconds to wait for a pong before disconnecting (default {})",
+                                             config::DEFAULT_PONG_TIMEOUT), "SECS");
+    opts.optopt("", "partial-frame-timeout", &format!("seconds a client may take to finish a frame it has started \
+                                                      (default {})", config::DEFAULT_PARTIAL_FRAME_TIMEOUT), "SECS");
+    opts.optopt("", "shutdown-timeout", &format!("seconds to keep sending queued objects when shutting down (default {})",
+                                                 config::DEFAULT_SHUTDOWN_TIMEOUT), "SECS");
+    opts.optopt("", "log-level", "log level filter, overridden by RUST_LOG", "LEVEL");
+    opts.optopt("", "object-log", "write a JSON line summarizing each received object to FILE, or - for stderr",
+                "FILE");
+    opts.optopt("", "object-log-sample-rate", &format!("fraction of objects to log (default {})",
+                                                       config::DEFAULT_OBJECT_LOG_SAMPLE_RATE), "RATE");
+    opts.optflag("", "object-log-payloads", "include text and JSON payloads in the object log");
+    opts.optflag("h", "help", "print this help");
+
+    let matches = opts.parse(&args[1..]).map_err(|e| e.to_string())?;
+
+    if matches.opt_present("h") {
+        print_usage(&program, &opts);
+        process::exit(0);
     }
 
-    Rc::new(BusinessObject {
-        _type: None,
-        payload: None,
-        size: None,
-        event: Some("pong".to_string()),
-        metadata: metadata,
-    })
-}
-
-
-struct Server {
-    socket: TcpListener,
-    token: Token,
-    clients: Slab<BusinessClient>,
-}
-
-
-fn client_for_token<'a>(server: &'a mut Server, token: Token) -> &'a mut BusinessClient {
-    &mut server.clients[token]
-}
-
-
-impl Server {
-    fn new(socket: TcpListener) -> Server {
-        Server {
-            socket: socket,
+    let mut config = match matches.opt_str("config") {
+        Some(path) => Config::from_file(&path).map_err(|e| format!("{}: {}", path, e))?,
+        None => Config::default()
+    };
 
-            // As per
-            // <https://github.com/hjr3/mob/blob/multi-echo-blog-post/src/main.rs>
-            // something else but actually our registered events come in with
-            // Token(0) by default.
-            token: Token(1),
-
-            clients: Slab::new_starting_at(Token(2), 128)
+    let listen = matches.opt_strs("listen");
+    if !listen.is_empty() {
+        config.listen.clear();
+        for address in listen {
+            config.listen.extend(config::resolve_listen_address("listen", &address).map_err(|e| e.to_string())?);
         }
     }
-
-    fn register(&mut self, event_loop: &mut EventLoop<Server>) -> io::Result<()> {
-        event_loop.register_opt(&self.socket, self.token, EventSet::readable(),
-                                PollOpt::edge() | PollOpt::oneshot()
-                                ).or_else(|e| {
-                                    error!("Failed to register server {:?}, {:?}", self.token, e);
-                                    Err(e)
-                                })
+    for bus in matches.opt_strs("bus") {
+        config.buses.push(config::parse_bus("bus", &bus).map_err(|e| e.to_string())?);
     }
-
-    fn reregister(&mut self, event_loop: &mut EventLoop<Server>) {
-        event_loop.reregister(&self.socket, self.token, EventSet::readable(),
-                              PollOpt::edge() | PollOpt::oneshot()
-                              ).unwrap_or_else(|e| {
-                                  error!("Failed to reregister server {:?}, {:?}", self.token, e);
-                                  let server_token = self.token;
-                                  self.reset_connection(event_loop, server_token);
-                              })
+    if let Some(listen) = matches.opt_str("websocket-listen") {
+        config.websocket_listen = Some(config::parse_listen_address("websocket-listen", &listen)
+                                       .map_err(|e| e.to_string())?);
     }
-
-    fn new_client(&mut self, event_loop: &mut EventLoop<Server>) {
-        // Log an error if there is no socket, but otherwise move on so we do not tear down the
-        // entire server.
-        let sock = match self.socket.accept() {
-            Ok(s) => {
-                match s {
-                    Some(sock) => {
-                        match sock.peer_addr() {
-                            Ok(addr) => {
-                                info!("Accepted connection from {:?}", addr);
-                            },
-                            Err(_) => {
-                                self.reregister(event_loop);
-                                return;
-                            }
-                        }
-                        sock
-                    },
-                    None => {
-                        error!("Failed to accept new socket");
-                        self.reregister(event_loop);
-                        return;
-                    }
-                }
-            },
-            Err(e) => {
-                error!("Failed to accept new socket, {:?}", e);
-                self.reregister(event_loop);
-                return;
-            }
-        };
-
-        match self.clients.insert_with(|token| {
-            trace!("Registering {:?} with event loop", token);
-            BusinessClient::new(sock, token)
-        }) {
-            Some(token) => {
-                match client_for_token(self, token).register(event_loop) {
-                    Ok(_) => {},
-                    Err(e) => {
-                        error!("Failed to register {:?} connection with event loop, {:?}", token, e);
-                        self.clients.remove(token);
-                    }
-                }
-            },
-            None => {
-                // If we fail to insert, `conn` will go out of scope and be dropped.
-                error!("Failed to insert connection into slab");
-            }
-        };
-
-        // Re-register server after received event
-        self.reregister(event_loop);
+    if let Some(listen) = matches.opt_str("tls-listen") {
+        config.tls_listen = Some(config::parse_listen_address("tls-listen", &listen)
+                                 .map_err(|e| e.to_string())?);
     }
-
-    fn readable(&mut self, event_loop: &mut EventLoop<Server>, token: Token) -> io::Result<()> {
-        trace!("Server conn readable, token: {:?}", token);
-        let objs_result = client_for_token(self, token).read_objects();
-
-        match objs_result {
-            Ok(objs) => {
-                for obj in objs.into_iter() {
-                    debug!("IN({:?}): {:?}", client_for_token(self, token).peer_addr, obj);
-                    self.handle_incoming_object(event_loop, token, Rc::new(obj));
-                }
-            },
-            Err(e) => {
-                warn!("Couldn't read objects: {:?}", e);
-            }
-        };
-
-
-        Ok(())
+    if let Some(path) = matches.opt_str("tls-certificate") {
+        config.tls_certificate = Some(PathBuf::from(path));
     }
-
-    // fn periodical(&mut self, event_loop: &mut EventLoop<Server>) {
-    //     for client in self.clients.iter_mut() {
-    //         if client.last_activity - time::get_time() >= Duration::seconds(1) {
-    //             // TODO: ping / schedule disconnect
-    //         }
-    //     }
-    // }
-
-    fn reset_connection(&mut self, event_loop: &mut EventLoop<Server>, token: Token) {
-        if self.token == token {
-            event_loop.shutdown();
-        } else {
-            trace!("Reset connection, token: {:?}", token);
-            self.clients.remove(token);
-        }
+    if let Some(path) = matches.opt_str("tls-private-key") {
+        config.tls_private_key = Some(PathBuf::from(path));
     }
-
-    fn handle_incoming_object(&mut self, event_loop: &mut EventLoop<Server>,
-                               token: Token, object: Rc<BusinessObject>) {
-        match client_for_token(self, token).subscription {
-            Some(_) => {
-                trace!("Would handle {:?}", &object);
-                client_for_token(self, token).last_activity = time::get_time();
-
-                let is_ping = match object.event { Some(ref event) => event == "ping",
-                                                   None => false };
-
-                let mut bad_tokens = Vec::new();
-                if is_ping {
-                    let event: Option<&str> = Some("pong");
-
-                    // TODO: this .clone() sucks, but it's needed for borrow checker. :(
-                    let sub_opt: Option<BusinessSubscription> = client_for_token(self, token).subscription.clone();
-                    let decision = routing_decision(None, event, None, &sub_opt.unwrap());
-
-                    let pong = ping_reply(&object);
-                    if decision {
-                        client_for_token(self, token).send_object(pong)
-                            .and_then(|_| client_for_token(self, token).reregister(event_loop))
-                            .unwrap_or_else(|e| {
-                                error!("Failed to queue message for {:?}: {:?}", token, e);
-                                bad_tokens.push(token)
-                            });
-                    }
-                } else {
-                    // Queue up a write for all connected clients.
-                    for client in self.clients.iter_mut() {
-                        if client.subscription.is_none() {
-                            trace!("Not subscribed; not routing {:?} to {:?}", object, client);
-                            break;
-                        }
-
-                        let natures = object.natures();
-                    
-                        let event: Option<&str> = match object.event {
-                            Some(ref t) => Some(t.as_ref()),
-                            None => None
-                        };
-
-                        let payload_type: Option<&str> = match object._type {
-                            Some(ref t) => Some(t.as_ref()),
-                            None => None
-                        };
-
-                        // TODO: this .clone() sucks, but it's needed for borrow checker. :(
-                        let sub_opt: Option<BusinessSubscription> = client.subscription.clone();
-                        let decision = routing_decision(Some(natures), event, payload_type, &sub_opt.unwrap());
-
-                        if decision {
-                            client.send_object(object.clone())
-                                .and_then(|_| client.reregister(event_loop))
-                                .unwrap_or_else(|e| {
-                                    error!("Failed to queue message for {:?}: {:?}", client.token, e);
-                                    bad_tokens.push(client.token)
-                                });
-                        }
-                    }
-                }
-
-                for t in bad_tokens {
-                    self.reset_connection(event_loop, t);
-                }
-            },
-            None => {
-                trace!("Would subscribe {:?}", &object);
-                match parse_subscription(&object) {
-                    Ok(subscription) => {
-                        let reply = subscription_reply(&subscription, &object);
-                        let client = client_for_token(self, token);
-                        let _ = client.send_object(reply);
-                        client.subscription = Some(subscription);
-                        client.last_activity = time::get_time();
-                        // TODO: routing announcements
-                    },
-                    Err(e) => {
-                        warn!("Couldn't parse subscription from client: {:?}", e);
-                        self.reset_connection(event_loop, token);
-                    }
-                }
-            }
+    if let Some(listen) = matches.opt_str("metrics-listen") {
+        config.metrics_listen = Some(config::parse_listen_address("metrics-listen", &listen)
+                                     .map_err(|e| e.to_string())?);
+    }
+    for upstream in matches.opt_strs("upstream") {
+        let address = config::parse_listen_address("upstream", &upstream).map_err(|e| e.to_string())?;
+        config.upstreams.push(Upstream::new(address));
+    }
+    if let Some(n) = matches.opt_str("workers") {
+        config.workers = config::parse_count("workers", &n).map_err(|e| e.to_string())?;
+    }
+    if let Some(n) = matches.opt_str("max-clients") {
+        config.max_clients = config::parse_count("max-clients", &n).map_err(|e| e.to_string())?;
+    }
+    if matches.opt_present("pause-accepting") {
+        config.pause_accepting = true;
+    }
+    if let Some(n) = matches.opt_str("max-queue-length") {
+        config.max_queue_length = config::parse_count("max-queue-length", &n).map_err(|e| e.to_string())?;
+    }
+    if let Some(n) = matches.opt_str("max-header-size") {
+        config.max_header_size = config::parse_count("max-header-size", &n).map_err(|e| e.to_string())?;
+    }
+    if let Some(n) = matches.opt_str("max-payload-size") {
+        config.max_payload_size = config::parse_count("max-payload-size", &n).map_err(|e| e.to_string())?;
+    }
+    if let Some(n) = matches.opt_str("history-max-objects") {
+        config.history_max_objects = config::parse_count("history-max-objects", &n).map_err(|e| e.to_string())?;
+    }
+    if let Some(n) = matches.opt_str("history-max-bytes") {
+        config.history_max_bytes = config::parse_count("history-max-bytes", &n).map_err(|e| e.to_string())?;
+    }
+    if let Some(n) = matches.opt_str("max-queue-age") {
+        config.max_queue_age = Some(config::parse_count("max-queue-age", &n).map_err(|e| e.to_string())? as u64);
+    }
+    if let Some(n) = matches.opt_str("history-max-age") {
+        config.history_max_age = config::parse_count("history-max-age", &n).map_err(|e| e.to_string())? as u64;
+    }
+    if matches.opt_present("nodelay") {
+        config.socket.nodelay = Some(true);
+    }
+    if matches.opt_present("validate-objects") {
+        config.validate_objects = true;
+    }
+    if matches.opt_present("dead-letters") {
+        config.dead_letters = true;
+    }
+    if matches.opt_present("verify-checksums") {
+        config.verify_checksums = true;
+    }
+    if matches.opt_present("publish-before-subscribing") {
+        config.publish_before_subscribing = true;
+    }
+    let rules = matches.opt_strs("default-subscription");
+    if !rules.is_empty() {
+        config.default_subscription = Some(config::parse_rules("default-subscription", &rules)
+                                           .map_err(|e| e.to_string())?);
+    }
+    if let Some(path) = matches.opt_str("auth-tokens-file") {
+        let tokens = fs::read_to_string(&path).map_err(|e| format!("{}: {}", path, e))?;
+        config.auth_tokens = tokens.lines().map(|token| token.trim()).filter(|token| !token.is_empty())
+            .map(|token| token.to_string()).collect();
+        if config.auth_tokens.is_empty() {
+            return Err(format!("{}: no tokens", path));
         }
     }
-}
-
-
-impl Handler for Server {
-    type Timeout = ();
-    type Message = ();
-
-    fn ready(&mut self, event_loop: &mut EventLoop<Server>, token: Token, events: EventSet) {
-        trace!("Events = {:?}", events);
-        assert!(token != Token(0), "[BUG]: Received event for Token(0)");
-
-        if events.is_error() {
-            warn!("Error event for {:?}", token);
-            self.reset_connection(event_loop, token);
-            return;
+    if let Some(path) = matches.opt_str("admin-token-file") {
+        let token = fs::read_to_string(&path).map_err(|e| format!("{}: {}", path, e))?;
+        match token.trim() {
+            "" => { return Err(format!("{}: no token", path)); },
+            token => { config.admin_token = Some(token.to_string()); }
         }
+    }
+    if let Some(n) = matches.opt_str("rate-limit-objects") {
+        config.rate_limit_objects = Some(config::parse_count("rate-limit-objects", &n).map_err(|e| e.to_string())?);
+    }
+    if let Some(n) = matches.opt_str("rate-limit-bytes") {
+        config.rate_limit_bytes = Some(config::parse_count("rate-limit-bytes", &n).map_err(|e| e.to_string())?);
+    }
+    if let Some(decoding) = matches.opt_str("header-decoding") {
+        config.header_decoding = config::parse_header_decoding("header-decoding", &decoding)
+            .map_err(|e| e.to_string())?;
+    }
+    if let Some(policy) = matches.opt_str("schema-violations") {
+        config.schema_violations = config::parse_schema_policy("schema-violations", &policy)
+            .map_err(|e| e.to_string())?;
+    }
+    if let Some(policy) = matches.opt_str("rate-limit-policy") {
+        config.rate_limit_policy = config::parse_rate_limit_policy("rate-limit-policy", &policy)
+            .map_err(|e| e.to_string())?;
+    }
+    if let Some(path) = matches.opt_str("journal") {
+        config.journal = Some(PathBuf::from(path));
+    }
+    if let Some(n) = matches.opt_str("journal-segment-bytes") {
+        config.journal_segment_bytes = config::parse_count("journal-segment-bytes", &n).map_err(|e| e.to_string())?;
+    }
+    if let Some(n) = matches.opt_str("journal-segment-age") {
+        config.journal_segment_age = config::parse_count("journal-segment-age", &n).map_err(|e| e.to_string())? as u64;
+    }
+    if matches.opt_present("journal-replay") {
+        config.journal_replay = true;
+    }
+    if let Some(n) = matches.opt_str("idle-timeout") {
+        config.idle_timeout = config::parse_count("idle-timeout", &n).map_err(|e| e.to_string())? as u64;
+    }
+    if let Some(n) = matches.opt_str("pong-timeout") {
+        config.pong_timeout = config::parse_count("pong-timeout", &n).map_err(|e| e.to_string())? as u64;
+    }
+    if let Some(n) = matches.opt_str("partial-frame-timeout") {
+        config.partial_frame_timeout = config::parse_count("partial-frame-timeout", &n)
+            .map_err(|e| e.to_string())? as u64;
+    }
+    if let Some(n) = matches.opt_str("shutdown-timeout") {
+        config.shutdown_timeout = config::parse_count("shutdown-timeout", &n).map_err(|e| e.to_string())? as u64;
+    }
+    if let Some(level) = matches.opt_str("log-level") {
+        config.log_level = Some(level);
+    }
+    if let Some(path) = matches.opt_str("archive") {
+        config.archive = Some(PathBuf::from(path));
+    }
+    if let Some(path) = matches.opt_str("object-log") {
+        config.object_log = Some(PathBuf::from(path));
+    }
+    if let Some(rate) = matches.opt_str("object-log-sample-rate") {
+        config.object_log_sample_rate = config::parse_rate("object-log-sample-rate", &rate)
+            .map_err(|e| e.to_string())?;
+    }
+    if matches.opt_present("object-log-payloads") {
+        config.object_log_payloads = true;
+    }
 
-        if events.is_hup() {
-            trace!("Hup event for {:?}", token);
-            self.reset_connection(event_loop, token);
-            return;
-        }
+    config.validate().map_err(|e| e.to_string())?;
+    Ok(config)
+}
 
-        // We never expect a write event for our `Server` token . A write event for any other token
-        // should be handed off to that connection.
-        if events.is_writable() {
-            trace!("Write event for {:?}", token);
-            assert!(self.token != token, "Received writable event for Server");
 
-            client_for_token(self, token).writable()
-                .and_then(|_| client_for_token(self, token).reregister(event_loop))
-                .unwrap_or_else(|e| {
-                    warn!("Write event failed for {:?}, {:?}", token, e);
-                    self.reset_connection(event_loop, token);
-                });
-        }
+fn init_logger(config: &Config) {
+    let mut builder = env_logger::LogBuilder::new();
 
-        if events.is_readable() {
-            trace!("Read event for {:?}", token);
-            if self.token == token {
-                self.new_client(event_loop);
-            } else {
-                self.readable(event_loop, token)
-                    .and_then(|_| client_for_token(self, token).reregister(event_loop))
-                    .unwrap_or_else(|e| {
-                        warn!("Read event failed for {:?}: {:?}", token, e);
-                        self.reset_connection(event_loop, token);
-                    });
+    match env::var("RUST_LOG") {
+        Ok(filters) => { builder.parse(&filters); },
+        Err(_) => {
+            if let Some(ref level) = config.log_level {
+                builder.parse(level);
             }
         }
     }
-}
-
-
-struct BusinessClient {
-    stream: BusinessObjectStream<TcpStream>,
-    token: Token,
-    interest: EventSet,
-    send_queue: Vec<Rc<BusinessObject>>,
 
-    subscription: Option<BusinessSubscription>,
-    last_activity: Timespec,
-
-    peer_addr: SocketAddr
+    builder.init().expect("Failed to init logger");
 }
 
 
-impl fmt::Debug for BusinessClient {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let timestamp = match time::strftime("%Y-%m-%dT%H:%M:%S",
-                                             &time::at_utc(self.last_activity)) {
-            Ok(ts) => ts,
-            Err(_) => "Couldn't format".to_string()
-        };
-
-        write!(f, "BusinessClient(token: {}, last_activity: {}, peer: {}, subscription: {:?})",
-               self.token.as_usize(),
-               timestamp,
-               self.peer_addr,
-               self.subscription)
+/// Blocks SIGINT, SIGTERM and SIGHUP in the calling thread and in the threads
+/// it spawns afterwards, so that they are only received by `sigwait`.
+fn block_signals() -> libc::sigset_t {
+    unsafe {
+        let mut signals: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut signals);
+        libc::sigaddset(&mut signals, libc::SIGINT);
+        libc::sigaddset(&mut signals, libc::SIGTERM);
+        libc::sigaddset(&mut signals, libc::SIGHUP);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &signals, ptr::null_mut());
+        signals
     }
 }
 
 
-impl BusinessClient {
-    fn new(socket: TcpStream, token: Token) -> BusinessClient {
-        BusinessClient {
-            peer_addr: socket.peer_addr().unwrap().clone(),
-
-            stream: BusinessObjectStream::new(socket),
-            token: token,
-
-            interest: EventSet::hup(),
-
-            send_queue: Vec::new(),
-
-            subscription: Option::None,
-            last_activity: time::get_time(),
-
-        }
-    }
-
-    fn read_objects(&mut self) -> io::Result<Vec<BusinessObject>> {
-        match self.stream.read_business_objects() {
-            Ok(objs) => { Ok(objs) }
-            Err(e) => { Err(Error::new(ErrorKind::Other, e)) }
-        }
+/// Reads the configuration again, from the same file and command line, and
+/// passes what can be changed without a restart to the server.
+fn reload_config(server: &ServerHandle) {
+    match parse_args() {
+        Ok(new) => server.reload(new),
+        Err(e) => error!("Failed to reload configuration, keeping the current one: {}", e)
     }
+}
 
-    fn writable(&mut self) -> io::Result<()> {
-        try!(self.send_queue.pop()
-            .ok_or(Error::new(ErrorKind::Other, "Could not pop send queue"))
-            .and_then(|object| {
-                let bytes = &object.to_bytes();
-                let mut buf = ByteBuf::from_slice(bytes);
-                match self.stream.try_write_buf(&mut buf) {
-                    Ok(None) => {
-                        warn!("Tried to write {}, none written, putting object back to queue", bytes.len());
-                        self.send_queue.push(object);
-                        Ok(())
-                    },
-                    Ok(Some(n)) => {
-                        if n != bytes.len() {
-                            panic!("Wrote only {:?}, should have written {:?}", n, bytes.len());
-                        }
-                        debug!("Sent object to {:?}", self);
-                        let _ = self.stream.flush();
-                        trace!("CONN : we wrote {} bytes", n);
-                        Ok(())
-                    },
-                    Err(e) => {
-                        error!("Failed to send buffer for {:?}, error: {}", self.token, e);
-                        Err(e)
-                    }
-                }
-            })
-        );
 
-        if self.send_queue.is_empty() {
-            self.interest.remove(EventSet::writable());
+/// Reloads the configuration on SIGHUP. Asks the server to shut down on
+/// the first of the other `signals`, and exits immediately on the second.
+fn handle_signals(signals: libc::sigset_t, server: ServerHandle) {
+    thread::Builder::new().name("signals".to_string()).spawn(move || {
+        let mut signal = 0;
+        let mut shutting_down = false;
+
+        loop {
+            unsafe { libc::sigwait(&signals, &mut signal); }
+            if signal == libc::SIGHUP {
+                info!("Received SIGHUP, reloading configuration");
+                reload_config(&server);
+            } else if shutting_down {
+                warn!("Received signal {} again, exiting immediately", signal);
+                process::exit(1);
+            } else {
+                info!("Received signal {}", signal);
+                server.shutdown();
+                shutting_down = true;
+            }
         }
-
-        Ok(())
-    }
-
-    fn send_object(&mut self, object: Rc<BusinessObject>) -> io::Result<()> {
-        debug!("OUT({:?}): {:?}", self.peer_addr, object);
-        self.send_queue.push(object);
-        self.interest.insert(EventSet::writable());
-        Ok(())
-    }
-
-    fn register(&mut self, event_loop: &mut EventLoop<Server>) -> io::Result<()> {
-        self.interest.insert(EventSet::readable());
-
-        event_loop.register_opt(&self.stream.socket, self.token, self.interest, 
-                                PollOpt::edge() | PollOpt::oneshot()
-                                ).or_else(|e| {
-                                    error!("Failed to register {:?}, {:?}", self.token, e);
-                                    Err(e)
-                                })
-    }
-
-    fn reregister(&mut self, event_loop: &mut EventLoop<Server>) -> io::Result<()> {
-        event_loop.reregister(&self.stream.socket, self.token, self.interest,
-                              PollOpt::edge() | PollOpt::oneshot()
-                              ).or_else(|e| {
-                                  error!("Failed to reregister {:?}, {:?}", self.token, e);
-                                  Err(e)
-                              })
-    }
+    }).expect("Failed to start signal handler");
 }
 
 
 fn main() {
-    env_logger::init().ok().expect("Failed to init logger");
-
-    let addr: SocketAddr = FromStr::from_str("127.0.0.1:7890")
-        .ok().expect("Failed to parse host:port string");
-    let sock = TcpListener::bind(&addr).ok().expect("Failed to bind address");
-
-    let mut event_loop = EventLoop::new().ok().expect("Failed to create event loop");
-
-    let mut server = Server::new(sock);
-    server.register(&mut event_loop).ok().expect("Failed to register server with event loop");
-
-    info!("Server starting...");
-    event_loop.run(&mut server).ok().expect("Failed to start event loop");
+    let config = match parse_args() {
+        Ok(config) => config,
+        Err(e) => {
+            println!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    init_logger(&config);
+
+    // Before the workers are started, so that they inherit the mask
+    let signals = block_signals();
+    let server = ServerBuilder::new(config).start().unwrap_or_else(|e| {
+        println!("{}", e);
+        process::exit(1);
+    });
+    handle_signals(signals, server.handle());
+    server.run();
 }
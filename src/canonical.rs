@@ -0,0 +1,168 @@
+//! The canonical form of JSON, which writes equal values as the same bytes
+//! whatever wrote them first, for checksums and for comparing frames byte
+//! for byte. It is compact JSON, with no whitespace between tokens, and:
+//!
+//! - Object keys are sorted by their UTF-8 bytes, which is code point order.
+//! - Integers are written in decimal, with a `-` if negative and no leading
+//!   zeros or `+`.
+//! - Floats are written with the fewest significant digits that read back
+//!   as the same float. One from 1e-6 up to but not including 1e19 is
+//!   written as a decimal with at least one digit after the point, like
+//!   `2.0` or `0.000125`, and any other as a single digit, a fraction if
+//!   any, `e`, a `+` or `-` and the exponent, like `1e+19` or `2.5e-7`. Both
+//!   read back as floats, so that they stay apart from integers, and the
+//!   digits before a point always fit in 64 bits for readers taking them
+//!   for an integer until they find the point. Zero is `0.0` whatever its
+//!   sign, and infinities and NaN, which JSON lacks, are `null`.
+//! - Strings escape `"` as `\"`, `\` as `\\`, backspace, form feed, newline,
+//!   carriage return and tab as `\b`, `\f`, `\n`, `\r` and `\t`, and other
+//!   characters below U+0020 as `\u` and four lowercase hex digits. Every
+//!   other character, `/` and non-ASCII included, is written as is in UTF-8.
+
+use rustc_serialize::json::Json;
+
+
+// The digits of a float and where its decimal point goes: the float is
+// `0.DIGITS` times ten to the power of `point`
+fn shortest_digits(value: f64) -> (String, i32) {
+    let scientific = format!("{:e}", value);
+    let (mantissa, exponent) = scientific.split_at(scientific.find('e').unwrap());
+    let digits = mantissa.chars().filter(|&c| c != '.').collect();
+    (digits, exponent[1 ..].parse::<i32>().unwrap() + 1)
+}
+
+
+fn write_float(value: f64, out: &mut Vec<u8>) {
+    if !value.is_finite() {
+        out.extend_from_slice(b"null");
+        return;
+    }
+    if value == 0.0 {
+        out.extend_from_slice(b"0.0");
+        return;
+    }
+    if value < 0.0 {
+        out.push(b'-');
+    }
+
+    let (digits, point) = shortest_digits(value.abs());
+    let len = digits.len() as i32;
+    let text = if len <= point && point <= 19 {
+        format!("{}{}.0", digits, "0".repeat((point - len) as usize))
+    } else if 0 < point && point <= 19 {
+        format!("{}.{}", &digits[.. point as usize], &digits[point as usize ..])
+    } else if -6 < point && point <= 0 {
+        format!("0.{}{}", "0".repeat(-point as usize), digits)
+    } else {
+        let sign = if point > 0 { '+' } else { '-' };
+        let exponent = (point - 1).abs();
+        match digits.len() {
+            1 => format!("{}e{}{}", digits, sign, exponent),
+            _ => format!("{}.{}e{}{}", &digits[.. 1], &digits[1 ..], sign, exponent),
+        }
+    };
+    out.extend_from_slice(text.as_bytes());
+}
+
+
+fn write_string(text: &str, out: &mut Vec<u8>) {
+    out.push(b'"');
+    for &byte in text.as_bytes() {
+        match byte {
+            b'"' => out.extend_from_slice(b"\\\""),
+            b'\\' => out.extend_from_slice(b"\\\\"),
+            0x08 => out.extend_from_slice(b"\\b"),
+            0x0c => out.extend_from_slice(b"\\f"),
+            b'\n' => out.extend_from_slice(b"\\n"),
+            b'\r' => out.extend_from_slice(b"\\r"),
+            b'\t' => out.extend_from_slice(b"\\t"),
+            byte if byte < 0x20 => out.extend_from_slice(format!("\\u{:04x}", byte).as_bytes()),
+            byte => out.push(byte),
+        }
+    }
+    out.push(b'"');
+}
+
+
+/// Appends `value` to `out` in canonical form.
+pub fn encode(value: &Json, out: &mut Vec<u8>) {
+    match *value {
+        Json::U64(value) => out.extend_from_slice(value.to_string().as_bytes()),
+        Json::I64(value) => out.extend_from_slice(value.to_string().as_bytes()),
+        Json::F64(value) => write_float(value, out),
+        Json::String(ref text) => write_string(text, out),
+        Json::Boolean(value) => out.extend_from_slice(if value { b"true" } else { b"false" }),
+        Json::Array(ref array) => {
+            out.push(b'[');
+            for (i, item) in array.iter().enumerate() {
+                if i > 0 {
+                    out.push(b',');
+                }
+                encode(item, out);
+            }
+            out.push(b']');
+        },
+        Json::Object(ref map) => {
+            out.push(b'{');
+            for (i, (key, value)) in map.iter().enumerate() {
+                if i > 0 {
+                    out.push(b',');
+                }
+                write_string(key, out);
+                out.push(b':');
+                encode(value, out);
+            }
+            out.push(b'}');
+        },
+        Json::Null => out.extend_from_slice(b"null"),
+    }
+}
+
+
+/// `value` in canonical form.
+pub fn to_bytes(value: &Json) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    encode(value, &mut bytes);
+    bytes
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::f64;
+
+    use rustc_serialize::json::Json;
+
+    use super::to_bytes;
+
+
+    fn canonical(value: &Json) -> String {
+        String::from_utf8(to_bytes(value)).unwrap()
+    }
+
+    #[test]
+    fn numbers_should_have_one_way_to_be_written() {
+        for &(value, expected) in &[(1.0, "1.0"), (-2.5, "-2.5"), (0.1, "0.1"), (1.0 / 3.0, "0.3333333333333333"),
+                                     (123456.789, "123456.789"), (1e18, "1000000000000000000.0"),
+                                     (1e19, "1e+19"), (1.5e300, "1.5e+300"), (0.000001, "0.000001"),
+                                     (2.5e-7, "2.5e-7"), (5e-324, "5e-324"), (-0.0, "0.0"),
+                                     (f64::NAN, "null"), (f64::INFINITY, "null")] {
+            assert_eq!(expected, canonical(&Json::F64(value)), "{}", value);
+            if value.is_finite() {
+                assert_eq!(value.abs(), expected.trim_start_matches('-').parse::<f64>().unwrap());
+                assert!(Json::from_str(expected).unwrap().is_f64());
+            }
+        }
+        assert_eq!("-9223372036854775808", canonical(&Json::I64(i64::MIN)));
+        assert_eq!("18446744073709551615", canonical(&Json::U64(u64::MAX)));
+    }
+
+    #[test]
+    fn values_should_be_written_compact_with_keys_sorted() {
+        let value = Json::from_str(r#"{"b": [1, true, null], "a": {"y": "é/\"\\\t\u0001\u007f", "x": {}},
+                                       "é": [], "B": -7}"#).unwrap();
+        let expected = "{\"B\":-7,\"a\":{\"x\":{},\"y\":\"é/\\\"\\\\\\t\\u0001\u{7f}\"},\"b\":[1,true,null],\"é\":[]}";
+        assert_eq!(expected, canonical(&value));
+        assert_eq!(value, Json::from_str(expected).unwrap());
+    }
+}
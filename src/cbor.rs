@@ -0,0 +1,273 @@
+//! Conversion between JSON values and CBOR (RFC 8949), for headers sent in
+//! the CBOR encoding. Only what has a JSON counterpart is understood: byte
+//! strings and maps with non-text keys are rejected, and tags are ignored.
+
+use std::collections::BTreeMap;
+
+use rustc_serialize::json::Json;
+
+
+// Nesting deeper than this is rejected rather than risking the stack
+const MAX_DEPTH: usize = 64;
+
+const BREAK: u8 = 0xff;
+
+
+#[derive(Debug, PartialEq)]
+enum Error {
+    /// The input ends before the item does.
+    Incomplete,
+    Invalid(&'static str),
+}
+
+
+struct Decoder<'a> {
+    input: &'a [u8],
+    position: usize,
+}
+
+
+impl<'a> Decoder<'a> {
+    fn byte(&mut self) -> Result<u8, Error> {
+        let byte = *self.input.get(self.position).ok_or(Error::Incomplete)?;
+        self.position += 1;
+        Ok(byte)
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        if self.input.len() - self.position < n {
+            return Err(Error::Incomplete);
+        }
+        let bytes = &self.input[self.position .. self.position + n];
+        self.position += n;
+        Ok(bytes)
+    }
+
+    fn uint(&mut self, n: usize) -> Result<u64, Error> {
+        Ok(self.bytes(n)?.iter().fold(0, |value, &byte| value << 8 | byte as u64))
+    }
+
+    /// Reads the initial byte of an item and its argument, `None` for an
+    /// indefinite length.
+    fn head(&mut self) -> Result<(u8, u8, Option<u64>), Error> {
+        let initial = self.byte()?;
+        let (major, info) = (initial >> 5, initial & 0x1f);
+        let argument = match info {
+            0 ..= 23 => Some(info as u64),
+            24 => Some(self.uint(1)?),
+            25 => Some(self.uint(2)?),
+            26 => Some(self.uint(4)?),
+            27 => Some(self.uint(8)?),
+            31 => None,
+            _ => { return Err(Error::Invalid("Reserved additional information")); }
+        };
+        Ok((major, info, argument))
+    }
+
+    fn at_break(&mut self) -> Result<bool, Error> {
+        match self.input.get(self.position) {
+            Some(&BREAK) => { self.position += 1; Ok(true) },
+            Some(_) => Ok(false),
+            None => Err(Error::Incomplete)
+        }
+    }
+
+    fn length(&self, length: u64) -> Result<usize, Error> {
+        // Every item takes at least a byte, so longer can't be complete
+        if length > (self.input.len() - self.position) as u64 {
+            return Err(Error::Incomplete);
+        }
+        Ok(length as usize)
+    }
+
+    fn text(&mut self, argument: Option<u64>) -> Result<String, Error> {
+        match argument {
+            Some(length) => {
+                let length = self.length(length)?;
+                String::from_utf8(self.bytes(length)?.to_vec())
+                    .map_err(|_| Error::Invalid("Text string isn't UTF-8"))
+            },
+            None => {
+                let mut text = String::new();
+                while !self.at_break()? {
+                    match self.head()? {
+                        (3, _, Some(length)) => text.push_str(&self.text(Some(length))?),
+                        _ => { return Err(Error::Invalid("Invalid text string chunk")); }
+                    }
+                }
+                Ok(text)
+            }
+        }
+    }
+
+    fn item(&mut self, depth: usize) -> Result<Json, Error> {
+        if depth > MAX_DEPTH {
+            return Err(Error::Invalid("Nested too deeply"));
+        }
+
+        match self.head()? {
+            (0, _, Some(value)) => Ok(Json::U64(value)),
+            (1, _, Some(value)) if value <= i64::MAX as u64 => Ok(Json::I64(-1 - value as i64)),
+            (1, _, Some(_)) => Err(Error::Invalid("Integer out of range")),
+            (2, _, _) => Err(Error::Invalid("Byte strings aren't supported")),
+            (3, _, argument) => self.text(argument).map(Json::String),
+            (4, _, argument) => {
+                let mut array = Vec::new();
+                match argument {
+                    Some(length) => for _ in 0 .. self.length(length)? {
+                        array.push(self.item(depth + 1)?);
+                    },
+                    None => while !self.at_break()? {
+                        array.push(self.item(depth + 1)?);
+                    }
+                }
+                Ok(Json::Array(array))
+            },
+            (5, _, argument) => {
+                let mut map = BTreeMap::new();
+                let mut remaining = match argument {
+                    Some(length) => Some(self.length(length)?),
+                    None => None
+                };
+                loop {
+                    match remaining {
+                        Some(0) => break,
+                        Some(ref mut n) => *n -= 1,
+                        None => if self.at_break()? { break; }
+                    }
+                    let key = match self.head()? {
+                        (3, _, argument) => self.text(argument)?,
+                        _ => { return Err(Error::Invalid("Map keys must be text strings")); }
+                    };
+                    let value = self.item(depth + 1)?;
+                    map.insert(key, value);
+                }
+                Ok(Json::Object(map))
+            },
+            (6, _, Some(_)) => self.item(depth + 1),
+            (7, 20, _) => Ok(Json::Boolean(false)),
+            (7, 21, _) => Ok(Json::Boolean(true)),
+            (7, 22, _) | (7, 23, _) => Ok(Json::Null),
+            (7, 25, Some(bits)) => Ok(Json::F64(half_to_f64(bits as u16))),
+            (7, 26, Some(bits)) => Ok(Json::F64(f32::from_bits(bits as u32) as f64)),
+            (7, 27, Some(bits)) => Ok(Json::F64(f64::from_bits(bits))),
+            _ => Err(Error::Invalid("Unsupported CBOR item"))
+        }
+    }
+}
+
+
+fn half_to_f64(bits: u16) -> f64 {
+    let exponent = (bits >> 10) & 0x1f;
+    let mantissa = (bits & 0x3ff) as f64;
+    let value = match exponent {
+        0 => mantissa * 2f64.powi(-24),
+        31 if mantissa == 0.0 => f64::INFINITY,
+        31 => f64::NAN,
+        _ => (mantissa + 1024.0) * 2f64.powi(exponent as i32 - 25)
+    };
+    if bits & 0x8000 != 0 { -value } else { value }
+}
+
+
+/// Decodes the item at the start of `input`, returning it with its length
+/// in bytes, or `None` if `input` ends before the item does.
+pub fn decode(input: &[u8]) -> Result<Option<(Json, usize)>, &'static str> {
+    let mut decoder = Decoder { input, position: 0 };
+    match decoder.item(0) {
+        Ok(value) => Ok(Some((value, decoder.position))),
+        Err(Error::Incomplete) => Ok(None),
+        Err(Error::Invalid(reason)) => Err(reason)
+    }
+}
+
+
+fn write_head(major: u8, argument: u64, out: &mut Vec<u8>) {
+    let major = major << 5;
+    if argument < 24 {
+        out.push(major | argument as u8);
+    } else if argument <= u8::MAX as u64 {
+        out.push(major | 24);
+        out.push(argument as u8);
+    } else if argument <= u16::MAX as u64 {
+        out.push(major | 25);
+        out.extend_from_slice(&(argument as u16).to_be_bytes());
+    } else if argument <= u32::MAX as u64 {
+        out.push(major | 26);
+        out.extend_from_slice(&(argument as u32).to_be_bytes());
+    } else {
+        out.push(major | 27);
+        out.extend_from_slice(&argument.to_be_bytes());
+    }
+}
+
+
+fn write_text(text: &str, out: &mut Vec<u8>) {
+    write_head(3, text.len() as u64, out);
+    out.extend_from_slice(text.as_bytes());
+}
+
+
+/// Appends `value` to `out` in CBOR.
+pub fn encode(value: &Json, out: &mut Vec<u8>) {
+    match *value {
+        Json::U64(value) => write_head(0, value, out),
+        Json::I64(value) if value >= 0 => write_head(0, value as u64, out),
+        Json::I64(value) => write_head(1, (-1 - value) as u64, out),
+        Json::F64(value) => {
+            out.push(0xfb);
+            out.extend_from_slice(&value.to_bits().to_be_bytes());
+        },
+        Json::String(ref text) => write_text(text, out),
+        Json::Boolean(value) => out.push(if value { 0xf5 } else { 0xf4 }),
+        Json::Array(ref array) => {
+            write_head(4, array.len() as u64, out);
+            for item in array {
+                encode(item, out);
+            }
+        },
+        Json::Object(ref map) => {
+            write_head(5, map.len() as u64, out);
+            for (key, value) in map {
+                write_text(key, out);
+                encode(value, out);
+            }
+        },
+        Json::Null => out.push(0xf6),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use rustc_serialize::json::Json;
+
+    use super::{decode, encode};
+
+
+    #[test]
+    fn should_decode_what_it_encodes() {
+        let value = Json::from_str(r#"{"event": "chat/message", "size": 70000, "offset": -500,
+                                       "ratio": 0.25, "tags": ["a", true, null], "nested": {"x": []}}"#).unwrap();
+        let mut bytes = Vec::new();
+        encode(&value, &mut bytes);
+
+        assert_eq!(Ok(Some((value, bytes.len()))), decode(&bytes));
+        for end in 0 .. bytes.len() {
+            assert_eq!(Ok(None), decode(&bytes[.. end]));
+        }
+    }
+
+    #[test]
+    fn should_decode_rfc_8949_examples() {
+        // Indefinite lengths, half-precision floats and a tagged date
+        let bytes = [0xbf, 0x61, 0x61, 0x9f, 0x01, 0xf9, 0x3e, 0x00, 0xff,
+                     0x61, 0x62, 0xc0, 0x7f, 0x62, 0x32, 0x30, 0x61, 0x31, 0xff, 0xff];
+        let expected = Json::from_str(r#"{"a": [1, 1.5], "b": "201"}"#).unwrap();
+        assert_eq!(Ok(Some((expected, bytes.len()))), decode(&bytes));
+
+        assert!(decode(&[0xa1, 0x01, 0x02]).is_err());
+        assert!(decode(&[0x42, 0x01, 0x02]).is_err());
+        assert!(decode(&[0x9f; 100]).is_err());
+    }
+}
@@ -0,0 +1,259 @@
+//! Sending payloads too large for one object as a sequence of chunks. Each
+//! chunk is an object with the event and metadata of the whole, the `id` of
+//! the whole, its index in `chunk` and the number of chunks in
+//! `chunk-count`. Chunk payloads are bytes; the type of the whole goes in
+//! `chunk-type`. If the whole has a `sha1`, each chunk gets that of its own
+//! payload, and the reassembled object that of the whole.
+
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use bytes::{Bytes, BytesMut};
+use rustc_serialize::json::ToJson;
+use uuid::Uuid;
+
+use ::object::{self, BusinessObject, Payload};
+
+
+pub const CHUNK_KEY: &str = "chunk";
+pub const CHUNK_COUNT_KEY: &str = "chunk-count";
+pub const CHUNK_TYPE_KEY: &str = "chunk-type";
+
+const CHUNK_CONTENT_TYPE: &str = "application/octet-stream";
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChunkError {
+    /// A chunk without the `id` of the object it is part of.
+    MissingId,
+    /// `chunk` or `chunk-count` is missing, or the index is out of range.
+    InvalidIndex,
+    /// A chunk disagrees with earlier ones on the number of chunks.
+    CountMismatch,
+}
+
+
+impl fmt::Display for ChunkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            ChunkError::MissingId => "Chunk without an id",
+            ChunkError::InvalidIndex => "Chunk with an invalid index",
+            ChunkError::CountMismatch => "Chunk count differs from earlier chunks",
+        })
+    }
+}
+
+
+/// Splits `object` into chunks with payloads of at most `max_chunk_size`
+/// bytes. An object whose payload fits is returned as it is. The chunks
+/// share the `id` of the object, or a new one if it has none.
+pub fn split(object: &BusinessObject, max_chunk_size: usize) -> Vec<BusinessObject> {
+    assert!(max_chunk_size > 0, "Chunks have to hold at least a byte");
+    let payload = match object.payload {
+        Some(Payload::Bytes(ref bytes)) => bytes.clone(),
+        Some(ref payload) => Bytes::from(payload.to_bytes(object.content_type().as_ref()).into_owned()),
+        None => { return vec![object.clone()]; }
+    };
+    if payload.len() <= max_chunk_size {
+        return vec![object.clone()];
+    }
+
+    let id = object.id().map(|id| id.to_string()).unwrap_or_else(|| Uuid::new_v4().hyphenated().to_string());
+    let count = payload.len().div_ceil(max_chunk_size);
+    (0 .. count).map(|index| {
+        let start = index * max_chunk_size;
+        let chunk = payload.slice(start .. cmp::min(payload.len(), start + max_chunk_size));
+
+        let mut metadata = object.metadata.clone();
+        metadata.id = Some(id.clone());
+        metadata.sha1 = object.metadata.sha1.as_ref().map(|_| object::sha1_hex(&chunk));
+        metadata.insert(CHUNK_KEY.to_string(), index.to_json());
+        metadata.insert(CHUNK_COUNT_KEY.to_string(), count.to_json());
+        if let Some(ref _type) = object._type {
+            metadata.insert(CHUNK_TYPE_KEY.to_string(), _type.to_json());
+        }
+
+        BusinessObject {
+            event: object.event.clone(),
+            _type: Some(CHUNK_CONTENT_TYPE.to_string()),
+            size: Some(chunk.len()),
+            payload: Some(Payload::Bytes(chunk)),
+            metadata,
+        }
+    }).collect()
+}
+
+
+/// The chunks of one object received so far.
+struct Sequence {
+    // The first chunk received, without its payload
+    header: BusinessObject,
+    count: usize,
+    chunks: BTreeMap<usize, Bytes>,
+    started: Instant,
+}
+
+
+/// Collects chunks until all of an object's have arrived. Sequences still
+/// incomplete after a timeout are given up on.
+pub struct Reassembler {
+    timeout: Duration,
+    sequences: HashMap<String, Sequence>,
+}
+
+
+impl Reassembler {
+    pub fn new(timeout: Duration) -> Reassembler {
+        Reassembler { timeout, sequences: HashMap::new() }
+    }
+
+    /// Takes in an object received. Returns objects that aren't chunks as
+    /// they are, and the reassembled object when its last chunk arrives.
+    pub fn push(&mut self, object: BusinessObject, now: Instant) -> Result<Option<BusinessObject>, ChunkError> {
+        let (index, count) = match (object.meta_u64(CHUNK_KEY), object.meta_u64(CHUNK_COUNT_KEY)) {
+            (None, None) => { return Ok(Some(object)); },
+            (Some(index), Some(count)) if index < count => (index as usize, count as usize),
+            _ => { return Err(ChunkError::InvalidIndex); }
+        };
+        let id = object.id().ok_or(ChunkError::MissingId)?.to_string();
+
+        let payload = match object.payload {
+            Some(Payload::Bytes(ref bytes)) => bytes.clone(),
+            Some(ref payload) => Bytes::from(payload.to_bytes(object.content_type().as_ref()).into_owned()),
+            None => Bytes::new()
+        };
+        let sequence = self.sequences.entry(id.clone()).or_insert_with(|| Sequence {
+            header: BusinessObject { payload: None, .. object },
+            count,
+            chunks: BTreeMap::new(),
+            started: now,
+        });
+        if sequence.count != count {
+            return Err(ChunkError::CountMismatch);
+        }
+        sequence.chunks.insert(index, payload);
+        if sequence.chunks.len() < sequence.count {
+            return Ok(None);
+        }
+
+        let sequence = self.sequences.remove(&id).unwrap();
+        let mut payload = BytesMut::with_capacity(sequence.chunks.values().map(|chunk| chunk.len()).sum());
+        for chunk in sequence.chunks.values() {
+            payload.extend_from_slice(chunk);
+        }
+        let payload = payload.freeze();
+
+        let mut whole = sequence.header;
+        whole.metadata.remove(CHUNK_KEY);
+        whole.metadata.remove(CHUNK_COUNT_KEY);
+        whole._type = whole.metadata.remove(CHUNK_TYPE_KEY).and_then(|_type| _type.as_string().map(|s| s.to_string()));
+        whole.size = Some(payload.len());
+        whole.payload = Some(Payload::decode(whole.content_type().as_ref(), payload));
+        if whole.metadata.sha1.is_some() {
+            whole.attach_checksum();
+        }
+        Ok(Some(whole))
+    }
+
+    /// Gives up on the sequences incomplete for longer than the timeout,
+    /// returning their ids.
+    pub fn expire(&mut self, now: Instant) -> Vec<String> {
+        let timeout = self.timeout;
+        let expired: Vec<String> = self.sequences.iter()
+            .filter(|&(_, sequence)| now.duration_since(sequence.started) >= timeout)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in &expired {
+            self.sequences.remove(id);
+        }
+        expired
+    }
+
+    /// Number of objects waiting for more chunks.
+    pub fn pending(&self) -> usize {
+        self.sequences.len()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{split, ChunkError, Reassembler};
+    use ::content_type::ContentType;
+    use ::object::{BusinessObject, Payload};
+    #[cfg(feature = "client")] use ::testing::TestRouter;
+
+
+    fn text(event: &str, text: &str) -> BusinessObject {
+        BusinessObject {
+            _type: Some("text/plain; charset=utf-8".to_string()),
+            payload: Some(Payload::decode(ContentType::parse("text/plain").as_ref(), text.as_bytes().to_vec().into())),
+            size: Some(text.len()),
+            event: Some(event.to_string()),
+            metadata: Default::default(),
+        }
+    }
+
+    #[test]
+    fn chunks_should_reassemble_in_any_order() {
+        let mut whole = text("files/upload", "hölökyn kölökyn").with_new_id();
+        whole.set_meta("name", "poem.txt");
+        whole.attach_checksum();
+        let chunks = split(&whole, 4);
+        assert_eq!(5, chunks.len());
+        assert!(chunks.iter().all(|chunk| chunk.id() == whole.id() && chunk.verify_checksum().is_ok()));
+        assert_eq!(Some("poem.txt"), chunks[0].meta_str("name"));
+
+        let now = Instant::now();
+        let mut reassembler = Reassembler::new(Duration::from_secs(10));
+        for index in &[3, 0, 4, 0, 2] {
+            assert_eq!(Ok(None), reassembler.push(chunks[*index].clone(), now));
+        }
+        let reassembled = reassembler.push(chunks[1].clone(), now).unwrap().unwrap();
+        assert_eq!(whole, reassembled);
+        assert_eq!(whole.metadata, reassembled.metadata);
+        assert_eq!(0, reassembler.pending());
+    }
+
+    #[test]
+    fn objects_that_fit_should_pass_as_they_are() {
+        let small = text("chat/message", "hello");
+        assert_eq!(vec![small.clone()], split(&small, 5));
+        assert_eq!(Ok(Some(small.clone())), Reassembler::new(Duration::from_secs(1)).push(small, Instant::now()));
+    }
+
+    #[test]
+    fn incomplete_sequences_should_expire() {
+        let chunks = split(&text("files/upload", "0123456789"), 5);
+        let start = Instant::now();
+        let mut reassembler = Reassembler::new(Duration::from_secs(10));
+        reassembler.push(chunks[0].clone(), start).unwrap();
+
+        assert!(reassembler.expire(start + Duration::from_secs(9)).is_empty());
+        assert_eq!(vec![chunks[0].id().unwrap().to_string()], reassembler.expire(start + Duration::from_secs(10)));
+        assert_eq!(0, reassembler.pending());
+
+        let mut stray = chunks[1].clone();
+        stray.set_meta("chunk", &2);
+        assert_eq!(Err(ChunkError::InvalidIndex), reassembler.push(stray, start));
+    }
+
+    #[test]
+    #[cfg(feature = "client")]
+    fn clients_should_send_and_receive_chunked_objects() {
+        let router = TestRouter::new();
+        let mut sender = router.client();
+        let mut receiver = router.client();
+        sender.subscribe(&[]).unwrap();
+        receiver.subscribe(&["@files/*"]).unwrap();
+        receiver.set_chunk_reassembly(Some(Duration::from_secs(10)));
+
+        let whole = text("files/upload", &"x".repeat(10_000));
+        sender.send_chunked(&whole, 4096).unwrap();
+        assert_eq!(whole, receiver.receive().unwrap());
+    }
+}
@@ -0,0 +1,897 @@
+//! Blocking client for talking to a router over plain TCP, TLS or an
+//! in-memory stream.
+
+use std::convert::TryFrom;
+use std::io::{Read, Write};
+use std::io;
+use std::mem;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use rustls;
+use rustls::pki_types::ServerName;
+
+use ::chunking::{self, Reassembler};
+use ::compression::{Compression, COMPRESSION_KEY};
+use ::error::{ClientError, RoutingError};
+use ::events::Event;
+use ::io::{encoding, BusinessObjectStream, Encoding, NextObject, CBOR, ENCODING_KEY, JSON};
+use ::object::{BusinessObject, Payload};
+use ::protocol::{self, Feature, Features, Negotiated, PROTOCOL_VERSION};
+use ::socket::SocketOptions;
+use ::subscription::match_hierarchical;
+use ::transport::mem::MemStream;
+
+
+enum Connection {
+    Tcp(TcpStream),
+    Tls(Box<rustls::StreamOwned<rustls::ClientConnection, TcpStream>>),
+    Memory(MemStream),
+}
+
+
+impl Connection {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        match *self {
+            Connection::Tcp(ref stream) => stream.set_read_timeout(timeout),
+            Connection::Tls(ref stream) => stream.sock.set_read_timeout(timeout),
+            Connection::Memory(ref mut stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    fn set_socket_options(&self, options: &SocketOptions) -> io::Result<()> {
+        match *self {
+            Connection::Tcp(ref stream) => options.apply(stream),
+            Connection::Tls(ref stream) => options.apply(&stream.sock),
+            Connection::Memory(_) => Ok(()),
+        }
+    }
+}
+
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Connection::Tcp(ref mut stream) => stream.read(buf),
+            Connection::Tls(ref mut stream) => stream.read(buf),
+            Connection::Memory(ref mut stream) => stream.read(buf),
+        }
+    }
+}
+
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Connection::Tcp(ref mut stream) => stream.write(buf),
+            Connection::Tls(ref mut stream) => stream.write(buf),
+            Connection::Memory(ref mut stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Connection::Tcp(ref mut stream) => stream.flush(),
+            Connection::Tls(ref mut stream) => stream.flush(),
+            Connection::Memory(ref mut stream) => stream.flush(),
+        }
+    }
+}
+
+
+/// Whether the router has been heard from recently enough, as judged by
+/// the keepalive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Health {
+    Healthy,
+    /// A ping went unanswered for a whole keepalive interval.
+    Unresponsive,
+}
+
+
+pub struct Client {
+    stream: BusinessObjectStream<Connection>,
+    keepalive: Option<Duration>,
+    // Whether a keepalive ping has been sent since the router was last heard
+    // from
+    ping_outstanding: bool,
+    health: Health,
+    on_health_change: Option<Box<dyn FnMut(Health) + Send>>,
+    // Objects that arrived while waiting for a reply, returned by `receive`
+    inbox: VecDeque<BusinessObject>,
+    // Compression and header encoding to ask for when subscribing
+    compression: Option<Compression>,
+    encoding: Option<&'static dyn Encoding>,
+    // Channels to declare when subscribing
+    channels: Vec<String>,
+    // Metadata keys and payloads to ask to be left out when subscribing
+    strip: Vec<String>,
+    omit_payloads: bool,
+    // Whether to ask for our own objects not to be routed back to us
+    no_echo: bool,
+    // What the router agreed on in its last subscription reply
+    negotiated: Option<Negotiated>,
+    // Collects chunked objects if reassembly is on
+    chunks: Option<Reassembler>,
+    // Objects queued by `try_send` in wire format, and where each ends
+    outgoing: Vec<u8>,
+    outgoing_ends: VecDeque<usize>,
+    // Handlers registered with `on` in order, and the one for the rest
+    handlers: Vec<(String, Handler)>,
+    fallback: Option<Handler>,
+}
+
+
+/// Handles an object `dispatch` passes to it, with the client to reply
+/// through.
+pub type Handler = Box<dyn FnMut(&mut Client, BusinessObject) + Send>;
+
+
+impl Client {
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<Client> {
+        let socket = TcpStream::connect(addr)?;
+
+        Ok(Client::new(Connection::Tcp(socket)))
+    }
+
+    /// Connects to a router listening for TLS connections. The router's
+    /// certificate must be valid for `server_name` and trusted by `config`
+    /// (see `tls::client_config`).
+    pub fn connect_tls<A: ToSocketAddrs>(addr: A, server_name: &str,
+                                         config: Arc<rustls::ClientConfig>) -> io::Result<Client> {
+        let name = ServerName::try_from(server_name.to_string())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let connection = rustls::ClientConnection::new(config, name)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let socket = TcpStream::connect(addr)?;
+
+        let tls = rustls::StreamOwned::new(connection, socket);
+        Ok(Client::new(Connection::Tls(Box::new(tls))))
+    }
+
+    /// Talks to a router over one end of a `transport::mem::pair`, e.g.
+    /// one connected with `testing::TestRouter::connect`.
+    pub fn connect_in_memory(stream: MemStream) -> Client {
+        Client::new(Connection::Memory(stream))
+    }
+
+    fn new(connection: Connection) -> Client {
+        Client {
+            stream: BusinessObjectStream::new(connection),
+            keepalive: None,
+            ping_outstanding: false,
+            health: Health::Healthy,
+            on_health_change: None,
+            inbox: VecDeque::new(),
+            compression: None,
+            encoding: None,
+            channels: Vec::new(),
+            strip: Vec::new(),
+            omit_payloads: false,
+            no_echo: false,
+            negotiated: None,
+            chunks: None,
+            outgoing: Vec::new(),
+            outgoing_ends: VecDeque::new(),
+            handlers: Vec::new(),
+            fallback: None,
+        }
+    }
+
+    /// Pings the router whenever nothing has been received for `interval`,
+    /// and considers it unresponsive if the ping isn't answered within
+    /// another interval. `None` turns pinging off. Routers only answer
+    /// clients subscribed to `pong`, so `subscribe` adds `@pong` to the
+    /// rules while the keepalive is on.
+    pub fn set_keepalive(&mut self, interval: Option<Duration>) -> io::Result<()> {
+        self.stream.socket.set_read_timeout(interval)?;
+        self.keepalive = interval;
+        self.ping_outstanding = false;
+        Ok(())
+    }
+
+    /// Sets TCP options such as `nodelay` on the connection. Does nothing
+    /// for in-memory connections.
+    pub fn set_socket_options(&mut self, options: &SocketOptions) -> io::Result<()> {
+        self.stream.socket.set_socket_options(options)
+    }
+
+    /// Asks for payloads to be compressed with `compression` on the next
+    /// `subscribe`. Routers not supporting it keep sending them as they are.
+    pub fn set_compression(&mut self, compression: Option<Compression>) {
+        self.compression = compression;
+    }
+
+    /// The compression agreed on with the router, if any.
+    pub fn compression(&self) -> Option<Compression> {
+        self.stream.compression()
+    }
+
+    /// Asks for headers to be sent in `encoding` on the next `subscribe`.
+    /// Routers not supporting it keep sending JSON.
+    pub fn set_encoding(&mut self, encoding: Option<&'static dyn Encoding>) {
+        self.encoding = encoding;
+    }
+
+    /// The header encoding agreed on with the router.
+    pub fn encoding(&self) -> &'static dyn Encoding {
+        self.stream.encoding()
+    }
+
+    /// Declares `channels` on the next `subscribe`, so that only objects
+    /// published to them are received, and only they may be published to.
+    /// None leaves the client on the default channel.
+    pub fn set_channels(&mut self, channels: &[&str]) {
+        self.channels = channels.iter().map(|channel| channel.to_string()).collect();
+    }
+
+    /// Asks on the next `subscribe` for the metadata `keys` to be stripped
+    /// from the objects delivered, such as tokens or large cached fields.
+    pub fn set_stripped_metadata(&mut self, keys: &[&str]) {
+        self.strip = keys.iter().map(|key| key.to_string()).collect();
+    }
+
+    /// Asks on the next `subscribe` for objects to be delivered without
+    /// their payloads, for clients only interested in the metadata.
+    pub fn set_omit_payloads(&mut self, omit: bool) {
+        self.omit_payloads = omit;
+    }
+
+    /// Asks on the next `subscribe` for the objects this client publishes
+    /// not to be routed back to it.
+    pub fn set_no_echo(&mut self, no_echo: bool) {
+        self.no_echo = no_echo;
+    }
+
+    /// The protocol version and features agreed on with the router on the
+    /// last `subscribe`, unless the router predates negotiation.
+    pub fn negotiated(&self) -> Option<&Negotiated> {
+        self.negotiated.as_ref()
+    }
+
+    /// Reassembles chunked objects, returning them from `receive` once all
+    /// their chunks have arrived, and gives up on those still incomplete
+    /// after `timeout`. `None` returns chunks as they are.
+    pub fn set_chunk_reassembly(&mut self, timeout: Option<Duration>) {
+        self.chunks = timeout.map(Reassembler::new);
+    }
+
+    /// Calls `callback` whenever the health of the connection changes.
+    pub fn on_health_change<F: FnMut(Health) + Send + 'static>(&mut self, callback: F) {
+        self.on_health_change = Some(Box::new(callback));
+    }
+
+    pub fn health(&self) -> Health {
+        self.health
+    }
+
+    fn set_health(&mut self, health: Health) {
+        if health != self.health {
+            self.health = health;
+            if let Some(ref mut callback) = self.on_health_change {
+                callback(health);
+            }
+        }
+    }
+
+    /// Called when the router has been quiet for a keepalive interval:
+    /// pings it, or fails if the previous ping went unanswered.
+    fn keep_alive(&mut self) -> Result<(), ClientError> {
+        if self.ping_outstanding {
+            self.set_health(Health::Unresponsive);
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "Router didn't answer ping").into());
+        }
+
+        self.send(&BusinessObject::event(Event::Ping).with_new_id())?;
+        self.ping_outstanding = true;
+        Ok(())
+    }
+
+    /// Sends `object` along with any objects queued before it.
+    pub fn send(&mut self, object: &BusinessObject) -> io::Result<()> {
+        self.try_send(object);
+        self.flush()
+    }
+
+    /// Queues `object` to be sent by the next `flush` or `send`, without
+    /// touching the socket. Many small objects queued are written with a
+    /// single write rather than one each.
+    pub fn try_send(&mut self, object: &BusinessObject) {
+        let bytes = self.stream.encode(object);
+        self.outgoing.extend_from_slice(&bytes);
+        self.outgoing_ends.push_back(self.outgoing.len());
+    }
+
+    /// Writes the queued objects, blocking until the socket has taken them
+    /// all. Whatever wasn't written when an error occurs stays queued.
+    pub fn flush(&mut self) -> io::Result<()> {
+        let mut written = 0;
+        let result = loop {
+            if written == self.outgoing.len() {
+                break self.stream.flush();
+            }
+            match self.stream.write(&self.outgoing[written ..]) {
+                Ok(0) => { break Err(io::Error::new(io::ErrorKind::WriteZero, "Connection closed")); },
+                Ok(n) => { written += n; },
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => { break Err(e); }
+            }
+        };
+
+        self.outgoing.drain(.. written);
+        while self.outgoing_ends.front().is_some_and(|&end| end <= written) {
+            self.outgoing_ends.pop_front();
+        }
+        for end in self.outgoing_ends.iter_mut() {
+            *end -= written;
+        }
+        result
+    }
+
+    /// Number of queued objects not yet completely written.
+    pub fn pending(&self) -> usize {
+        self.outgoing_ends.len()
+    }
+
+    /// Sends `object` in chunks with payloads of at most `max_chunk_size`
+    /// bytes, or as it is if its payload fits in one or the router didn't
+    /// agree on chunking.
+    pub fn send_chunked(&mut self, object: &BusinessObject, max_chunk_size: usize) -> io::Result<()> {
+        if !protocol::allows(self.negotiated.as_ref(), Feature::Chunking) {
+            return self.send(object);
+        }
+        for chunk in chunking::split(object, max_chunk_size) {
+            self.try_send(&chunk);
+        }
+        self.flush()
+    }
+
+    /// Blocks until the next object arrives. Pings from the router are
+    /// answered, and pongs consumed while the keepalive is on, without
+    /// returning them. With the keepalive on, fails with `TimedOut` if the
+    /// router stops answering.
+    pub fn receive(&mut self) -> Result<BusinessObject, ClientError> {
+        if let Some(object) = self.inbox.pop_front() {
+            return Ok(object);
+        }
+
+        loop {
+            let object = match self.receive_any() {
+                Ok(object) => object,
+                Err(ref e) if e.is_timeout() && self.keepalive.is_some() => {
+                    self.keep_alive()?;
+                    continue;
+                },
+                Err(e) => { return Err(e); }
+            };
+
+            if let Some(object) = self.accept(object)? {
+                return Ok(object);
+            }
+        }
+    }
+
+    /// Has `dispatch` pass objects whose event matches `pattern`, such as
+    /// `services/request/*`, to `handler`. Patterns are tried in the order
+    /// they were registered in.
+    pub fn on<F: FnMut(&mut Client, BusinessObject) + Send + 'static>(&mut self, pattern: &str, handler: F) {
+        self.handlers.push((pattern.to_string(), Box::new(handler)));
+    }
+
+    /// Has `dispatch` pass objects no pattern matches to `handler`.
+    pub fn on_other<F: FnMut(&mut Client, BusinessObject) + Send + 'static>(&mut self, handler: F) {
+        self.fallback = Some(Box::new(handler));
+    }
+
+    /// Passes `object` to the first handler whose pattern matches its
+    /// event, or to the one for the rest. Returns whether one took it.
+    pub fn dispatch(&mut self, object: BusinessObject) -> bool {
+        // Handlers may register others while they have the client
+        let mut handlers = mem::take(&mut self.handlers);
+        let matching = object.event.as_ref()
+            .and_then(|event| handlers.iter_mut().find(|(pattern, _)| match_hierarchical(pattern, event)));
+        let handled = match matching {
+            Some((_, handler)) => {
+                handler(self, object);
+                true
+            },
+            None => match self.fallback.take() {
+                Some(mut fallback) => {
+                    fallback(self, object);
+                    self.fallback.get_or_insert(fallback);
+                    true
+                },
+                None => {
+                    trace!("No handler for {:?}", object);
+                    false
+                }
+            }
+        };
+        handlers.append(&mut self.handlers);
+        self.handlers = handlers;
+        handled
+    }
+
+    /// Dispatches objects as they arrive until receiving fails.
+    pub fn run(&mut self) -> ClientError {
+        loop {
+            match self.receive() {
+                Ok(object) => { self.dispatch(object); },
+                Err(e) => { return e; }
+            }
+        }
+    }
+
+    /// Sends `request`, stamped with an `id` unless it has one, and waits
+    /// at most `timeout` for the object that is `in-reply-to` it. Other
+    /// objects arriving meanwhile are kept for `receive`. The keepalive
+    /// doesn't ping while waiting.
+    pub fn request(&mut self, request: &BusinessObject, timeout: Duration)
+                   -> Result<BusinessObject, ClientError> {
+        let request = match request.id() {
+            Some(_) => request.clone(),
+            None => request.clone().with_new_id()
+        };
+        let id = request.id().unwrap().to_string();
+        self.send(&request)?;
+
+        let deadline = Instant::now() + timeout;
+        let reply = self.wait_for_reply(&id, deadline);
+        self.stream.socket.set_read_timeout(self.keepalive)?;
+        reply
+    }
+
+    fn wait_for_reply(&mut self, id: &str, deadline: Instant) -> Result<BusinessObject, ClientError> {
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::from_secs(0) {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "No reply to request").into());
+            }
+            self.stream.socket.set_read_timeout(Some(remaining))?;
+
+            let object = match self.receive_any() {
+                Ok(object) => object,
+                Err(ref e) if e.is_timeout() => { continue; },
+                Err(e) => { return Err(e); }
+            };
+            if let Some(object) = self.accept(object)? {
+                if object.in_reply_to() == Some(id) {
+                    return Ok(object);
+                }
+                self.inbox.push_back(object);
+            }
+        }
+    }
+
+    /// Notes that the router is alive and handles pings, pongs and chunks.
+    /// Returns the object unless it was handled.
+    fn accept(&mut self, object: BusinessObject) -> Result<Option<BusinessObject>, ClientError> {
+        self.ping_outstanding = false;
+        self.set_health(Health::Healthy);
+
+        match object.typed_event() {
+            Some(Event::Ping) => {
+                let mut pong = BusinessObject::reply_to(&object);
+                pong.event = Some(Event::Pong.to_string());
+                self.send(&pong)?;
+                Ok(None)
+            },
+            Some(Event::Pong) if self.keepalive.is_some() => Ok(None),
+            _ => match self.chunks {
+                Some(ref mut chunks) => {
+                    let now = Instant::now();
+                    for id in chunks.expire(now) {
+                        warn!("Gave up on reassembling {}, chunks are missing", id);
+                    }
+                    chunks.push(object, now).or_else(|e| {
+                        warn!("Dropping chunk: {}", e);
+                        Ok(None)
+                    })
+                },
+                None => Ok(Some(object))
+            }
+        }
+    }
+
+    fn receive_any(&mut self) -> Result<BusinessObject, ClientError> {
+        match self.stream.next_object()? {
+            NextObject::Object(object) => Ok(object),
+            NextObject::Streamed(object, mut reader) => {
+                let mut payload = Vec::new();
+                reader.read_to_end(&mut payload)?;
+
+                let payload = Payload::decode(object.content_type().as_ref(), payload.into());
+                Ok(BusinessObject { payload: Some(payload), .. object })
+            }
+        }
+    }
+
+    /// Logs in with `token` to a router requiring it, waiting at most
+    /// `timeout` for the reply. A rejected token fails with the
+    /// `ClientError::Routing` the router answered with.
+    pub fn authenticate(&mut self, token: &str, timeout: Duration) -> Result<(), ClientError> {
+        let mut login = BusinessObject::event(Event::AuthLogin);
+        login.set_meta("token", token);
+
+        let reply = self.request(&login, timeout)?;
+        match RoutingError::from_reply(&reply) {
+            Some(error) => Err(error.into()),
+            None => Ok(())
+        }
+    }
+
+    /// Sends a `routing/subscribe` with the given rules and waits for the
+    /// router's reply. Objects arriving meanwhile are kept for `receive`.
+    /// Compression and encoding asked for with
+    /// `set_compression` and `set_encoding` are used from then on if the
+    /// router agrees to them.
+    pub fn subscribe(&mut self, rules: &[&str]) -> Result<BusinessObject, ClientError> {
+        let mut request = BusinessObject::event(Event::RoutingSubscribe).with_new_id();
+        let mut rules: Vec<String> = rules.iter().map(|rule| rule.to_string()).collect();
+        if self.keepalive.is_some() {
+            rules.push("@pong".to_string());
+        }
+        request.set_meta("subscriptions", &rules);
+        if let Some(compression) = self.compression {
+            request.set_meta(COMPRESSION_KEY, &compression.to_string());
+        }
+        if let Some(encoding) = self.encoding {
+            request.set_meta(ENCODING_KEY, encoding.name());
+        }
+        if !self.channels.is_empty() {
+            request.set_meta("channels", &self.channels);
+        }
+        if !self.strip.is_empty() {
+            request.set_meta("strip", &self.strip);
+        }
+        if self.omit_payloads {
+            request.set_meta("payload", &false);
+        }
+        if self.no_echo {
+            request.set_meta("no-echo", &true);
+        }
+        Negotiated { version: PROTOCOL_VERSION, features: Features::all() }.set_on(&mut request);
+
+        self.send(&request)?;
+
+        loop {
+            // Objects kept for `receive` stay there, and those arriving
+            // ahead of the reply join them
+            let object = match self.receive_any() {
+                Ok(object) => object,
+                Err(ref e) if e.is_timeout() && self.keepalive.is_some() => {
+                    self.keep_alive()?;
+                    continue;
+                },
+                Err(e) => { return Err(e); }
+            };
+            let object = match self.accept(object)? {
+                Some(object) => object,
+                None => { continue; }
+            };
+            if object.is_event(Event::RoutingSubscribeReply) {
+                self.negotiated = Negotiated::from_object(&object);
+                let negotiated = self.negotiated.as_ref();
+                let agreed = object.meta_str(COMPRESSION_KEY).and_then(|name| name.parse().ok())
+                    .filter(|_| protocol::allows(negotiated, Feature::Compression));
+                let requested = self.compression;
+                self.stream.set_compression(agreed.filter(|agreed| Some(*agreed) == requested));
+                let requested = self.encoding.map(|encoding| encoding.name());
+                let agreed = object.meta_str(ENCODING_KEY).filter(|&agreed| Some(agreed) == requested)
+                    .filter(|&agreed| agreed != CBOR.name() || protocol::allows(negotiated, Feature::Cbor));
+                self.stream.set_encoding(agreed.and_then(encoding).unwrap_or(&JSON));
+                return Ok(object);
+            }
+            self.inbox.push_back(object);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    use rustls;
+
+    use super::{Client, Health};
+    use ::error::ClientError;
+    use ::io::{BusinessObjectStream, NextObject};
+    use ::object::BusinessObject;
+    use ::tls;
+    use ::testing::TestRouter;
+
+
+    fn testdata(name: &str) -> String {
+        format!("{}/testdata/tls/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    // Answers one object with a copy of it whose event has "/reply" appended
+    fn echo_reply<S: Read + Write>(socket: S) {
+        let mut stream = BusinessObjectStream::new(socket);
+        let request = match stream.next_object().unwrap() {
+            NextObject::Object(object) => object,
+            NextObject::Streamed(_, _) => panic!("Unexpected streamed object")
+        };
+
+        let mut reply = BusinessObject::reply_to(&request);
+        reply.event = Some(format!("{}/reply", request.event.unwrap()));
+        reply.write_to(&mut stream).unwrap();
+        stream.flush().unwrap();
+    }
+
+    #[test]
+    fn subscribe_should_wait_for_reply_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || echo_reply(listener.accept().unwrap().0));
+
+        let mut client = Client::connect(addr).unwrap();
+        let reply = client.subscribe(&["*"]).unwrap();
+
+        assert_eq!(Some("routing/subscribe/reply".to_string()), reply.event);
+        assert!(reply.in_reply_to().is_some());
+        server.join().unwrap();
+    }
+
+    fn next_object<S: Read + Write>(stream: &mut BusinessObjectStream<S>) -> BusinessObject {
+        match stream.next_object().unwrap() {
+            NextObject::Object(object) => object,
+            NextObject::Streamed(_, _) => panic!("Unexpected streamed object")
+        }
+    }
+
+    fn send_event<S: Read + Write>(stream: &mut BusinessObjectStream<S>, event: &str) -> BusinessObject {
+        let object = BusinessObject::event(event).with_new_id();
+        object.write_to(stream).unwrap();
+        stream.flush().unwrap();
+        object
+    }
+
+    #[test]
+    fn receive_should_answer_pings() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut stream = BusinessObjectStream::new(listener.accept().unwrap().0);
+            let ping = send_event(&mut stream, "ping");
+            send_event(&mut stream, "hello");
+
+            let pong = next_object(&mut stream);
+            assert_eq!(Some("pong".to_string()), pong.event);
+            assert_eq!(ping.id(), pong.in_reply_to());
+        });
+
+        let mut client = Client::connect(addr).unwrap();
+        assert_eq!(Some("hello".to_string()), client.receive().unwrap().event);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn keepalive_should_report_unanswered_pings() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut stream = BusinessObjectStream::new(listener.accept().unwrap().0);
+            assert_eq!(Some("ping".to_string()), next_object(&mut stream).event);
+            // Answer only after the client has given up on the router
+            thread::sleep(Duration::from_millis(300));
+            send_event(&mut stream, "hello");
+        });
+
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let mut client = Client::connect(addr).unwrap();
+        client.set_keepalive(Some(Duration::from_millis(100))).unwrap();
+        let recorded = changes.clone();
+        client.on_health_change(move |health| recorded.lock().unwrap().push(health));
+
+        assert!(client.receive().is_err());
+        assert_eq!(Health::Unresponsive, client.health());
+
+        client.set_keepalive(None).unwrap();
+        assert_eq!(Some("hello".to_string()), client.receive().unwrap().event);
+        assert_eq!(vec![Health::Unresponsive, Health::Healthy], *changes.lock().unwrap());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn request_should_wait_for_reply_and_keep_other_objects() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut stream = BusinessObjectStream::new(listener.accept().unwrap().0);
+            let request = next_object(&mut stream);
+            send_event(&mut stream, "unrelated");
+
+            let mut reply = BusinessObject::reply_to(&request);
+            reply.event = Some("services/reply".to_string());
+            reply.write_to(&mut stream).unwrap();
+            stream.flush().unwrap();
+        });
+
+        let mut client = Client::connect(addr).unwrap();
+        let request = BusinessObject::event("services/request");
+        let reply = client.request(&request, Duration::from_secs(5)).unwrap();
+
+        assert_eq!(Some("services/reply".to_string()), reply.event);
+        assert!(reply.in_reply_to().is_some());
+        assert_eq!(Some("unrelated".to_string()), client.receive().unwrap().event);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn subscribe_should_keep_objects_arriving_ahead_of_reply() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut stream = BusinessObjectStream::new(listener.accept().unwrap().0);
+            for (before, event) in &[("kept", "services/reply"), ("live", "routing/subscribe/reply")] {
+                let request = next_object(&mut stream);
+                send_event(&mut stream, before);
+
+                let mut reply = BusinessObject::reply_to(&request);
+                reply.event = Some(event.to_string());
+                reply.write_to(&mut stream).unwrap();
+                stream.flush().unwrap();
+            }
+        });
+
+        let mut client = Client::connect(addr).unwrap();
+        let request = BusinessObject::event("services/request");
+        let reply = client.request(&request, Duration::from_secs(5)).unwrap();
+        assert_eq!(Some("services/reply".to_string()), reply.event);
+        client.subscribe(&["*"]).unwrap();
+
+        assert_eq!(Some("kept".to_string()), client.receive().unwrap().event);
+        assert_eq!(Some("live".to_string()), client.receive().unwrap().event);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn request_should_time_out_without_reply() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut stream = BusinessObjectStream::new(listener.accept().unwrap().0);
+            next_object(&mut stream);
+            // Hold the connection open until the client gives up
+            let _ = stream.next_object();
+        });
+
+        let mut client = Client::connect(addr).unwrap();
+        let request = BusinessObject::event("services/request");
+        assert!(client.request(&request, Duration::from_millis(100)).is_err());
+
+        drop(client);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn connect_tls_should_talk_to_tls_server() {
+        let server_config = tls::server_config(testdata("server.pem"), testdata("server.key")).unwrap();
+        let client_config = tls::client_config(testdata("ca.pem")).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let socket = listener.accept().unwrap().0;
+            let connection = rustls::ServerConnection::new(server_config).unwrap();
+            echo_reply(rustls::StreamOwned::new(connection, socket));
+        });
+
+        let mut client = Client::connect_tls(addr, "localhost", client_config).unwrap();
+        let ping = BusinessObject::event("ping").with_new_id();
+        client.send(&ping).unwrap();
+        let reply = client.receive().unwrap();
+
+        assert_eq!(Some("ping/reply".to_string()), reply.event);
+        assert_eq!(ping.id(), reply.in_reply_to());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn connect_tls_should_reject_wrong_server_name() {
+        let server_config = tls::server_config(testdata("server.pem"), testdata("server.key")).unwrap();
+        let client_config = tls::client_config(testdata("ca.pem")).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let socket = listener.accept().unwrap().0;
+            let connection = rustls::ServerConnection::new(server_config).unwrap();
+            let mut stream = rustls::StreamOwned::new(connection, socket);
+            let _ = stream.read(&mut [0; 16]);
+        });
+
+        let mut client = Client::connect_tls(addr, "example.com", client_config).unwrap();
+        assert!(client.receive().is_err());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn authenticate_should_fail_when_token_is_rejected() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut stream = BusinessObjectStream::new(listener.accept().unwrap().0);
+            for _ in 0 .. 2 {
+                let login = next_object(&mut stream);
+                assert_eq!(Some("auth/login".to_string()), login.event);
+                let mut reply = BusinessObject::reply_to(&login);
+                reply.event = Some("auth/login/reply".to_string());
+                if login.meta_str("token") != Some("s3cret") {
+                    reply.set_meta("error", "Invalid token");
+                }
+                reply.write_to(&mut stream).unwrap();
+                stream.flush().unwrap();
+            }
+        });
+
+        let mut client = Client::connect(addr).unwrap();
+        match client.authenticate("guess", Duration::from_secs(5)) {
+            Err(ClientError::Routing(ref e)) if e.message == "Invalid token" => {},
+            other => panic!("Expected the login to be rejected, got {:?}", other)
+        }
+        assert!(client.authenticate("s3cret", Duration::from_secs(5)).is_ok());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn dispatch_should_pass_objects_to_the_handler_of_their_event() {
+        let router = TestRouter::new();
+        let mut sender = router.client();
+        let mut bot = router.client();
+        sender.subscribe(&["@services/reply"]).unwrap();
+        bot.subscribe(&["@services/request/*", "@services/register", "@chat/*"]).unwrap();
+
+        let handled = Arc::new(Mutex::new(Vec::new()));
+        let log = handled.clone();
+        bot.on("services/request/*", move |client, request| {
+            log.lock().unwrap().push(format!("request {}", request.event.as_ref().unwrap()));
+            let mut reply = BusinessObject::reply_to(&request);
+            reply.event = Some("services/reply".to_string());
+            client.send(&reply).unwrap();
+        });
+        let log = handled.clone();
+        bot.on("services/**", move |_, object| log.lock().unwrap().push(format!("other {:?}", object.event)));
+        let log = handled.clone();
+        bot.on_other(move |_, object| log.lock().unwrap().push(format!("rest {:?}", object.event)));
+
+        for event in &["services/request/echo", "services/register", "chat/message"] {
+            sender.send(&BusinessObject::event(event)).unwrap();
+            let object = bot.receive().unwrap();
+            assert!(bot.dispatch(object));
+        }
+
+        assert_eq!(Some("services/reply".to_string()), sender.receive().unwrap().event);
+        assert_eq!(vec!["request services/request/echo", "other Some(\"services/register\")",
+                        "rest Some(\"chat/message\")"], *handled.lock().unwrap());
+    }
+
+    #[test]
+    fn queued_objects_should_be_written_when_flushed() {
+        let router = TestRouter::new();
+        let mut sender = router.client();
+        let mut receiver = router.client();
+        sender.subscribe(&[]).unwrap();
+        receiver.subscribe(&["@batch/*"]).unwrap();
+
+        for n in 0 .. 3 {
+            sender.try_send(&BusinessObject::event(format!("batch/{}", n)));
+        }
+        assert_eq!(3, sender.pending());
+        assert_eq!(0, router.routed());
+
+        sender.flush().unwrap();
+        assert_eq!(0, sender.pending());
+        for n in 0 .. 3 {
+            assert_eq!(Some(format!("batch/{}", n)), receiver.receive().unwrap().event);
+        }
+    }
+}
@@ -0,0 +1,69 @@
+//! The clock the router reads the timestamps it stamps on objects and
+//! records them with off. It is a monotonic clock anchored to the wall
+//! clock once, so that timestamps never go backwards when the system time
+//! is set.
+
+use std::time::Instant;
+
+use ::timestamp::Timestamp;
+
+
+/// When the router passed an object on to its clients.
+pub const ROUTED_AT_KEY: &str = "routed-at";
+/// When the router read an object from the client publishing it.
+pub const RECEIVED_AT_KEY: &str = "received-at";
+
+
+#[derive(Debug, Clone, Copy)]
+pub struct Clock {
+    // Wall clock time at `started`
+    epoch: Timestamp,
+    started: Instant,
+}
+
+
+impl Clock {
+    pub fn new() -> Clock {
+        Clock { epoch: Timestamp::now(), started: Instant::now() }
+    }
+
+    /// The wall clock time at `instant`.
+    pub fn at(&self, instant: Instant) -> Timestamp {
+        match instant.checked_duration_since(self.started) {
+            Some(since) => self.epoch + since,
+            None => self.epoch - self.started.duration_since(instant)
+        }
+    }
+
+    pub fn now(&self) -> Timestamp {
+        self.at(Instant::now())
+    }
+}
+
+
+impl Default for Clock {
+    fn default() -> Clock {
+        Clock::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Clock;
+    use ::timestamp::Timestamp;
+
+
+    #[test]
+    fn stamps_should_follow_the_monotonic_clock_from_the_wall_clock() {
+        let wall = Timestamp::now();
+        let clock = Clock::new();
+        let start = clock.now();
+        assert!(start >= wall && start.duration_since(wall) < Duration::from_secs(1));
+
+        assert_eq!(clock.epoch + Duration::from_millis(1500), clock.at(clock.started + Duration::from_millis(1500)));
+        assert!(clock.now() >= start);
+    }
+}
@@ -0,0 +1,186 @@
+//! Payload compression, negotiated per connection when subscribing: a client
+//! asks for it with `compression` and the router agrees by echoing it in
+//! the reply. A compressed object says so in `content-encoding`, with
+//! `size` giving the compressed size, so the framing stays the same and
+//! streams decompress whatever they read.
+
+use std::borrow::Cow;
+use std::fmt;
+use std::io::{Read, Write};
+use std::str::FromStr;
+
+use bytes::Bytes;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use rustc_serialize::json::{Json, ToJson};
+
+use ::error::{ParseError, ReadBusinessObjectError};
+use ::object::BusinessObject;
+
+
+/// Metadata key of the compression asked for when subscribing.
+pub const COMPRESSION_KEY: &str = "compression";
+/// Metadata key of the compression of an object's payload.
+pub const CONTENT_ENCODING_KEY: &str = "content-encoding";
+/// Payloads smaller than this are sent as they are.
+pub const MIN_COMPRESSED_SIZE: usize = 256;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Deflate,
+}
+
+
+impl FromStr for Compression {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Compression, ()> {
+        match s {
+            "deflate" => Ok(Compression::Deflate),
+            _ => Err(())
+        }
+    }
+}
+
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.write_str(match *self {
+            Compression::Deflate => "deflate",
+        })
+    }
+}
+
+
+/// Compresses `payload` into a raw DEFLATE stream, without zlib's header.
+fn deflate(payload: &[u8]) -> Vec<u8> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(payload).expect("Writing to a Vec failed");
+    encoder.finish().expect("Writing to a Vec failed")
+}
+
+
+/// Decompresses a raw DEFLATE stream, reading no more than one byte past
+/// `max_size` of it.
+fn inflate(payload: &[u8], max_size: usize) -> Result<Vec<u8>, ParseError> {
+    let mut decompressed = Vec::new();
+    DeflateDecoder::new(payload).take((max_size as u64).saturating_add(1)).read_to_end(&mut decompressed)
+        .map_err(|_| ParseError::Decompression("Invalid deflate data"))?;
+    if decompressed.len() > max_size {
+        return Err(ParseError::Decompression("Decompressed payload too large"));
+    }
+    Ok(decompressed)
+}
+
+
+/// Compresses `payload` to be sent with `header`, noting the compression in
+/// `header`. Payloads that are too small or don't compress are returned as
+/// they are.
+pub fn compress<'a>(header: &mut Json, payload: Cow<'a, [u8]>, compression: Compression) -> Cow<'a, [u8]> {
+    if payload.len() < MIN_COMPRESSED_SIZE {
+        return payload;
+    }
+    let compressed = match compression {
+        Compression::Deflate => deflate(&payload),
+    };
+    if compressed.len() >= payload.len() {
+        return payload;
+    }
+
+    if let Json::Object(ref mut fields) = *header {
+        fields.insert(CONTENT_ENCODING_KEY.to_string(), compression.to_string().to_json());
+    }
+    Cow::Owned(compressed)
+}
+
+
+/// Decompresses the payload read for `header` if it is compressed, leaving
+/// `header` as if it had been sent uncompressed. The decompressed payload
+/// may be at most `max_size` bytes.
+pub fn decode_payload(header: &mut BusinessObject, payload: Bytes,
+                      max_size: usize) -> Result<Bytes, ReadBusinessObjectError> {
+    let compression = match header.metadata.remove(CONTENT_ENCODING_KEY) {
+        Some(Json::String(name)) => Compression::from_str(&name)
+            .map_err(|_| ParseError::UnknownContentEncoding(name))?,
+        Some(other) => { return Err(ParseError::UnknownContentEncoding(other.to_string()).into()); },
+        None => { return Ok(payload); }
+    };
+
+    let payload = match compression {
+        Compression::Deflate => inflate(&payload, max_size)?,
+    };
+    header.size = Some(payload.len());
+    Ok(payload.into())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::{deflate, inflate, Compression, MIN_COMPRESSED_SIZE};
+    use ::error::ParseError;
+    use ::io::{BusinessObjectStream, NextObject};
+    use ::object::{BusinessObject, Payload};
+
+
+    fn text_object(text: &str) -> BusinessObject {
+        BusinessObject {
+            _type: Some("text/plain".to_string()),
+            payload: Some(Payload::Text(text.to_string(), None)),
+            size: Some(text.len()),
+            event: Some("chat/message".to_string()),
+            metadata: Default::default(),
+        }
+    }
+
+    fn encode(object: &BusinessObject, compression: Compression) -> Vec<u8> {
+        let mut stream = BusinessObjectStream::new(Cursor::new(Vec::new()));
+        stream.set_compression(Some(compression));
+        stream.encode(object)
+    }
+
+    fn read_back(bytes: Vec<u8>) -> BusinessObject {
+        let mut stream = BusinessObjectStream::new(Cursor::new(bytes));
+        match stream.next_object().unwrap() {
+            NextObject::Object(object) => object,
+            NextObject::Streamed(_, _) => panic!("Unexpected streamed object")
+        }
+    }
+
+    #[test]
+    fn should_compress_large_payloads_only() {
+        let small = text_object("hello");
+        assert_eq!(small.to_bytes(), encode(&small, Compression::Deflate));
+
+        let large = text_object(&"hello world ".repeat(MIN_COMPRESSED_SIZE));
+        let bytes = encode(&large, Compression::Deflate);
+        assert!(bytes.len() < large.to_bytes().len() / 10);
+
+        let object = read_back(bytes);
+        assert_eq!(large, object);
+        assert!(!object.metadata.extra.contains_key("content-encoding"));
+    }
+
+    #[test]
+    fn should_reject_unknown_or_corrupt_encodings() {
+        let mut bytes = br#"{"type": "text/plain", "size": 3, "content-encoding": "gzip"}"#.to_vec();
+        bytes.extend_from_slice(b"\0abc");
+        let mut stream = BusinessObjectStream::new(Cursor::new(bytes));
+        assert!(stream.next_object().is_err());
+
+        let mut bytes = br#"{"type": "text/plain", "size": 3, "content-encoding": "deflate"}"#.to_vec();
+        bytes.extend_from_slice(b"\0\x07bc");
+        let mut stream = BusinessObjectStream::new(Cursor::new(bytes));
+        assert!(stream.next_object().is_err());
+    }
+
+    #[test]
+    fn should_decompress_no_more_than_allowed() {
+        let compressed = deflate(&[b'x'; 1000]);
+        assert_eq!(vec![b'x'; 1000], inflate(&compressed, 1000).unwrap());
+        assert_eq!(Err(ParseError::Decompression("Decompressed payload too large")), inflate(&compressed, 999));
+        assert!(inflate(&compressed[.. compressed.len() - 1], 1000).is_err());
+    }
+}
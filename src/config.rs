@@ -0,0 +1,1130 @@
+use std::collections::BTreeMap;
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use rustc_serialize::json::ToJson;
+use toml;
+
+use ::acl::{AclRule, Cidr};
+use ::io::HeaderDecoding;
+use ::rate_limit::RateLimitPolicy;
+use ::schema::{SchemaPolicy, SchemaRule};
+use ::socket::SocketOptions;
+use ::subscription::{self, BusinessSubscription};
+
+
+pub const DEFAULT_LISTEN_ADDRESS: &str = "127.0.0.1:7890";
+pub const DEFAULT_WORKERS: usize = 1;
+pub const DEFAULT_MAX_CLIENTS: usize = 128;
+pub const DEFAULT_MAX_QUEUE_LENGTH: usize = 1024;
+pub const DEFAULT_IDLE_TIMEOUT: u64 = 60;
+pub const DEFAULT_PONG_TIMEOUT: u64 = 10;
+pub const DEFAULT_PARTIAL_FRAME_TIMEOUT: u64 = 30;
+pub const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 5;
+pub const DEFAULT_HISTORY_MAX_OBJECTS: usize = 1000;
+pub const DEFAULT_HISTORY_MAX_BYTES: usize = 16 * 1024 * 1024;
+pub const DEFAULT_HISTORY_MAX_AGE: u64 = 3600;
+pub const DEFAULT_MAX_HEADER_SIZE: usize = 1024 * 1024;
+pub const DEFAULT_MAX_PAYLOAD_SIZE: usize = 64 * 1024 * 1024;
+pub const DEFAULT_OBJECT_LOG_SAMPLE_RATE: f64 = 1.0;
+pub const DEFAULT_JOURNAL_SEGMENT_BYTES: usize = 64 * 1024 * 1024;
+pub const DEFAULT_JOURNAL_SEGMENT_AGE: u64 = 24 * 3600;
+
+
+/// Another router that rabboe connects to as a client, exchanging the
+/// objects matching `subscriptions` in both directions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Upstream {
+    pub address: SocketAddr,
+    pub subscriptions: Vec<String>,
+    /// Token to log in with. The upstream only takes us for a router if it
+    /// is the token of one of its `peer-routers`.
+    pub auth_token: Option<String>,
+}
+
+
+impl Upstream {
+    /// An upstream exchanging all objects.
+    pub fn new(address: SocketAddr) -> Upstream {
+        Upstream { address, subscriptions: vec!["*".to_string()], auth_token: None }
+    }
+}
+
+
+/// A bus of its own served by the same router, with listeners of its own.
+/// Its clients only see each other: their objects, services, groups and
+/// history are kept apart from those of other buses.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bus {
+    pub name: String,
+    pub listen: Vec<SocketAddr>,
+}
+
+
+/// Server configuration, either built programmatically or read from a TOML
+/// file where the keys are the field names in kebab-case.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    /// Addresses accepting clients, IPv4 or IPv6. In TOML, an address or a
+    /// list of them, where a host name stands for every address it resolves
+    /// to.
+    pub listen: Vec<SocketAddr>,
+    /// Address for accepting WebSocket clients, if any.
+    pub websocket_listen: Option<SocketAddr>,
+    /// Address for accepting TLS clients, if any. Requires `tls_certificate`
+    /// and `tls_private_key`.
+    pub tls_listen: Option<SocketAddr>,
+    pub tls_certificate: Option<PathBuf>,
+    pub tls_private_key: Option<PathBuf>,
+    /// Address for serving Prometheus metrics over HTTP, if any.
+    pub metrics_listen: Option<SocketAddr>,
+    /// Socket options for every connection, upstreams included. In TOML, a
+    /// `[socket]` table with `nodelay`, `keepalive`, `send-buffer` and
+    /// `recv-buffer`.
+    pub socket: SocketOptions,
+    /// Options overriding those of `socket` for the clients of the WebSocket
+    /// and TLS listeners, in `[websocket-socket]` and `[tls-socket]` tables.
+    pub websocket_socket: SocketOptions,
+    pub tls_socket: SocketOptions,
+    /// Buses isolated from the one of the listeners above and from each
+    /// other. In TOML, each is a `[[bus]]` table with a `name` and `listen`
+    /// addresses like those above.
+    pub buses: Vec<Bus>,
+    /// Routers to forward objects to and from. In TOML, each is an
+    /// `[[upstream]]` table with `address` and optional `subscriptions`.
+    pub upstreams: Vec<Upstream>,
+    /// Number of threads routing objects, each with its share of clients.
+    pub workers: usize,
+    /// Total for all workers, split evenly between them.
+    pub max_clients: usize,
+    /// Whether a worker with as many clients as it takes stops accepting
+    /// connections until one leaves, leaving the rest waiting in the
+    /// listen backlog, instead of accepting them only to tell them it is
+    /// full and close them.
+    pub pause_accepting: bool,
+    pub max_queue_length: usize,
+    /// Seconds an object may wait in a client's send queue before it is
+    /// dropped as stale, if there is a limit.
+    pub max_queue_age: Option<u64>,
+    /// Bytes of metadata a client may send in one object before it is
+    /// disconnected.
+    pub max_header_size: usize,
+    /// Largest payload accepted from a client, in bytes. WebSocket clients
+    /// may send frames of at most one header and payload of these sizes.
+    pub max_payload_size: usize,
+    /// Tokens clients log in with using `auth/login` before they may
+    /// subscribe or publish. No tokens means no login is required.
+    pub auth_tokens: Vec<String>,
+    /// Tokens by the identity logging in with them gets, for `acl` rules to
+    /// refer to. In TOML, an `[identities]` table.
+    pub identities: BTreeMap<String, String>,
+    /// Rules limiting what clients may publish and subscribe to. No rules
+    /// means no limits; otherwise anything no rule allows is denied. In
+    /// TOML, each is an `[[acl]]` table with optional `identity`, `peer`,
+    /// `publish` and `subscribe`.
+    pub acl: Vec<AclRule>,
+    /// Identities that may subscribe as other routers, with a `router-id`.
+    /// Anyone else's `router-id` is ignored.
+    pub peer_routers: Vec<String>,
+    /// Token `admin/*` requests have to carry in `admin-token` to be served. No token means
+    /// they are refused.
+    pub admin_token: Option<String>,
+    /// Whether objects breaking the protocol's invariants are answered with
+    /// a `routing/error` instead of being routed.
+    pub validate_objects: bool,
+    /// Whether objects reaching no client are routed again wrapped in a
+    /// `routing/dead-letter`, so that their publishers can tell.
+    pub dead_letters: bool,
+    /// Whether objects routed are stamped with the time they were passed
+    /// on in `routed-at`, as milliseconds since the epoch.
+    pub stamp_routed_at: bool,
+    /// Whether objects from clients are stamped with the time they were
+    /// read in `received-at`, as milliseconds since the epoch.
+    pub stamp_received_at: bool,
+    /// Rules a `routing/subscribe` without `subscriptions` subscribes to.
+    /// None means such a subscription is rejected.
+    pub default_subscription: Option<BusinessSubscription>,
+    /// Whether objects from clients that haven't subscribed yet are routed,
+    /// for clients that publish without subscribing first. Such clients get
+    /// nothing routed to them until they subscribe.
+    pub publish_before_subscribing: bool,
+    /// Whether objects whose payload doesn't match their `sha1` are
+    /// answered with a `routing/error` instead of being routed.
+    pub verify_checksums: bool,
+    /// Whether headers that aren't UTF-8 are answered with a
+    /// `routing/error`, or read with what isn't UTF-8 replaced.
+    pub header_decoding: HeaderDecoding,
+    /// JSON Schemas the payloads of published objects are checked against.
+    /// In TOML, each is a `[[schema]]` table with `file` and an `event` or
+    /// `type` pattern, or both.
+    pub schemas: Vec<SchemaRule>,
+    /// What is done with objects violating their schema.
+    pub schema_violations: SchemaPolicy,
+    /// Objects and bytes per second a client may publish, if limited.
+    pub rate_limit_objects: Option<usize>,
+    pub rate_limit_bytes: Option<usize>,
+    /// What is done with clients publishing faster than that.
+    pub rate_limit_policy: RateLimitPolicy,
+    /// Recently routed objects kept for `history/replay`, bounded by count,
+    /// total payload bytes and age in seconds.
+    pub history_max_objects: usize,
+    pub history_max_bytes: usize,
+    pub history_max_age: u64,
+    /// Directory of the journal recording every object routed on the bus of
+    /// `listen`, if any.
+    pub journal: Option<PathBuf>,
+    /// Size in bytes and age in seconds after which a new journal segment
+    /// is started.
+    pub journal_segment_bytes: usize,
+    pub journal_segment_age: u64,
+    /// Whether to fill the history from the end of the journal at startup.
+    pub journal_replay: bool,
+    /// SQLite database archiving every object clients publish, if any.
+    /// Needs the `rusqlite` feature.
+    pub archive: Option<PathBuf>,
+    pub log_level: Option<String>,
+    /// File receiving a JSON line summarizing each received object, or `-`
+    /// for standard error.
+    pub object_log: Option<PathBuf>,
+    /// Fraction of received objects written to the object log.
+    pub object_log_sample_rate: f64,
+    /// Whether the object log includes text and JSON payloads; otherwise
+    /// they are redacted.
+    pub object_log_payloads: bool,
+
+    /// Seconds of inactivity after which a client is sent a `ping`.
+    pub idle_timeout: u64,
+    /// Seconds a pinged client has to answer with `pong` before it is
+    /// disconnected.
+    pub pong_timeout: u64,
+    /// Seconds a client may take to send the rest of a frame, header and
+    /// payload, once it has started one, so that one trickling in a header
+    /// can't hold its connection forever.
+    pub partial_frame_timeout: u64,
+    /// Seconds to keep sending queued objects after being asked to shut
+    /// down.
+    pub shutdown_timeout: u64,
+}
+
+
+#[derive(Debug)]
+pub enum ConfigError {
+    ReadError(io::Error),
+
+    TomlSyntaxError(String),
+    InvalidValue(String, String),
+}
+
+
+fn extract_reason(error: &ConfigError) -> &str {
+    match *error {
+        ConfigError::ReadError(_) => "Read error",
+        ConfigError::TomlSyntaxError(ref reason) => reason,
+        ConfigError::InvalidValue(_, ref reason) => reason,
+    }
+}
+
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            ConfigError::InvalidValue(ref key, ref reason) => write!(f, "{}: {}", key, reason),
+            _ => write!(f, "{}", extract_reason(self))
+        }
+    }
+}
+
+impl error::Error for ConfigError {
+    fn description(&self) -> &str {
+        extract_reason(self)
+    }
+}
+
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            listen: vec![FromStr::from_str(DEFAULT_LISTEN_ADDRESS).unwrap()],
+            websocket_listen: None,
+            tls_listen: None,
+            tls_certificate: None,
+            tls_private_key: None,
+            metrics_listen: None,
+            socket: SocketOptions::default(),
+            websocket_socket: SocketOptions::default(),
+            tls_socket: SocketOptions::default(),
+            buses: Vec::new(),
+            upstreams: Vec::new(),
+            workers: DEFAULT_WORKERS,
+            max_clients: DEFAULT_MAX_CLIENTS,
+            pause_accepting: false,
+            max_queue_length: DEFAULT_MAX_QUEUE_LENGTH,
+            max_queue_age: None,
+            max_header_size: DEFAULT_MAX_HEADER_SIZE,
+            max_payload_size: DEFAULT_MAX_PAYLOAD_SIZE,
+            auth_tokens: Vec::new(),
+            identities: BTreeMap::new(),
+            acl: Vec::new(),
+            peer_routers: Vec::new(),
+            admin_token: None,
+            validate_objects: false,
+            dead_letters: false,
+            stamp_routed_at: true,
+            stamp_received_at: false,
+            default_subscription: None,
+            publish_before_subscribing: false,
+            verify_checksums: false,
+            header_decoding: HeaderDecoding::Strict,
+            schemas: Vec::new(),
+            schema_violations: SchemaPolicy::Reject,
+            rate_limit_objects: None,
+            rate_limit_bytes: None,
+            rate_limit_policy: RateLimitPolicy::Throttle,
+            history_max_objects: DEFAULT_HISTORY_MAX_OBJECTS,
+            history_max_bytes: DEFAULT_HISTORY_MAX_BYTES,
+            history_max_age: DEFAULT_HISTORY_MAX_AGE,
+            journal: None,
+            journal_segment_bytes: DEFAULT_JOURNAL_SEGMENT_BYTES,
+            journal_segment_age: DEFAULT_JOURNAL_SEGMENT_AGE,
+            journal_replay: false,
+            archive: None,
+            log_level: None,
+            object_log: None,
+            object_log_sample_rate: DEFAULT_OBJECT_LOG_SAMPLE_RATE,
+            object_log_payloads: false,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            pong_timeout: DEFAULT_PONG_TIMEOUT,
+            partial_frame_timeout: DEFAULT_PARTIAL_FRAME_TIMEOUT,
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+        }
+    }
+}
+
+
+fn invalid(key: &str, reason: &str) -> ConfigError {
+    ConfigError::InvalidValue(key.to_string(), reason.to_string())
+}
+
+
+pub fn parse_listen_address(key: &str, value: &str) -> Result<SocketAddr, ConfigError> {
+    FromStr::from_str(value).map_err(|_| invalid(key, "expected an address of the form host:port"))
+}
+
+
+/// Parses an address to listen on, resolving a host name to all of its
+/// addresses.
+pub fn resolve_listen_address(key: &str, value: &str) -> Result<Vec<SocketAddr>, ConfigError> {
+    if let Ok(address) = SocketAddr::from_str(value) {
+        return Ok(vec![address]);
+    }
+    if !value.contains(':') {
+        return Err(invalid(key, "expected an address of the form host:port"));
+    }
+
+    let mut addresses: Vec<SocketAddr> = value.to_socket_addrs()
+        .map_err(|e| invalid(key, &format!("failed to resolve {}: {}", value, e)))?
+        .collect();
+    addresses.sort();
+    addresses.dedup();
+    if addresses.is_empty() {
+        return Err(invalid(key, &format!("{} resolves to no addresses", value)));
+    }
+    Ok(addresses)
+}
+
+
+pub fn parse_count(key: &str, value: &str) -> Result<usize, ConfigError> {
+    match usize::from_str(value) {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(invalid(key, "expected a positive integer"))
+    }
+}
+
+
+pub fn parse_schema_policy(key: &str, value: &str) -> Result<SchemaPolicy, ConfigError> {
+    SchemaPolicy::from_str(value).map_err(|_| invalid(key, "expected reject or tag"))
+}
+
+
+pub fn parse_header_decoding(key: &str, value: &str) -> Result<HeaderDecoding, ConfigError> {
+    HeaderDecoding::from_str(value).map_err(|_| invalid(key, "expected strict or lossy"))
+}
+
+
+pub fn parse_rate_limit_policy(key: &str, value: &str) -> Result<RateLimitPolicy, ConfigError> {
+    RateLimitPolicy::from_str(value).map_err(|_| invalid(key, "expected throttle, warn or disconnect"))
+}
+
+
+fn valid_rate(key: &str, rate: f64) -> Result<f64, ConfigError> {
+    if rate > 0.0 && rate <= 1.0 {
+        Ok(rate)
+    } else {
+        Err(invalid(key, "expected a number greater than 0 and at most 1"))
+    }
+}
+
+
+pub fn parse_rate(key: &str, value: &str) -> Result<f64, ConfigError> {
+    match f64::from_str(value) {
+        Ok(rate) => valid_rate(key, rate),
+        Err(_) => Err(invalid(key, "expected a number greater than 0 and at most 1"))
+    }
+}
+
+
+fn toml_rate(key: &str, value: &toml::Value) -> Result<f64, ConfigError> {
+    match *value {
+        toml::Value::Float(rate) => valid_rate(key, rate),
+        toml::Value::Integer(rate) => valid_rate(key, rate as f64),
+        _ => Err(invalid(key, "expected a number greater than 0 and at most 1"))
+    }
+}
+
+
+fn toml_bool(key: &str, value: &toml::Value) -> Result<bool, ConfigError> {
+    value.as_bool().ok_or_else(|| invalid(key, "expected a boolean"))
+}
+
+
+fn toml_upstream(key: &str, value: &toml::Value) -> Result<Upstream, ConfigError> {
+    let table = value.as_table().ok_or_else(|| invalid(key, "expected a table"))?;
+
+    let mut upstream = match table.get("address") {
+        Some(address) => Upstream::new(parse_listen_address(key, toml_str(key, address)?)?),
+        None => { return Err(invalid(key, "address is required")); }
+    };
+    for (name, value) in table.iter() {
+        match name.as_ref() {
+            "address" => {},
+            "auth-token" => { upstream.auth_token = Some(toml_str(key, value)?.to_string()); },
+            "subscriptions" => {
+                let rules = value.as_slice().ok_or_else(|| invalid(key, "expected a list of subscription rules"))?;
+                upstream.subscriptions = rules.iter()
+                    .map(|rule| toml_str(key, rule).map(|rule| rule.to_string()))
+                    .collect::<Result<Vec<String>, ConfigError>>()?;
+            },
+            _ => { return Err(invalid(key, "unknown upstream key")); }
+        }
+    }
+
+    Ok(upstream)
+}
+
+
+fn toml_listen(key: &str, value: &toml::Value) -> Result<Vec<SocketAddr>, ConfigError> {
+    match value.as_slice() {
+        Some(addresses) => {
+            let mut listen = Vec::new();
+            for address in addresses {
+                listen.extend(resolve_listen_address(key, toml_str(key, address)?)?);
+            }
+            Ok(listen)
+        },
+        None => resolve_listen_address(key, toml_str(key, value)?)
+    }
+}
+
+
+fn toml_bus(key: &str, value: &toml::Value) -> Result<Bus, ConfigError> {
+    let table = value.as_table().ok_or_else(|| invalid(key, "expected a table"))?;
+
+    let mut bus = Bus { name: String::new(), listen: Vec::new() };
+    for (name, value) in table.iter() {
+        match name.as_ref() {
+            "name" => { bus.name = toml_str(key, value)?.to_string(); },
+            "listen" => { bus.listen = toml_listen(key, value)?; },
+            _ => { return Err(invalid(key, "unknown bus key")); }
+        }
+    }
+
+    Ok(bus)
+}
+
+
+/// Parses a bus given as `name=host:port`.
+pub fn parse_bus(key: &str, value: &str) -> Result<Bus, ConfigError> {
+    let mut parts = value.splitn(2, '=');
+    match (parts.next(), parts.next()) {
+        (Some(name), Some(address)) => Ok(Bus { name: name.to_string(), listen: resolve_listen_address(key, address)? }),
+        _ => Err(invalid(key, "expected a bus of the form name=host:port"))
+    }
+}
+
+
+fn toml_socket_options(key: &str, value: &toml::Value) -> Result<SocketOptions, ConfigError> {
+    let table = value.as_table().ok_or_else(|| invalid(key, "expected a table"))?;
+
+    let mut options = SocketOptions::default();
+    for (name, value) in table.iter() {
+        match name.as_ref() {
+            "nodelay" => { options.nodelay = Some(toml_bool(key, value)?); },
+            "keepalive" => {
+                match value.as_integer() {
+                    Some(seconds) if seconds >= 0 && seconds <= i64::from(i32::MAX) => {
+                        options.keepalive = Some(seconds as u32);
+                    },
+                    _ => { return Err(invalid(key, "expected keepalive seconds, or 0 for none")); }
+                }
+            },
+            "send-buffer" => { options.send_buffer = Some(toml_count(key, value)?); },
+            "recv-buffer" => { options.recv_buffer = Some(toml_count(key, value)?); },
+            _ => { return Err(invalid(key, "unknown socket key")); }
+        }
+    }
+
+    Ok(options)
+}
+
+
+/// Parses subscription rules given for `key`.
+pub fn parse_rules(key: &str, rules: &[String]) -> Result<BusinessSubscription, ConfigError> {
+    subscription::parse_subscription(&rules.to_json()).map_err(|e| invalid(key, &e.to_string()))
+}
+
+
+fn toml_rules(key: &str, value: &toml::Value) -> Result<BusinessSubscription, ConfigError> {
+    let rules = value.as_slice().ok_or_else(|| invalid(key, "expected a list of subscription rules"))?;
+    let rules = rules.iter()
+        .map(|rule| toml_str(key, rule).map(|rule| rule.to_string()))
+        .collect::<Result<Vec<String>, ConfigError>>()?;
+    parse_rules(key, &rules)
+}
+
+
+fn toml_schema_rule(key: &str, value: &toml::Value) -> Result<SchemaRule, ConfigError> {
+    let table = value.as_table().ok_or_else(|| invalid(key, "expected a table"))?;
+
+    let mut rule = SchemaRule { event: None, _type: None, file: PathBuf::new() };
+    for (name, value) in table.iter() {
+        match name.as_ref() {
+            "event" => { rule.event = Some(toml_str(key, value)?.to_string()); },
+            "type" => { rule._type = Some(toml_str(key, value)?.to_string()); },
+            "file" => { rule.file = PathBuf::from(toml_str(key, value)?); },
+            _ => { return Err(invalid(key, "unknown schema key")); }
+        }
+    }
+
+    Ok(rule)
+}
+
+
+fn toml_acl_rule(key: &str, value: &toml::Value) -> Result<AclRule, ConfigError> {
+    let table = value.as_table().ok_or_else(|| invalid(key, "expected a table"))?;
+
+    let mut rule = AclRule {
+        identity: None,
+        peer: None,
+        publish: BusinessSubscription::List(Vec::new()),
+        subscribe: BusinessSubscription::List(Vec::new()),
+    };
+    for (name, value) in table.iter() {
+        match name.as_ref() {
+            "identity" => { rule.identity = Some(toml_str(key, value)?.to_string()); },
+            "peer" => {
+                let peer = Cidr::from_str(toml_str(key, value)?)
+                    .map_err(|_| invalid(key, "expected an address range of the form address/prefix"))?;
+                rule.peer = Some(peer);
+            },
+            "publish" => { rule.publish = toml_rules(key, value)?; },
+            "subscribe" => { rule.subscribe = toml_rules(key, value)?; },
+            _ => { return Err(invalid(key, "unknown acl key")); }
+        }
+    }
+
+    Ok(rule)
+}
+
+
+fn toml_count(key: &str, value: &toml::Value) -> Result<usize, ConfigError> {
+    match value.as_integer() {
+        Some(n) if n > 0 => Ok(n as usize),
+        _ => Err(invalid(key, "expected a positive integer"))
+    }
+}
+
+
+fn toml_str<'a>(key: &str, value: &'a toml::Value) -> Result<&'a str, ConfigError> {
+    value.as_str().ok_or_else(|| invalid(key, "expected a string"))
+}
+
+
+impl Config {
+    pub fn from_toml_str(input: &str) -> Result<Config, ConfigError> {
+        let mut parser = toml::Parser::new(input);
+        let table = match parser.parse() {
+            Some(table) => table,
+            None => {
+                let reasons: Vec<String> = parser.errors.iter().map(|e| {
+                    let (line, col) = parser.to_linecol(e.lo);
+                    format!("{}:{}: {}", line + 1, col + 1, e.desc)
+                }).collect();
+                return Err(ConfigError::TomlSyntaxError(reasons.join("; ")));
+            }
+        };
+
+        let mut config = Config::default();
+
+        for (key, value) in table.iter() {
+            match key.as_ref() {
+                "listen" => { config.listen = toml_listen(key, value)?; },
+                "websocket-listen" => {
+                    config.websocket_listen = Some(parse_listen_address(key, toml_str(key, value)?)?);
+                },
+                "tls-listen" => {
+                    config.tls_listen = Some(parse_listen_address(key, toml_str(key, value)?)?);
+                },
+                "tls-certificate" => { config.tls_certificate = Some(PathBuf::from(toml_str(key, value)?)); },
+                "tls-private-key" => { config.tls_private_key = Some(PathBuf::from(toml_str(key, value)?)); },
+                "metrics-listen" => {
+                    config.metrics_listen = Some(parse_listen_address(key, toml_str(key, value)?)?);
+                },
+                "socket" => { config.socket = toml_socket_options(key, value)?; },
+                "websocket-socket" => { config.websocket_socket = toml_socket_options(key, value)?; },
+                "tls-socket" => { config.tls_socket = toml_socket_options(key, value)?; },
+                "bus" => {
+                    let buses = value.as_slice().ok_or_else(|| invalid(key, "expected [[bus]] tables"))?;
+                    config.buses = buses.iter()
+                        .map(|bus| toml_bus(key, bus))
+                        .collect::<Result<Vec<Bus>, ConfigError>>()?;
+                },
+                "upstream" => {
+                    let upstreams = value.as_slice().ok_or_else(|| invalid(key, "expected [[upstream]] tables"))?;
+                    config.upstreams = upstreams.iter()
+                        .map(|upstream| toml_upstream(key, upstream))
+                        .collect::<Result<Vec<Upstream>, ConfigError>>()?;
+                },
+                "workers" => { config.workers = toml_count(key, value)?; },
+                "max-clients" => { config.max_clients = toml_count(key, value)?; },
+                "pause-accepting" => { config.pause_accepting = toml_bool(key, value)?; },
+                "max-queue-length" => { config.max_queue_length = toml_count(key, value)?; },
+                "max-queue-age" => { config.max_queue_age = Some(toml_count(key, value)? as u64); },
+                "max-header-size" => { config.max_header_size = toml_count(key, value)?; },
+                "max-payload-size" => { config.max_payload_size = toml_count(key, value)?; },
+                "auth-tokens" => {
+                    let tokens = value.as_slice().ok_or_else(|| invalid(key, "expected a list of tokens"))?;
+                    config.auth_tokens = tokens.iter()
+                        .map(|token| toml_str(key, token).map(|token| token.to_string()))
+                        .collect::<Result<Vec<String>, ConfigError>>()?;
+                },
+                "identities" => {
+                    let identities = value.as_table().ok_or_else(|| invalid(key, "expected an [identities] table"))?;
+                    config.identities = identities.iter()
+                        .map(|(identity, token)| toml_str(key, token).map(|token| (identity.clone(), token.to_string())))
+                        .collect::<Result<BTreeMap<String, String>, ConfigError>>()?;
+                },
+                "acl" => {
+                    let rules = value.as_slice().ok_or_else(|| invalid(key, "expected [[acl]] tables"))?;
+                    config.acl = rules.iter()
+                        .map(|rule| toml_acl_rule(key, rule))
+                        .collect::<Result<Vec<AclRule>, ConfigError>>()?;
+                },
+                "peer-routers" => {
+                    let identities = value.as_slice().ok_or_else(|| invalid(key, "expected a list of identities"))?;
+                    config.peer_routers = identities.iter()
+                        .map(|identity| toml_str(key, identity).map(|identity| identity.to_string()))
+                        .collect::<Result<Vec<String>, ConfigError>>()?;
+                },
+                "admin-token" => { config.admin_token = Some(toml_str(key, value)?.to_string()); },
+                "validate-objects" => { config.validate_objects = toml_bool(key, value)?; },
+                "dead-letters" => { config.dead_letters = toml_bool(key, value)?; },
+                "stamp-routed-at" => { config.stamp_routed_at = toml_bool(key, value)?; },
+                "stamp-received-at" => { config.stamp_received_at = toml_bool(key, value)?; },
+                "default-subscription" => { config.default_subscription = Some(toml_rules(key, value)?); },
+                "publish-before-subscribing" => { config.publish_before_subscribing = toml_bool(key, value)?; },
+                "verify-checksums" => { config.verify_checksums = toml_bool(key, value)?; },
+                "header-decoding" => {
+                    config.header_decoding = parse_header_decoding(key, toml_str(key, value)?)?;
+                },
+                "schema" => {
+                    let rules = value.as_slice().ok_or_else(|| invalid(key, "expected [[schema]] tables"))?;
+                    config.schemas = rules.iter()
+                        .map(|rule| toml_schema_rule(key, rule))
+                        .collect::<Result<Vec<SchemaRule>, ConfigError>>()?;
+                },
+                "schema-violations" => {
+                    config.schema_violations = parse_schema_policy(key, toml_str(key, value)?)?;
+                },
+                "rate-limit-objects" => { config.rate_limit_objects = Some(toml_count(key, value)?); },
+                "rate-limit-bytes" => { config.rate_limit_bytes = Some(toml_count(key, value)?); },
+                "rate-limit-policy" => {
+                    config.rate_limit_policy = parse_rate_limit_policy(key, toml_str(key, value)?)?;
+                },
+                "history-max-objects" => { config.history_max_objects = toml_count(key, value)?; },
+                "history-max-bytes" => { config.history_max_bytes = toml_count(key, value)?; },
+                "history-max-age" => { config.history_max_age = toml_count(key, value)? as u64; },
+                "journal" => { config.journal = Some(PathBuf::from(toml_str(key, value)?)); },
+                "journal-segment-bytes" => { config.journal_segment_bytes = toml_count(key, value)?; },
+                "journal-segment-age" => { config.journal_segment_age = toml_count(key, value)? as u64; },
+                "journal-replay" => { config.journal_replay = toml_bool(key, value)?; },
+                "archive" => { config.archive = Some(PathBuf::from(toml_str(key, value)?)); },
+                "log-level" => { config.log_level = Some(toml_str(key, value)?.to_string()); },
+                "object-log" => { config.object_log = Some(PathBuf::from(toml_str(key, value)?)); },
+                "object-log-sample-rate" => { config.object_log_sample_rate = toml_rate(key, value)?; },
+                "object-log-payloads" => { config.object_log_payloads = toml_bool(key, value)?; },
+                "idle-timeout" => { config.idle_timeout = toml_count(key, value)? as u64; },
+                "pong-timeout" => { config.pong_timeout = toml_count(key, value)? as u64; },
+                "partial-frame-timeout" => { config.partial_frame_timeout = toml_count(key, value)? as u64; },
+                "shutdown-timeout" => { config.shutdown_timeout = toml_count(key, value)? as u64; },
+                _ => { return Err(invalid(key, "unknown configuration key")); }
+            }
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Whether clients have to log in before subscribing or publishing.
+    pub fn requires_login(&self) -> bool {
+        !self.auth_tokens.is_empty() || !self.identities.is_empty()
+    }
+
+    /// Checks that settings depending on each other are consistent.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.listen.is_empty() {
+            return Err(invalid("listen", "expected at least one address"));
+        }
+        if self.workers == 0 {
+            return Err(invalid("workers", "expected a positive integer"));
+        }
+
+        if self.tls_listen.is_some() && (self.tls_certificate.is_none() || self.tls_private_key.is_none()) {
+            return Err(invalid("tls-listen", "requires tls-certificate and tls-private-key"));
+        }
+
+        for (i, bus) in self.buses.iter().enumerate() {
+            if bus.name.is_empty() {
+                return Err(invalid("bus", "name is required"));
+            }
+            if bus.listen.is_empty() {
+                return Err(invalid("bus", &format!("{} has no listen addresses", bus.name)));
+            }
+            if self.buses[.. i].iter().any(|other| other.name == bus.name) {
+                return Err(invalid("bus", &format!("{} is defined twice", bus.name)));
+            }
+        }
+
+        if self.journal_replay && self.journal.is_none() {
+            return Err(invalid("journal-replay", "requires journal"));
+        }
+        if self.archive.is_some() && !cfg!(any(feature = "rusqlite", test)) {
+            return Err(invalid("archive", "requires building with the rusqlite feature"));
+        }
+
+        for upstream in &self.upstreams {
+            if let Err(e) = subscription::parse_subscription(&upstream.subscriptions.to_json()) {
+                return Err(ConfigError::InvalidValue("upstream".to_string(), e.to_string()));
+            }
+        }
+
+        for rule in &self.schemas {
+            if rule.file.as_os_str().is_empty() {
+                return Err(invalid("schema", "file is required"));
+            }
+            if rule.event.is_none() && rule._type.is_none() {
+                return Err(invalid("schema", &format!("{} applies to no event or type", rule.file.display())));
+            }
+        }
+
+        for rule in &self.acl {
+            if let Some(ref identity) = rule.identity {
+                if !self.identities.contains_key(identity) {
+                    return Err(invalid("acl", &format!("unknown identity {}", identity)));
+                }
+            }
+        }
+
+        for identity in &self.peer_routers {
+            if !self.identities.contains_key(identity) {
+                return Err(invalid("peer-routers", &format!("unknown identity {}", identity)));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// This configuration with the settings that can be changed while the
+    /// router is running taken from `new`. Also returns the keys of the
+    /// settings that differ in `new` but only take effect on restart.
+    pub fn reload(&self, new: Config) -> (Config, Vec<&'static str>) {
+        let mut reloaded = new;
+        let mut needs_restart = Vec::new();
+        macro_rules! keep {
+            ($($field:ident => $key:expr),*) => {
+                $(
+                    if reloaded.$field != self.$field {
+                        needs_restart.push($key);
+                        reloaded.$field = self.$field.clone();
+                    }
+                )*
+            }
+        }
+        keep!(listen => "listen", websocket_listen => "websocket-listen", tls_listen => "tls-listen",
+              tls_certificate => "tls-certificate", tls_private_key => "tls-private-key",
+              metrics_listen => "metrics-listen", buses => "bus", upstreams => "upstream", workers => "workers",
+              max_clients => "max-clients", pause_accepting => "pause-accepting",
+              history_max_objects => "history-max-objects", history_max_bytes => "history-max-bytes",
+              history_max_age => "history-max-age", journal => "journal",
+              journal_segment_bytes => "journal-segment-bytes",
+              journal_segment_age => "journal-segment-age", journal_replay => "journal-replay",
+              schemas => "schema", archive => "archive", log_level => "log-level", object_log => "object-log");
+
+        (reloaded, needs_restart)
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Config, ConfigError> {
+        let mut input = String::new();
+        File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut input))
+            .map_err(ConfigError::ReadError)?;
+
+        Config::from_toml_str(&input)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::path::PathBuf;
+    use std::str::FromStr;
+
+    use rustc_serialize::json::ToJson;
+
+    use super::{Bus, Config, ConfigError, Upstream, DEFAULT_MAX_CLIENTS};
+    use ::io::HeaderDecoding;
+    use ::rate_limit::RateLimitPolicy;
+    use ::schema::SchemaPolicy;
+    use ::socket::SocketOptions;
+
+
+    #[test]
+    fn empty_toml_should_give_defaults() {
+        assert_eq!(Config::default(), Config::from_toml_str("").unwrap());
+    }
+
+    #[test]
+    fn should_read_all_keys_from_toml() {
+        let config = Config::from_toml_str(r#"
+listen = "0.0.0.0:7891"
+websocket-listen = "0.0.0.0:7892"
+tls-listen = "0.0.0.0:7893"
+tls-certificate = "/etc/rabboe/cert.pem"
+tls-private-key = "/etc/rabboe/key.pem"
+metrics-listen = "127.0.0.1:9100"
+workers = 4
+max-clients = 16
+pause-accepting = true
+max-queue-length = 32
+max-queue-age = 10
+max-header-size = 4096
+max-payload-size = 65536
+auth-tokens = ["s3cret"]
+admin-token = "4dmin"
+validate-objects = true
+dead-letters = true
+stamp-routed-at = false
+stamp-received-at = true
+default-subscription = ["@routing/*", "@ping", "@pong"]
+publish-before-subscribing = true
+verify-checksums = true
+header-decoding = "lossy"
+schema-violations = "tag"
+rate-limit-objects = 100
+rate-limit-bytes = 1000000
+rate-limit-policy = "disconnect"
+history-max-objects = 100
+history-max-bytes = 1048576
+history-max-age = 600
+journal = "/var/lib/rabboe/journal"
+journal-segment-bytes = 1000000
+journal-segment-age = 3600
+journal-replay = true
+archive = "/var/lib/rabboe/archive.sqlite"
+log-level = "debug"
+object-log = "/var/log/rabboe/objects.jsonl"
+object-log-sample-rate = 0.1
+object-log-payloads = true
+idle-timeout = 30
+pong-timeout = 5
+partial-frame-timeout = 15
+shutdown-timeout = 2
+"#).unwrap();
+
+        assert_eq!(vec![SocketAddr::from_str("0.0.0.0:7891").unwrap()], config.listen);
+        assert_eq!(FromStr::from_str("0.0.0.0:7892").ok(), config.websocket_listen);
+        assert_eq!(FromStr::from_str("0.0.0.0:7893").ok(), config.tls_listen);
+        assert_eq!(Some(PathBuf::from("/etc/rabboe/cert.pem")), config.tls_certificate);
+        assert_eq!(Some(PathBuf::from("/etc/rabboe/key.pem")), config.tls_private_key);
+        assert_eq!(FromStr::from_str("127.0.0.1:9100").ok(), config.metrics_listen);
+        assert_eq!(4, config.workers);
+        assert_eq!(16, config.max_clients);
+        assert!(config.pause_accepting);
+        assert_eq!(32, config.max_queue_length);
+        assert_eq!(Some(10), config.max_queue_age);
+        assert_eq!(4096, config.max_header_size);
+        assert_eq!(65536, config.max_payload_size);
+        assert_eq!(vec!["s3cret".to_string()], config.auth_tokens);
+        assert_eq!(Some("4dmin".to_string()), config.admin_token);
+        assert!(config.validate_objects);
+        assert!(config.dead_letters);
+        assert!(!config.stamp_routed_at);
+        assert!(config.stamp_received_at);
+        assert_eq!(Some(vec!["@routing/*".to_string(), "@ping".to_string(), "@pong".to_string()].to_json()),
+                   config.default_subscription.map(|rules| rules.to_json()));
+        assert!(config.publish_before_subscribing);
+        assert!(config.verify_checksums);
+        assert_eq!(HeaderDecoding::Lossy, config.header_decoding);
+        assert_eq!(SchemaPolicy::Tag, config.schema_violations);
+        assert_eq!(Some(100), config.rate_limit_objects);
+        assert_eq!(Some(1000000), config.rate_limit_bytes);
+        assert_eq!(RateLimitPolicy::Disconnect, config.rate_limit_policy);
+        assert_eq!(100, config.history_max_objects);
+        assert_eq!(1048576, config.history_max_bytes);
+        assert_eq!(600, config.history_max_age);
+        assert_eq!(Some(PathBuf::from("/var/lib/rabboe/journal")), config.journal);
+        assert_eq!(1000000, config.journal_segment_bytes);
+        assert_eq!(3600, config.journal_segment_age);
+        assert!(config.journal_replay);
+        assert_eq!(Some(PathBuf::from("/var/lib/rabboe/archive.sqlite")), config.archive);
+        assert_eq!(Some("debug".to_string()), config.log_level);
+        assert_eq!(Some(PathBuf::from("/var/log/rabboe/objects.jsonl")), config.object_log);
+        assert_eq!(0.1, config.object_log_sample_rate);
+        assert!(config.object_log_payloads);
+        assert_eq!(30, config.idle_timeout);
+        assert_eq!(5, config.pong_timeout);
+        assert_eq!(15, config.partial_frame_timeout);
+        assert_eq!(2, config.shutdown_timeout);
+    }
+
+    #[test]
+    fn unset_keys_should_keep_defaults() {
+        let config = Config::from_toml_str("max-queue-length = 5").unwrap();
+
+        assert_eq!(DEFAULT_MAX_CLIENTS, config.max_clients);
+        assert_eq!(5, config.max_queue_length);
+    }
+
+    #[test]
+    fn should_reject_invalid_values() {
+        for input in &[r#"listen = "nowhere""#, "max-clients = 0", r#"max-clients = "many""#,
+                       "no-such-key = 1", r#"tls-listen = "0.0.0.0:7893""#,
+                       "object-log-sample-rate = 1.5", "object-log-sample-rate = 0",
+                       r#"object-log-payloads = "yes""#, "journal-replay = true",
+                       r#"rate-limit-policy = "ignore""#, "rate-limit-objects = 0", r#"header-decoding = "loose""#,
+                       "[socket]\nkeepalive = -1", "[socket]\nsend-buffer = 0", "[tls-socket]\nlinger = 1"] {
+            match Config::from_toml_str(input) {
+                Err(ConfigError::InvalidValue(_, _)) => {},
+                other => panic!("Expected InvalidValue for {}, got {:?}", input, other)
+            }
+        }
+
+        // Configurations built in code aren't read through `toml_count`
+        match (Config { workers: 0, .. Config::default() }).validate() {
+            Err(ConfigError::InvalidValue(ref key, _)) if key == "workers" => {},
+            other => panic!("Expected InvalidValue for workers, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn should_read_several_listen_addresses() {
+        let config = Config::from_toml_str(r#"listen = ["0.0.0.0:7890", "[::]:7890", "localhost:7891"]"#).unwrap();
+
+        assert_eq!(FromStr::from_str("0.0.0.0:7890").ok(), config.listen.first().cloned());
+        assert_eq!(FromStr::from_str("[::]:7890").ok(), config.listen.get(1).cloned());
+        assert!(config.listen[2 ..].iter().all(|address| address.ip().is_loopback() && address.port() == 7891));
+        assert!(config.listen.len() > 2);
+
+        for input in &["listen = []", "listen = [7891]", r#"listen = "[::]""#] {
+            match Config::from_toml_str(input) {
+                Err(ConfigError::InvalidValue(_, _)) => {},
+                other => panic!("Expected InvalidValue for {}, got {:?}", input, other)
+            }
+        }
+    }
+
+    #[test]
+    fn should_read_upstreams_from_toml() {
+        let config = Config::from_toml_str(r#"
+[[upstream]]
+address = "10.0.0.1:7890"
+
+[[upstream]]
+address = "10.0.0.2:7890"
+subscriptions = ["@chat/*", "!@chat/private"]
+auth-token = "s3cret"
+"#).unwrap();
+
+        assert_eq!(vec![Upstream::new(FromStr::from_str("10.0.0.1:7890").unwrap()),
+                        Upstream { address: FromStr::from_str("10.0.0.2:7890").unwrap(),
+                                   subscriptions: vec!["@chat/*".to_string(), "!@chat/private".to_string()],
+                                   auth_token: Some("s3cret".to_string()) }],
+                   config.upstreams);
+    }
+
+    #[test]
+    fn should_read_socket_options_from_toml() {
+        let config = Config::from_toml_str(r#"
+[socket]
+nodelay = true
+keepalive = 60
+send-buffer = 262144
+recv-buffer = 262144
+
+[tls-socket]
+keepalive = 0
+"#).unwrap();
+
+        assert_eq!(SocketOptions { nodelay: Some(true), keepalive: Some(60), send_buffer: Some(262144),
+                                   recv_buffer: Some(262144) },
+                   config.socket);
+        assert!(config.websocket_socket.is_empty());
+        assert_eq!(SocketOptions { keepalive: Some(0), .. SocketOptions::default() }, config.tls_socket);
+    }
+
+    #[test]
+    fn should_read_buses_from_toml() {
+        let config = Config::from_toml_str(r#"
+[[bus]]
+name = "tenant-a"
+listen = "127.0.0.1:7900"
+
+[[bus]]
+name = "tenant-b"
+listen = ["127.0.0.1:7901", "127.0.0.1:7902"]
+"#).unwrap();
+
+        assert_eq!(vec![Bus { name: "tenant-a".to_string(), listen: vec![FromStr::from_str("127.0.0.1:7900").unwrap()] },
+                        Bus { name: "tenant-b".to_string(),
+                              listen: vec![FromStr::from_str("127.0.0.1:7901").unwrap(),
+                                           FromStr::from_str("127.0.0.1:7902").unwrap()] }],
+                   config.buses);
+
+        for input in &["[[bus]]\nlisten = \"127.0.0.1:7900\"", "[[bus]]\nname = \"a\"\nlisten = []",
+                       "[[bus]]\nname = \"a\"\nlisten = \"127.0.0.1:7900\"\n[[bus]]\nname = \"a\"\nlisten = \"127.0.0.1:7901\""] {
+            match Config::from_toml_str(input) {
+                Err(ConfigError::InvalidValue(_, _)) => {},
+                other => panic!("Expected InvalidValue for {}, got {:?}", input, other)
+            }
+        }
+    }
+
+    #[test]
+    fn should_reject_invalid_upstreams() {
+        for input in &["upstream = \"10.0.0.1:7890\"", "[[upstream]]\nsubscriptions = [\"*\"]",
+                       "[[upstream]]\naddress = \"10.0.0.1:7890\"\nsubscriptions = [\"!\"]",
+                       "[[upstream]]\naddress = \"10.0.0.1:7890\"\nport = 1"] {
+            match Config::from_toml_str(input) {
+                Err(ConfigError::InvalidValue(_, _)) => {},
+                other => panic!("Expected InvalidValue for {}, got {:?}", input, other)
+            }
+        }
+    }
+
+    #[test]
+    fn should_read_acl_from_toml() {
+        let config = Config::from_toml_str(r#"
+peer-routers = ["admin"]
+
+[identities]
+admin = "s3cret"
+
+[[acl]]
+peer = "10.0.0.0/8"
+publish = ["@chat/*"]
+subscribe = ["@chat/*", "!@chat/private"]
+
+[[acl]]
+identity = "admin"
+publish = ["*"]
+subscribe = ["*"]
+"#).unwrap();
+
+        assert!(config.requires_login());
+        assert_eq!(Some(&"s3cret".to_string()), config.identities.get("admin"));
+        assert_eq!(2, config.acl.len());
+        assert_eq!(None, config.acl[0].identity);
+        assert_eq!(FromStr::from_str("10.0.0.0/8").ok(), config.acl[0].peer);
+        assert_eq!(r#"["@chat/*","!@chat/private"]"#, config.acl[0].subscribe.to_json().to_string());
+        assert_eq!(Some("admin".to_string()), config.acl[1].identity);
+        assert_eq!(None, config.acl[1].peer);
+        assert_eq!(vec!["admin".to_string()], config.peer_routers);
+    }
+
+    #[test]
+    fn should_reject_invalid_acl() {
+        for input in &["acl = \"allow\"", "[[acl]]\npeer = \"10.0.0.0/40\"",
+                       "[[acl]]\npublish = [\"!\"]", "[[acl]]\nidentity = \"nobody\"",
+                       "[[acl]]\naction = \"allow\"", "[identities]\nadmin = 1",
+                       "peer-routers = [\"nobody\"]"] {
+            match Config::from_toml_str(input) {
+                Err(ConfigError::InvalidValue(_, _)) => {},
+                other => panic!("Expected InvalidValue for {}, got {:?}", input, other)
+            }
+        }
+    }
+
+    #[test]
+    fn should_read_schemas_from_toml() {
+        let config = Config::from_toml_str(r#"
+[[schema]]
+event = "sensors/*"
+file = "/etc/rabboe/reading.json"
+
+[[schema]]
+type = "application/json"
+file = "/etc/rabboe/json.json"
+"#).unwrap();
+
+        assert_eq!(2, config.schemas.len());
+        assert_eq!(Some("sensors/*".to_string()), config.schemas[0].event);
+        assert_eq!(None, config.schemas[0]._type);
+        assert_eq!(PathBuf::from("/etc/rabboe/reading.json"), config.schemas[0].file);
+        assert_eq!(Some("application/json".to_string()), config.schemas[1]._type);
+
+        for input in &["schema = \"reading.json\"", "[[schema]]\nevent = \"sensors/*\"",
+                       "[[schema]]\nfile = \"reading.json\"", "[[schema]]\nfile = \"a.json\"\nname = \"a\"",
+                       "schema-violations = \"ignore\""] {
+            match Config::from_toml_str(input) {
+                Err(ConfigError::InvalidValue(_, _)) => {},
+                other => panic!("Expected InvalidValue for {}, got {:?}", input, other)
+            }
+        }
+    }
+
+    #[test]
+    fn reload_should_keep_settings_needing_restart() {
+        let current = Config::default();
+        let new = Config::from_toml_str(r#"
+workers = 4
+max-queue-length = 32
+rate-limit-objects = 100
+auth-tokens = ["s3cret"]
+"#).unwrap();
+
+        let (reloaded, needs_restart) = current.reload(new);
+        assert_eq!(vec!["workers"], needs_restart);
+        assert_eq!(current.workers, reloaded.workers);
+        assert_eq!(32, reloaded.max_queue_length);
+        assert_eq!(Some(100), reloaded.rate_limit_objects);
+        assert_eq!(vec!["s3cret".to_string()], reloaded.auth_tokens);
+
+        let (reloaded, needs_restart) = current.reload(current.clone());
+        assert_eq!(current, reloaded);
+        assert!(needs_restart.is_empty());
+    }
+
+    #[test]
+    fn should_reject_toml_syntax_errors() {
+        match Config::from_toml_str("listen = ") {
+            Err(ConfigError::TomlSyntaxError(_)) => {},
+            other => panic!("Expected TomlSyntaxError, got {:?}", other)
+        }
+    }
+}
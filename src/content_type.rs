@@ -0,0 +1,137 @@
+//! Parsing of the MIME types found in the `type` field of business objects,
+//! e.g. `text/plain; charset=ISO-8859-1`.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use encoding_rs::{Encoding, UTF_8};
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContentType {
+    pub _type: String,
+    pub subtype: String,
+    /// Parameters with lowercased names and unquoted values.
+    pub parameters: BTreeMap<String, String>,
+}
+
+
+fn unquote(value: &str) -> String {
+    let value = value.trim();
+
+    if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        value[1 .. value.len() - 1].replace("\\\"", "\"")
+    } else {
+        value.to_string()
+    }
+}
+
+
+impl ContentType {
+    /// Parses `type/subtype` followed by any number of `; name=value`
+    /// parameters. Type, subtype and parameter names are case-insensitive
+    /// and lowercased; malformed parameters are ignored.
+    pub fn parse(content_type: &str) -> Option<ContentType> {
+        let mut parts = content_type.split(';');
+        let essence = parts.next().unwrap().trim().to_lowercase();
+
+        let (_type, subtype) = match essence.find('/') {
+            Some(slash) => (essence[.. slash].trim().to_string(), essence[slash + 1 ..].trim().to_string()),
+            None => { return None; }
+        };
+        if _type.is_empty() || subtype.is_empty() {
+            return None;
+        }
+
+        let mut parameters = BTreeMap::new();
+        for parameter in parts {
+            if let Some(eq) = parameter.find('=') {
+                let name = parameter[.. eq].trim().to_lowercase();
+                if !name.is_empty() {
+                    parameters.insert(name, unquote(&parameter[eq + 1 ..]));
+                }
+            }
+        }
+
+        Some(ContentType { _type, subtype, parameters })
+    }
+
+    /// `type/subtype` without parameters.
+    pub fn essence(&self) -> String {
+        format!("{}/{}", self._type, self.subtype)
+    }
+
+    pub fn is_text(&self) -> bool {
+        self._type == "text"
+    }
+
+    pub fn is_json(&self) -> bool {
+        self._type == "application" && self.subtype == "json"
+    }
+
+    pub fn charset(&self) -> Option<&str> {
+        self.parameters.get("charset").map(|charset| charset.as_ref())
+    }
+
+    /// The encoding named by the `charset` parameter, UTF-8 if there is
+    /// none, or `None` if the charset is unknown.
+    pub fn encoding(&self) -> Option<&'static Encoding> {
+        match self.charset() {
+            Some(charset) => Encoding::for_label(charset.as_bytes()),
+            None => Some(UTF_8)
+        }
+    }
+}
+
+
+impl fmt::Display for ContentType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self._type, self.subtype)?;
+        for (name, value) in &self.parameters {
+            write!(f, "; {}={}", name, value)?;
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use encoding_rs::{UTF_8, WINDOWS_1252};
+
+    use super::ContentType;
+
+
+    #[test]
+    fn should_parse_type_and_subtype() {
+        let content_type = ContentType::parse("Text/Plain").unwrap();
+
+        assert_eq!("text", content_type._type);
+        assert_eq!("plain", content_type.subtype);
+        assert!(content_type.parameters.is_empty());
+        assert!(content_type.is_text());
+    }
+
+    #[test]
+    fn should_parse_parameters() {
+        let content_type = ContentType::parse(r#"text/plain; CHARSET="ISO-8859-1" ; format=flowed"#).unwrap();
+
+        assert_eq!(Some("ISO-8859-1"), content_type.charset());
+        assert_eq!(Some(&"flowed".to_string()), content_type.parameters.get("format"));
+        assert_eq!("text/plain; charset=ISO-8859-1; format=flowed", content_type.to_string());
+    }
+
+    #[test]
+    fn should_reject_malformed_types() {
+        for malformed in &["", "text", "text/", "/plain", "; charset=UTF-8"] {
+            assert!(ContentType::parse(malformed).is_none(), "{} should not parse", malformed);
+        }
+    }
+
+    #[test]
+    fn should_look_up_encoding_from_charset() {
+        assert_eq!(Some(UTF_8), ContentType::parse("text/plain").unwrap().encoding());
+        assert_eq!(Some(WINDOWS_1252), ContentType::parse("text/plain; charset=latin1").unwrap().encoding());
+        assert_eq!(None, ContentType::parse("text/plain; charset=klingon").unwrap().encoding());
+    }
+}
@@ -0,0 +1,370 @@
+//! The errors of the library, in layers like the library itself. Each
+//! error wraps the one it stems from, which `source` returns:
+//!
+//! - `ParseError`: bytes that can't be read as an object
+//! - `ProtocolError`: an object read but breaking the limits or checks of
+//!   the protocol
+//! - `ReadBusinessObjectError`: either of those, or the stream failing
+//! - `RoutingError`: what a router reports in a `routing/error` or in the
+//!   `error` of a reply
+//! - `ClientError`: anything going wrong talking to a router
+
+use std::error;
+use std::fmt;
+use std::io;
+
+use ::events::{ErrorCode, Event};
+use ::object::{BusinessObject, ChecksumMismatch};
+
+
+/// Bytes that can't be read as an object.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    /// The header isn't JSON. Has what the parser said and the header.
+    Json { message: String, header: String },
+    /// The header is JSON but not a JSON object.
+    NotAnObject,
+    /// The header isn't UTF-8, from the byte at `offset` into it on.
+    Utf8 { offset: usize },
+    /// A header in CBOR is malformed or has no JSON counterpart.
+    Cbor(&'static str),
+    /// The payload is compressed with a `content-encoding` we don't know.
+    UnknownContentEncoding(String),
+    /// A compressed payload couldn't be decompressed.
+    Decompression(&'static str),
+}
+
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::Json { ref message, .. } => write!(f, "Invalid JSON: {}", message),
+            ParseError::NotAnObject => f.write_str("Unsupported JSON type"),
+            ParseError::Utf8 { offset } => write!(f, "Character encoding error at byte {}", offset),
+            ParseError::Cbor(reason) => write!(f, "Invalid CBOR: {}", reason),
+            ParseError::UnknownContentEncoding(ref name) => write!(f, "Unknown content-encoding {}", name),
+            ParseError::Decompression(reason) => f.write_str(reason),
+        }
+    }
+}
+
+
+impl error::Error for ParseError {}
+
+
+/// An object breaking the limits or checks of the protocol.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProtocolError {
+    /// Sizes in bytes.
+    HeaderTooLarge { size: usize, limit: usize },
+    PayloadTooLarge { size: usize, limit: usize },
+    /// The payload doesn't match the `sha1` of its object.
+    ChecksumMismatch(ChecksumMismatch),
+}
+
+
+impl ProtocolError {
+    /// The code a router answers the error with.
+    pub fn code(&self) -> ErrorCode {
+        match *self {
+            ProtocolError::HeaderTooLarge { .. } | ProtocolError::PayloadTooLarge { .. } => ErrorCode::ObjectTooLarge,
+            ProtocolError::ChecksumMismatch(_) => ErrorCode::ChecksumMismatch,
+        }
+    }
+}
+
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ProtocolError::HeaderTooLarge { size, limit } => {
+                write!(f, "Header too large: {} bytes, at most {} allowed", size, limit)
+            },
+            ProtocolError::PayloadTooLarge { size, limit } => {
+                write!(f, "Payload too large: {} bytes, at most {} allowed", size, limit)
+            },
+            ProtocolError::ChecksumMismatch(_) => f.write_str("Payload doesn't match its checksum"),
+        }
+    }
+}
+
+
+impl error::Error for ProtocolError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            ProtocolError::ChecksumMismatch(ref e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+
+impl From<ChecksumMismatch> for ProtocolError {
+    fn from(e: ChecksumMismatch) -> ProtocolError {
+        ProtocolError::ChecksumMismatch(e)
+    }
+}
+
+
+#[derive(Debug)]
+pub enum ReadBusinessObjectError {
+    ReadError(io::Error),
+    Parse(ParseError),
+    Protocol(ProtocolError),
+}
+
+
+impl ReadBusinessObjectError {
+    /// The code a router answers the error with, unless the stream failed.
+    pub fn code(&self) -> Option<ErrorCode> {
+        match *self {
+            ReadBusinessObjectError::ReadError(_) => None,
+            ReadBusinessObjectError::Parse(_) => Some(ErrorCode::MalformedObject),
+            ReadBusinessObjectError::Protocol(ref e) => Some(e.code()),
+        }
+    }
+}
+
+
+impl fmt::Display for ReadBusinessObjectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ReadBusinessObjectError::ReadError(ref e) => write!(f, "Read error: {}", e),
+            ReadBusinessObjectError::Parse(ref e) => e.fmt(f),
+            ReadBusinessObjectError::Protocol(ref e) => e.fmt(f),
+        }
+    }
+}
+
+
+impl error::Error for ReadBusinessObjectError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            ReadBusinessObjectError::ReadError(ref e) => Some(e),
+            ReadBusinessObjectError::Parse(ref e) => Some(e),
+            ReadBusinessObjectError::Protocol(ref e) => Some(e),
+        }
+    }
+}
+
+
+impl From<io::Error> for ReadBusinessObjectError {
+    fn from(e: io::Error) -> ReadBusinessObjectError {
+        ReadBusinessObjectError::ReadError(e)
+    }
+}
+
+
+impl From<ParseError> for ReadBusinessObjectError {
+    fn from(e: ParseError) -> ReadBusinessObjectError {
+        ReadBusinessObjectError::Parse(e)
+    }
+}
+
+
+impl From<ProtocolError> for ReadBusinessObjectError {
+    fn from(e: ProtocolError) -> ReadBusinessObjectError {
+        ReadBusinessObjectError::Protocol(e)
+    }
+}
+
+
+impl From<ChecksumMismatch> for ReadBusinessObjectError {
+    fn from(e: ChecksumMismatch) -> ReadBusinessObjectError {
+        ReadBusinessObjectError::Protocol(e.into())
+    }
+}
+
+
+/// An error reported by a router.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RoutingError {
+    /// Unknown to routers predating error codes.
+    pub code: Option<ErrorCode>,
+    pub message: String,
+    /// What exactly was wrong, like the violations of a schema.
+    pub errors: Vec<String>,
+}
+
+
+impl RoutingError {
+    pub fn new(code: ErrorCode, message: &str) -> RoutingError {
+        RoutingError { code: Some(code), message: message.to_string(), errors: Vec::new() }
+    }
+
+    /// The error `reply` reports in its `error`, if any.
+    pub fn from_reply(reply: &BusinessObject) -> Option<RoutingError> {
+        reply.meta_str("error").map(|message| RoutingError {
+            code: reply.error_code(),
+            message: message.to_string(),
+            errors: reply.meta_array_of_str("errors").unwrap_or_default().iter()
+                .map(|error| error.to_string())
+                .collect(),
+        })
+    }
+
+    /// What a router answers an unreadable or rejected object with, unless
+    /// the stream failed.
+    pub fn from_read_error(e: &ReadBusinessObjectError) -> Option<RoutingError> {
+        e.code().map(|code| RoutingError::new(code, &e.to_string()))
+    }
+
+    /// A `routing/error`, or other error `event`, reporting the error.
+    pub fn to_object(&self, event: Event) -> BusinessObject {
+        let mut object = BusinessObject::event(event);
+        if let Some(code) = self.code {
+            object.set_meta("code", code.as_str());
+        }
+        object.set_meta("error", &self.message);
+        if !self.errors.is_empty() {
+            object.set_meta("errors", &self.errors);
+        }
+        object
+    }
+}
+
+
+impl fmt::Display for RoutingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.code {
+            Some(code) => write!(f, "{} ({})", self.message, code.as_str()),
+            None => f.write_str(&self.message),
+        }
+    }
+}
+
+
+impl error::Error for RoutingError {}
+
+
+/// What may go wrong talking to a router.
+#[cfg(feature = "client")]
+#[derive(Debug)]
+pub enum ClientError {
+    /// The connection failed or timed out.
+    Io(io::Error),
+    /// The router sent something unreadable.
+    Parse(ParseError),
+    /// The router sent something breaking the protocol.
+    Protocol(ProtocolError),
+    /// The router refused a request.
+    Routing(RoutingError),
+}
+
+
+#[cfg(feature = "client")]
+impl ClientError {
+    /// Whether the error is waiting for the router having taken too long.
+    pub fn is_timeout(&self) -> bool {
+        match *self {
+            ClientError::Io(ref e) => e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut,
+            _ => false
+        }
+    }
+}
+
+
+#[cfg(feature = "client")]
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ClientError::Io(ref e) => e.fmt(f),
+            ClientError::Parse(ref e) => e.fmt(f),
+            ClientError::Protocol(ref e) => e.fmt(f),
+            ClientError::Routing(ref e) => write!(f, "Refused by the router: {}", e),
+        }
+    }
+}
+
+
+#[cfg(feature = "client")]
+impl error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            ClientError::Io(ref e) => Some(e),
+            ClientError::Parse(ref e) => Some(e),
+            ClientError::Protocol(ref e) => Some(e),
+            ClientError::Routing(ref e) => Some(e),
+        }
+    }
+}
+
+
+#[cfg(feature = "client")]
+impl From<io::Error> for ClientError {
+    fn from(e: io::Error) -> ClientError {
+        ClientError::Io(e)
+    }
+}
+
+
+#[cfg(feature = "client")]
+impl From<ReadBusinessObjectError> for ClientError {
+    fn from(e: ReadBusinessObjectError) -> ClientError {
+        match e {
+            ReadBusinessObjectError::ReadError(e) => ClientError::Io(e),
+            ReadBusinessObjectError::Parse(e) => ClientError::Parse(e),
+            ReadBusinessObjectError::Protocol(e) => ClientError::Protocol(e),
+        }
+    }
+}
+
+
+#[cfg(feature = "client")]
+impl From<RoutingError> for ClientError {
+    fn from(e: RoutingError) -> ClientError {
+        ClientError::Routing(e)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+    use std::io;
+
+    #[cfg(feature = "client")] use super::ClientError;
+    use super::{ParseError, ProtocolError, ReadBusinessObjectError, RoutingError};
+    use ::events::{ErrorCode, Event};
+    use ::object::ChecksumMismatch;
+
+
+    #[test]
+    fn errors_should_chain_to_what_they_stem_from() {
+        let mismatch = ChecksumMismatch { declared: "00".to_string(), actual: "ff".to_string() };
+        let error = ReadBusinessObjectError::from(mismatch.clone());
+        assert_eq!(Some(ErrorCode::ChecksumMismatch), error.code());
+        let protocol = error.source().unwrap();
+        assert_eq!("Payload doesn't match its checksum", protocol.to_string());
+        assert_eq!(mismatch.to_string(), protocol.source().unwrap().to_string());
+
+        let error = ReadBusinessObjectError::from(ParseError::Utf8 { offset: 3 });
+        assert_eq!(Some(ErrorCode::MalformedObject), error.code());
+        assert_eq!(error.to_string(), error.source().unwrap().to_string());
+    }
+
+    #[test]
+    #[cfg(feature = "client")]
+    fn client_errors_should_tell_timeouts_apart() {
+        let timeout = ClientError::from(ReadBusinessObjectError::from(io::Error::from(io::ErrorKind::TimedOut)));
+        assert!(timeout.is_timeout());
+        let mismatch = ChecksumMismatch { declared: "00".to_string(), actual: "ff".to_string() };
+        let error = ClientError::from(ReadBusinessObjectError::from(mismatch));
+        assert!(matches!(error, ClientError::Protocol(ProtocolError::ChecksumMismatch(_))));
+        assert!(!error.is_timeout());
+    }
+
+    #[test]
+    fn routing_errors_should_survive_the_trip_through_a_reply() {
+        let read = ReadBusinessObjectError::from(ProtocolError::HeaderTooLarge { size: 2048, limit: 1024 });
+        let error = RoutingError::from_read_error(&read).unwrap();
+        assert_eq!(Some(ErrorCode::ObjectTooLarge), error.code);
+        assert_eq!(Some(error.clone()), RoutingError::from_reply(&error.to_object(Event::RoutingError)));
+
+        let mut error = RoutingError::new(ErrorCode::SchemaViolation, "Payload violates its schema");
+        error.errors = vec!["/: celsius is required".to_string()];
+        assert_eq!(Some(error.clone()), RoutingError::from_reply(&error.to_object(Event::RoutingError)));
+        assert_eq!("Payload violates its schema (schema-violation)", error.to_string());
+        assert_eq!(None, RoutingError::from_read_error(&io::Error::from(io::ErrorKind::BrokenPipe).into()));
+    }
+}
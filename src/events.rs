@@ -0,0 +1,214 @@
+//! The events the router and its clients understand, so that they are not
+//! spelled out as string literals all over.
+
+use std::fmt;
+use std::str::FromStr;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Event {
+    Ping,
+    Pong,
+    RoutingSubscribe,
+    RoutingSubscribeReply,
+    RoutingAnnouncementConnect,
+    RoutingAnnouncementDisconnect,
+    RoutingDisconnect,
+    RoutingError,
+    RoutingDenied,
+    RoutingRateLimit,
+    RoutingDeadLetter,
+    ClientsRegister,
+    ClientsRegisterReply,
+    ClientsList,
+    ClientsListReply,
+    ServicesRegister,
+    ServicesRegisterReply,
+    ServicesRequest,
+    ServicesReply,
+    HistoryReplay,
+    HistoryReplayReply,
+    PayloadFetch,
+    PayloadFetchReply,
+    AuthLogin,
+    AuthLoginReply,
+    AdminStats,
+    AdminStatsReply,
+    AdminClients,
+    AdminClientsReply,
+    AdminDisconnect,
+    AdminDisconnectReply,
+    AdminDrain,
+    AdminDrainReply,
+}
+
+
+const EVENTS: &[(Event, &str)] = &[
+    (Event::Ping, "ping"),
+    (Event::Pong, "pong"),
+    (Event::RoutingSubscribe, "routing/subscribe"),
+    (Event::RoutingSubscribeReply, "routing/subscribe/reply"),
+    (Event::RoutingAnnouncementConnect, "routing/announcement/connect"),
+    (Event::RoutingAnnouncementDisconnect, "routing/announcement/disconnect"),
+    (Event::RoutingDisconnect, "routing/disconnect"),
+    (Event::RoutingError, "routing/error"),
+    (Event::RoutingDenied, "routing/denied"),
+    (Event::RoutingRateLimit, "routing/rate-limit"),
+    (Event::RoutingDeadLetter, "routing/dead-letter"),
+    (Event::ClientsRegister, "clients/register"),
+    (Event::ClientsRegisterReply, "clients/register/reply"),
+    (Event::ClientsList, "clients/list"),
+    (Event::ClientsListReply, "clients/list/reply"),
+    (Event::ServicesRegister, "services/register"),
+    (Event::ServicesRegisterReply, "services/register/reply"),
+    (Event::ServicesRequest, "services/request"),
+    (Event::ServicesReply, "services/reply"),
+    (Event::HistoryReplay, "history/replay"),
+    (Event::HistoryReplayReply, "history/replay/reply"),
+    (Event::PayloadFetch, "payload/fetch"),
+    (Event::PayloadFetchReply, "payload/fetch/reply"),
+    (Event::AuthLogin, "auth/login"),
+    (Event::AuthLoginReply, "auth/login/reply"),
+    (Event::AdminStats, "admin/stats"),
+    (Event::AdminStatsReply, "admin/stats/reply"),
+    (Event::AdminClients, "admin/clients"),
+    (Event::AdminClientsReply, "admin/clients/reply"),
+    (Event::AdminDisconnect, "admin/disconnect"),
+    (Event::AdminDisconnectReply, "admin/disconnect/reply"),
+    (Event::AdminDrain, "admin/drain"),
+    (Event::AdminDrainReply, "admin/drain/reply"),
+];
+
+
+/// Why the router rejected what a client sent, given as the `code` of its
+/// error replies along with an `error` message for people.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ErrorCode {
+    /// The bytes sent couldn't be read as an object.
+    MalformedObject,
+    /// The object exceeds the router's size limits.
+    ObjectTooLarge,
+    /// The object breaks the protocol's invariants.
+    InvalidObject,
+    InvalidSubscription,
+    /// The router requires logging in first.
+    NotLoggedIn,
+    InvalidToken,
+    /// The access control rules don't allow it.
+    AccessDenied,
+    /// A reserved event the router doesn't know.
+    UnknownEvent,
+    /// The payload doesn't match the checksum sent with it.
+    ChecksumMismatch,
+    /// The router is draining for maintenance and takes no new clients.
+    Draining,
+    /// The router has as many clients as it takes.
+    ServerFull,
+    /// The payload violates the schema configured for its event or type.
+    SchemaViolation,
+}
+
+
+const ERROR_CODES: &[(ErrorCode, &str)] = &[
+    (ErrorCode::MalformedObject, "malformed-object"),
+    (ErrorCode::ObjectTooLarge, "object-too-large"),
+    (ErrorCode::InvalidObject, "invalid-object"),
+    (ErrorCode::InvalidSubscription, "invalid-subscription"),
+    (ErrorCode::NotLoggedIn, "not-logged-in"),
+    (ErrorCode::InvalidToken, "invalid-token"),
+    (ErrorCode::AccessDenied, "access-denied"),
+    (ErrorCode::UnknownEvent, "unknown-event"),
+    (ErrorCode::ChecksumMismatch, "checksum-mismatch"),
+    (ErrorCode::Draining, "draining"),
+    (ErrorCode::ServerFull, "server-full"),
+    (ErrorCode::SchemaViolation, "schema-violation"),
+];
+
+
+impl Event {
+    /// The event as it appears in the `event` field.
+    pub fn as_str(&self) -> &'static str {
+        EVENTS.iter().find(|&&(event, _)| event == *self).unwrap().1
+    }
+
+    /// Whether the event is control traffic of the connection: pings,
+    /// pongs, logging in and `routing/*` events other than dead letters,
+    /// which carry what was routed.
+    pub fn is_control(&self) -> bool {
+        match *self {
+            Event::Ping | Event::Pong | Event::AuthLogin | Event::AuthLoginReply => true,
+            Event::RoutingDeadLetter => false,
+            _ => self.as_str().starts_with("routing/")
+        }
+    }
+}
+
+
+impl FromStr for Event {
+    type Err = ();
+
+    /// Fails for events not listed here, which applications are free to
+    /// use.
+    fn from_str(s: &str) -> Result<Event, ()> {
+        EVENTS.iter().find(|&&(_, name)| name == s).map(|&(event, _)| event).ok_or(())
+    }
+}
+
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.write_str(self.as_str())
+    }
+}
+
+
+impl ErrorCode {
+    pub fn as_str(&self) -> &'static str {
+        ERROR_CODES.iter().find(|&&(code, _)| code == *self).unwrap().1
+    }
+}
+
+
+impl FromStr for ErrorCode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<ErrorCode, ()> {
+        ERROR_CODES.iter().find(|&&(_, name)| name == s).map(|&(code, _)| code).ok_or(())
+    }
+}
+
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.write_str(self.as_str())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::{ErrorCode, Event, ERROR_CODES, EVENTS};
+
+
+    #[test]
+    fn should_parse_every_event_from_its_name() {
+        for &(event, name) in EVENTS {
+            assert_eq!(name, event.as_str());
+            assert_eq!(Ok(event), Event::from_str(name));
+        }
+        for &(code, name) in ERROR_CODES {
+            assert_eq!(name, code.to_string());
+            assert_eq!(Ok(code), ErrorCode::from_str(name));
+        }
+    }
+
+    #[test]
+    fn should_not_parse_application_events() {
+        assert_eq!(Err(()), Event::from_str("chat/message"));
+        assert_eq!(Err(()), Event::from_str("routing"));
+    }
+}
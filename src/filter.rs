@@ -0,0 +1,383 @@
+//! Filter expressions picking objects by their metadata, like
+//! `event == "ping" && "image" in natures && size > 1024`.
+//!
+//! A name stands for the header field of that name, `event`, `type`,
+//! `size` or any metadata key, with dots reaching into JSON objects
+//! (`sender.name`). Fields an object lacks are `null`. Names, strings,
+//! numbers, `true`, `false` and `null` compare with `==`, `!=`, `<`, `<=`,
+//! `>` and `>=`, the latter four only between two numbers or two strings.
+//! `x in y` holds if the array `y` has an element equal to `x`, the string
+//! `y` contains the string `x` or the object `y` has a key `x`. Comparisons
+//! combine with `&&`, `||`, `!` and parentheses, and a name alone holds if
+//! the field is there and neither `false` nor `null`. Parentheses and `!`
+//! nest at most 64 deep.
+
+use std::cmp::Ordering;
+use std::error;
+use std::fmt;
+
+use rustc_serialize::json::{Json, ToJson};
+
+use ::json::{as_number, equal};
+use ::object::BusinessObject;
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilterError {
+    /// Byte offset into the expression where it went wrong.
+    pub position: usize,
+    pub message: String,
+}
+
+
+impl fmt::Display for FilterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid filter at {}: {}", self.position, self.message)
+    }
+}
+
+
+impl error::Error for FilterError {}
+
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Name(String),
+    Value(Json),
+    Operator(&'static str),
+}
+
+
+// Parentheses and negations nested deeper than this are rejected rather than
+// risking the stack
+const MAX_DEPTH: usize = 64;
+
+
+const OPERATORS: &[&str] = &["==", "!=", "<=", ">=", "&&", "||", "<", ">", "!", "(", ")"];
+
+
+fn unterminated_string(start: usize) -> FilterError {
+    FilterError { position: start, message: "unterminated string".to_string() }
+}
+
+
+fn tokenize(expression: &str) -> Result<Vec<(usize, Token)>, FilterError> {
+    let mut tokens = Vec::new();
+    let mut rest = expression.char_indices().peekable();
+
+    while let Some(&(start, c)) = rest.peek() {
+        if c.is_whitespace() {
+            rest.next();
+            continue;
+        }
+
+        if let Some(operator) = OPERATORS.iter().find(|operator| expression[start ..].starts_with(**operator)) {
+            for _ in 0 .. operator.len() {
+                rest.next();
+            }
+            tokens.push((start, Token::Operator(operator)));
+        } else if c == '"' {
+            rest.next();
+            let mut string = String::new();
+            loop {
+                match rest.next() {
+                    Some((_, '"')) => { break; },
+                    Some((_, '\\')) => match rest.next() {
+                        Some((_, escaped)) => { string.push(escaped); },
+                        None => { return Err(unterminated_string(start)); }
+                    },
+                    Some((_, c)) => { string.push(c); },
+                    None => { return Err(unterminated_string(start)); }
+                }
+            }
+            tokens.push((start, Token::Value(Json::String(string))));
+        } else if c.is_ascii_digit() || c == '-' {
+            let mut end = start;
+            while let Some(&(i, c)) = rest.peek() {
+                if !(c.is_ascii_digit() || c == '.' || (i == start && c == '-')) {
+                    break;
+                }
+                end = i + c.len_utf8();
+                rest.next();
+            }
+            let number = &expression[start .. end];
+            let value = number.parse::<i64>().map(Json::I64)
+                .or_else(|_| number.parse::<f64>().map(Json::F64))
+                .map_err(|_| FilterError { position: start, message: format!("invalid number {}", number) })?;
+            tokens.push((start, Token::Value(value)));
+        } else if c.is_alphabetic() || c == '_' {
+            let mut end = start;
+            while let Some(&(i, c)) = rest.peek() {
+                if !(c.is_alphanumeric() || c == '_' || c == '-' || c == '.') {
+                    break;
+                }
+                end = i + c.len_utf8();
+                rest.next();
+            }
+            let token = match &expression[start .. end] {
+                "true" => Token::Value(Json::Boolean(true)),
+                "false" => Token::Value(Json::Boolean(false)),
+                "null" => Token::Value(Json::Null),
+                "in" => Token::Operator("in"),
+                name => Token::Name(name.to_string()),
+            };
+            tokens.push((start, token));
+        } else {
+            return Err(FilterError { position: start, message: format!("unexpected {:?}", c) });
+        }
+    }
+
+    Ok(tokens)
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+enum Operand {
+    Field(Vec<String>),
+    Value(Json),
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expression {
+    Compare(Operand, &'static str, Operand),
+    Present(Operand),
+    Not(Box<Expression>),
+    // Chains are kept flat rather than nested, so that their length doesn't
+    // add to the depth of the expression
+    And(Vec<Expression>),
+    Or(Vec<Expression>),
+}
+
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    next: usize,
+    end: usize,
+    depth: usize,
+}
+
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.next).map(|(_, token)| token)
+    }
+
+    fn position(&self) -> usize {
+        self.tokens.get(self.next).map_or(self.end, |&(position, _)| position)
+    }
+
+    fn error(&self, message: &str) -> FilterError {
+        FilterError { position: self.position(), message: message.to_string() }
+    }
+
+    fn eat(&mut self, operator: &str) -> bool {
+        let found = matches!(self.peek(), Some(&Token::Operator(next)) if next == operator);
+        if found {
+            self.next += 1;
+        }
+        found
+    }
+
+    fn or(&mut self) -> Result<Expression, FilterError> {
+        let mut expressions = vec![self.and()?];
+        while self.eat("||") {
+            expressions.push(self.and()?);
+        }
+        Ok(if expressions.len() == 1 { expressions.remove(0) } else { Expression::Or(expressions) })
+    }
+
+    fn and(&mut self) -> Result<Expression, FilterError> {
+        let mut expressions = vec![self.unary()?];
+        while self.eat("&&") {
+            expressions.push(self.unary()?);
+        }
+        Ok(if expressions.len() == 1 { expressions.remove(0) } else { Expression::And(expressions) })
+    }
+
+    fn unary(&mut self) -> Result<Expression, FilterError> {
+        if let Some(&Token::Operator(operator)) = self.peek() {
+            if operator == "!" || operator == "(" {
+                if self.depth == MAX_DEPTH {
+                    return Err(self.error("nested too deeply"));
+                }
+                self.depth += 1;
+                let expression = self.nested(operator);
+                self.depth -= 1;
+                return expression;
+            }
+        }
+
+        let left = self.operand()?;
+        let operator = match self.peek() {
+            Some(&Token::Operator(operator)) if ["==", "!=", "<", "<=", ">", ">=", "in"].contains(&operator) => operator,
+            _ => { return Ok(Expression::Present(left)); }
+        };
+        self.next += 1;
+        Ok(Expression::Compare(left, operator, self.operand()?))
+    }
+
+    // The negation or parenthesized expression starting with `operator`
+    fn nested(&mut self, operator: &str) -> Result<Expression, FilterError> {
+        self.next += 1;
+        if operator == "!" {
+            return Ok(Expression::Not(Box::new(self.unary()?)));
+        }
+
+        let expression = self.or()?;
+        if !self.eat(")") {
+            return Err(self.error("expected )"));
+        }
+        Ok(expression)
+    }
+
+    fn operand(&mut self) -> Result<Operand, FilterError> {
+        let operand = match self.peek() {
+            Some(Token::Name(name)) => Operand::Field(name.split('.').map(|part| part.to_string()).collect()),
+            Some(Token::Value(value)) => Operand::Value(value.clone()),
+            _ => { return Err(self.error("expected a name or a value")); }
+        };
+        self.next += 1;
+        Ok(operand)
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filter {
+    expression: Expression,
+}
+
+
+fn order(left: &Json, right: &Json) -> Option<Ordering> {
+    match (left, right) {
+        (Json::String(left), Json::String(right)) => Some(left.cmp(right)),
+        _ => as_number(left)?.partial_cmp(&as_number(right)?)
+    }
+}
+
+
+fn contains(container: &Json, item: &Json) -> bool {
+    match (container, item) {
+        (Json::Array(items), _) => items.iter().any(|element| equal(element, item)),
+        (Json::String(string), Json::String(substring)) => string.contains(substring.as_str()),
+        (Json::Object(fields), Json::String(key)) => fields.contains_key(key),
+        _ => false
+    }
+}
+
+
+impl Filter {
+    pub fn parse(expression: &str) -> Result<Filter, FilterError> {
+        let mut parser = Parser { tokens: tokenize(expression)?, next: 0, end: expression.len(), depth: 0 };
+        let parsed = parser.or()?;
+        if parser.peek().is_some() {
+            return Err(parser.error("unexpected input"));
+        }
+        Ok(Filter { expression: parsed })
+    }
+
+    pub fn matches(&self, object: &BusinessObject) -> bool {
+        evaluate(&self.expression, &object.to_json())
+    }
+}
+
+
+fn resolve<'a>(operand: &'a Operand, header: &'a Json) -> &'a Json {
+    const NULL: &Json = &Json::Null;
+    match *operand {
+        Operand::Value(ref value) => value,
+        Operand::Field(ref path) => path.iter().try_fold(header, |json, key| json.find(key)).unwrap_or(NULL)
+    }
+}
+
+
+fn evaluate(expression: &Expression, header: &Json) -> bool {
+    match *expression {
+        Expression::Compare(ref left, operator, ref right) => {
+            let (left, right) = (resolve(left, header), resolve(right, header));
+            match operator {
+                "==" => equal(left, right),
+                "!=" => !equal(left, right),
+                "in" => contains(right, left),
+                _ => order(left, right).is_some_and(|ordering| match operator {
+                    "<" => ordering == Ordering::Less,
+                    "<=" => ordering != Ordering::Greater,
+                    ">" => ordering == Ordering::Greater,
+                    _ => ordering != Ordering::Less,
+                })
+            }
+        },
+        Expression::Present(ref operand) => !matches!(*resolve(operand, header), Json::Null | Json::Boolean(false)),
+        Expression::Not(ref expression) => !evaluate(expression, header),
+        Expression::And(ref expressions) => expressions.iter().all(|expression| evaluate(expression, header)),
+        Expression::Or(ref expressions) => expressions.iter().any(|expression| evaluate(expression, header)),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use rustc_serialize::json::Json;
+
+    use super::Filter;
+    use ::nature::Nature;
+    use ::object::{BusinessObject, Payload};
+
+
+    fn image(size: usize) -> BusinessObject {
+        let mut object = BusinessObject {
+            _type: Some("image/png".to_string()),
+            payload: Some(Payload::Bytes(vec![0; size].into())),
+            size: Some(size),
+            event: Some("ping".to_string()),
+            metadata: Default::default(),
+        };
+        object.add_nature(Nature::IMAGE);
+        object.set_meta("sender", &Json::from_str(r#"{"name": "otto", "tags": ["a", "b"]}"#).unwrap());
+        object
+    }
+
+    fn matches(expression: &str, object: &BusinessObject) -> bool {
+        Filter::parse(expression).unwrap().matches(object)
+    }
+
+    #[test]
+    fn filters_should_match_on_metadata() {
+        let big = image(2048);
+        let small = image(10);
+        let expression = r#"event == "ping" && "image" in natures && size > 1024"#;
+        assert!(matches(expression, &big));
+        assert!(!matches(expression, &small));
+
+        assert!(matches(r#"sender.name == "otto" && "b" in sender.tags"#, &small));
+        assert!(matches(r#"!(type != "image/png") || nothing"#, &small));
+        assert!(matches(r#""png" in type && size >= 10 && size <= 10.0 && "name" in sender"#, &small));
+        assert!(matches("sender && !missing && missing == null", &small));
+        assert!(!matches(r#"event < 5 || size > "a""#, &small));
+        assert!(matches(r#"event < "pong" && -1 < size"#, &small));
+    }
+
+    #[test]
+    fn malformed_filters_should_tell_where() {
+        for &(expression, position) in &[("event ==", 8), ("(size > 1", 9), ("size > 1 size", 9),
+                                          (r#"event == "ping"#, 9), (r#"event == "ping\"#, 9),
+                                          ("size # 1", 5), ("&& true", 0)] {
+            assert_eq!(position, Filter::parse(expression).unwrap_err().position, "{}", expression);
+        }
+    }
+
+    #[test]
+    fn deeply_nested_filters_should_be_rejected() {
+        let nested = |depth: usize, open: &str| format!("{}true{}", open.repeat(depth), ")".repeat(depth));
+        assert!(Filter::parse(&nested(64, "(")).is_ok());
+        assert!(Filter::parse(&nested(50000, "(")).is_err());
+        assert!(Filter::parse(&format!("{}true", "!".repeat(50000))).is_err());
+
+        let error = Filter::parse(&format!("{}(true)", "!(".repeat(40))).unwrap_err();
+        assert_eq!("nested too deeply", error.message);
+
+        let chain = format!("true{}", " && true || true".repeat(100000));
+        assert!(matches(&chain, &image(10)));
+    }
+}
@@ -0,0 +1,157 @@
+//! Shared subscriptions: clients that subscribe under a common group name
+//! split the objects matching their subscriptions between them, so that a
+//! pool of workers gets each object once.
+
+use std::collections::HashMap;
+
+use ::acl::Permissions;
+use ::metadata;
+use ::object::BusinessObject;
+use ::subscription::{RoutingKey, SubscriptionMatcher};
+
+
+struct Member<M> {
+    id: M,
+    subscription: SubscriptionMatcher,
+    permissions: Option<Permissions>,
+    channels: Option<Vec<String>>,
+}
+
+
+impl<M> Member<M> {
+    fn wants(&self, key: &RoutingKey, object: &BusinessObject) -> bool {
+        metadata::is_on_channel(object.metadata.channel.as_deref(), self.channels.as_deref()) &&
+            self.subscription.matches_object(key, object) &&
+            self.permissions.as_ref().is_none_or(|permissions| permissions.may_receive(key))
+    }
+}
+
+
+struct Group<M> {
+    members: Vec<Member<M>>,
+    // Where to start looking for the member to get the next object
+    next: usize,
+}
+
+
+/// The groups by name, with members identified by `M`.
+pub struct ConsumerGroups<M> {
+    groups: HashMap<String, Group<M>>,
+}
+
+
+impl<M> Default for ConsumerGroups<M> {
+    fn default() -> ConsumerGroups<M> {
+        ConsumerGroups { groups: HashMap::new() }
+    }
+}
+
+
+impl<M: Copy + PartialEq> ConsumerGroups<M> {
+    pub fn new() -> ConsumerGroups<M> {
+        ConsumerGroups::default()
+    }
+
+    /// Adds `id` to `group`, replacing its membership of any group.
+    pub fn join(&mut self, group: &str, id: M, subscription: SubscriptionMatcher,
+                permissions: Option<Permissions>, channels: Option<Vec<String>>) {
+        self.leave(id);
+        self.groups.entry(group.to_string())
+            .or_insert_with(|| Group { members: Vec::new(), next: 0 })
+            .members.push(Member { id, subscription, permissions, channels });
+    }
+
+    pub fn leave(&mut self, id: M) {
+        for group in self.groups.values_mut() {
+            group.members.retain(|member| member.id != id);
+        }
+        self.groups.retain(|_, group| !group.members.is_empty());
+    }
+
+    /// The names of the groups and how many members each has.
+    pub fn sizes(&self) -> Vec<(&str, usize)> {
+        self.groups.iter().map(|(name, group)| (name.as_ref(), group.members.len())).collect()
+    }
+
+    /// Picks the member of each group that gets `object`, going round the
+    /// members that want it in turn. `exclude` gets nothing.
+    pub fn pick(&mut self, object: &BusinessObject, exclude: Option<M>) -> Vec<M> {
+        let key = RoutingKey::of(object);
+        let mut picked = Vec::new();
+        for group in self.groups.values_mut() {
+            let count = group.members.len();
+            let found = (0 .. count)
+                .map(|offset| (group.next + offset) % count)
+                .find(|&i| Some(group.members[i].id) != exclude && group.members[i].wants(&key, object));
+            if let Some(i) = found {
+                picked.push(group.members[i].id);
+                group.next = (i + 1) % count;
+            }
+        }
+        picked
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use rustc_serialize::json::Json;
+
+    use super::ConsumerGroups;
+    use ::object::BusinessObject;
+    use ::subscription::{self, SubscriptionMatcher};
+
+
+    fn matcher(rules: &str) -> SubscriptionMatcher {
+        SubscriptionMatcher::new(subscription::parse_subscription(&Json::from_str(rules).unwrap()).unwrap())
+    }
+
+    fn event(event: &str, channel: Option<&str>) -> BusinessObject {
+        let mut object = BusinessObject::event(event);
+        object.metadata.channel = channel.map(|channel| channel.to_string());
+        object
+    }
+
+    #[test]
+    fn should_deliver_to_one_member_per_group_in_turn() {
+        let mut groups = ConsumerGroups::new();
+        groups.join("workers", 1, matcher(r#"["*"]"#), None, None);
+        groups.join("workers", 2, matcher(r#"["@job/*"]"#), None, None);
+        groups.join("workers", 3, matcher(r#"["*"]"#), None, None);
+        groups.join("audit", 4, matcher(r#"["*"]"#), None, None);
+
+        let job = event("job/resize", None);
+        let mut picked: Vec<Vec<i32>> = (0 .. 4).map(|_| {
+            let mut picked = groups.pick(&job, None);
+            picked.sort();
+            picked
+        }).collect();
+        assert_eq!(vec![vec![1, 4], vec![2, 4], vec![3, 4], vec![1, 4]], picked);
+
+        // Only the members whose subscription matches share the rest
+        let other = event("chat/message", None);
+        picked = (0 .. 3).map(|_| groups.pick(&other, Some(4))).collect();
+        assert_eq!(vec![vec![3], vec![1], vec![3]], picked);
+
+        groups.leave(3);
+        groups.leave(4);
+        assert_eq!(vec![("workers", 2)], groups.sizes());
+        groups.join("audit", 1, matcher(r#"["*"]"#), None, None);
+        let mut sizes = groups.sizes();
+        sizes.sort();
+        assert_eq!(vec![("audit", 1), ("workers", 1)], sizes);
+
+        // Members only share the objects on their channels
+        groups.join("workers", 5, matcher(r#"["*"]"#), None, Some(vec!["lab".to_string()]));
+        assert_eq!(vec![5], groups.pick(&event("job/resize", Some("lab")), Some(1)));
+        assert_eq!(vec![2], groups.pick(&job, Some(1)));
+
+        // And the objects whose metadata their field rules accept
+        groups.join("mail", 6, matcher(r#"["*", {"field": "priority", "equals": "bulk", "negate": true}]"#),
+                    None, None);
+        let mut bulk = event("mail/send", None);
+        bulk.set_meta("priority", "bulk");
+        assert!(!groups.pick(&bulk, None).contains(&6));
+        assert!(groups.pick(&event("mail/send", None), None).contains(&6));
+    }
+}
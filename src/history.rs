@@ -0,0 +1,187 @@
+//! A bounded buffer of recently routed objects, so that clients joining
+//! late can catch up on recent context.
+
+use std::collections::VecDeque;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use ::config;
+use ::object::BusinessObject;
+use ::storage::{Records, Storage};
+use ::timestamp::Timestamp;
+
+
+struct Entry {
+    routed: Timestamp,
+    object: Arc<BusinessObject>,
+}
+
+
+/// Keeps the most recently routed objects, dropping the oldest ones once
+/// there are more than `max_objects` of them, their payloads take more than
+/// `max_bytes` or they are older than `max_age`.
+pub struct History {
+    entries: VecDeque<Entry>,
+    payload_bytes: usize,
+    max_objects: usize,
+    max_bytes: usize,
+    max_age: Duration,
+}
+
+
+fn payload_size(object: &BusinessObject) -> usize {
+    object.size.unwrap_or(0)
+}
+
+
+impl History {
+    pub fn new(max_objects: usize, max_bytes: usize, max_age_secs: u64) -> History {
+        History {
+            entries: VecDeque::new(),
+            payload_bytes: 0,
+            max_objects,
+            max_bytes,
+            max_age: Duration::from_secs(max_age_secs),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Records `object` as routed at `now`.
+    pub fn push(&mut self, object: Arc<BusinessObject>, now: Timestamp) {
+        self.payload_bytes += payload_size(&object);
+        self.entries.push_back(Entry { routed: now, object });
+
+        while self.entries.len() > self.max_objects || self.payload_bytes > self.max_bytes {
+            self.pop_oldest();
+        }
+        self.expire(now);
+    }
+
+    fn pop_oldest(&mut self) {
+        if let Some(entry) = self.entries.pop_front() {
+            self.payload_bytes -= payload_size(&entry.object);
+        }
+    }
+
+    fn expire(&mut self, now: Timestamp) {
+        while self.entries.front().is_some_and(|entry| now.duration_since(entry.routed) > self.max_age) {
+            self.pop_oldest();
+        }
+    }
+
+    /// Objects routed after `since`, or all of them, oldest first.
+    pub fn since(&mut self, since: Option<Timestamp>, now: Timestamp) -> Vec<Arc<BusinessObject>> {
+        self.expire(now);
+
+        self.entries.iter()
+            .filter(|entry| since.is_none_or(|since| entry.routed > since))
+            .map(|entry| entry.object.clone())
+            .collect()
+    }
+}
+
+
+impl Storage for History {
+    fn append(&mut self, object: Arc<BusinessObject>, routed: Timestamp) -> io::Result<()> {
+        self.push(object, routed);
+        Ok(())
+    }
+
+    fn scan(&mut self, since: Option<Timestamp>, now: Timestamp) -> io::Result<Records> {
+        self.expire(now);
+
+        Ok(self.entries.iter()
+           .filter(|entry| since.is_none_or(|since| entry.routed > since))
+           .map(|entry| (entry.routed, entry.object.clone()))
+           .collect())
+    }
+}
+
+
+impl Default for History {
+    fn default() -> History {
+        History::new(config::DEFAULT_HISTORY_MAX_OBJECTS, config::DEFAULT_HISTORY_MAX_BYTES,
+                     config::DEFAULT_HISTORY_MAX_AGE)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::History;
+    use ::object::{BusinessObject, Payload};
+    use ::timestamp::Timestamp;
+
+
+    fn object(event: &str, size: usize) -> Arc<BusinessObject> {
+        Arc::new(BusinessObject {
+            _type: Some("application/octet-stream".to_string()),
+            payload: Some(Payload::Bytes(vec![0; size].into())),
+            size: Some(size),
+            event: Some(event.to_string()),
+            metadata: Default::default(),
+        })
+    }
+
+    fn events(objects: &[Arc<BusinessObject>]) -> Vec<&str> {
+        objects.iter().map(|object| object.event.as_ref().unwrap().as_ref()).collect()
+    }
+
+    fn at(seconds: i64) -> Timestamp {
+        Timestamp::from_secs(1_000_000 + seconds)
+    }
+
+    #[test]
+    fn should_keep_at_most_max_objects() {
+        let mut history = History::new(2, 1000, 60);
+        for event in &["a", "b", "c"] {
+            history.push(object(event, 1), at(0));
+        }
+
+        assert_eq!(vec!["b", "c"], events(&history.since(None, at(0))));
+    }
+
+    #[test]
+    fn should_keep_at_most_max_bytes_of_payload() {
+        let mut history = History::new(10, 100, 60);
+        history.push(object("a", 60), at(0));
+        history.push(object("b", 30), at(0));
+        history.push(object("c", 30), at(0));
+
+        assert_eq!(vec!["b", "c"], events(&history.since(None, at(0))));
+    }
+
+    #[test]
+    fn should_expire_old_objects() {
+        let mut history = History::new(10, 1000, 60);
+        history.push(object("a", 1), at(0));
+        history.push(object("b", 1), at(30));
+
+        assert_eq!(2, history.since(None, at(60)).len());
+        assert_eq!(vec!["b"], events(&history.since(None, at(61))));
+        assert!(history.since(None, at(91)).is_empty());
+        assert!(history.is_empty());
+    }
+
+    #[test]
+    fn since_should_return_newer_objects_oldest_first() {
+        let mut history = History::new(10, 1000, 60);
+        for (i, event) in ["a", "b", "c"].iter().enumerate() {
+            history.push(object(event, 1), at(i as i64 * 10));
+        }
+
+        assert_eq!(vec!["b", "c"], events(&history.since(Some(at(5)), at(20))));
+        assert_eq!(vec!["c"], events(&history.since(Some(at(20) - Duration::from_secs(1)), at(20))));
+    }
+}
@@ -0,0 +1,325 @@
+//! Append-only journal of routed objects, kept on disk for auditing and for
+//! restoring the history after a restart.
+//!
+//! The journal is a directory of numbered segment files. A segment is a
+//! sequence of frames, each a 4-byte big-endian length, the time the object
+//! was routed as 8-byte big-endian milliseconds since the epoch and then
+//! that many bytes of the object in wire format. Segments are rotated once
+//! they grow too large or too old, and a segment cut short by a crash is
+//! read up to its last whole frame.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, Cursor, Read, Write};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use ::io::{BusinessObjectStream, NextObject};
+use ::object::BusinessObject;
+use ::storage::{Records, Storage};
+use ::timestamp::Timestamp;
+
+
+pub const SEGMENT_EXTENSION: &str = "journal";
+
+const FRAME_HEADER_SIZE: usize = 12;
+
+
+pub struct Journal {
+    directory: PathBuf,
+    segment: File,
+    sequence: u64,
+    segment_bytes: u64,
+    // When the first frame of the current segment was routed
+    segment_started: Timestamp,
+    max_segment_bytes: u64,
+    max_segment_age: Duration,
+}
+
+
+fn segment_path(directory: &Path, sequence: u64) -> PathBuf {
+    directory.join(format!("{:016}.{}", sequence, SEGMENT_EXTENSION))
+}
+
+
+/// The segments in `directory` with their sequence numbers, oldest first.
+fn segments(directory: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.extension().is_none_or(|extension| extension != SEGMENT_EXTENSION) {
+            continue;
+        }
+        if let Some(sequence) = path.file_stem().and_then(|stem| stem.to_str()).and_then(|stem| stem.parse().ok()) {
+            segments.push((sequence, path));
+        }
+    }
+
+    segments.sort();
+    Ok(segments)
+}
+
+
+fn open_segment(directory: &Path, sequence: u64) -> io::Result<File> {
+    OpenOptions::new().create_new(true).append(true).open(segment_path(directory, sequence))
+}
+
+
+/// Encodes `object`, routed at `routed`, as one frame.
+pub fn frame(object: &BusinessObject, routed: Timestamp) -> io::Result<Vec<u8>> {
+    let bytes = object.to_bytes();
+    if bytes.len() > u32::MAX as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Object too large for a journal frame"));
+    }
+
+    let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + bytes.len());
+    frame.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&routed.as_millis().to_be_bytes());
+    frame.extend_from_slice(&bytes);
+    Ok(frame)
+}
+
+
+fn decode_object(bytes: Vec<u8>) -> Option<BusinessObject> {
+    match BusinessObjectStream::new(Cursor::new(bytes)).next_object() {
+        Ok(NextObject::Object(object)) => Some(object),
+        Ok(NextObject::Streamed(..)) => None,
+        Err(e) => {
+            warn!("Skipping unreadable journal frame: {}", e);
+            None
+        }
+    }
+}
+
+
+/// Reads frames until the end of `reader`. A frame cut short ends the
+/// reading like the end of the input does.
+pub fn read_frames<R: Read>(reader: R) -> io::Result<Vec<(Timestamp, BusinessObject)>> {
+    let mut reader = BufReader::new(reader);
+    let mut frames = Vec::new();
+
+    loop {
+        let mut header = [0; FRAME_HEADER_SIZE];
+        match reader.read_exact(&mut header) {
+            Ok(()) => {},
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => { return Ok(frames); },
+            Err(e) => { return Err(e); }
+        }
+
+        let mut length = [0; 4];
+        let mut millis = [0; 8];
+        length.copy_from_slice(&header[.. 4]);
+        millis.copy_from_slice(&header[4 ..]);
+
+        let mut bytes = vec![0; u32::from_be_bytes(length) as usize];
+        match reader.read_exact(&mut bytes) {
+            Ok(()) => {},
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                warn!("Journal ends with an incomplete frame");
+                return Ok(frames);
+            },
+            Err(e) => { return Err(e); }
+        }
+
+        if let Some(object) = decode_object(bytes) {
+            frames.push((Timestamp::from_millis(i64::from_be_bytes(millis)), object));
+        }
+    }
+}
+
+
+/// Reads every object journaled in `directory`, oldest first, with the
+/// times they were routed.
+pub fn read_all(directory: &Path) -> io::Result<Vec<(Timestamp, BusinessObject)>> {
+    let mut frames = Vec::new();
+    for (_, path) in segments(directory)? {
+        frames.append(&mut read_frames(File::open(path)?)?);
+    }
+    Ok(frames)
+}
+
+
+/// Reads the last `max_objects` objects journaled in `directory`, oldest
+/// first, with the times they were routed.
+pub fn read_tail(directory: &Path, max_objects: usize) -> io::Result<Vec<(Timestamp, BusinessObject)>> {
+    let mut tail = Vec::new();
+
+    for (_, path) in segments(directory)?.into_iter().rev() {
+        if tail.len() >= max_objects {
+            break;
+        }
+        let mut frames = read_frames(File::open(path)?)?;
+        let skip = frames.len().saturating_sub(max_objects - tail.len());
+        frames.drain(.. skip);
+        frames.append(&mut tail);
+        tail = frames;
+    }
+
+    Ok(tail)
+}
+
+
+impl Journal {
+    /// Starts a new segment in `directory`, creating the directory if
+    /// needed. Segments are rotated once they exceed `max_segment_bytes` or
+    /// `max_segment_age_secs`.
+    pub fn open(directory: &Path, max_segment_bytes: usize, max_segment_age_secs: u64) -> io::Result<Journal> {
+        fs::create_dir_all(directory)?;
+        let sequence = segments(directory)?.last().map_or(0, |&(sequence, _)| sequence + 1);
+
+        Ok(Journal {
+            directory: directory.to_path_buf(),
+            segment: open_segment(directory, sequence)?,
+            sequence,
+            segment_bytes: 0,
+            segment_started: Timestamp::from_millis(0),
+            max_segment_bytes: max_segment_bytes as u64,
+            max_segment_age: Duration::from_secs(max_segment_age_secs),
+        })
+    }
+
+    /// The segment currently written to.
+    pub fn segment_path(&self) -> PathBuf {
+        segment_path(&self.directory, self.sequence)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let segment = open_segment(&self.directory, self.sequence + 1)?;
+        self.segment = segment;
+        self.sequence += 1;
+        self.segment_bytes = 0;
+        debug!("Rotated journal to {}", self.segment_path().display());
+        Ok(())
+    }
+
+    /// Appends `object`, routed at `routed`, starting a new segment first if
+    /// the current one is full or too old.
+    pub fn append(&mut self, object: &BusinessObject, routed: Timestamp) -> io::Result<()> {
+        if self.segment_bytes > 0 && (self.segment_bytes >= self.max_segment_bytes ||
+                                      routed.duration_since(self.segment_started) >= self.max_segment_age) {
+            self.rotate()?;
+        }
+        if self.segment_bytes == 0 {
+            self.segment_started = routed;
+        }
+
+        let frame = frame(object, routed)?;
+        self.segment.write_all(&frame)?;
+        self.segment_bytes += frame.len() as u64;
+        Ok(())
+    }
+}
+
+
+/// Scans read every segment, so they are meant for audits rather than for
+/// serving clients.
+impl Storage for Journal {
+    fn append(&mut self, object: Arc<BusinessObject>, routed: Timestamp) -> io::Result<()> {
+        Journal::append(self, &object, routed)
+    }
+
+    fn scan(&mut self, since: Option<Timestamp>, _now: Timestamp) -> io::Result<Records> {
+        Ok(read_all(&self.directory)?.into_iter()
+           .filter(|&(routed, _)| since.is_none_or(|since| routed > since))
+           .map(|(routed, object)| (routed, Arc::new(object)))
+           .collect())
+    }
+
+    fn tail(&mut self, max_objects: usize, _now: Timestamp) -> io::Result<Records> {
+        Ok(read_tail(&self.directory, max_objects)?.into_iter()
+           .map(|(routed, object)| (routed, Arc::new(object)))
+           .collect())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, OpenOptions};
+    use std::path::PathBuf;
+
+    use uuid::Uuid;
+
+    use super::{read_all, read_frames, read_tail, segments, Journal};
+    use ::object::{BusinessObject, Payload};
+    use ::storage::Storage;
+    use ::timestamp::Timestamp;
+
+
+    fn temp_dir() -> PathBuf {
+        env::temp_dir().join(format!("journal-test-{}", Uuid::new_v4().simple()))
+    }
+
+    fn object(event: &str) -> BusinessObject {
+        BusinessObject {
+            _type: Some("text/plain".to_string()),
+            payload: Some(Payload::Text(format!("payload of {}", event), None)),
+            size: Some(11 + event.len()),
+            event: Some(event.to_string()),
+            metadata: Default::default(),
+        }
+    }
+
+    fn at(seconds: i64) -> Timestamp {
+        Timestamp::from_millis((1_000_000 + seconds) * 1000 + 250)
+    }
+
+    fn events(frames: &[(Timestamp, BusinessObject)]) -> Vec<String> {
+        frames.iter().map(|(_, object)| object.event.clone().unwrap()).collect()
+    }
+
+    #[test]
+    fn should_read_back_appended_objects() {
+        let dir = temp_dir();
+        let mut journal = Journal::open(&dir, 1024 * 1024, 3600).unwrap();
+        journal.append(&object("a"), at(0)).unwrap();
+        journal.append(&object("b"), at(1)).unwrap();
+
+        let frames = read_tail(&dir, 10).unwrap();
+        assert_eq!(vec![(at(0), object("a")), (at(1), object("b"))], frames);
+        let scanned = journal.scan(Some(at(0)), at(1)).unwrap();
+        assert_eq!(vec![(at(1), object("b"))], scanned.into_iter().map(|(routed, object)| (routed, (*object).clone()))
+                   .collect::<Vec<_>>());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn should_rotate_segments_by_size_and_age() {
+        let dir = temp_dir();
+        let mut journal = Journal::open(&dir, 1, 60).unwrap();
+        journal.append(&object("a"), at(0)).unwrap();
+        journal.append(&object("b"), at(0)).unwrap();
+        assert_eq!(2, segments(&dir).unwrap().len());
+
+        let mut journal = Journal::open(&dir, 1024 * 1024, 60).unwrap();
+        journal.append(&object("c"), at(0)).unwrap();
+        journal.append(&object("d"), at(30)).unwrap();
+        journal.append(&object("e"), at(61)).unwrap();
+        assert_eq!(4, segments(&dir).unwrap().len());
+
+        assert_eq!(vec!["a", "b", "c", "d", "e"], events(&read_tail(&dir, 10).unwrap()));
+        assert_eq!(vec!["a", "b", "c", "d", "e"], events(&read_all(&dir).unwrap()));
+        assert_eq!(vec!["d", "e"], events(&read_tail(&dir, 2).unwrap()));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn should_ignore_truncated_last_frame() {
+        let dir = temp_dir();
+        let mut journal = Journal::open(&dir, 1024 * 1024, 3600).unwrap();
+        journal.append(&object("a"), at(0)).unwrap();
+        journal.append(&object("b"), at(0)).unwrap();
+
+        let path = journal.segment_path();
+        let length = fs::metadata(&path).unwrap().len();
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(length - 3).unwrap();
+
+        assert_eq!(vec!["a"], events(&read_frames(fs::File::open(&path).unwrap()).unwrap()));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,25 @@
+//! Comparisons of JSON values shared by filters and schemas, under which
+//! numbers are equal whatever their representation, so that `1`, `1.0` and
+//! `1u64` are all the same.
+
+use rustc_serialize::json::Json;
+
+
+/// `value` as a float, if it is a number.
+pub fn as_number(value: &Json) -> Option<f64> {
+    match *value {
+        Json::I64(n) => Some(n as f64),
+        Json::U64(n) => Some(n as f64),
+        Json::F64(n) => Some(n),
+        _ => None
+    }
+}
+
+
+/// Whether `left` and `right` are equal, comparing numbers by value.
+pub fn equal(left: &Json, right: &Json) -> bool {
+    match (as_number(left), as_number(right)) {
+        (Some(left), Some(right)) => left == right,
+        _ => left == right
+    }
+}
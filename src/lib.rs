@@ -1,15 +1,80 @@
-extern crate env_logger;
-extern crate rustc_serialize;
-extern crate bufstream;
-extern crate mio;
+#[cfg(feature = "object")] extern crate bytes;
+#[cfg(feature = "server")] extern crate env_logger;
+#[cfg(feature = "object")] extern crate rustc_serialize;
+#[cfg(feature = "io")] extern crate bufstream;
+#[cfg(feature = "object")] extern crate encoding_rs;
+#[cfg(feature = "io")] extern crate flate2;
+#[cfg(feature = "client")] extern crate libc;
+#[cfg(feature = "server")] extern crate mio;
+#[cfg(feature = "client")] extern crate regex;
+#[cfg(feature = "client")] extern crate rustls;
+#[cfg(feature = "client")] extern crate rustls_pemfile;
+#[cfg(feature = "object")] extern crate sha1;
+#[cfg(any(feature = "rusqlite", test))] extern crate rusqlite;
+#[cfg(any(feature = "tokio", test))] extern crate tokio;
+#[cfg(any(feature = "async-client", test))] extern crate futures_core;
+#[cfg(any(feature = "async-client", test))] extern crate futures_sink;
+#[cfg(test)] extern crate futures;
+#[cfg(feature = "derive")] extern crate object_system_derive;
+#[cfg(feature = "server")] extern crate toml;
+#[cfg(feature = "object")] extern crate uuid;
 
-#[macro_use] extern crate log;
+#[cfg(feature = "io")] #[macro_use] extern crate log;
 
 
-mod object;
+#[cfg(feature = "io")] mod cbor;
+#[cfg(feature = "object")] mod json;
+#[cfg(feature = "object")] mod object;
 
-pub mod subscription;
-pub mod io;
-pub use object::{BusinessObject, Payload};
+#[cfg(feature = "server")] pub mod acl;
+#[cfg(all(feature = "server", any(feature = "rusqlite", test)))] pub mod archive;
+#[cfg(feature = "object")] pub mod canonical;
+#[cfg(feature = "io")] pub mod chunking;
+#[cfg(feature = "server")] pub mod clock;
+#[cfg(all(feature = "io", any(feature = "tokio", test)))] pub mod async_io;
+#[cfg(any(feature = "async-client", all(feature = "io", test)))] pub mod async_client;
+#[cfg(feature = "client")] pub mod client;
+#[cfg(feature = "io")] pub mod compression;
+#[cfg(feature = "server")] pub mod config;
+#[cfg(feature = "object")] pub mod content_type;
+#[cfg(feature = "object")] pub mod error;
+#[cfg(feature = "object")] pub mod events;
+#[cfg(feature = "object")] pub mod filter;
+#[cfg(feature = "server")] pub mod groups;
+#[cfg(feature = "server")] pub mod history;
+#[cfg(feature = "client")] pub mod subscription;
+#[cfg(feature = "io")] pub mod io;
+#[cfg(feature = "server")] pub mod journal;
+#[cfg(feature = "object")] pub mod mapping;
+#[cfg(feature = "object")] pub mod metadata;
+#[cfg(feature = "server")] pub mod metrics;
+#[cfg(feature = "server")] pub mod middleware;
+#[cfg(feature = "object")] pub mod nature;
+#[cfg(feature = "server")] pub mod object_log;
+#[cfg(feature = "io")] pub mod protocol;
+#[cfg(feature = "server")] pub mod rate_limit;
+#[cfg(feature = "client")] pub mod reconnect;
+#[cfg(feature = "client")] pub mod schema;
+#[cfg(feature = "server")] pub mod send_queue;
+#[cfg(feature = "server")] pub mod server;
+#[cfg(feature = "client")] pub mod service;
+#[cfg(feature = "client")] pub mod socket;
+#[cfg(feature = "server")] pub mod storage;
+#[cfg(feature = "client")] pub mod testing;
+#[cfg(feature = "object")] pub mod timestamp;
+#[cfg(feature = "client")] pub mod tls;
+#[cfg(feature = "client")] pub mod transport;
+#[cfg(feature = "server")] pub mod websocket;
+#[cfg(feature = "object")] pub use object::{BusinessObject, ChecksumMismatch, Payload, Priority, ValidationError};
+#[cfg(feature = "object")] pub use error::{ParseError, ProtocolError, ReadBusinessObjectError, RoutingError};
+#[cfg(feature = "client")] pub use client::Client;
+#[cfg(feature = "client")] pub use error::ClientError;
+#[cfg(feature = "client")] pub use reconnect::ReconnectingClient;
+#[cfg(feature = "server")] pub use config::Config;
+#[cfg(feature = "object")] pub use content_type::ContentType;
+#[cfg(feature = "object")] pub use events::{ErrorCode, Event};
+#[cfg(feature = "object")] pub use metadata::StandardMetadata;
+#[cfg(feature = "object")] pub use nature::Nature;
+#[cfg(feature = "derive")] pub use object_system_derive::BusinessObject;
 
 
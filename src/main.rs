@@ -21,13 +21,7 @@ fn main() {
     //                      "@ping".to_string(), "@pong".to_string()].to_json());
     metadata.insert("subscriptions".to_string(), vec!["*".to_string()].to_json());
 
-    let subscription = BusinessObject {
-        _type: None,
-        payload: None,
-        size: None,
-        event: Some("routing/subscribe".to_string()),
-        metadata: metadata,
-    };
+    let subscription = BusinessObject { metadata: metadata.into(), .. BusinessObject::event("routing/subscribe") };
 
     match stream.write(&subscription.to_bytes()) {
         Ok(bytes) => {
@@ -43,13 +37,7 @@ fn main() {
     let obj = stream.read_business_objects().unwrap();
     println!("Got: {:?}", &obj.to_json());
 
-    let ping = BusinessObject {
-        _type: None,
-        payload: None,
-        size: None,
-        event: Some("ping".to_string()),
-        metadata: BTreeMap::new(),
-    };
+    let ping = BusinessObject::event("ping");
 
     println!("Wrote {} bytes.", stream.write(&ping.to_bytes()).unwrap());
 
@@ -0,0 +1,173 @@
+//! Mapping between application structs and objects carrying them as JSON
+//! payloads. With the `derive` feature, structs with named fields derive
+//! `ToBusinessObject` and `FromBusinessObject`, their fields being
+//! `ToJson` and `Decodable`:
+//!
+//! ```ignore
+//! #[derive(BusinessObject)]
+//! #[business_object(event = "chat/message")]
+//! struct Message {
+//!     text: String,
+//!     priority: Option<u32>,
+//! }
+//! ```
+//!
+//! Without it, `to_object`, `payload_fields` and `field` make implementing
+//! them by hand short. Fields missing from a payload decode like `null`, so
+//! that `Option` fields may be left out.
+
+use std::error;
+use std::fmt;
+
+use rustc_serialize::Decodable;
+use rustc_serialize::json::{self, Json};
+
+use ::object::{BusinessObject, Payload};
+
+// For the derived implementations, so that they work without a dependency on
+// rustc-serialize
+#[doc(hidden)] pub use rustc_serialize::json::{Json as __Json, ToJson as __ToJson};
+
+
+/// The `type` of the objects structs map to.
+pub const JSON_TYPE: &str = "application/json";
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MappingError {
+    /// The object has another event than the struct maps to.
+    WrongEvent { expected: String, found: Option<String> },
+    /// The object has no payload, or one that isn't JSON.
+    NotJson,
+    /// The payload isn't a JSON object.
+    NotAnObject,
+    /// A field of the payload doesn't fit the struct.
+    InvalidField { field: String, message: String },
+}
+
+
+impl fmt::Display for MappingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MappingError::WrongEvent { ref expected, found: Some(ref found) } =>
+                write!(f, "Expected event {}, got {}", expected, found),
+            MappingError::WrongEvent { ref expected, found: None } =>
+                write!(f, "Expected event {}, got none", expected),
+            MappingError::NotJson => write!(f, "The payload isn't JSON"),
+            MappingError::NotAnObject => write!(f, "The payload isn't a JSON object"),
+            MappingError::InvalidField { ref field, ref message } => write!(f, "Invalid {}: {}", field, message),
+        }
+    }
+}
+
+
+impl error::Error for MappingError {}
+
+
+pub trait ToBusinessObject {
+    fn to_business_object(&self) -> BusinessObject;
+}
+
+
+pub trait FromBusinessObject: Sized {
+    fn from_business_object(object: &BusinessObject) -> Result<Self, MappingError>;
+}
+
+
+/// An object with `event` carrying `payload`.
+pub fn to_object(event: Option<&str>, payload: Json) -> BusinessObject {
+    BusinessObject {
+        _type: Some(JSON_TYPE.to_string()),
+        size: Some(payload.to_string().len()),
+        payload: Some(Payload::Json(payload, None)),
+        event: event.map(|event| event.to_string()),
+        metadata: Default::default(),
+    }
+}
+
+
+/// The fields of the JSON object `object` carries, if it has `event`, or
+/// whatever event it has if `event` is None.
+pub fn payload_fields(event: Option<&str>, object: &BusinessObject) -> Result<json::Object, MappingError> {
+    if let Some(expected) = event {
+        if object.event.as_deref() != Some(expected) {
+            return Err(MappingError::WrongEvent { expected: expected.to_string(), found: object.event.clone() });
+        }
+    }
+
+    let payload = match object.payload {
+        Some(Payload::Json(ref json, _)) => json.clone(),
+        Some(_) => object.payload_as_str().and_then(|text| Json::from_str(text).ok()).ok_or(MappingError::NotJson)?,
+        None => { return Err(MappingError::NotJson); }
+    };
+    match payload {
+        Json::Object(fields) => Ok(fields),
+        _ => Err(MappingError::NotAnObject)
+    }
+}
+
+
+/// The field `name` of `fields` decoded.
+pub fn field<T: Decodable>(fields: &json::Object, name: &str) -> Result<T, MappingError> {
+    let value = fields.get(name).cloned().unwrap_or(Json::Null);
+    Decodable::decode(&mut json::Decoder::new(value))
+        .map_err(|e| MappingError::InvalidField { field: name.to_string(), message: e.to_string() })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use rustc_serialize::json::{Json, ToJson};
+
+    use super::{field, payload_fields, to_object, FromBusinessObject, MappingError, ToBusinessObject, JSON_TYPE};
+    use ::object::{BusinessObject, Payload};
+
+
+    #[derive(Debug, PartialEq)]
+    struct Message {
+        text: String,
+        priority: Option<u32>,
+    }
+
+    impl ToBusinessObject for Message {
+        fn to_business_object(&self) -> BusinessObject {
+            let mut fields = BTreeMap::new();
+            fields.insert("text".to_string(), self.text.to_json());
+            fields.insert("priority".to_string(), self.priority.to_json());
+            to_object(Some("chat/message"), Json::Object(fields))
+        }
+    }
+
+    impl FromBusinessObject for Message {
+        fn from_business_object(object: &BusinessObject) -> Result<Message, MappingError> {
+            let fields = payload_fields(Some("chat/message"), object)?;
+            Ok(Message { text: field(&fields, "text")?, priority: field(&fields, "priority")? })
+        }
+    }
+
+    #[test]
+    fn structs_should_map_to_json_payloads_and_back() {
+        let message = Message { text: "hello".to_string(), priority: Some(2) };
+        let object = message.to_business_object();
+        assert_eq!(Some(JSON_TYPE.to_string()), object._type);
+        assert_eq!(Some(r#"{"priority":2,"text":"hello"}"#.len()), object.size);
+        assert_eq!(Ok(message), Message::from_business_object(&object));
+
+        let with = |payload: Payload| BusinessObject { payload: Some(payload), .. object.clone() };
+        assert_eq!(Ok(Message { text: "hi".to_string(), priority: None }),
+                   Message::from_business_object(&with(Payload::Text(r#"{"text": "hi"}"#.to_string(), None))));
+        assert_eq!(Err(MappingError::NotJson), Message::from_business_object(&with(Payload::Bytes(vec![0xff].into()))));
+        assert_eq!(Err(MappingError::NotAnObject), Message::from_business_object(&with(Payload::Json(Json::I64(1), None))));
+        match Message::from_business_object(&with(Payload::Text(r#"{"text": 1}"#.to_string(), None))) {
+            Err(MappingError::InvalidField { ref field, .. }) if field == "text" => {},
+            result => panic!("Expected the text to be invalid, got {:?}", result)
+        }
+
+        let reply = BusinessObject { event: Some("chat/reply".to_string()), .. object };
+        assert_eq!(Err(MappingError::WrongEvent { expected: "chat/message".to_string(),
+                                                  found: Some("chat/reply".to_string()) }),
+                   Message::from_business_object(&reply));
+    }
+}
@@ -0,0 +1,255 @@
+//! The metadata of objects. The fields the protocol gives a meaning to are
+//! parsed into typed fields when an object is read, and everything else is
+//! kept as it is in `extra`. A standard field whose value isn't of the type
+//! expected is kept in `extra` too, so that it is passed on unchanged and
+//! validation can tell what is wrong with it.
+
+use std::collections::BTreeMap;
+
+use rustc_serialize::json::{Json, ToJson};
+
+use ::nature::Nature;
+
+
+pub const ID: &str = "id";
+pub const IN_REPLY_TO: &str = "in-reply-to";
+pub const ROUTE: &str = "route";
+pub const NATURES: &str = "natures";
+pub const TO: &str = "to";
+pub const SHA1: &str = "sha1";
+pub const CHANNEL: &str = "channel";
+pub const TTL: &str = "ttl";
+
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StandardMetadata {
+    pub id: Option<String>,
+    pub in_reply_to: Option<String>,
+    /// Ids of the routers the object has passed through.
+    pub route: Option<Vec<String>>,
+    pub natures: Option<Vec<Nature>>,
+    /// Routing ids or registered names of the clients the object is meant
+    /// for. A single recipient may be given as a string.
+    pub to: Option<Vec<String>>,
+    /// Hex SHA-1 digest of the payload.
+    pub sha1: Option<String>,
+    /// The channel within its bus the object is published to. Objects
+    /// without one are on the default channel.
+    pub channel: Option<String>,
+    /// How many more routers the object may be routed by. Each router takes
+    /// one off, and one out of hops passes it on to no other router.
+    pub ttl: Option<u64>,
+    pub extra: BTreeMap<String, Json>,
+}
+
+
+/// Whether an object published to `channel` reaches clients on `channels`,
+/// either being the default channel if not given.
+pub fn is_on_channel(channel: Option<&str>, channels: Option<&[String]>) -> bool {
+    match (channel, channels) {
+        (None, None) => true,
+        (Some(channel), Some(channels)) => channels.iter().any(|on| on == channel),
+        _ => false
+    }
+}
+
+
+/// `value`, if it is an array of nothing but strings.
+fn strings(value: &Json) -> Option<Vec<String>> {
+    value.as_array()
+        .and_then(|items| items.iter().map(|item| item.as_string().map(|s| s.to_string())).collect())
+}
+
+
+/// `value`, if it is a string or an array of nothing but strings.
+fn one_or_more_strings(value: &Json) -> Option<Vec<String>> {
+    match value.as_string() {
+        Some(s) => Some(vec![s.to_string()]),
+        None => strings(value)
+    }
+}
+
+
+impl StandardMetadata {
+    /// Sets the field `key`, replacing any previous value.
+    pub fn insert(&mut self, key: String, value: Json) {
+        self.remove(&key);
+        let typed = match key.as_str() {
+            ID => value.as_string().map(|id| self.id = Some(id.to_string())),
+            IN_REPLY_TO => value.as_string().map(|id| self.in_reply_to = Some(id.to_string())),
+            ROUTE => strings(&value).map(|route| self.route = Some(route)),
+            NATURES => strings(&value).map(|natures| self.natures = Some(natures.into_iter().map(Nature::from).collect())),
+            TO => one_or_more_strings(&value).map(|to| self.to = Some(to)),
+            SHA1 => value.as_string().map(|sha1| self.sha1 = Some(sha1.to_string())),
+            CHANNEL => value.as_string().map(|channel| self.channel = Some(channel.to_string())),
+            TTL => value.as_u64().map(|ttl| self.ttl = Some(ttl)),
+            _ => None
+        };
+        if typed.is_none() {
+            self.extra.insert(key, value);
+        }
+    }
+
+    /// Removes the field `key`, returning its value if it was set.
+    pub fn remove(&mut self, key: &str) -> Option<Json> {
+        let typed = match key {
+            ID => self.id.take().map(Json::String),
+            IN_REPLY_TO => self.in_reply_to.take().map(Json::String),
+            ROUTE => self.route.take().map(|route| route.to_json()),
+            NATURES => self.natures.take().map(|natures| natures.to_json()),
+            TO => self.to.take().map(|to| to.to_json()),
+            SHA1 => self.sha1.take().map(Json::String),
+            CHANNEL => self.channel.take().map(Json::String),
+            TTL => self.ttl.take().map(Json::U64),
+            _ => None
+        };
+        let extra = self.extra.remove(key);
+        typed.or(extra)
+    }
+
+    /// The field `key`, if it is a string.
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        let typed = match key {
+            ID => self.id.as_deref(),
+            IN_REPLY_TO => self.in_reply_to.as_deref(),
+            SHA1 => self.sha1.as_deref(),
+            CHANNEL => self.channel.as_deref(),
+            _ => None
+        };
+        typed.or_else(|| self.extra.get(key).and_then(|value| value.as_string()))
+    }
+
+    /// The field `key`, whatever it is.
+    pub fn get(&self, key: &str) -> Option<Json> {
+        let typed = match key {
+            ID => self.id.as_ref().map(|id| id.to_json()),
+            IN_REPLY_TO => self.in_reply_to.as_ref().map(|id| id.to_json()),
+            ROUTE => self.route.as_ref().map(|route| route.to_json()),
+            NATURES => self.natures.as_ref().map(|natures| natures.to_json()),
+            TO => self.to.as_ref().map(|to| to.to_json()),
+            SHA1 => self.sha1.as_ref().map(|sha1| sha1.to_json()),
+            CHANNEL => self.channel.as_ref().map(|channel| channel.to_json()),
+            TTL => self.ttl.map(Json::U64),
+            _ => None
+        };
+        typed.or_else(|| self.extra.get(key).cloned())
+    }
+
+    /// The field `key`, if it is an array of nothing but strings.
+    pub fn get_array_of_str(&self, key: &str) -> Option<Vec<&str>> {
+        let typed = match key {
+            ROUTE => self.route.as_ref().map(|route| route.iter().map(|id| id.as_str()).collect()),
+            NATURES => self.natures.as_ref().map(|natures| natures.iter().map(Nature::as_str).collect()),
+            TO => self.to.as_ref().map(|to| to.iter().map(|recipient| recipient.as_str()).collect()),
+            _ => None
+        };
+        typed.or_else(|| {
+            self.extra.get(key)
+                .and_then(|value| value.as_array())
+                .and_then(|items| items.iter().map(|item| item.as_string()).collect())
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == StandardMetadata::default()
+    }
+
+    /// All the fields in a single map, as they go on the wire.
+    pub fn to_fields(&self) -> BTreeMap<String, Json> {
+        let mut fields = self.extra.clone();
+        let mut typed = |key: &str, value: Option<Json>| if let Some(value) = value {
+            fields.insert(key.to_string(), value);
+        };
+        typed(ID, self.id.as_ref().map(|id| id.to_json()));
+        typed(IN_REPLY_TO, self.in_reply_to.as_ref().map(|id| id.to_json()));
+        typed(ROUTE, self.route.as_ref().map(|route| route.to_json()));
+        typed(NATURES, self.natures.as_ref().map(|natures| natures.to_json()));
+        typed(TO, self.to.as_ref().map(|to| to.to_json()));
+        typed(SHA1, self.sha1.as_ref().map(|sha1| sha1.to_json()));
+        typed(CHANNEL, self.channel.as_ref().map(|channel| channel.to_json()));
+        typed(TTL, self.ttl.map(Json::U64));
+        fields
+    }
+}
+
+
+impl From<BTreeMap<String, Json>> for StandardMetadata {
+    fn from(fields: BTreeMap<String, Json>) -> StandardMetadata {
+        let mut metadata = StandardMetadata::default();
+        for (key, value) in fields {
+            metadata.insert(key, value);
+        }
+        metadata
+    }
+}
+
+
+impl ToJson for StandardMetadata {
+    fn to_json(&self) -> Json {
+        Json::Object(self.to_fields())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use rustc_serialize::json::{Json, ToJson};
+
+    use super::{is_on_channel, StandardMetadata};
+    use ::nature::Nature;
+
+
+    #[test]
+    fn should_parse_standard_fields_and_keep_the_rest_as_they_are() {
+        let mut fields = BTreeMap::new();
+        fields.insert("id".to_string(), "abc".to_json());
+        fields.insert("natures".to_string(), vec!["chat".to_string()].to_json());
+        fields.insert("route".to_string(), vec![1, 2].to_json());
+        fields.insert("to".to_string(), 5.to_json());
+        fields.insert("ttl".to_string(), 3u64.to_json());
+        fields.insert("name".to_string(), "tester".to_json());
+        let metadata = StandardMetadata::from(fields.clone());
+
+        assert_eq!(Some("abc"), metadata.id.as_deref());
+        assert_eq!(Some(vec![Nature::CHAT]), metadata.natures);
+        assert_eq!(Some(3), metadata.ttl);
+        // Not of the type expected, so kept for validation to report
+        assert_eq!(None, metadata.route);
+        assert_eq!(None, metadata.to);
+        assert_eq!(vec!["name", "route", "to"], metadata.extra.keys().map(|key| key.as_str()).collect::<Vec<_>>());
+        assert_eq!(Json::Object(fields), metadata.to_json());
+    }
+
+    #[test]
+    fn insert_and_remove_should_move_fields_between_typed_and_extra() {
+        let mut metadata = StandardMetadata::default();
+        metadata.insert("route".to_string(), "router-1".to_json());
+        assert_eq!(None, metadata.get_array_of_str("route"));
+        metadata.insert("route".to_string(), vec!["router-1".to_string()].to_json());
+        assert_eq!(Some(vec!["router-1"]), metadata.get_array_of_str("route"));
+        assert!(metadata.extra.is_empty());
+
+        assert_eq!(Some(vec!["router-1".to_string()].to_json()), metadata.remove("route"));
+        assert!(metadata.is_empty());
+
+        metadata.insert("to".to_string(), "tester".to_json());
+        assert_eq!(Some(vec!["tester".to_string()]), metadata.to);
+    }
+
+    #[test]
+    fn objects_should_only_be_on_the_channel_they_name() {
+        let channels = vec!["lab".to_string(), "ops".to_string()];
+        assert!(is_on_channel(None, None));
+        assert!(!is_on_channel(None, Some(&channels)));
+
+        let mut metadata = StandardMetadata::default();
+        metadata.insert("channel".to_string(), "ops".to_json());
+        assert_eq!(Some("ops"), metadata.get_str("channel"));
+        let channel = metadata.channel.as_deref();
+        assert!(is_on_channel(channel, Some(&channels)));
+        assert!(!is_on_channel(channel, Some(&channels[.. 1])));
+        assert!(!is_on_channel(channel, None));
+    }
+}
@@ -0,0 +1,376 @@
+//! Router metrics and an HTTP endpoint exposing them in the Prometheus text
+//! format.
+
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Write};
+use std::io;
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use rustc_serialize::json::{Json, ToJson};
+
+
+const REQUEST_TIMEOUT_SECS: u64 = 5;
+
+
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+
+#[derive(Debug, Default)]
+pub struct Gauge(AtomicI64);
+
+
+impl Gauge {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn dec(&self) {
+        self.add(-1);
+    }
+
+    pub fn add(&self, n: i64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+
+/// Metrics of one router, updated by all of its workers.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub connected_clients: Gauge,
+    pub queued_objects: Gauge,
+    pub objects_received: Counter,
+    pub objects_routed: Counter,
+    /// Subscribed clients an object was not routed to because their
+    /// subscription didn't match it.
+    pub routing_rejections: Counter,
+    /// Reads after which a client was over its rate limit.
+    pub rate_limit_violations: Counter,
+    /// Objects dropped instead of sent because they expired or waited in a
+    /// send queue for too long.
+    pub expired_objects: Counter,
+    /// Objects routed as dead letters because they reached no client.
+    pub dead_letters: Counter,
+    /// Objects dropped because they were out of hops.
+    pub hop_limit_drops: Counter,
+    /// Clients disconnected for taking too long to finish a frame.
+    pub partial_frame_timeouts: Counter,
+    /// Connections closed because the router had as many clients as it
+    /// takes.
+    pub refused_connections: Counter,
+    pub bytes_received: Counter,
+    pub bytes_sent: Counter,
+    /// Statistics of each connection by routing id.
+    pub connections: Mutex<BTreeMap<String, Arc<ConnectionStats>>>,
+}
+
+
+/// What one connection has sent and received, for telling noisy and stuck
+/// clients apart from the rest.
+#[derive(Debug)]
+pub struct ConnectionStats {
+    pub peer: String,
+    pub objects_received: Counter,
+    pub objects_sent: Counter,
+    pub bytes_received: Counter,
+    pub bytes_sent: Counter,
+    /// Objects meant for the client that it never got, because they went
+    /// stale or its send queue was full.
+    pub dropped: Counter,
+    last_error: Mutex<Option<String>>,
+}
+
+
+impl ConnectionStats {
+    pub fn new(peer: &str) -> ConnectionStats {
+        ConnectionStats {
+            peer: peer.to_string(),
+            objects_received: Counter::default(),
+            objects_sent: Counter::default(),
+            bytes_received: Counter::default(),
+            bytes_sent: Counter::default(),
+            dropped: Counter::default(),
+            last_error: Mutex::new(None),
+        }
+    }
+
+    /// The last error the client ran into, if any.
+    pub fn last_error(&self) -> Option<String> {
+        self.last_error.lock().unwrap().clone()
+    }
+
+    pub fn set_last_error(&self, error: &str) {
+        *self.last_error.lock().unwrap() = Some(error.to_string());
+    }
+}
+
+
+impl ToJson for ConnectionStats {
+    fn to_json(&self) -> Json {
+        let mut values = BTreeMap::new();
+        values.insert("objects-received".to_string(), self.objects_received.get().to_json());
+        values.insert("objects-sent".to_string(), self.objects_sent.get().to_json());
+        values.insert("bytes-received".to_string(), self.bytes_received.get().to_json());
+        values.insert("bytes-sent".to_string(), self.bytes_sent.get().to_json());
+        values.insert("dropped".to_string(), self.dropped.get().to_json());
+        if let Some(error) = self.last_error() {
+            values.insert("last-error".to_string(), error.to_json());
+        }
+
+        Json::Object(values)
+    }
+}
+
+
+fn render_metric(output: &mut String, name: &str, kind: &str, help: &str, value: String) {
+    output.push_str(&format!("# HELP rabboe_{} {}\n# TYPE rabboe_{} {}\nrabboe_{} {}\n",
+                             name, help, name, kind, name, value));
+}
+
+
+/// Renders a counter with a sample per connection, labelled with its
+/// routing id and peer address.
+fn render_connection_metric<F>(output: &mut String, name: &str, help: &str,
+                               connections: &BTreeMap<String, Arc<ConnectionStats>>, counter: F)
+    where F: Fn(&ConnectionStats) -> &Counter {
+    output.push_str(&format!("# HELP rabboe_{} {}\n# TYPE rabboe_{} counter\n", name, help, name));
+    for (routing_id, stats) in connections {
+        output.push_str(&format!("rabboe_{}{{client=\"{}\",peer=\"{}\"}} {}\n",
+                                 name, routing_id, stats.peer, counter(stats).get()));
+    }
+}
+
+
+impl Metrics {
+    /// Starts keeping statistics of a new connection.
+    pub fn connection(&self, routing_id: &str, peer: &str) -> Arc<ConnectionStats> {
+        let stats = Arc::new(ConnectionStats::new(peer));
+        self.connections.lock().unwrap().insert(routing_id.to_string(), stats.clone());
+        stats
+    }
+
+    pub fn forget_connection(&self, routing_id: &str) {
+        self.connections.lock().unwrap().remove(routing_id);
+    }
+
+    /// The statistics of the connection with `routing_id`, as reported over
+    /// the bus.
+    pub fn connection_json(&self, routing_id: &str) -> Option<Json> {
+        self.connections.lock().unwrap().get(routing_id).map(|stats| stats.to_json())
+    }
+
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+
+        render_metric(&mut output, "connected_clients", "gauge", "Clients currently connected.",
+                      self.connected_clients.get().to_string());
+        render_metric(&mut output, "queued_objects", "gauge", "Objects waiting in client send queues.",
+                      self.queued_objects.get().to_string());
+        render_metric(&mut output, "objects_received_total", "counter", "Objects received from clients.",
+                      self.objects_received.get().to_string());
+        render_metric(&mut output, "objects_routed_total", "counter", "Objects queued for subscribers.",
+                      self.objects_routed.get().to_string());
+        render_metric(&mut output, "routing_rejections_total", "counter",
+                      "Objects not routed to a subscriber because its subscription didn't match.",
+                      self.routing_rejections.get().to_string());
+        render_metric(&mut output, "rate_limit_violations_total", "counter",
+                      "Reads after which a client was over its rate limit.",
+                      self.rate_limit_violations.get().to_string());
+        render_metric(&mut output, "expired_objects_total", "counter",
+                      "Objects dropped from send queues because they were stale.",
+                      self.expired_objects.get().to_string());
+        render_metric(&mut output, "dead_letters_total", "counter",
+                      "Objects that reached no client, routed again as dead letters.",
+                      self.dead_letters.get().to_string());
+        render_metric(&mut output, "hop_limit_drops_total", "counter",
+                      "Objects dropped because their ttl ran out.",
+                      self.hop_limit_drops.get().to_string());
+        render_metric(&mut output, "partial_frame_timeouts_total", "counter",
+                      "Clients disconnected for taking too long to finish a frame they had started.",
+                      self.partial_frame_timeouts.get().to_string());
+        render_metric(&mut output, "refused_connections_total", "counter",
+                      "Connections closed because the router was full.",
+                      self.refused_connections.get().to_string());
+        render_metric(&mut output, "received_bytes_total", "counter", "Bytes of objects received.",
+                      self.bytes_received.get().to_string());
+        render_metric(&mut output, "sent_bytes_total", "counter", "Bytes of objects sent.",
+                      self.bytes_sent.get().to_string());
+
+        let connections = self.connections.lock().unwrap();
+        render_connection_metric(&mut output, "connection_received_objects_total", "Objects received from a client.",
+                                 &connections, |stats| &stats.objects_received);
+        render_connection_metric(&mut output, "connection_sent_objects_total", "Objects sent to a client.",
+                                 &connections, |stats| &stats.objects_sent);
+        render_connection_metric(&mut output, "connection_received_bytes_total", "Bytes received from a client.",
+                                 &connections, |stats| &stats.bytes_received);
+        render_connection_metric(&mut output, "connection_sent_bytes_total", "Bytes sent to a client.",
+                                 &connections, |stats| &stats.bytes_sent);
+        render_connection_metric(&mut output, "connection_dropped_objects_total",
+                                 "Objects meant for a client that it never got.",
+                                 &connections, |stats| &stats.dropped);
+
+        output
+    }
+}
+
+
+impl ToJson for Metrics {
+    /// The current values, for reporting over the bus.
+    fn to_json(&self) -> Json {
+        let mut values = BTreeMap::new();
+        values.insert("connected-clients".to_string(), self.connected_clients.get().to_json());
+        values.insert("queued-objects".to_string(), self.queued_objects.get().to_json());
+        values.insert("objects-received".to_string(), self.objects_received.get().to_json());
+        values.insert("objects-routed".to_string(), self.objects_routed.get().to_json());
+        values.insert("routing-rejections".to_string(), self.routing_rejections.get().to_json());
+        values.insert("rate-limit-violations".to_string(), self.rate_limit_violations.get().to_json());
+        values.insert("expired-objects".to_string(), self.expired_objects.get().to_json());
+        values.insert("dead-letters".to_string(), self.dead_letters.get().to_json());
+        values.insert("hop-limit-drops".to_string(), self.hop_limit_drops.get().to_json());
+        values.insert("partial-frame-timeouts".to_string(), self.partial_frame_timeouts.get().to_json());
+        values.insert("refused-connections".to_string(), self.refused_connections.get().to_json());
+        values.insert("bytes-received".to_string(), self.bytes_received.get().to_json());
+        values.insert("bytes-sent".to_string(), self.bytes_sent.get().to_json());
+
+        Json::Object(values)
+    }
+}
+
+
+fn answer(stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(REQUEST_TIMEOUT_SECS)))?;
+
+    let mut request_line = String::new();
+    BufReader::new(&stream).read_line(&mut request_line)?;
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", metrics.render()),
+        _ => ("404 Not Found", "Not found\n".to_string())
+    };
+
+    write!(&stream, "HTTP/1.0 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\
+                     Connection: close\r\n\r\n{}", status, body.len(), body)
+}
+
+
+/// Answers `GET /metrics` on `listener` from a thread of its own.
+pub fn serve(listener: TcpListener, metrics: Arc<Metrics>) -> io::Result<thread::JoinHandle<()>> {
+    thread::Builder::new().name("metrics".to_string()).spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = answer(stream, &metrics) {
+                        debug!("Failed to answer metrics request: {}", e);
+                    }
+                },
+                Err(e) => { warn!("Failed to accept metrics connection: {}", e); }
+            }
+        }
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Arc;
+
+    use rustc_serialize::json::ToJson;
+
+    use super::{serve, Metrics};
+
+
+    #[test]
+    fn render_should_describe_every_metric() {
+        let metrics = Metrics::default();
+        metrics.connected_clients.inc();
+        metrics.connected_clients.inc();
+        metrics.connected_clients.dec();
+        metrics.bytes_sent.add(1234);
+        metrics.connection("c1", "127.0.0.1:1").objects_sent.add(7);
+        metrics.connection("c2", "127.0.0.1:2");
+        metrics.forget_connection("c2");
+
+        let output = metrics.render();
+
+        assert!(output.contains("# TYPE rabboe_connected_clients gauge\nrabboe_connected_clients 1\n"));
+        assert!(output.contains("# TYPE rabboe_sent_bytes_total counter\nrabboe_sent_bytes_total 1234\n"));
+        assert!(output.contains("\nrabboe_connection_sent_objects_total{client=\"c1\",peer=\"127.0.0.1:1\"} 7\n"));
+        assert!(!output.contains("c2"));
+        assert_eq!(18, output.lines().filter(|line| line.starts_with("# HELP")).count());
+    }
+
+    #[test]
+    fn to_json_should_include_every_metric() {
+        let metrics = Metrics::default();
+        metrics.objects_received.add(5);
+
+        let json = metrics.to_json();
+        assert_eq!(Some(5), json.find("objects-received").and_then(|value| value.as_u64()));
+        assert_eq!(13, json.as_object().unwrap().len());
+    }
+
+    #[test]
+    fn connection_json_should_report_the_last_error_once_there_is_one() {
+        let metrics = Metrics::default();
+        let stats = metrics.connection("c1", "127.0.0.1:1");
+        stats.bytes_received.add(42);
+
+        let json = metrics.connection_json("c1").unwrap();
+        assert_eq!(Some(42), json.find("bytes-received").and_then(|value| value.as_u64()));
+        assert_eq!(None, json.find("last-error"));
+        stats.set_last_error("Send queue full");
+        let json = metrics.connection_json("c1").unwrap();
+        assert_eq!(Some("Send queue full"), json.find("last-error").and_then(|value| value.as_string()));
+        assert_eq!(None, metrics.connection_json("c2"));
+    }
+
+    fn get(addr: &str, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn serve_should_answer_metrics_requests() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let metrics = Arc::new(Metrics::default());
+        metrics.objects_routed.add(3);
+        serve(listener, metrics).unwrap();
+
+        let response = get(&addr, "/metrics");
+        assert!(response.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(response.contains("\nrabboe_objects_routed_total 3\n"));
+
+        assert!(get(&addr, "/").starts_with("HTTP/1.0 404 Not Found\r\n"));
+    }
+}
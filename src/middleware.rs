@@ -0,0 +1,202 @@
+//! Hooks the router runs on the objects passing through it. Inbound
+//! middlewares see each object a client publishes before it is routed, and
+//! outbound middlewares each object the router passes on to a client:
+//! routed objects, shared group deliveries and service calls, but not the
+//! router's own replies. Either may change the object, drop it, or reject
+//! an inbound one with a reply to its sender.
+//!
+//! Middlewares run in the order they were added, the router's own first,
+//! and the first one not passing an object on decides its fate.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use ::config::Config;
+use ::events::{ErrorCode, Event};
+use ::metadata;
+use ::object::BusinessObject;
+
+
+/// What a middleware does with an object.
+pub enum Verdict {
+    /// Passes the object, changed or not, on to the next middleware.
+    Pass(Arc<BusinessObject>),
+    /// Drops the object without telling anyone.
+    Drop,
+    /// Drops the object, sending the reply to the client that published it.
+    /// An outbound object rejected is just dropped.
+    Reject(Arc<BusinessObject>),
+}
+
+
+/// The client an object comes from or goes to.
+pub struct Peer<'a> {
+    pub routing_id: &'a str,
+    pub addr: SocketAddr,
+    /// Another router rather than a client.
+    pub is_router: bool,
+}
+
+
+pub struct Context<'a> {
+    pub config: &'a Config,
+    /// Identifies this router in the `route` of forwarded objects.
+    pub router_id: &'a str,
+    pub peer: Peer<'a>,
+}
+
+
+pub trait Middleware: Send + Sync {
+    fn inbound(&self, _context: &Context, object: Arc<BusinessObject>) -> Verdict {
+        Verdict::Pass(object)
+    }
+
+    fn outbound(&self, _context: &Context, object: Arc<BusinessObject>) -> Verdict {
+        Verdict::Pass(object)
+    }
+}
+
+
+/// The middlewares of a router.
+#[derive(Default)]
+pub struct Chain {
+    middlewares: Vec<Box<dyn Middleware>>,
+}
+
+
+impl Chain {
+    /// A chain of the middlewares the router itself relies on.
+    pub fn new() -> Chain {
+        Chain { middlewares: vec![Box::new(Validation), Box::new(RouteStamp)] }
+    }
+
+    pub fn push(&mut self, middleware: Box<dyn Middleware>) {
+        self.middlewares.push(middleware);
+    }
+
+    pub fn inbound(&self, context: &Context, object: Arc<BusinessObject>) -> Verdict {
+        self.run(object, |middleware, object| middleware.inbound(context, object))
+    }
+
+    pub fn outbound(&self, context: &Context, object: Arc<BusinessObject>) -> Verdict {
+        self.run(object, |middleware, object| middleware.outbound(context, object))
+    }
+
+    fn run<F>(&self, mut object: Arc<BusinessObject>, mut hook: F) -> Verdict
+        where F: FnMut(&dyn Middleware, Arc<BusinessObject>) -> Verdict {
+        for middleware in &self.middlewares {
+            object = match hook(middleware.as_ref(), object) {
+                Verdict::Pass(object) => object,
+                verdict => { return verdict; }
+            };
+        }
+        Verdict::Pass(object)
+    }
+}
+
+
+/// Rejects objects from clients breaking the protocol, listing what is
+/// wrong with them in the reply, when `validate-objects` is on. Objects
+/// from other routers are theirs to check.
+pub struct Validation;
+
+
+impl Middleware for Validation {
+    fn inbound(&self, context: &Context, object: Arc<BusinessObject>) -> Verdict {
+        if !context.config.validate_objects || context.peer.is_router {
+            return Verdict::Pass(object);
+        }
+
+        match object.validate() {
+            Ok(()) => Verdict::Pass(object),
+            Err(errors) => {
+                debug!("Rejected invalid object from {}: {:?}", context.peer.routing_id, errors);
+                let errors: Vec<String> = errors.iter().map(|error| error.to_string()).collect();
+                let mut reply = BusinessObject::error_reply(&object, Event::RoutingError, ErrorCode::InvalidObject,
+                                                            "Invalid object");
+                reply.set_meta("errors", &errors);
+                Verdict::Reject(Arc::new(reply))
+            }
+        }
+    }
+}
+
+
+/// Appends the router's id to the `route` of objects sent to other
+/// routers, so that they aren't routed back to it.
+pub struct RouteStamp;
+
+
+impl Middleware for RouteStamp {
+    fn outbound(&self, context: &Context, object: Arc<BusinessObject>) -> Verdict {
+        if !context.peer.is_router {
+            return Verdict::Pass(object);
+        }
+
+        let mut route = object.metadata.route.clone().unwrap_or_default();
+        route.push(context.router_id.to_string());
+        Verdict::Pass(Arc::new(object.with_meta(metadata::ROUTE, &route)))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{Chain, Context, Middleware, Peer, Verdict};
+    use ::config::Config;
+    use ::events::ErrorCode;
+    use ::object::BusinessObject;
+
+
+    struct Tag(&'static str);
+
+    impl Middleware for Tag {
+        fn inbound(&self, _context: &Context, object: Arc<BusinessObject>) -> Verdict {
+            if object.metadata.extra.contains_key("secret") {
+                return Verdict::Drop;
+            }
+            let mut object = (*object).clone();
+            let mut tags = object.meta_array_of_str("tags").unwrap_or_default()
+                .iter().map(|tag| tag.to_string()).collect::<Vec<String>>();
+            tags.push(self.0.to_string());
+            object.set_meta("tags", &tags);
+            Verdict::Pass(Arc::new(object))
+        }
+    }
+
+    #[test]
+    fn should_run_middlewares_in_order_until_one_stops_the_object() {
+        let mut chain = Chain::new();
+        chain.push(Box::new(Tag("first")));
+        chain.push(Box::new(Tag("second")));
+        let config = Config { validate_objects: true, .. Config::default() };
+        let mut context = Context {
+            config: &config,
+            router_id: "r1",
+            peer: Peer { routing_id: "c1", addr: "127.0.0.1:1".parse().unwrap(), is_router: false },
+        };
+
+        match chain.inbound(&context, Arc::new(BusinessObject::event("chat/message"))) {
+            Verdict::Pass(object) => assert_eq!(Some(vec!["first", "second"]), object.meta_array_of_str("tags")),
+            _ => panic!("Expected the object to pass")
+        }
+        let mut secret = BusinessObject::event("chat/message");
+        secret.set_meta("secret", &true);
+        assert!(matches!(chain.inbound(&context, Arc::new(secret)), Verdict::Drop));
+        match chain.inbound(&context, Arc::new(BusinessObject::event(""))) {
+            Verdict::Reject(reply) => assert_eq!(Some(ErrorCode::InvalidObject), reply.error_code()),
+            _ => panic!("Expected an invalid object to be rejected")
+        }
+
+        // Only objects going to other routers get stamped
+        let stamped = |context: &Context| match chain.outbound(context, Arc::new(BusinessObject::event("chat/message"))) {
+            Verdict::Pass(object) => object.metadata.route.clone(),
+            _ => panic!("Expected the object to pass")
+        };
+        assert_eq!(None, stamped(&context));
+        context.peer.is_router = true;
+        assert_eq!(Some(vec!["r1".to_string()]), stamped(&context));
+    }
+}
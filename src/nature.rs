@@ -0,0 +1,65 @@
+//! Natures, the tags in an object's `natures` that say what kind of thing
+//! it is, independently of its event or payload type. Any string is a
+//! nature; the ones clients commonly agree on have constants here.
+
+use std::borrow::{Borrow, Cow};
+use std::fmt;
+
+use rustc_serialize::json::{Json, ToJson};
+
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Nature(Cow<'static, str>);
+
+
+impl Nature {
+    pub const CHAT: Nature = Nature(Cow::Borrowed("chat"));
+    pub const MESSAGE: Nature = Nature(Cow::Borrowed("message"));
+    pub const NOTIFICATION: Nature = Nature(Cow::Borrowed("notification"));
+    pub const ERROR: Nature = Nature(Cow::Borrowed("error"));
+    pub const URL: Nature = Nature(Cow::Borrowed("url"));
+    pub const IMAGE: Nature = Nature(Cow::Borrowed("image"));
+
+    pub fn new(name: &str) -> Nature {
+        Nature(Cow::Owned(name.to_string()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+
+impl<'a> From<&'a str> for Nature {
+    fn from(name: &'a str) -> Nature {
+        Nature::new(name)
+    }
+}
+
+
+impl From<String> for Nature {
+    fn from(name: String) -> Nature {
+        Nature(Cow::Owned(name))
+    }
+}
+
+
+impl Borrow<str> for Nature {
+    fn borrow(&self) -> &str {
+        self.as_str()
+    }
+}
+
+
+impl fmt::Display for Nature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+
+impl ToJson for Nature {
+    fn to_json(&self) -> Json {
+        Json::String(self.as_str().to_string())
+    }
+}
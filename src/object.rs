@@ -1,35 +1,157 @@
+use std::borrow::Cow;
 use std::cmp::PartialEq;
-use std::collections::BTreeMap;
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet};
 use std::error;
 use std::fmt;
+use std::io::Write;
 use std::io;
+use std::str::{self, FromStr};
 
+use bytes::Bytes;
 use rustc_serialize::json::{ToJson, Json};
+use sha1::Sha1;
+use uuid::Uuid;
 
+use ::canonical;
+use ::content_type::ContentType;
+use ::error::{ParseError, ReadBusinessObjectError};
+use ::events::{ErrorCode, Event};
+use ::metadata::{self, StandardMetadata};
+use ::nature::Nature;
+use ::timestamp::Timestamp;
 
+
+/// An object on the bus. Objects are `Send + Sync`, so a routed object can
+/// be shared between threads behind an `Arc`.
 #[derive(Debug, Clone)]
 pub struct BusinessObject {
     pub event: Option<String>,
     pub _type: Option<String>,
     pub size: Option<usize>,
     pub payload: Option<Payload>,
-    pub metadata: BTreeMap<String,Json>
+    pub metadata: StandardMetadata
 }
 
 
-#[derive(Eq, PartialEq, Debug, Clone)]
+/// A payload, decoded according to the object's type. Bytes are shared
+/// between the clones of an object, so that an object changed on its way to
+/// each of its recipients doesn't have its payload copied for each, and may
+/// share the buffer they were read into.
+///
+/// Text and JSON decoded from bytes keep those bytes, which are what is sent
+/// on, so that a payload passes through unchanged whatever its charset or
+/// formatting. Payloads built locally have none and are encoded when sent.
+/// Payloads are equal if their contents are, whatever they were read from.
+#[derive(Debug, Clone)]
 pub enum Payload {
-    Bytes(Vec<u8>)
+    Bytes(Bytes),
+    Text(String, Option<Bytes>),
+    Json(Json, Option<Bytes>),
 }
 
 
-#[derive(Debug)]
-pub enum ReadBusinessObjectError {
-    ReadError(io::Error),
+/// A payload whose SHA-1 digest isn't the `sha1` declared for it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChecksumMismatch {
+    pub declared: String,
+    pub actual: String,
+}
 
-    JsonSemanticsError(&'static str),
-    JsonSyntaxError(String, String),
-    BufferCharacterDecodingError
+
+/// A way in which an object breaks the protocol, see
+/// `BusinessObject::validate`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValidationError {
+    /// `size` disagrees with the length of the payload.
+    SizeMismatch { declared: usize, actual: usize },
+    /// There is a payload but no `type`.
+    MissingType,
+    /// `natures` is not an array of strings.
+    InvalidNatures,
+    /// `route` is not an array of strings.
+    InvalidRoute,
+    /// `ttl` is not a non-negative integer.
+    InvalidTtl,
+    EmptyEvent,
+}
+
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            ValidationError::SizeMismatch { declared, actual } => {
+                write!(f, "size is {} but the payload has {} bytes", declared, actual)
+            },
+            ValidationError::MissingType => f.write_str("payload without a type"),
+            ValidationError::InvalidNatures => f.write_str("natures is not an array of strings"),
+            ValidationError::InvalidRoute => f.write_str("route is not an array of strings"),
+            ValidationError::InvalidTtl => f.write_str("ttl is not a non-negative integer"),
+            ValidationError::EmptyEvent => f.write_str("event is empty"),
+        }
+    }
+}
+
+
+impl fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "sha1 is {} but the payload's is {}", self.declared, self.actual)
+    }
+}
+
+
+impl error::Error for ChecksumMismatch {}
+
+
+/// Hex SHA-1 digest of `bytes`, as in the `sha1` field.
+pub fn sha1_hex(bytes: &[u8]) -> String {
+    Sha1::from(bytes).digest().to_string()
+}
+
+
+/// Checks payload bytes against the `sha1` declared for them.
+pub fn check_sha1(declared: &str, bytes: &[u8]) -> Result<(), ChecksumMismatch> {
+    let actual = sha1_hex(bytes);
+    if declared.eq_ignore_ascii_case(&actual) {
+        Ok(())
+    } else {
+        Err(ChecksumMismatch { declared: declared.to_string(), actual })
+    }
+}
+
+
+/// How urgently an object should be sent, from the `priority` metadata
+/// field. Queued objects of a higher priority are sent first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    High,
+    Normal,
+    Low,
+}
+
+
+impl FromStr for Priority {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Priority, ()> {
+        match s {
+            "high" => Ok(Priority::High),
+            "normal" => Ok(Priority::Normal),
+            "low" => Ok(Priority::Low),
+            _ => Err(())
+        }
+    }
+}
+
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.write_str(match *self {
+            Priority::High => "high",
+            Priority::Normal => "normal",
+            Priority::Low => "low",
+        })
+    }
 }
 
 
@@ -43,36 +165,94 @@ impl PartialEq for BusinessObject {
 }
 
 
-fn extract_reason(error: &ReadBusinessObjectError) -> &str {
-    match *error {
-        ReadBusinessObjectError::JsonSemanticsError(ref reason) => reason,
-        ReadBusinessObjectError::JsonSyntaxError(_, ref reason) => reason,
-        ReadBusinessObjectError::BufferCharacterDecodingError => "Character encoding error",
-        ReadBusinessObjectError::ReadError(_) => "Read error"
+impl PartialEq for Payload {
+    fn eq(&self, other: &Payload) -> bool {
+        match (self, other) {
+            (Payload::Bytes(left), Payload::Bytes(right)) => left == right,
+            (Payload::Text(left, _), Payload::Text(right, _)) => left == right,
+            (Payload::Json(left, _), Payload::Json(right, _)) => left == right,
+            _ => false
+        }
     }
 }
 
 
-impl fmt::Display for ReadBusinessObjectError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        write!(f, "{:?}", extract_reason(self))
+/// `text` in `encoding`. encoding_rs writes UTF-8 for UTF-16, so that is
+/// encoded here.
+fn encode_text<'a>(text: &'a str, encoding: &'static encoding_rs::Encoding) -> Cow<'a, [u8]> {
+    if encoding == encoding_rs::UTF_16LE {
+        Cow::Owned(text.encode_utf16().flat_map(|unit| unit.to_le_bytes()).collect())
+    } else if encoding == encoding_rs::UTF_16BE {
+        Cow::Owned(text.encode_utf16().flat_map(|unit| unit.to_be_bytes()).collect())
+    } else {
+        encoding.encode(text).0
     }
 }
 
-impl error::Error for ReadBusinessObjectError {
-    fn description(&self) -> &str {
-        extract_reason(self)
+
+impl Payload {
+    /// Decodes payload bytes according to the object's content type:
+    /// `text/*` as text in the declared charset (UTF-8 by default) and
+    /// `application/json` as JSON. Anything else, or anything that fails to
+    /// decode, is kept as bytes.
+    pub fn decode(content_type: Option<&ContentType>, bytes: Bytes) -> Payload {
+        let content_type = match content_type {
+            Some(content_type) => content_type,
+            None => { return Payload::Bytes(bytes); }
+        };
+
+        if content_type.is_text() {
+            let text = content_type.encoding().and_then(|encoding| {
+                encoding.decode_without_bom_handling_and_without_replacement(&bytes)
+                    .map(|text| text.into_owned())
+            });
+            match text {
+                Some(text) => Payload::Text(text, Some(bytes)),
+                None => Payload::Bytes(bytes)
+            }
+        } else if content_type.is_json() {
+            let json = str::from_utf8(&bytes).ok().and_then(|text| Json::from_str(text).ok());
+            match json {
+                Some(json) => Payload::Json(json, Some(bytes)),
+                None => Payload::Bytes(bytes)
+            }
+        } else {
+            Payload::Bytes(bytes)
+        }
+    }
+
+    /// The payload in wire format: the bytes it was read as, if any, and
+    /// otherwise text encoded in the charset of `content_type` and JSON in
+    /// canonical form, see `canonical`.
+    pub fn to_bytes(&self, content_type: Option<&ContentType>) -> Cow<'_, [u8]> {
+        match *self {
+            Payload::Bytes(ref bytes) |
+            Payload::Text(_, Some(ref bytes)) |
+            Payload::Json(_, Some(ref bytes)) => Cow::Borrowed(&bytes[..]),
+            Payload::Text(ref text, None) => {
+                match content_type.and_then(|content_type| content_type.encoding()) {
+                    Some(encoding) => encode_text(text, encoding),
+                    None => Cow::Borrowed(text.as_bytes())
+                }
+            },
+            Payload::Json(ref json, None) => Cow::Owned(canonical::to_bytes(json)),
+        }
+    }
+
+    /// Like `to_bytes`, but with JSON in canonical form even if it was read
+    /// in some other form.
+    pub fn to_canonical_bytes(&self, content_type: Option<&ContentType>) -> Cow<'_, [u8]> {
+        match *self {
+            Payload::Json(ref json, _) => Cow::Owned(canonical::to_bytes(json)),
+            _ => self.to_bytes(content_type)
+        }
     }
 }
 
 
 impl ToJson for BusinessObject {
     fn to_json(&self) -> Json {
-        let mut d = BTreeMap::new();
-
-        for (key, value) in self.metadata.iter() {
-            d.insert(key.to_string(), value.clone());
-        }
+        let mut d = self.metadata.to_fields();
 
         if self._type.is_some() { d.insert("type".to_string(), (&self._type).clone().unwrap().to_json()); }
         if self.size.is_some() { d.insert("size".to_string(), (&self.size).clone().unwrap().to_json()); }
@@ -87,25 +267,218 @@ impl BusinessObject {
     pub fn from_json(obj: &Json) -> Result<BusinessObject, ReadBusinessObjectError> {
         match obj.as_object() {
             Some(btree_obj) => Ok(btree_obj.to_business_object()),
-            None => Err(ReadBusinessObjectError::JsonSemanticsError("Unsupported JSON type"))
+            None => Err(ParseError::NotAnObject.into())
         }
     }
 
     pub fn to_bytes(&self) -> Vec<u8> {
-        let mut result = self.to_json().to_string().into_bytes();
-        result.push(b'\0');
+        let mut result = Vec::new();
+        self.write_to(&mut result).unwrap();
+        result
+    }
+
+    /// Writes the metadata frame and its terminating NUL, but not the payload.
+    pub fn write_header_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(self.to_json().to_string().as_bytes())?;
+        writer.write_all(b"\0")
+    }
+
+    /// The header, with the `size` of the payload as sent, and the payload,
+    /// in canonical form if `canonical`.
+    fn frame(&self, canonical: bool) -> (Json, Option<Cow<'_, [u8]>>) {
+        let mut header = self.to_json();
+        let content_type = self.content_type();
+        let payload = self.payload.as_ref().map(|payload| match canonical {
+            true => payload.to_canonical_bytes(content_type.as_ref()),
+            false => payload.to_bytes(content_type.as_ref()),
+        });
+        if let (Json::Object(ref mut fields), Some(ref payload)) = (&mut header, &payload) {
+            fields.insert("size".to_string(), payload.len().to_json());
+        }
+        (header, payload)
+    }
 
+    /// Writes the object in wire format without first collecting it into a
+    /// single buffer. The `size` written is that of the payload actually sent.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let (header, payload) = match self.frame(false) {
+            (header, Some(payload)) => (header, payload),
+            _ => { return self.write_header_to(writer); }
+        };
+        writer.write_all(header.to_string().as_bytes())?;
+        writer.write_all(b"\0")?;
+        writer.write_all(&payload)
+    }
+
+    /// The object in wire format with its header and any JSON payload in
+    /// canonical form, see `canonical`, so that equal objects are written as
+    /// the same bytes whatever they were read from.
+    pub fn to_canonical_bytes(&self) -> Vec<u8> {
+        let (header, payload) = self.frame(true);
+        let mut bytes = canonical::to_bytes(&header);
+        bytes.push(0);
+        bytes.extend_from_slice(&payload.unwrap_or_default());
+        bytes
+    }
+
+    /// The parsed `type` field, if present and well-formed.
+    pub fn content_type(&self) -> Option<ContentType> {
+        self._type.as_ref().and_then(|_type| ContentType::parse(_type))
+    }
+
+    /// The payload as text, if it was decoded as text or is valid UTF-8.
+    pub fn payload_as_str(&self) -> Option<&str> {
         match self.payload {
-            Some(Payload::Bytes(ref payload)) => {
-                assert!(self.has_payload());
-                assert!(self.size.unwrap() == payload.len());
+            Some(Payload::Text(ref text, _)) => Some(text),
+            Some(Payload::Bytes(ref bytes)) => str::from_utf8(bytes).ok(),
+            _ => None
+        }
+    }
+
+    pub fn payload_as_json(&self) -> Option<&Json> {
+        match self.payload {
+            Some(Payload::Json(ref json, _)) => Some(json),
+            _ => None
+        }
+    }
+
+    /// Stamps a newly generated UUID into the `id` metadata field, replacing
+    /// any previous id.
+    pub fn with_new_id(mut self) -> BusinessObject {
+        self.set_meta(metadata::ID, &Uuid::new_v4().hyphenated().to_string());
+        self
+    }
+
+    /// The metadata field `key`, if it is a string.
+    pub fn meta_str(&self, key: &str) -> Option<&str> {
+        self.metadata.get_str(key)
+    }
+
+    /// When the object goes stale, from the `expires` metadata field: Unix
+    /// time in seconds or an RFC 3339 timestamp.
+    pub fn expires(&self) -> Option<Timestamp> {
+        match self.metadata.extra.get("expires") {
+            Some(&Json::U64(seconds)) => Some(Timestamp::from_secs(cmp::min(seconds, i64::MAX as u64) as i64)),
+            Some(&Json::I64(seconds)) => Some(Timestamp::from_secs(seconds)),
+            Some(&Json::F64(seconds)) if seconds.is_finite() => Some(Timestamp::from_secs_f64(seconds)),
+            Some(Json::String(timestamp)) => Timestamp::parse_rfc3339(timestamp),
+            _ => None
+        }
+    }
+
+    pub fn is_expired(&self, now: Timestamp) -> bool {
+        self.expires().is_some_and(|expires| expires <= now)
+    }
+
+    /// The metadata field `key`, if it is a non-negative integer or a
+    /// string of one.
+    pub fn meta_u64(&self, key: &str) -> Option<u64> {
+        match self.metadata.extra.get(key) {
+            Some(&Json::U64(n)) => Some(n),
+            Some(&Json::I64(n)) if n >= 0 => Some(n as u64),
+            Some(Json::String(s)) => s.trim().parse().ok(),
+            _ => None
+        }
+    }
+
+    /// The metadata field `key`, if it is an array of nothing but strings.
+    pub fn meta_array_of_str(&self, key: &str) -> Option<Vec<&str>> {
+        self.metadata.get_array_of_str(key)
+    }
+
+    /// A copy of the object with its metadata changed by `f`, sharing the
+    /// payload with the original if it is bytes.
+    pub fn map_metadata<F>(&self, f: F) -> BusinessObject where F: FnOnce(&mut StandardMetadata) {
+        let mut object = self.clone();
+        f(&mut object.metadata);
+        object
+    }
+
+    /// A copy of the object with the metadata field `key` set to `value`.
+    pub fn with_meta<T: ToJson + ?Sized>(&self, key: &str, value: &T) -> BusinessObject {
+        self.map_metadata(|metadata| { metadata.insert(key.to_string(), value.to_json()); })
+    }
+
+    /// Sets the metadata field `key`, replacing any previous value.
+    pub fn set_meta<T: ToJson + ?Sized>(&mut self, key: &str, value: &T) {
+        self.metadata.insert(key.to_string(), value.to_json());
+    }
+
+    pub fn id(&self) -> Option<&str> {
+        self.metadata.id.as_deref()
+    }
+
+    /// Creates an object with `event` and nothing else, such as a `ping`.
+    pub fn event<E: ToString>(event: E) -> BusinessObject {
+        BusinessObject {
+            _type: None,
+            payload: None,
+            size: None,
+            event: Some(event.to_string()),
+            metadata: Default::default(),
+        }
+    }
+
+    /// An error the router tells a client about, with a `code` for
+    /// programs and an `error` message for people.
+    pub fn error(event: Event, code: ErrorCode, message: &str) -> BusinessObject {
+        let mut metadata = BTreeMap::new();
+        metadata.insert("code".to_string(), code.as_str().to_json());
+        metadata.insert("error".to_string(), message.to_json());
+
+        BusinessObject { metadata: metadata.into(), .. BusinessObject::event(event) }
+    }
+
+    /// An error telling the sender of `request` why it was rejected.
+    pub fn error_reply(request: &BusinessObject, event: Event, code: ErrorCode, message: &str) -> BusinessObject {
+        let mut reply = BusinessObject::error(event, code, message);
+        reply.metadata.in_reply_to = request.id().map(|id| id.to_string());
+        reply
+    }
+
+    /// The `code` of an error reply, if it is one.
+    pub fn error_code(&self) -> Option<ErrorCode> {
+        self.meta_str("code").and_then(|code| code.parse().ok())
+    }
 
-                result.extend(payload);
+    /// Creates an empty reply to `request` with `in-reply-to` set to the id
+    /// of the request, if it has one.
+    pub fn reply_to(request: &BusinessObject) -> BusinessObject {
+        BusinessObject {
+            _type: None,
+            payload: None,
+            size: None,
+            event: None,
+            metadata: StandardMetadata {
+                in_reply_to: request.id().map(|id| id.to_string()),
+                .. StandardMetadata::default()
             },
-            None => {}
         }
+    }
 
-        result
+    /// The event, if it is one of those the router and clients understand.
+    pub fn typed_event(&self) -> Option<Event> {
+        self.event.as_ref().and_then(|event| event.parse().ok())
+    }
+
+    /// The `priority` field if it names one, otherwise high for control
+    /// traffic such as pings and normal for the rest. Replies such as those
+    /// to `history/replay` are normal, so they don't overtake the objects
+    /// queued ahead of them.
+    pub fn priority(&self) -> Priority {
+        match self.meta_str("priority").and_then(|priority| priority.parse().ok()) {
+            Some(priority) => priority,
+            None if self.typed_event().is_some_and(|event| event.is_control()) => Priority::High,
+            None => Priority::Normal
+        }
+    }
+
+    pub fn is_event(&self, event: Event) -> bool {
+        self.event.as_ref().map(|e| e.as_ref()) == Some(event.as_str())
+    }
+
+    pub fn in_reply_to(&self) -> Option<&str> {
+        self.metadata.in_reply_to.as_deref()
     }
 
     pub fn has_payload(&self) -> bool {
@@ -115,28 +488,157 @@ impl BusinessObject {
         }
     }
 
-    pub fn natures(&self) -> Vec<&str> {
-        let mut result = Vec::new();
-
-        match self.metadata.get("natures") {
-            Some(natures) => {
-                match natures.as_array() {
-                    Some(natures) => {
-                        for item in natures {
-                            match item.as_string() {
-                                Some(nature) => { result.push(nature); },
-                                _ => { trace!("Cannot use {} as a nature", item); }
-                            }
-                        }
-                    },
-                    None => {}
-                }
+    /// Checks the invariants the protocol places on objects and returns
+    /// every one broken. The `size` of JSON payloads built locally isn't
+    /// checked, since they are serialized when sent.
+    pub fn validate(&self) -> Result<(), Vec<ValidationError>> {
+        let mut errors = Vec::new();
 
-            }
-            None => {}
+        let declared = self.size.unwrap_or(0);
+        let actual = match self.payload {
+            Some(Payload::Json(_, None)) => declared,
+            Some(ref payload) => payload.to_bytes(self.content_type().as_ref()).len(),
+            None => 0
         };
+        if declared != actual {
+            errors.push(ValidationError::SizeMismatch { declared, actual });
+        }
+        if self.payload.is_some() && self._type.is_none() {
+            errors.push(ValidationError::MissingType);
+        }
+        // Standard fields only end up among the rest if they aren't of the right type
+        if self.metadata.extra.contains_key(metadata::NATURES) && self.meta_array_of_str(metadata::NATURES).is_none() {
+            errors.push(ValidationError::InvalidNatures);
+        }
+        if self.metadata.extra.contains_key(metadata::ROUTE) && self.meta_array_of_str(metadata::ROUTE).is_none() {
+            errors.push(ValidationError::InvalidRoute);
+        }
+        if self.metadata.extra.contains_key(metadata::TTL) {
+            errors.push(ValidationError::InvalidTtl);
+        }
+        if self.event.as_ref().is_some_and(|event| event.is_empty()) {
+            errors.push(ValidationError::EmptyEvent);
+        }
 
-        result
+        if errors.is_empty() { Ok(()) } else { Err(errors) }
+    }
+
+    /// Sets `sha1` to the digest of the payload, for recipients to check it
+    /// against. That of a JSON payload is computed over its canonical form,
+    /// whatever form it is sent in. Objects without a payload get none.
+    pub fn attach_checksum(&mut self) {
+        let checksum = self.payload.as_ref()
+            .map(|payload| sha1_hex(&payload.to_canonical_bytes(self.content_type().as_ref())));
+        self.metadata.sha1 = checksum;
+    }
+
+    /// Checks the payload against `sha1`, if the object has one. Only SHA-1
+    /// is supported; an `xxh3` alone isn't checked. JSON payloads are
+    /// checked in canonical form, so one whose sender computed its checksum
+    /// over the payload written some other way fails here.
+    pub fn verify_checksum(&self) -> Result<(), ChecksumMismatch> {
+        match self.metadata.sha1 {
+            Some(ref declared) => {
+                let payload = self.payload.as_ref()
+                    .map(|payload| payload.to_canonical_bytes(self.content_type().as_ref()))
+                    .unwrap_or_default();
+                check_sha1(declared, &payload)
+            },
+            None => Ok(())
+        }
+    }
+
+    /// The natures in `natures`, or none if it isn't an array of strings.
+    pub fn natures(&self) -> BTreeSet<Nature> {
+        self.metadata.natures.iter().flatten().cloned().collect()
+    }
+
+    pub fn has_nature(&self, nature: &Nature) -> bool {
+        self.metadata.natures.as_ref().is_some_and(|natures| natures.contains(nature))
+    }
+
+    /// Whether the object has every one of `natures`.
+    pub fn has_all_natures(&self, natures: &[Nature]) -> bool {
+        let own = self.natures();
+        natures.iter().all(|nature| own.contains(nature))
+    }
+
+    /// Whether the object has at least one of `natures`.
+    pub fn has_any_nature(&self, natures: &[Nature]) -> bool {
+        let own = self.natures();
+        natures.iter().any(|nature| own.contains(nature))
+    }
+
+    /// Adds `nature` to `natures` unless the object already has it. A
+    /// `natures` that isn't an array is replaced.
+    pub fn add_nature(&mut self, nature: Nature) {
+        if self.has_nature(&nature) {
+            return;
+        }
+        if self.metadata.natures.is_none() {
+            self.metadata.extra.remove(metadata::NATURES);
+        }
+        self.metadata.natures.get_or_insert_with(Vec::new).push(nature);
+    }
+
+    pub fn remove_nature(&mut self, nature: &Nature) {
+        if let Some(ref mut natures) = self.metadata.natures {
+            natures.retain(|item| item != nature);
+        }
+    }
+
+    /// A one-line summary of the object for logs, with each field cut to
+    /// at most `max_field_len` characters. `Display` uses
+    /// `SUMMARY_FIELD_LEN`.
+    pub fn summary(&self, max_field_len: usize) -> Summary<'_> {
+        Summary { object: self, max_field_len }
+    }
+}
+
+
+/// How many characters of each field `Display` shows of an object.
+pub const SUMMARY_FIELD_LEN: usize = 40;
+
+
+/// A one-line summary of an object, like
+/// `event=ping type=- size=0 natures=[] id=9f0c…`, see
+/// `BusinessObject::summary`.
+pub struct Summary<'a> {
+    object: &'a BusinessObject,
+    max_field_len: usize,
+}
+
+
+impl<'a> Summary<'a> {
+    fn field(&self, f: &mut fmt::Formatter, name: &str, value: Option<&str>) -> fmt::Result {
+        let value = value.unwrap_or("-");
+        match value.char_indices().nth(self.max_field_len) {
+            Some((end, _)) => write!(f, "{}={}…", name, &value[.. end]),
+            None => write!(f, "{}={}", name, value),
+        }
+    }
+}
+
+
+impl<'a> fmt::Display for Summary<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let object = self.object;
+        let natures: Vec<&str> = object.metadata.natures.iter().flatten().map(|nature| nature.as_str()).collect();
+
+        self.field(f, "event", object.event.as_deref())?;
+        f.write_str(" ")?;
+        self.field(f, "type", object._type.as_deref())?;
+        write!(f, " size={} ", object.size.unwrap_or(0))?;
+        self.field(f, "natures", Some(&format!("[{}]", natures.join(","))))?;
+        f.write_str(" ")?;
+        self.field(f, "id", object.id())
+    }
+}
+
+
+impl fmt::Display for BusinessObject {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.summary(SUMMARY_FIELD_LEN).fmt(f)
     }
 }
 
@@ -153,7 +655,7 @@ impl ToBusinessObject for BTreeMap<String,Json> {
             _type: None,
             size: None,
             payload: None,
-            metadata: BTreeMap::new()
+            metadata: StandardMetadata::default()
         };
 
         let event = self.get("event");
@@ -200,8 +702,12 @@ impl ToBusinessObject for BTreeMap<String,Json> {
 mod tests {
     use std::collections::BTreeMap;
     use rustc_serialize::json::{Json, ToJson};
+    use ::timestamp::Timestamp;
 
-    use super::BusinessObject;
+    use super::{BusinessObject, ChecksumMismatch, Payload, ValidationError};
+    use ::content_type::ContentType;
+    use ::events::Event;
+    use ::nature::Nature;
 
 
     #[test]
@@ -212,13 +718,7 @@ mod tests {
                              "@ping".to_string(), "@pong".to_string()].to_json());
         metadata.insert("subscriptions".to_string(), vec!["*".to_string()].to_json());
 
-        let subscription = BusinessObject {
-            _type: None,
-            payload: None,
-            size: None,
-            event: Some("routing/subscribe".to_string()),
-            metadata: metadata,
-        };
+        let subscription = BusinessObject { metadata: metadata.into(), .. BusinessObject::event("routing/subscribe") };
 
         let json_repr_from = subscription.to_json();
         let string_repr = json_repr_from.to_string();
@@ -228,4 +728,304 @@ mod tests {
         assert!(json_repr_from == json_repr_to);
         assert!(subscription == back);
     }
+
+    fn ping() -> BusinessObject {
+        BusinessObject::event("ping")
+    }
+
+    #[test]
+    fn business_objects_should_be_shareable_between_threads() {
+        fn assert_send_sync<T: Send + Sync>() {}
+
+        assert_send_sync::<BusinessObject>();
+        assert_send_sync::<Payload>();
+    }
+
+    #[test]
+    fn with_new_id_should_stamp_unique_ids() {
+        let first = ping().with_new_id();
+        let second = ping().with_new_id();
+
+        assert_eq!(36, first.id().unwrap().len());
+        assert!(first.id() != second.id());
+    }
+
+    #[test]
+    fn typed_event_should_recognize_known_events() {
+        assert_eq!(Some(Event::Ping), ping().typed_event());
+        assert!(ping().is_event(Event::Ping));
+        assert!(!ping().is_event(Event::Pong));
+
+        let mut object = ping();
+        object.event = Some("chat/message".to_string());
+        assert_eq!(None, object.typed_event());
+    }
+
+    #[test]
+    fn reply_to_should_copy_id_to_in_reply_to() {
+        let request = ping().with_new_id();
+        let reply = BusinessObject::reply_to(&request);
+
+        assert_eq!(request.id(), reply.in_reply_to());
+        assert!(reply.id().is_none());
+        assert!(reply.event.is_none());
+    }
+
+    #[test]
+    fn reply_to_should_handle_requests_without_id() {
+        let reply = BusinessObject::reply_to(&ping());
+
+        assert!(reply.in_reply_to().is_none());
+        assert!(reply.metadata.is_empty());
+    }
+
+    fn object_with_payload(content_type: &str, payload: &[u8]) -> BusinessObject {
+        BusinessObject {
+            _type: Some(content_type.to_string()),
+            payload: Some(Payload::decode(ContentType::parse(content_type).as_ref(), payload.to_vec().into())),
+            size: Some(payload.len()),
+            event: None,
+            metadata: Default::default(),
+        }
+    }
+
+    #[test]
+    fn should_decode_text_payloads() {
+        let obj = object_with_payload("text/plain; charset=UTF-8", "hölökyn kölökyn".as_bytes());
+
+        assert_eq!(Some(&Payload::Text("hölökyn kölökyn".to_string(), None)), obj.payload.as_ref());
+        assert_eq!(Some("hölökyn kölökyn"), obj.payload_as_str());
+        assert!(obj.payload_as_json().is_none());
+    }
+
+    #[test]
+    fn should_decode_json_payloads() {
+        let obj = object_with_payload("application/json", br#"{"answer": 42}"#);
+
+        assert_eq!(Some(42), obj.payload_as_json().and_then(|json| json.find("answer")).and_then(|a| a.as_u64()));
+        assert!(obj.payload_as_str().is_none());
+    }
+
+    #[test]
+    fn should_keep_undecodable_payloads_as_bytes() {
+        let invalid_text = object_with_payload("text/plain", &[0xff, 0xfe]);
+        let invalid_json = object_with_payload("application/json", b"{");
+        let binary = object_with_payload("image/png", b"PNG");
+
+        assert_eq!(Some(&Payload::Bytes(vec![0xff, 0xfe].into())), invalid_text.payload.as_ref());
+        assert_eq!(Some(&Payload::Bytes(b"{".to_vec().into())), invalid_json.payload.as_ref());
+        assert_eq!(Some(&Payload::Bytes(b"PNG".to_vec().into())), binary.payload.as_ref());
+        assert_eq!(Some("PNG"), binary.payload_as_str());
+    }
+
+    #[test]
+    fn write_to_should_send_json_as_read_and_json_built_locally_in_canonical_form() {
+        let read = object_with_payload("application/json", br#"{ "answer" : 42 }"#);
+        let mut built = read.clone();
+        built.payload = Some(Payload::Json(read.payload_as_json().unwrap().clone(), None));
+
+        for &(obj, expected) in &[(&read, &br#"{ "answer" : 42 }"#[..]), (&built, &br#"{"answer":42}"#[..])] {
+            let bytes = obj.to_bytes();
+            let nul = bytes.iter().position(|b| *b == 0).unwrap();
+            let header = Json::from_str(::std::str::from_utf8(&bytes[.. nul]).unwrap()).unwrap();
+
+            assert_eq!(expected.to_vec(), bytes[nul + 1 ..].to_vec());
+            assert_eq!(Some(expected.len() as u64), header.find("size").and_then(|size| size.as_u64()));
+        }
+    }
+
+    #[test]
+    fn text_should_be_sent_as_read_and_text_built_locally_in_its_charset() {
+        let read = object_with_payload("text/plain; charset=utf-16le", &[104, 0, 105, 0]);
+        assert_eq!(Some("hi"), read.payload_as_str());
+        assert_eq!(&[104, 0, 105, 0][..], &read.payload.as_ref().unwrap().to_bytes(read.content_type().as_ref())[..]);
+
+        let content_type = ContentType::parse("text/plain; charset=utf-16be");
+        assert_eq!(&[0, 104, 0, 105][..], &Payload::Text("hi".to_string(), None).to_bytes(content_type.as_ref())[..]);
+    }
+
+    #[test]
+    fn should_decode_text_in_declared_charset() {
+        let obj = object_with_payload("text/plain; charset=ISO-8859-1", b"h\xf6l\xf6kyn");
+
+        assert_eq!(Some("hölökyn"), obj.payload_as_str());
+        assert_eq!("text", obj.content_type().unwrap()._type);
+    }
+
+    #[test]
+    fn write_to_should_encode_text_in_declared_charset() {
+        let obj = object_with_payload("text/plain; charset=ISO-8859-1", b"h\xf6l\xf6kyn");
+        let bytes = obj.to_bytes();
+
+        assert!(bytes.ends_with(b"\0h\xf6l\xf6kyn"));
+    }
+
+    #[test]
+    fn should_keep_text_in_unknown_charset_as_bytes() {
+        let obj = object_with_payload("text/plain; charset=klingon", b"Qapla'");
+
+        assert_eq!(Some(&Payload::Bytes(b"Qapla'".to_vec().into())), obj.payload.as_ref());
+    }
+
+    #[test]
+    fn validate_should_accept_well_formed_objects() {
+        let mut obj = object_with_payload("application/json", br#"{ "answer" : 42 }"#);
+        obj.event = Some("chat/message".to_string());
+        obj.metadata.insert("natures".to_string(), vec!["chat".to_string()].to_json());
+        obj.metadata.insert("route".to_string(), vec!["router-1".to_string()].to_json());
+
+        assert_eq!(Ok(()), obj.validate());
+        assert_eq!(Ok(()), object_with_payload("text/plain", b"hello").validate());
+    }
+
+    #[test]
+    fn validate_should_report_every_broken_invariant() {
+        let mut obj = object_with_payload("text/plain", b"hello");
+        obj._type = None;
+        obj.size = Some(3);
+        obj.event = Some(String::new());
+        obj.metadata.insert("natures".to_string(), "chat".to_json());
+        obj.metadata.insert("route".to_string(), vec![1, 2].to_json());
+        obj.metadata.insert("ttl".to_string(), (-1).to_json());
+
+        assert_eq!(Err(vec![ValidationError::SizeMismatch { declared: 3, actual: 5 },
+                            ValidationError::MissingType,
+                            ValidationError::InvalidNatures,
+                            ValidationError::InvalidRoute,
+                            ValidationError::InvalidTtl,
+                            ValidationError::EmptyEvent]),
+                   obj.validate());
+    }
+
+    #[test]
+    fn metadata_accessors_should_coerce_or_reject_values() {
+        let mut obj = BusinessObject::reply_to(&object_with_payload("text/plain", b""));
+        obj.set_meta("name", "tester");
+        obj.set_meta("limit", &10);
+        obj.set_meta("since", "60");
+        obj.set_meta("offset", &-1);
+        obj.set_meta("natures", &vec!["chat".to_string(), "urgent".to_string()]);
+        obj.set_meta("route", &Json::Array(vec!["router-1".to_json(), 2.to_json()]));
+
+        assert_eq!(Some("tester"), obj.meta_str("name"));
+        assert_eq!(None, obj.meta_str("limit"));
+        assert_eq!(Some(10), obj.meta_u64("limit"));
+        assert_eq!(Some(60), obj.meta_u64("since"));
+        assert_eq!(None, obj.meta_u64("offset"));
+        assert_eq!(None, obj.meta_u64("name"));
+        assert_eq!(Some(vec!["chat", "urgent"]), obj.meta_array_of_str("natures"));
+        assert_eq!(None, obj.meta_array_of_str("route"));
+        assert_eq!(None, obj.meta_array_of_str("missing"));
+    }
+
+    #[test]
+    fn map_metadata_should_share_the_payload_bytes() {
+        let obj = object_with_payload("image/png", b"PNG");
+        let stamped = obj.with_meta("route", &vec!["router-1".to_string()]);
+        let renamed = stamped.map_metadata(|metadata| { metadata.remove("route"); });
+
+        assert_eq!(None, obj.meta_array_of_str("route"));
+        assert_eq!(Some(vec!["router-1"]), stamped.meta_array_of_str("route"));
+        assert_eq!(obj.metadata, renamed.metadata);
+        match (&obj.payload, &renamed.payload) {
+            (&Some(Payload::Bytes(ref original)), &Some(Payload::Bytes(ref copy))) => {
+                assert_eq!(original.as_ptr(), copy.as_ptr());
+            },
+            _ => panic!("Expected byte payloads")
+        }
+    }
+
+    #[test]
+    fn natures_should_behave_as_a_set() {
+        let mut obj = BusinessObject::reply_to(&object_with_payload("text/plain", b""));
+        assert!(obj.natures().is_empty());
+
+        obj.add_nature(Nature::CHAT);
+        obj.add_nature(Nature::new("urgent"));
+        obj.add_nature(Nature::CHAT);
+        assert_eq!(Some(vec!["chat", "urgent"]), obj.meta_array_of_str("natures"));
+        assert!(obj.has_nature(&Nature::CHAT));
+        assert!(obj.has_all_natures(&[Nature::CHAT, "urgent".into()]));
+        assert!(!obj.has_all_natures(&[Nature::CHAT, Nature::URL]));
+        assert!(obj.has_any_nature(&[Nature::URL, "urgent".into()]));
+
+        // Owned, so the object can be changed while they are looked at
+        for nature in obj.natures() {
+            obj.remove_nature(&nature);
+        }
+        assert!(obj.natures().is_empty());
+
+        obj.set_meta("natures", "chat");
+        assert!(!obj.has_nature(&Nature::CHAT));
+        obj.add_nature(Nature::MESSAGE);
+        assert_eq!(Some(vec!["message"]), obj.meta_array_of_str("natures"));
+    }
+
+    #[test]
+    fn display_should_summarize_objects_on_one_line() {
+        assert_eq!("event=ping type=- size=0 natures=[] id=-", ping().to_string());
+
+        let mut object = object_with_payload("text/plain", b"ABCDE");
+        object.add_nature(Nature::IMAGE);
+        object.add_nature(Nature::URL);
+        object.metadata.id = Some("0123456789".to_string());
+        assert_eq!("event=- type=text/plain size=5 natures=[image,url] id=0123456789", object.to_string());
+        assert_eq!("event=- type=text/pl… size=5 natures=[image,… id=0123456…",
+                   object.summary(7).to_string());
+    }
+
+    #[test]
+    fn attached_checksums_should_verify_until_the_payload_changes() {
+        let mut obj = object_with_payload("text/plain", b"hello");
+        assert_eq!(Ok(()), obj.verify_checksum());
+
+        obj.attach_checksum();
+        assert_eq!(Some("aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d"), obj.meta_str("sha1"));
+        assert_eq!(Ok(()), obj.verify_checksum());
+
+        obj.payload = Some(Payload::Text("jello".to_string(), None));
+        assert_eq!(Err(ChecksumMismatch { declared: "aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d".to_string(),
+                                          actual: "2ced3ee86f82bf91c15cc30605df6d3ddf0769ff".to_string() }),
+                   obj.verify_checksum());
+    }
+
+    #[test]
+    fn checksums_of_json_payloads_should_be_over_their_canonical_form() {
+        let obj = object_with_payload("application/json", br#"{ "station": "Kumpula", "celsius": -2.50 }"#);
+        let mut rewritten = obj.clone();
+        rewritten.payload = Some(Payload::Json(Json::from_str(r#"{"celsius":-2.5,"station":"Kumpula"}"#).unwrap(), None));
+
+        assert_eq!(obj.to_canonical_bytes(), rewritten.to_canonical_bytes());
+        let bytes = obj.to_canonical_bytes();
+        let header_len = bytes.iter().position(|&byte| byte == 0).unwrap();
+        assert_eq!(&br#"{"celsius":-2.5,"station":"Kumpula"}"#[..], &bytes[header_len + 1 ..]);
+
+        // Whether read in some other form or built locally
+        for obj in &mut [obj, rewritten] {
+            obj.attach_checksum();
+            assert_eq!(Some("fc21b37a501b59a8639060239623aeb99ee78afa"), obj.meta_str("sha1"));
+            assert_eq!(Ok(()), obj.verify_checksum());
+        }
+    }
+
+    #[test]
+    fn expires_should_accept_unix_time_and_rfc_3339() {
+        let mut obj = BusinessObject::reply_to(&object_with_payload("text/plain", b""));
+        assert_eq!(None, obj.expires());
+
+        obj.set_meta("expires", &1714564800);
+        assert_eq!(Some(Timestamp::from_millis(1_714_564_800_000)), obj.expires());
+        obj.set_meta("expires", &1714564800.5);
+        assert_eq!(Some(Timestamp::from_millis(1_714_564_800_500)), obj.expires());
+        obj.set_meta("expires", "2024-05-01T12:00:00Z");
+        assert_eq!(Some(Timestamp::from_millis(1_714_564_800_000)), obj.expires());
+        obj.set_meta("expires", "2024-05-01T14:00:00.25+02:00");
+        assert_eq!(Some(Timestamp::from_millis(1_714_564_800_250)), obj.expires());
+        assert!(obj.is_expired(Timestamp::from_millis(1_714_564_801_000)));
+        assert!(!obj.is_expired(Timestamp::from_millis(1_714_564_800_000)));
+
+        obj.set_meta("expires", "tomorrow");
+        assert_eq!(None, obj.expires());
+        assert!(!obj.is_expired(Timestamp::from_millis(1_714_564_801_000)));
+    }
 }
@@ -0,0 +1,206 @@
+//! Structured log of the objects passing through the router, one JSON object
+//! per line. Each line summarizes an object instead of dumping it, and
+//! payloads are left out unless explicitly asked for.
+
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use rustc_serialize::json::{Json, ToJson};
+
+use ::object::{BusinessObject, Payload};
+use ::timestamp::Timestamp;
+
+
+/// Characters of a text payload included in a summary.
+pub const PAYLOAD_PREVIEW_CHARS: usize = 256;
+
+
+pub struct ObjectLog {
+    writer: Mutex<Box<dyn Write + Send>>,
+    // The bits of an f64, so that it can be changed while logging
+    sample_rate: AtomicU64,
+    log_payloads: AtomicBool,
+    seen: AtomicU64,
+}
+
+
+impl ObjectLog {
+    /// Logs the given fraction of objects, spread evenly, to `writer`.
+    /// Payloads are redacted unless `log_payloads` is set.
+    pub fn new(writer: Box<dyn Write + Send>, sample_rate: f64, log_payloads: bool) -> ObjectLog {
+        ObjectLog {
+            writer: Mutex::new(writer),
+            sample_rate: AtomicU64::new(sample_rate.to_bits()),
+            log_payloads: AtomicBool::new(log_payloads),
+            seen: AtomicU64::new(0),
+        }
+    }
+
+    /// Changes what is logged from now on.
+    pub fn set_sampling(&self, sample_rate: f64, log_payloads: bool) {
+        self.sample_rate.store(sample_rate.to_bits(), Ordering::Relaxed);
+        self.log_payloads.store(log_payloads, Ordering::Relaxed);
+    }
+
+    /// Appends to the file at `path`, or writes to standard error if `path`
+    /// is `-`.
+    pub fn open(path: &Path, sample_rate: f64, log_payloads: bool) -> io::Result<ObjectLog> {
+        let writer: Box<dyn Write + Send> = if path == Path::new("-") {
+            Box::new(io::stderr())
+        } else {
+            Box::new(OpenOptions::new().create(true).append(true).open(path)?)
+        };
+
+        Ok(ObjectLog::new(writer, sample_rate, log_payloads))
+    }
+
+    fn sampled(&self) -> bool {
+        let sample_rate = f64::from_bits(self.sample_rate.load(Ordering::Relaxed));
+        let n = self.seen.fetch_add(1, Ordering::Relaxed) as f64;
+        ((n + 1.0) * sample_rate).floor() > (n * sample_rate).floor()
+    }
+
+    pub fn summary(&self, peer: &SocketAddr, object: &BusinessObject) -> Json {
+        let mut summary = BTreeMap::new();
+        summary.insert("time".to_string(), Timestamp::now().to_rfc3339().to_json());
+        summary.insert("peer".to_string(), peer.to_string().to_json());
+
+        if let Some(ref event) = object.event {
+            summary.insert("event".to_string(), event.to_json());
+        }
+        if let Some(ref _type) = object._type {
+            summary.insert("type".to_string(), _type.to_json());
+        }
+        if let Some(size) = object.size {
+            summary.insert("size".to_string(), size.to_json());
+        }
+        let natures: Vec<Json> = object.natures().iter().map(|nature| nature.to_json()).collect();
+        if !natures.is_empty() {
+            summary.insert("natures".to_string(), Json::Array(natures));
+        }
+        if let Some(ref id) = object.metadata.id {
+            summary.insert("id".to_string(), id.to_json());
+        }
+
+        if self.log_payloads.load(Ordering::Relaxed) {
+            match object.payload {
+                Some(Payload::Text(ref text, _)) => {
+                    let preview: String = text.chars().take(PAYLOAD_PREVIEW_CHARS).collect();
+                    summary.insert("payload".to_string(), preview.to_json());
+                },
+                Some(Payload::Json(ref json, _)) => { summary.insert("payload".to_string(), json.clone()); },
+                _ => {}
+            }
+        }
+
+        Json::Object(summary)
+    }
+
+    /// Writes a summary of `object`, received from `peer`, if it is
+    /// sampled.
+    pub fn log(&self, peer: &SocketAddr, object: &BusinessObject) {
+        if !self.sampled() {
+            return;
+        }
+
+        let line = format!("{}\n", self.summary(peer, object));
+        if let Err(e) = self.writer.lock().unwrap().write_all(line.as_bytes()) {
+            warn!("Failed to write object log: {}", e);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::io::Write;
+    use std::io;
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+    use std::sync::{Arc, Mutex};
+
+    use rustc_serialize::json::{Json, ToJson};
+
+    use super::{ObjectLog, PAYLOAD_PREVIEW_CHARS};
+    use ::object::{BusinessObject, Payload};
+
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.0.lock().unwrap().write(buf) }
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    fn peer() -> SocketAddr {
+        FromStr::from_str("10.0.0.1:4567").unwrap()
+    }
+
+    fn text_object(text: &str) -> BusinessObject {
+        let mut metadata = BTreeMap::new();
+        metadata.insert("natures".to_string(), vec!["hello".to_string(), "world".to_string()].to_json());
+        metadata.insert("id".to_string(), "abc".to_json());
+
+        BusinessObject {
+            _type: Some("text/plain".to_string()),
+            payload: Some(Payload::Text(text.to_string(), None)),
+            size: Some(text.len()),
+            event: Some("chat/message".to_string()),
+            metadata: metadata.into(),
+        }
+    }
+
+    #[test]
+    fn summary_should_describe_object_without_payload() {
+        let log = ObjectLog::new(Box::new(io::sink()), 1.0, false);
+        let summary = log.summary(&peer(), &text_object("secret"));
+
+        assert_eq!(Some("10.0.0.1:4567"), summary.find("peer").and_then(|j| j.as_string()));
+        assert_eq!(Some("chat/message"), summary.find("event").and_then(|j| j.as_string()));
+        assert_eq!(Some("text/plain"), summary.find("type").and_then(|j| j.as_string()));
+        assert_eq!(Some(6), summary.find("size").and_then(|j| j.as_u64()));
+        assert_eq!(Some(&vec!["hello".to_string(), "world".to_string()].to_json()), summary.find("natures"));
+        assert_eq!(Some("abc"), summary.find("id").and_then(|j| j.as_string()));
+        assert!(summary.find("time").is_some());
+        assert!(summary.find("payload").is_none());
+    }
+
+    #[test]
+    fn summary_should_truncate_logged_payloads() {
+        let log = ObjectLog::new(Box::new(io::sink()), 1.0, true);
+        let text: String = "x".repeat(PAYLOAD_PREVIEW_CHARS * 2);
+        let summary = log.summary(&peer(), &text_object(&text));
+
+        assert_eq!(Some(PAYLOAD_PREVIEW_CHARS), summary.find("payload").and_then(|j| j.as_string()).map(str::len));
+    }
+
+    #[test]
+    fn log_should_write_sampled_objects_as_json_lines() {
+        let buffer = SharedBuffer::default();
+        let log = ObjectLog::new(Box::new(buffer.clone()), 0.25, false);
+
+        for _ in 0 .. 100 {
+            log.log(&peer(), &text_object("hello"));
+        }
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(25, output.lines().count());
+
+        log.set_sampling(1.0, true);
+        log.log(&peer(), &text_object("hello"));
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(26, output.lines().count());
+        assert!(output.lines().last().unwrap().contains("\"payload\":\"hello\""));
+        for line in output.lines() {
+            assert!(Json::from_str(line).unwrap().is_object());
+        }
+    }
+}
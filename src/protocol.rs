@@ -0,0 +1,216 @@
+//! Negotiating which optional parts of the protocol a client and a router
+//! both support. A client states the `protocol-version` it speaks and the
+//! `features` it supports in its `routing/subscribe`, and the router
+//! answers with the version they share and the features both of them
+//! support. Either side then uses only those.
+//!
+//! A subscription without `protocol-version` comes from a client predating
+//! negotiation, which gets whatever it asks for like before. Feature names
+//! a side doesn't know are ignored, so that new features don't break old
+//! peers.
+
+use std::cmp;
+use std::fmt;
+use std::str::FromStr;
+
+use rustc_serialize::json::{Json, ToJson};
+
+use ::object::BusinessObject;
+
+
+/// The version of the protocol this library speaks.
+pub const PROTOCOL_VERSION: u64 = 1;
+
+pub const PROTOCOL_VERSION_KEY: &str = "protocol-version";
+pub const FEATURES_KEY: &str = "features";
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Feature {
+    /// Compressed payloads, see `compression`.
+    Compression,
+    /// CBOR headers.
+    Cbor,
+    /// Objects split in chunks, see `chunking`.
+    Chunking,
+    /// Not getting one's own objects routed back.
+    NoEcho,
+}
+
+
+const FEATURES: &[(Feature, &str)] = &[
+    (Feature::Compression, "compression"),
+    (Feature::Cbor, "cbor"),
+    (Feature::Chunking, "chunking"),
+    (Feature::NoEcho, "no-echo"),
+];
+
+
+impl Feature {
+    pub fn as_str(&self) -> &'static str {
+        FEATURES.iter().find(|&&(feature, _)| feature == *self).unwrap().1
+    }
+
+    fn bit(&self) -> u8 {
+        1 << FEATURES.iter().position(|&(feature, _)| feature == *self).unwrap()
+    }
+}
+
+
+impl FromStr for Feature {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Feature, ()> {
+        FEATURES.iter().find(|&&(_, name)| name == s).map(|&(feature, _)| feature).ok_or(())
+    }
+}
+
+
+impl fmt::Display for Feature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+
+/// A set of features.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Features {
+    bits: u8,
+}
+
+
+impl Features {
+    pub fn none() -> Features {
+        Features::default()
+    }
+
+    /// Every feature this library supports.
+    pub fn all() -> Features {
+        FEATURES.iter().map(|&(feature, _)| feature).collect()
+    }
+
+    pub fn contains(&self, feature: Feature) -> bool {
+        self.bits & feature.bit() != 0
+    }
+
+    pub fn insert(&mut self, feature: Feature) {
+        self.bits |= feature.bit();
+    }
+
+    pub fn remove(&mut self, feature: Feature) {
+        self.bits &= !feature.bit();
+    }
+
+    pub fn intersection(&self, other: Features) -> Features {
+        Features { bits: self.bits & other.bits }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Feature> + '_ {
+        FEATURES.iter().map(|&(feature, _)| feature).filter(move |&feature| self.contains(feature))
+    }
+}
+
+
+impl ::std::iter::FromIterator<Feature> for Features {
+    fn from_iter<I: IntoIterator<Item = Feature>>(features: I) -> Features {
+        let mut set = Features::none();
+        for feature in features {
+            set.insert(feature);
+        }
+        set
+    }
+}
+
+
+impl ToJson for Features {
+    fn to_json(&self) -> Json {
+        Json::Array(self.iter().map(|feature| feature.as_str().to_json()).collect())
+    }
+}
+
+
+/// The protocol version and features a client and a router agreed on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Negotiated {
+    pub version: u64,
+    pub features: Features,
+}
+
+
+impl Negotiated {
+    /// What a `routing/subscribe`, or a reply to one, states, unless it
+    /// predates negotiation.
+    pub fn from_object(object: &BusinessObject) -> Option<Negotiated> {
+        let version = object.metadata.extra.get(PROTOCOL_VERSION_KEY).and_then(|version| version.as_u64())?;
+        let features = object.meta_array_of_str(FEATURES_KEY).unwrap_or_default().iter()
+            .filter_map(|name| name.parse().ok())
+            .collect();
+        Some(Negotiated { version, features })
+    }
+
+    /// What a router supporting `supported` agrees on with the client
+    /// sending the `routing/subscribe` `request`.
+    pub fn agree(request: &BusinessObject, supported: Features) -> Option<Negotiated> {
+        Negotiated::from_object(request).map(|requested| Negotiated {
+            version: cmp::min(requested.version, PROTOCOL_VERSION),
+            features: requested.features.intersection(supported),
+        })
+    }
+
+    /// States the version and features in `object`.
+    pub fn set_on(&self, object: &mut BusinessObject) {
+        object.set_meta(PROTOCOL_VERSION_KEY, &self.version);
+        object.set_meta(FEATURES_KEY, &self.features);
+    }
+}
+
+
+/// Whether `feature` may be used with a peer that agreed on `negotiated`.
+/// Peers predating negotiation may use anything they ask for.
+pub fn allows(negotiated: Option<&Negotiated>, feature: Feature) -> bool {
+    negotiated.is_none_or(|negotiated| negotiated.features.contains(feature))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use rustc_serialize::json::Json;
+
+    use super::{allows, Feature, Features, Negotiated, FEATURES_KEY, PROTOCOL_VERSION, PROTOCOL_VERSION_KEY};
+    use ::object::BusinessObject;
+
+
+    fn subscribe(version: Option<u64>, features: &[&str]) -> BusinessObject {
+        let mut request = BusinessObject::event("routing/subscribe");
+        if let Some(version) = version {
+            request.set_meta(PROTOCOL_VERSION_KEY, &version);
+        }
+        request.set_meta(FEATURES_KEY, &features.iter().map(|name| name.to_string()).collect::<Vec<String>>());
+        request
+    }
+
+    #[test]
+    fn routers_should_agree_on_what_both_sides_support() {
+        let mut supported = Features::all();
+        supported.remove(Feature::Chunking);
+        let request = subscribe(Some(PROTOCOL_VERSION + 1), &["cbor", "chunking", "teleportation"]);
+        let agreed = Negotiated::agree(&request, supported).unwrap();
+        assert_eq!(PROTOCOL_VERSION, agreed.version);
+        assert_eq!(vec![Feature::Cbor], agreed.features.iter().collect::<Vec<Feature>>());
+
+        let mut reply = subscribe(None, &[]);
+        agreed.set_on(&mut reply);
+        assert_eq!(Some(&Json::Array(vec![Json::String("cbor".to_string())])), reply.metadata.extra.get(FEATURES_KEY));
+        assert_eq!(Some(agreed), Negotiated::from_object(&reply));
+        assert!(allows(Some(&agreed), Feature::Cbor));
+        assert!(!allows(Some(&agreed), Feature::Compression));
+    }
+
+    #[test]
+    fn clients_predating_negotiation_should_be_allowed_everything() {
+        let request = subscribe(None, &["cbor"]);
+        assert_eq!(None, Negotiated::agree(&request, Features::all()));
+        assert!(allows(None, Feature::NoEcho));
+    }
+}
@@ -0,0 +1,178 @@
+//! Token buckets limiting how fast a client may publish, in objects and in
+//! bytes per second.
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+
+/// What the router does with a client that exceeds its rate limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitPolicy {
+    /// Stop reading from the client until it is back within the limit.
+    Throttle,
+    /// Keep routing, but tell the client it is over the limit.
+    Warn,
+    Disconnect,
+}
+
+
+impl FromStr for RateLimitPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<RateLimitPolicy, ()> {
+        match s {
+            "throttle" => Ok(RateLimitPolicy::Throttle),
+            "warn" => Ok(RateLimitPolicy::Warn),
+            "disconnect" => Ok(RateLimitPolicy::Disconnect),
+            _ => Err(())
+        }
+    }
+}
+
+
+impl fmt::Display for RateLimitPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.write_str(match *self {
+            RateLimitPolicy::Throttle => "throttle",
+            RateLimitPolicy::Warn => "warn",
+            RateLimitPolicy::Disconnect => "disconnect",
+        })
+    }
+}
+
+
+/// Refills at `rate` tokens per second up to a second's worth. Taking more
+/// tokens than there are leaves the bucket in debt, which has to be paid
+/// back before it is within the limit again.
+pub struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+
+impl TokenBucket {
+    pub fn new(rate: usize, now: Instant) -> TokenBucket {
+        TokenBucket { rate: rate as f64, tokens: rate as f64, updated: now }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if now > self.updated {
+            self.tokens = (self.tokens + now.duration_since(self.updated).as_secs_f64() * self.rate).min(self.rate);
+            self.updated = now;
+        }
+    }
+
+    /// Takes `amount` tokens and returns whether they were available.
+    pub fn take(&mut self, amount: usize, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens -= amount as f64;
+        self.tokens >= 0.0
+    }
+
+    pub fn is_exceeded(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens < 0.0
+    }
+
+    /// How long until the debt, if any, has been paid back.
+    pub fn time_until_within(&mut self, now: Instant) -> Duration {
+        self.refill(now);
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_micros((-self.tokens / self.rate * 1e6).ceil() as u64)
+        }
+    }
+}
+
+
+/// The object and byte limits of one client.
+pub struct RateLimiter {
+    objects: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+}
+
+
+impl RateLimiter {
+    /// A limiter for at most `objects_per_sec` objects and `bytes_per_sec`
+    /// bytes, or `None` if neither is limited.
+    pub fn new(objects_per_sec: Option<usize>, bytes_per_sec: Option<usize>, now: Instant) -> Option<RateLimiter> {
+        if objects_per_sec.is_none() && bytes_per_sec.is_none() {
+            return None;
+        }
+
+        Some(RateLimiter {
+            objects: objects_per_sec.map(|rate| TokenBucket::new(rate, now)),
+            bytes: bytes_per_sec.map(|rate| TokenBucket::new(rate, now)),
+        })
+    }
+
+    /// Charges the client for `objects` objects taking `bytes` bytes and
+    /// returns whether it is still within the limit.
+    pub fn record(&mut self, objects: usize, bytes: usize, now: Instant) -> bool {
+        let objects_ok = self.objects.as_mut().is_none_or(|bucket| bucket.take(objects, now));
+        let bytes_ok = self.bytes.as_mut().is_none_or(|bucket| bucket.take(bytes, now));
+        objects_ok && bytes_ok
+    }
+
+    pub fn is_exceeded(&mut self, now: Instant) -> bool {
+        self.objects.as_mut().is_some_and(|bucket| bucket.is_exceeded(now)) ||
+            self.bytes.as_mut().is_some_and(|bucket| bucket.is_exceeded(now))
+    }
+
+    /// How long until the client is within the limit again.
+    pub fn time_until_within(&mut self, now: Instant) -> Duration {
+        let objects = self.objects.as_mut().map_or(Duration::ZERO, |bucket| bucket.time_until_within(now));
+        let bytes = self.bytes.as_mut().map_or(Duration::ZERO, |bucket| bucket.time_until_within(now));
+        objects.max(bytes)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{RateLimiter, TokenBucket};
+
+
+    fn clock() -> impl Fn(u64) -> Instant {
+        let start = Instant::now();
+        move |millis| start + Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn bucket_should_allow_a_seconds_worth_and_refill_over_time() {
+        let at = clock();
+        let mut bucket = TokenBucket::new(10, at(0));
+        for _ in 0 .. 10 {
+            assert!(bucket.take(1, at(0)));
+        }
+        assert!(!bucket.take(1, at(0)));
+        assert_eq!(Duration::from_millis(100), bucket.time_until_within(at(0)));
+
+        assert!(!bucket.is_exceeded(at(100)));
+        assert!(bucket.take(5, at(600)));
+        assert!(!bucket.take(100, at(10_000)));
+        assert_eq!(Duration::from_secs(9), bucket.time_until_within(at(10_000)));
+    }
+
+    #[test]
+    fn limiter_should_be_exceeded_by_either_limit() {
+        let at = clock();
+        assert!(RateLimiter::new(None, None, at(0)).is_none());
+
+        let mut limiter = RateLimiter::new(Some(100), Some(1000), at(0)).unwrap();
+        assert!(limiter.record(1, 500, at(0)));
+        assert!(!limiter.record(1, 1000, at(0)));
+        assert!(limiter.is_exceeded(at(0)));
+        assert_eq!(Duration::from_millis(500), limiter.time_until_within(at(0)));
+        assert!(!limiter.is_exceeded(at(500)));
+
+        let mut limiter = RateLimiter::new(Some(2), None, at(0)).unwrap();
+        assert!(!limiter.record(3, 1_000_000, at(0)));
+        assert_eq!(Duration::from_millis(500), limiter.time_until_within(at(0)));
+    }
+}
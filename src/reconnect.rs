@@ -0,0 +1,339 @@
+//! A client that survives losing its connection: it reconnects with
+//! exponential backoff and restores its subscription and registration, so
+//! long-running applications don't need to handle reconnecting themselves.
+
+use std::cmp;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::io;
+use std::net::ToSocketAddrs;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rustc_serialize::json::Json;
+
+use ::client::Client;
+use ::error::ClientError;
+use ::events::Event;
+use ::object::BusinessObject;
+
+
+pub const DEFAULT_INITIAL_BACKOFF_MS: u64 = 100;
+pub const DEFAULT_MAX_BACKOFF_MS: u64 = 30_000;
+pub const DEFAULT_MAX_QUEUED: usize = 1024;
+
+
+/// What `ReconnectingClient::receive` returns.
+#[derive(Debug)]
+pub enum Notification {
+    Object(Box<BusinessObject>),
+    /// The connection was lost and has been established again, with the
+    /// subscription and registration restored. Objects sent to the client
+    /// in between were missed.
+    Reconnected,
+}
+
+
+pub struct ReconnectingClient {
+    connect: Box<dyn FnMut() -> io::Result<Client> + Send>,
+    client: Option<Client>,
+    subscription: Option<Vec<String>>,
+    registration: Option<BusinessObject>,
+    keepalive: Option<Duration>,
+    // Objects sent while disconnected, flushed once reconnected
+    outgoing: VecDeque<BusinessObject>,
+    max_queued: usize,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    backoff: Duration,
+    next_attempt: Instant,
+    has_connected: bool,
+    reconnected: bool,
+}
+
+
+impl ReconnectingClient {
+    /// A client that connects by calling `connect`, e.g. to use TLS. The
+    /// first connection is made when the client is first used.
+    pub fn new<F: FnMut() -> io::Result<Client> + Send + 'static>(connect: F) -> ReconnectingClient {
+        let initial_backoff = Duration::from_millis(DEFAULT_INITIAL_BACKOFF_MS);
+        ReconnectingClient {
+            connect: Box::new(connect),
+            client: None,
+            subscription: None,
+            registration: None,
+            keepalive: None,
+            outgoing: VecDeque::new(),
+            max_queued: DEFAULT_MAX_QUEUED,
+            initial_backoff,
+            max_backoff: Duration::from_millis(DEFAULT_MAX_BACKOFF_MS),
+            backoff: initial_backoff,
+            next_attempt: Instant::now(),
+            has_connected: false,
+            reconnected: false,
+        }
+    }
+
+    /// A client connecting to `addr` over plain TCP.
+    pub fn connect<A: ToSocketAddrs + Send + 'static>(addr: A) -> ReconnectingClient {
+        ReconnectingClient::new(move || Client::connect(&addr))
+    }
+
+    /// Waits `initial` after losing the connection, doubling the wait after
+    /// each failed attempt up to `max`.
+    pub fn set_backoff(&mut self, initial: Duration, max: Duration) {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self.backoff = initial;
+    }
+
+    /// Most objects kept for sending while disconnected.
+    pub fn set_max_queued(&mut self, max_queued: usize) {
+        self.max_queued = max_queued;
+    }
+
+    /// Sets the keepalive of this and later connections, see
+    /// `Client::set_keepalive`. A router that stops answering is treated
+    /// like a lost connection.
+    pub fn set_keepalive(&mut self, interval: Option<Duration>) -> io::Result<()> {
+        self.keepalive = interval;
+        match self.client {
+            Some(ref mut client) => client.set_keepalive(interval),
+            None => Ok(())
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.client.is_some()
+    }
+
+    fn disconnected(&mut self, reason: &dyn fmt::Display) {
+        if self.client.take().is_some() {
+            warn!("Lost connection to router: {}", reason);
+            self.backoff = self.initial_backoff;
+            self.next_attempt = Instant::now() + self.backoff;
+        }
+    }
+
+    /// Restores the subscription and registration on a new connection and
+    /// flushes the objects queued while disconnected.
+    fn handshake(&mut self, client: &mut Client) -> Result<(), ClientError> {
+        client.set_keepalive(self.keepalive)?;
+        if let Some(ref rules) = self.subscription {
+            let rules: Vec<&str> = rules.iter().map(|rule| rule.as_ref()).collect();
+            client.subscribe(&rules)?;
+        }
+        if let Some(ref registration) = self.registration {
+            client.send(registration)?;
+        }
+        while let Some(object) = self.outgoing.pop_front() {
+            if let Err(e) = client.send(&object) {
+                self.outgoing.push_front(object);
+                return Err(e.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Connects if disconnected and the backoff has passed. Returns whether
+    /// there is a connection.
+    fn try_connect(&mut self) -> bool {
+        if self.client.is_some() {
+            return true;
+        }
+        if Instant::now() < self.next_attempt {
+            return false;
+        }
+
+        let result = (self.connect)().map_err(ClientError::from)
+            .and_then(|mut client| self.handshake(&mut client).map(|_| client));
+        match result {
+            Ok(client) => {
+                info!("Connected to router");
+                self.client = Some(client);
+                self.backoff = self.initial_backoff;
+                self.reconnected = self.has_connected;
+                self.has_connected = true;
+                true
+            },
+            Err(e) => {
+                debug!("Failed to connect to router, retrying in {:?}: {}", self.backoff, e);
+                self.next_attempt = Instant::now() + self.backoff;
+                self.backoff = cmp::min(self.backoff * 2, self.max_backoff);
+                false
+            }
+        }
+    }
+
+    /// Subscribes with `rules` now if connected, and on every reconnection.
+    pub fn subscribe(&mut self, rules: &[&str]) {
+        self.subscription = Some(rules.iter().map(|rule| rule.to_string()).collect());
+
+        if self.client.is_none() {
+            self.try_connect();
+        } else if let Err(e) = self.client.as_mut().unwrap().subscribe(rules) {
+            self.disconnected(&e);
+        }
+    }
+
+    /// Registers the client with `metadata` such as `name` and `user` now if
+    /// connected, and on every reconnection.
+    pub fn register(&mut self, metadata: BTreeMap<String, Json>) {
+        let registration = BusinessObject {
+            metadata: metadata.into(),
+            .. BusinessObject::event(Event::ClientsRegister)
+        };
+        self.registration = Some(registration.clone());
+
+        if self.client.is_none() {
+            self.try_connect();
+        } else if let Err(e) = self.client.as_mut().unwrap().send(&registration) {
+            self.disconnected(&e);
+        }
+    }
+
+    /// Sends `object`, or queues it until reconnected if disconnected. Fails
+    /// only if the queue is full.
+    pub fn send(&mut self, object: &BusinessObject) -> io::Result<()> {
+        if self.try_connect() {
+            match self.client.as_mut().unwrap().send(object) {
+                Ok(()) => { return Ok(()); },
+                Err(e) => { self.disconnected(&e); }
+            }
+        }
+
+        if self.outgoing.len() >= self.max_queued {
+            return Err(io::Error::other(format!("Send queue full ({} objects)", self.outgoing.len())));
+        }
+        self.outgoing.push_back(object.clone());
+        Ok(())
+    }
+
+    /// Blocks until the next object arrives, reconnecting as often as it
+    /// takes.
+    pub fn receive(&mut self) -> Notification {
+        loop {
+            if !self.try_connect() {
+                thread::sleep(self.next_attempt.saturating_duration_since(Instant::now()));
+                continue;
+            }
+            if self.reconnected {
+                self.reconnected = false;
+                return Notification::Reconnected;
+            }
+
+            match self.client.as_mut().unwrap().receive() {
+                Ok(object) => { return Notification::Object(Box::new(object)); },
+                Err(e) => { self.disconnected(&e); }
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::io::{Read, Write};
+    use std::io;
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    use rustc_serialize::json::ToJson;
+
+    use super::{Notification, ReconnectingClient};
+    use ::client::Client;
+    use ::io::{BusinessObjectStream, NextObject};
+    use ::object::BusinessObject;
+
+
+    fn next_event<S: Read + Write>(stream: &mut BusinessObjectStream<S>) -> BusinessObject {
+        match stream.next_object().unwrap() {
+            NextObject::Object(object) => object,
+            NextObject::Streamed(_, _) => panic!("Unexpected streamed object")
+        }
+    }
+
+    fn send<S: Read + Write>(stream: &mut BusinessObjectStream<S>, object: &BusinessObject) {
+        object.write_to(stream).unwrap();
+        stream.flush().unwrap();
+    }
+
+    // Expects a subscription and a registration, as after every connection
+    fn expect_handshake(stream: &mut BusinessObjectStream<TcpStream>) {
+        let subscribe = next_event(stream);
+        assert_eq!(Some("routing/subscribe".to_string()), subscribe.event);
+        assert_eq!(Some(&vec!["@test".to_string()].to_json()), subscribe.metadata.extra.get("subscriptions"));
+        let mut reply = BusinessObject::reply_to(&subscribe);
+        reply.event = Some("routing/subscribe/reply".to_string());
+        send(stream, &reply);
+
+        let register = next_event(stream);
+        assert_eq!(Some("clients/register".to_string()), register.event);
+        assert_eq!(Some(&"tester".to_json()), register.metadata.extra.get("name"));
+    }
+
+    #[test]
+    fn should_restore_session_and_flush_queue_on_reconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut first = BusinessObjectStream::new(listener.accept().unwrap().0);
+            expect_handshake(&mut first);
+            assert_eq!(Some("queued".to_string()), next_event(&mut first).event);
+            send(&mut first, &BusinessObject::event("hello"));
+            drop(first);
+
+            let mut second = BusinessObjectStream::new(listener.accept().unwrap().0);
+            expect_handshake(&mut second);
+            send(&mut second, &BusinessObject::event("again"));
+        });
+
+        // Refuse the first attempt so that everything is done while
+        // disconnected
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let counter = attempts.clone();
+        let mut client = ReconnectingClient::new(move || {
+            match counter.fetch_add(1, Ordering::SeqCst) {
+                0 => Err(io::Error::new(io::ErrorKind::ConnectionRefused, "refused")),
+                _ => Client::connect(addr)
+            }
+        });
+        client.set_backoff(Duration::from_millis(10), Duration::from_millis(100));
+
+        client.subscribe(&["@test"]);
+        let mut metadata = BTreeMap::new();
+        metadata.insert("name".to_string(), "tester".to_json());
+        client.register(metadata);
+        client.send(&BusinessObject::event("queued")).unwrap();
+        assert!(!client.is_connected());
+
+        match client.receive() {
+            Notification::Object(object) => assert_eq!(Some("hello".to_string()), object.event),
+            other => panic!("Expected hello, got {:?}", other)
+        }
+        match client.receive() {
+            Notification::Reconnected => {},
+            other => panic!("Expected to reconnect, got {:?}", other)
+        }
+        match client.receive() {
+            Notification::Object(object) => assert_eq!(Some("again".to_string()), object.event),
+            other => panic!("Expected again, got {:?}", other)
+        }
+        assert_eq!(3, attempts.load(Ordering::SeqCst));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn send_should_fail_when_queue_is_full() {
+        let mut client = ReconnectingClient::new(|| Err(io::Error::new(io::ErrorKind::ConnectionRefused, "refused")));
+        client.set_max_queued(2);
+
+        assert!(client.send(&BusinessObject::event("a")).is_ok());
+        assert!(client.send(&BusinessObject::event("b")).is_ok());
+        assert!(client.send(&BusinessObject::event("c")).is_err());
+    }
+}
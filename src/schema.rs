@@ -0,0 +1,621 @@
+//! JSON Schemas for the payloads of objects by their `event` or `type`.
+//! Routers check what clients publish against the schemas of their
+//! configuration, and producers can check what they are about to send
+//! against the same schemas with a `SchemaRegistry` of their own.
+//!
+//! The keywords understood are `type`, `enum`, `const`, `properties`,
+//! `required`, `additionalProperties`, `items`, `minItems`, `maxItems`,
+//! `minimum`, `maximum`, `exclusiveMinimum`, `exclusiveMaximum`,
+//! `minLength`, `maxLength`, `pattern`, `allOf` and `anyOf`. Others, like
+//! `title` or `$schema`, are ignored.
+
+use std::collections::BTreeMap;
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+#[cfg(feature = "server")] use std::sync::Arc;
+
+use regex::Regex;
+use rustc_serialize::json::Json;
+
+#[cfg(feature = "server")] use ::events::{ErrorCode, Event};
+#[cfg(feature = "server")] use ::middleware::{Context, Middleware, Verdict};
+use ::json::{as_number, equal};
+use ::object::{BusinessObject, Payload};
+use ::subscription::match_hierarchical;
+
+
+/// The metadata key listing the violations of objects let through with
+/// `schema-violations = "tag"`.
+pub const SCHEMA_VIOLATIONS_KEY: &str = "schema-violations";
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SchemaError(pub String);
+
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid schema: {}", self.0)
+    }
+}
+
+
+impl error::Error for SchemaError {}
+
+
+/// Something in a payload its schema doesn't allow, at the JSON Pointer
+/// `path` into it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Violation {
+    pub path: String,
+    pub message: String,
+}
+
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let path = if self.path.is_empty() { "/" } else { &self.path };
+        write!(f, "{}: {}", path, self.message)
+    }
+}
+
+
+#[derive(Debug, Clone)]
+enum Additional {
+    Allowed,
+    Denied,
+    Schema(Box<Schema>),
+}
+
+
+#[derive(Debug, Clone)]
+pub struct Schema {
+    /// A `false` schema, allowing nothing.
+    never: bool,
+    types: Option<Vec<String>>,
+    one_of_values: Option<Vec<Json>>,
+    properties: BTreeMap<String, Schema>,
+    required: Vec<String>,
+    additional_properties: Additional,
+    items: Option<Box<Schema>>,
+    min_items: Option<usize>,
+    max_items: Option<usize>,
+    minimum: Option<f64>,
+    maximum: Option<f64>,
+    exclusive_minimum: Option<f64>,
+    exclusive_maximum: Option<f64>,
+    min_length: Option<usize>,
+    max_length: Option<usize>,
+    pattern: Option<Regex>,
+    all_of: Vec<Schema>,
+    any_of: Vec<Schema>,
+}
+
+
+const TYPES: &[&str] = &["null", "boolean", "object", "array", "number", "integer", "string"];
+
+
+fn has_type(value: &Json, name: &str) -> bool {
+    match (name, value) {
+        ("null", &Json::Null) | ("boolean", &Json::Boolean(_)) | ("object", &Json::Object(_)) |
+        ("array", &Json::Array(_)) | ("string", &Json::String(_)) |
+        ("number", &Json::I64(_)) | ("number", &Json::U64(_)) | ("number", &Json::F64(_)) |
+        ("integer", &Json::I64(_)) | ("integer", &Json::U64(_)) => true,
+        ("integer", &Json::F64(n)) => n.fract() == 0.0,
+        _ => false
+    }
+}
+
+
+fn type_name(value: &Json) -> &'static str {
+    match *value {
+        Json::Null => "null",
+        Json::Boolean(_) => "boolean",
+        Json::Object(_) => "object",
+        Json::Array(_) => "array",
+        Json::String(_) => "string",
+        _ => "number"
+    }
+}
+
+
+fn keyword_error(keyword: &str, expected: &str) -> SchemaError {
+    SchemaError(format!("{}: expected {}", keyword, expected))
+}
+
+
+fn count(keyword: &str, value: &Json) -> Result<usize, SchemaError> {
+    value.as_u64().map(|n| n as usize).ok_or_else(|| keyword_error(keyword, "a non-negative integer"))
+}
+
+
+fn number(keyword: &str, value: &Json) -> Result<f64, SchemaError> {
+    as_number(value).ok_or_else(|| keyword_error(keyword, "a number"))
+}
+
+
+fn schemas(keyword: &str, value: &Json) -> Result<Vec<Schema>, SchemaError> {
+    match *value {
+        Json::Array(ref items) if !items.is_empty() => items.iter().map(Schema::from_json).collect(),
+        _ => Err(keyword_error(keyword, "a non-empty array of schemas"))
+    }
+}
+
+
+/// `key` escaped as a JSON Pointer reference token.
+fn pointer(path: &str, key: &str) -> String {
+    format!("{}/{}", path, key.replace('~', "~0").replace('/', "~1"))
+}
+
+
+impl Schema {
+    pub fn from_json(json: &Json) -> Result<Schema, SchemaError> {
+        let mut schema = Schema {
+            never: false,
+            types: None,
+            one_of_values: None,
+            properties: BTreeMap::new(),
+            required: Vec::new(),
+            additional_properties: Additional::Allowed,
+            items: None,
+            min_items: None,
+            max_items: None,
+            minimum: None,
+            maximum: None,
+            exclusive_minimum: None,
+            exclusive_maximum: None,
+            min_length: None,
+            max_length: None,
+            pattern: None,
+            all_of: Vec::new(),
+            any_of: Vec::new(),
+        };
+        let fields = match *json {
+            Json::Boolean(allowed) => {
+                schema.never = !allowed;
+                return Ok(schema);
+            },
+            Json::Object(ref fields) => fields,
+            _ => { return Err(SchemaError("expected an object or a boolean".to_string())); }
+        };
+
+        for (keyword, value) in fields {
+            match keyword.as_ref() {
+                "type" => {
+                    let types = match *value {
+                        Json::String(ref name) => vec![name.clone()],
+                        Json::Array(ref names) => names.iter()
+                            .map(|name| name.as_string().map(|name| name.to_string()))
+                            .collect::<Option<Vec<String>>>()
+                            .ok_or_else(|| keyword_error(keyword, "a type name or an array of them"))?,
+                        _ => { return Err(keyword_error(keyword, "a type name or an array of them")); }
+                    };
+                    if let Some(unknown) = types.iter().find(|name| !TYPES.contains(&name.as_str())) {
+                        return Err(SchemaError(format!("type: unknown type {}", unknown)));
+                    }
+                    schema.types = Some(types);
+                },
+                "enum" => {
+                    let values = value.as_array().ok_or_else(|| keyword_error(keyword, "an array"))?;
+                    schema.one_of_values = Some(values.clone());
+                },
+                "const" => { schema.one_of_values = Some(vec![value.clone()]); },
+                "properties" => {
+                    let properties = value.as_object().ok_or_else(|| keyword_error(keyword, "an object"))?;
+                    for (name, property) in properties {
+                        schema.properties.insert(name.clone(), Schema::from_json(property)?);
+                    }
+                },
+                "required" => {
+                    schema.required = value.as_array()
+                        .and_then(|names| names.iter()
+                                  .map(|name| name.as_string().map(|name| name.to_string()))
+                                  .collect())
+                        .ok_or_else(|| keyword_error(keyword, "an array of property names"))?;
+                },
+                "additionalProperties" => {
+                    schema.additional_properties = match *value {
+                        Json::Boolean(true) => Additional::Allowed,
+                        Json::Boolean(false) => Additional::Denied,
+                        _ => Additional::Schema(Box::new(Schema::from_json(value)?))
+                    };
+                },
+                "items" => { schema.items = Some(Box::new(Schema::from_json(value)?)); },
+                "minItems" => { schema.min_items = Some(count(keyword, value)?); },
+                "maxItems" => { schema.max_items = Some(count(keyword, value)?); },
+                "minimum" => { schema.minimum = Some(number(keyword, value)?); },
+                "maximum" => { schema.maximum = Some(number(keyword, value)?); },
+                "exclusiveMinimum" => { schema.exclusive_minimum = Some(number(keyword, value)?); },
+                "exclusiveMaximum" => { schema.exclusive_maximum = Some(number(keyword, value)?); },
+                "minLength" => { schema.min_length = Some(count(keyword, value)?); },
+                "maxLength" => { schema.max_length = Some(count(keyword, value)?); },
+                "pattern" => {
+                    let pattern = value.as_string().ok_or_else(|| keyword_error(keyword, "a regular expression"))?;
+                    schema.pattern = Some(Regex::new(pattern).map_err(|e| SchemaError(format!("pattern: {}", e)))?);
+                },
+                "allOf" => { schema.all_of = schemas(keyword, value)?; },
+                "anyOf" => { schema.any_of = schemas(keyword, value)?; },
+                _ => {}
+            }
+        }
+
+        Ok(schema)
+    }
+
+    /// Reads a schema from the JSON file at `path`.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Schema, SchemaError> {
+        let path = path.as_ref();
+        let mut input = String::new();
+        File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut input))
+            .map_err(|e| SchemaError(format!("{}: {}", path.display(), e)))?;
+        let json = Json::from_str(&input).map_err(|e| SchemaError(format!("{}: {}", path.display(), e)))?;
+        Schema::from_json(&json).map_err(|e| SchemaError(format!("{}: {}", path.display(), e.0)))
+    }
+
+    /// What about `value` the schema doesn't allow; nothing if it is valid.
+    pub fn validate(&self, value: &Json) -> Vec<Violation> {
+        let mut violations = Vec::new();
+        self.check(value, "", &mut violations);
+        violations
+    }
+
+    fn check(&self, value: &Json, path: &str, violations: &mut Vec<Violation>) {
+        let violation = |message: String| Violation { path: path.to_string(), message };
+
+        if self.never {
+            violations.push(violation("nothing is allowed here".to_string()));
+            return;
+        }
+        if let Some(ref types) = self.types {
+            if !types.iter().any(|name| has_type(value, name)) {
+                violations.push(violation(format!("expected {}, found {}", types.join(" or "), type_name(value))));
+                return;
+            }
+        }
+
+        violations.extend(self.check_value(value).into_iter().map(violation));
+
+        match *value {
+            Json::Array(ref items) => {
+                if let Some(ref schema) = self.items {
+                    for (i, item) in items.iter().enumerate() {
+                        schema.check(item, &pointer(path, &i.to_string()), violations);
+                    }
+                }
+            },
+            Json::Object(ref fields) => {
+                for (name, field) in fields {
+                    let schema = match (self.properties.get(name), &self.additional_properties) {
+                        (Some(schema), _) => schema,
+                        (None, Additional::Schema(schema)) => schema,
+                        (None, Additional::Denied) => {
+                            violations.push(Violation {
+                                path: pointer(path, name),
+                                message: "not an allowed property".to_string(),
+                            });
+                            continue;
+                        },
+                        (None, Additional::Allowed) => { continue; }
+                    };
+                    schema.check(field, &pointer(path, name), violations);
+                }
+            },
+            _ => {}
+        }
+
+        for schema in &self.all_of {
+            schema.check(value, path, violations);
+        }
+        if !self.any_of.is_empty() && self.any_of.iter().all(|schema| !schema.validate(value).is_empty()) {
+            violations.push(violation("matches none of anyOf".to_string()));
+        }
+    }
+
+    /// What `value` itself breaks of the keywords for its type.
+    fn check_value(&self, value: &Json) -> Vec<String> {
+        let mut messages = Vec::new();
+
+        if let Some(ref values) = self.one_of_values {
+            if !values.iter().any(|allowed| equal(allowed, value)) {
+                messages.push(format!("{} is not one of the allowed values", value));
+            }
+        }
+
+        match *value {
+            Json::String(ref string) => {
+                let length = string.chars().count();
+                if let Some(minimum) = self.min_length.filter(|&minimum| length < minimum) {
+                    messages.push(format!("shorter than {} characters", minimum));
+                }
+                if let Some(maximum) = self.max_length.filter(|&maximum| length > maximum) {
+                    messages.push(format!("longer than {} characters", maximum));
+                }
+                if let Some(ref pattern) = self.pattern {
+                    if !pattern.is_match(string) {
+                        messages.push(format!("doesn't match {}", pattern.as_str()));
+                    }
+                }
+            },
+            Json::Array(ref items) => {
+                if let Some(minimum) = self.min_items.filter(|&minimum| items.len() < minimum) {
+                    messages.push(format!("fewer than {} items", minimum));
+                }
+                if let Some(maximum) = self.max_items.filter(|&maximum| items.len() > maximum) {
+                    messages.push(format!("more than {} items", maximum));
+                }
+            },
+            Json::Object(ref fields) => {
+                for name in self.required.iter().filter(|name| !fields.contains_key(*name)) {
+                    messages.push(format!("{} is required", name));
+                }
+            },
+            _ => {
+                if let Some(n) = as_number(value) {
+                    if let Some(minimum) = self.minimum.filter(|&minimum| n < minimum) {
+                        messages.push(format!("{} is less than the minimum {}", n, minimum));
+                    }
+                    if let Some(maximum) = self.maximum.filter(|&maximum| n > maximum) {
+                        messages.push(format!("{} is greater than the maximum {}", n, maximum));
+                    }
+                    if let Some(minimum) = self.exclusive_minimum.filter(|&minimum| n <= minimum) {
+                        messages.push(format!("{} is not greater than {}", n, minimum));
+                    }
+                    if let Some(maximum) = self.exclusive_maximum.filter(|&maximum| n >= maximum) {
+                        messages.push(format!("{} is not less than {}", n, maximum));
+                    }
+                }
+            }
+        }
+
+        messages
+    }
+}
+
+
+/// A schema file and the objects it applies to, from an `[[schema]]`
+/// table of the configuration.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SchemaRule {
+    /// Pattern the `event` of the objects has to match, like in
+    /// subscriptions, if any.
+    pub event: Option<String>,
+    /// Pattern the `type` of the objects has to match, parameters aside,
+    /// if any.
+    pub _type: Option<String>,
+    pub file: PathBuf,
+}
+
+
+/// What the router does with objects whose payload violates its schema.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchemaPolicy {
+    /// Answer with a `routing/error` instead of routing the object.
+    Reject,
+    /// Route the object with its violations listed in `schema-violations`.
+    Tag,
+}
+
+
+impl FromStr for SchemaPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<SchemaPolicy, ()> {
+        match s {
+            "reject" => Ok(SchemaPolicy::Reject),
+            "tag" => Ok(SchemaPolicy::Tag),
+            _ => Err(())
+        }
+    }
+}
+
+
+struct Registered {
+    event: Option<String>,
+    _type: Option<String>,
+    schema: Schema,
+}
+
+
+impl Registered {
+    fn applies_to(&self, object: &BusinessObject) -> bool {
+        let event_matches = match (&self.event, &object.event) {
+            (Some(pattern), Some(event)) => match_hierarchical(pattern, event),
+            (Some(_), None) => false,
+            (None, _) => true,
+        };
+        let type_matches = match (&self._type, object.content_type()) {
+            (Some(pattern), Some(content_type)) => match_hierarchical(pattern, &content_type.essence()),
+            (Some(_), None) => false,
+            (None, _) => true,
+        };
+        event_matches && type_matches
+    }
+}
+
+
+/// The payload of `object` as JSON, whatever form it came in.
+fn payload_json(object: &BusinessObject) -> Result<Json, String> {
+    let text = match object.payload {
+        Some(Payload::Json(ref json, _)) => { return Ok(json.clone()); },
+        Some(Payload::Text(ref text, _)) => text.as_str(),
+        Some(Payload::Bytes(ref bytes)) => std::str::from_utf8(bytes).map_err(|_| "payload isn't JSON".to_string())?,
+        None => { return Err("payload is missing".to_string()); }
+    };
+    Json::from_str(text).map_err(|_| "payload isn't JSON".to_string())
+}
+
+
+/// Schemas by the objects they apply to. An object has to satisfy every
+/// schema whose patterns it matches, and objects matching none are valid.
+#[derive(Default)]
+pub struct SchemaRegistry {
+    schemas: Vec<Registered>,
+}
+
+
+impl SchemaRegistry {
+    pub fn new() -> SchemaRegistry {
+        SchemaRegistry::default()
+    }
+
+    /// The schemas of `rules`, read from their files.
+    pub fn from_rules(rules: &[SchemaRule]) -> Result<SchemaRegistry, SchemaError> {
+        let mut registry = SchemaRegistry::new();
+        for rule in rules {
+            registry.register(rule.event.as_deref(), rule._type.as_deref(), Schema::from_file(&rule.file)?);
+        }
+        Ok(registry)
+    }
+
+    /// Has `schema` apply to the objects whose event matches `event` and
+    /// whose type matches `_type`, either of which may be left out.
+    pub fn register(&mut self, event: Option<&str>, _type: Option<&str>, schema: Schema) {
+        self.schemas.push(Registered {
+            event: event.map(|event| event.to_string()),
+            _type: _type.map(|_type| _type.to_string()),
+            schema,
+        });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.schemas.is_empty()
+    }
+
+    /// What about the payload of `object` the schemas applying to it don't
+    /// allow; nothing if it is valid.
+    pub fn validate(&self, object: &BusinessObject) -> Vec<Violation> {
+        let mut applying = self.schemas.iter().filter(|registered| registered.applies_to(object)).peekable();
+        if applying.peek().is_none() {
+            return Vec::new();
+        }
+
+        let payload = match payload_json(object) {
+            Ok(payload) => payload,
+            Err(message) => { return vec![Violation { path: String::new(), message }]; }
+        };
+        applying.flat_map(|registered| registered.schema.validate(&payload)).collect()
+    }
+}
+
+
+/// Checks the payloads clients publish against the schemas of `schema`,
+/// dealing with violations as `schema-violations` says. Objects from other
+/// routers are theirs to check.
+#[cfg(feature = "server")]
+pub struct SchemaValidation {
+    registry: SchemaRegistry,
+}
+
+
+#[cfg(feature = "server")]
+impl SchemaValidation {
+    pub fn new(registry: SchemaRegistry) -> SchemaValidation {
+        SchemaValidation { registry }
+    }
+}
+
+
+#[cfg(feature = "server")]
+impl Middleware for SchemaValidation {
+    fn inbound(&self, context: &Context, object: Arc<BusinessObject>) -> Verdict {
+        if context.peer.is_router {
+            return Verdict::Pass(object);
+        }
+
+        let violations = self.registry.validate(&object);
+        if violations.is_empty() {
+            return Verdict::Pass(object);
+        }
+        debug!("Object from {} violates its schema: {:?}", context.peer.routing_id, violations);
+        let violations: Vec<String> = violations.iter().map(|violation| violation.to_string()).collect();
+        match context.config.schema_violations {
+            SchemaPolicy::Reject => {
+                let mut reply = BusinessObject::error_reply(&object, Event::RoutingError, ErrorCode::SchemaViolation,
+                                                            "Payload violates its schema");
+                reply.set_meta("errors", &violations);
+                Verdict::Reject(Arc::new(reply))
+            },
+            SchemaPolicy::Tag => Verdict::Pass(Arc::new(object.with_meta(SCHEMA_VIOLATIONS_KEY, &violations))),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use rustc_serialize::json::Json;
+
+    use super::{Schema, SchemaRegistry};
+    use ::object::{BusinessObject, Payload};
+
+
+    fn schema(json: &str) -> Schema {
+        Schema::from_json(&Json::from_str(json).unwrap()).unwrap()
+    }
+
+    fn violations(schema: &Schema, json: &str) -> Vec<String> {
+        schema.validate(&Json::from_str(json).unwrap()).iter().map(|violation| violation.to_string()).collect()
+    }
+
+    fn object(event: &str, _type: &str, payload: &str) -> BusinessObject {
+        BusinessObject {
+            _type: Some(_type.to_string()),
+            payload: Some(Payload::Text(payload.to_string(), None)),
+            size: Some(payload.len()),
+            event: Some(event.to_string()),
+            metadata: Default::default(),
+        }
+    }
+
+    #[test]
+    fn schemas_should_list_what_they_dont_allow() {
+        let reading = schema(r#"{
+            "$schema": "https://json-schema.org/draft/2020-12/schema",
+            "type": "object",
+            "required": ["sensor", "celsius"],
+            "additionalProperties": false,
+            "properties": {
+                "sensor": {"type": "string", "pattern": "^[a-z]+-[0-9]+$", "maxLength": 12},
+                "celsius": {"type": "number", "minimum": -273.15},
+                "unit": {"enum": ["C", "F"]},
+                "tags": {"type": "array", "maxItems": 2, "items": {"type": "string", "minLength": 1}},
+                "count": {"anyOf": [{"type": "integer", "exclusiveMinimum": 0}, {"type": "null"}]}
+            }
+        }"#);
+
+        assert!(violations(&reading, r#"{"sensor": "attic-1", "celsius": 21.5, "count": null}"#).is_empty());
+        assert_eq!(vec!["/: celsius is required", "/sensor: doesn't match ^[a-z]+-[0-9]+$"],
+                   violations(&reading, r#"{"sensor": "Attic"}"#));
+        assert_eq!(vec!["/celsius: -300 is less than the minimum -273.15", "/extra: not an allowed property",
+                        "/tags: more than 2 items", "/tags/1: shorter than 1 characters",
+                        "/tags/2: expected string, found number", "/unit: \"K\" is not one of the allowed values"],
+                   violations(&reading, r#"{"sensor": "a-1", "celsius": -300, "unit": "K", "extra": 1,
+                                            "tags": ["a", "", 3]}"#));
+        assert_eq!(vec!["/count: matches none of anyOf"],
+                   violations(&reading, r#"{"sensor": "a-1", "celsius": 0, "count": 0.5}"#));
+        assert_eq!(vec!["/: expected object, found array"], violations(&reading, "[]"));
+
+        for invalid in &[r#"{"type": "text"}"#, r#"{"minimum": "0"}"#, r#"{"pattern": "("}"#, "[]"] {
+            assert!(Schema::from_json(&Json::from_str(invalid).unwrap()).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn registries_should_check_objects_against_the_schemas_they_match() {
+        let mut registry = SchemaRegistry::new();
+        registry.register(Some("sensors/*"), None, schema(r#"{"type": "object", "required": ["celsius"]}"#));
+        registry.register(None, Some("application/json"), schema(r#"{"type": ["object", "array"]}"#));
+
+        assert!(registry.validate(&object("sensors/reading", "application/json", r#"{"celsius": 1}"#)).is_empty());
+        assert_eq!(1, registry.validate(&object("sensors/reading", "text/plain", "{}")).len());
+        assert_eq!(2, registry.validate(&object("sensors/reading", "application/json; charset=utf-8", "1")).len());
+        assert_eq!("/: payload isn't JSON",
+                   registry.validate(&object("sensors/reading", "text/plain", "hot")).remove(0).to_string());
+        assert!(registry.validate(&object("chat/message", "text/plain", "hot")).is_empty());
+    }
+}
@@ -0,0 +1,99 @@
+//! The objects waiting to be sent to a client, kept in a bucket per
+//! priority so that control traffic isn't stuck behind bulk transfers.
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Instant;
+
+use ::object::{BusinessObject, Priority};
+
+
+const PRIORITIES: usize = 3;
+
+
+fn bucket(priority: Priority) -> usize {
+    match priority {
+        Priority::High => 0,
+        Priority::Normal => 1,
+        Priority::Low => 2,
+    }
+}
+
+
+/// Objects with when they were queued. They come out highest priority
+/// first, and in the order they were queued within a priority.
+#[derive(Default)]
+pub struct SendQueue {
+    buckets: [VecDeque<(Arc<BusinessObject>, Instant)>; PRIORITIES],
+}
+
+
+impl SendQueue {
+    pub fn new() -> SendQueue {
+        SendQueue::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(VecDeque::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buckets.iter().all(VecDeque::is_empty)
+    }
+
+    /// Queues `object` at its own priority.
+    pub fn push(&mut self, object: Arc<BusinessObject>, now: Instant) {
+        let priority = object.priority();
+        self.buckets[bucket(priority)].push_back((object, now));
+    }
+
+    pub fn pop(&mut self) -> Option<(Arc<BusinessObject>, Instant)> {
+        self.buckets.iter_mut().find_map(VecDeque::pop_front)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+
+    use std::time::Instant;
+
+    use rustc_serialize::json::ToJson;
+
+    use super::SendQueue;
+    use ::object::BusinessObject;
+
+
+    fn object(event: &str, priority: Option<&str>) -> Arc<BusinessObject> {
+        let mut metadata = BTreeMap::new();
+        if let Some(priority) = priority {
+            metadata.insert("priority".to_string(), priority.to_json());
+        }
+        Arc::new(BusinessObject { metadata: metadata.into(), .. BusinessObject::event(event) })
+    }
+
+    #[test]
+    fn should_send_higher_priorities_first() {
+        let mut queue = SendQueue::new();
+        let now = Instant::now();
+        queue.push(object("bulk/1", Some("low")), now);
+        queue.push(object("chat/1", None), now);
+        queue.push(object("bulk/2", Some("low")), now);
+        queue.push(object("ping", None), now);
+        queue.push(object("chat/2", Some("bogus")), now);
+        queue.push(object("history/replay/reply", None), now);
+        queue.push(object("routing/error", None), now);
+        queue.push(object("alert", Some("high")), now);
+        assert_eq!(8, queue.len());
+
+        let mut events = Vec::new();
+        while let Some((object, _)) = queue.pop() {
+            events.push(object.event.clone().unwrap());
+        }
+        assert_eq!(vec!["ping", "routing/error", "alert", "chat/1", "chat/2", "history/replay/reply",
+                        "bulk/1", "bulk/2"], events);
+        assert!(queue.is_empty());
+    }
+}
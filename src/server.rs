@@ -0,0 +1,3115 @@
+//! The router: workers routing objects between the clients connected to
+//! them, each on an event loop of its own. `rabboe` is argument parsing
+//! around a `Server`, and tests can start one with `ServerBuilder` on
+//! whatever addresses they like.
+
+use std::cmp::Ordering;
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io::{Read, Write, Error, ErrorKind};
+use std::io;
+use std::mem;
+use std::net::SocketAddr;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rustc_serialize::json::{Json, ToJson};
+
+use libc;
+use mio::*;
+use mio::tcp::*;
+use mio::util::Slab;
+use rustls;
+use uuid::Uuid;
+
+use ::acl::Permissions;
+#[cfg(any(feature = "rusqlite", test))] use ::archive::{Archive, Archiver};
+use ::clock::{self, Clock};
+use ::compression::{Compression, COMPRESSION_KEY};
+use ::config::{Config, Upstream};
+use ::error::{ProtocolError, ReadBusinessObjectError, RoutingError};
+use ::events::{ErrorCode, Event};
+use ::filter::Filter;
+use ::groups::ConsumerGroups;
+use ::history::History;
+use ::io::*;
+use ::journal::Journal;
+use ::metadata;
+use ::metrics;
+use ::metrics::{ConnectionStats, Metrics};
+use ::middleware::{self, Chain, Middleware, Verdict};
+use ::object::BusinessObject;
+use ::object_log::ObjectLog;
+use ::protocol::{self, Feature, Features, Negotiated};
+use ::rate_limit::{RateLimiter, RateLimitPolicy};
+use ::schema::{SchemaRegistry, SchemaValidation};
+use ::send_queue::SendQueue;
+use ::storage::Storage;
+use ::subscription;
+use ::timestamp::Timestamp;
+use ::tls;
+use ::subscription::{BusinessSubscription, BusinessSubscriptionError, RoutingKey, SubscriptionMatcher};
+use ::websocket::WebSocketStream;
+
+
+const PERIODICAL_INTERVAL_MS: u64 = 1000;
+// Objects that may be waiting in a worker's channel
+const NOTIFY_CAPACITY: usize = 64 * 1024;
+// Seconds between attempts to connect to an upstream router
+const UPSTREAM_RETRY_INTERVAL: u64 = 5;
+// Connections a full worker may hold at once while telling them it is full
+const REFUSAL_SLOTS: usize = 16;
+/// Seconds an `admin/drain` waits for clients to leave if it doesn't say.
+pub const DEFAULT_DRAIN_TIMEOUT: u64 = 300;
+/// The size of the payload left out of an object delivered without it.
+pub const PAYLOAD_SIZE_KEY: &str = "payload-size";
+/// What to ask for the payload left out of an object with `payload/fetch`.
+pub const PAYLOAD_TICKET_KEY: &str = "payload-ticket";
+
+
+/// The rules of a `routing/subscribe`, or `default` if it gives none.
+fn parse_subscription(obj: &BusinessObject,
+                      default: Option<&BusinessSubscription>) -> Result<BusinessSubscription, BusinessSubscriptionError> {
+    // trace!("Parsing subscription: {:?}", &obj.to_json());
+    match obj.event {
+        Some(_) => {
+            if obj.is_event(Event::RoutingSubscribe) {
+                match obj.metadata.extra.get("subscriptions") {
+                    Some(subscriptions) => {
+                        match subscription::parse_subscription(subscriptions) {
+                            Ok(subs) => Ok(subs),
+                            Err(e) => Err(e)
+                        }
+                    },
+                    None => default.cloned().ok_or(BusinessSubscriptionError::NoSubscriptionMetadataKey)
+                }
+            } else {
+                Err(BusinessSubscriptionError::UnknownSubscriptionEvent)
+            }
+        },
+        None => Err(BusinessSubscriptionError::SubscriptionNotEvent)
+    }
+}
+
+
+/// The protocol version and features we agree on with a subscribing
+/// client, unless it predates negotiation.
+fn negotiated(subscription: &BusinessObject) -> Option<Negotiated> {
+    Negotiated::agree(subscription, Features::all())
+}
+
+
+/// Whether the negotiation of a subscription, if any, allows `feature`.
+fn allows(subscription: &BusinessObject, feature: Feature) -> bool {
+    protocol::allows(negotiated(subscription).as_ref(), feature)
+}
+
+
+/// Whether a subscription asks for the client's own objects not to be
+/// routed back to it.
+fn wants_no_echo(subscription: &BusinessObject) -> bool {
+    subscription.metadata.extra.get("no-echo").and_then(|no_echo| no_echo.as_boolean()).unwrap_or(false)
+        && allows(subscription, Feature::NoEcho)
+}
+
+
+/// The shared subscription group a client subscribes under, if any. Other
+/// routers don't get to join one.
+fn requested_group(subscription: &BusinessObject) -> Option<&str> {
+    if subscription.metadata.extra.contains_key("router-id") {
+        return None;
+    }
+    subscription.meta_str("group").filter(|group| !group.is_empty())
+}
+
+
+/// The channels a subscription declares, for objects published to them
+/// alone to reach the client. Other routers get every channel.
+fn requested_channels(subscription: &BusinessObject) -> Option<Vec<String>> {
+    if subscription.metadata.extra.contains_key("router-id") {
+        return None;
+    }
+    subscription.meta_array_of_str("channels")
+        .map(|channels| channels.iter().map(|channel| channel.to_string()).collect::<Vec<String>>())
+        .filter(|channels| !channels.is_empty())
+}
+
+
+/// What a subscription asks to have left out of the objects delivered to
+/// it: the metadata keys listed in `strip` and, with `payload` false,
+/// payloads. Objects delivered without theirs carry its `payload-size` and,
+/// if they have an id, a `payload-ticket` to get it with `payload/fetch`
+/// while they are in the history.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Transform {
+    strip: Vec<String>,
+    omit_payload: bool,
+}
+
+
+impl Transform {
+    /// The transform a subscription asks for. Other routers get objects whole.
+    fn requested(subscription: &BusinessObject) -> Transform {
+        if subscription.metadata.extra.contains_key("router-id") {
+            return Transform::default();
+        }
+        Transform {
+            strip: subscription.meta_array_of_str("strip").unwrap_or_default()
+                .iter().map(|key| key.to_string()).collect(),
+            omit_payload: subscription.metadata.extra.get("payload").and_then(|payload| payload.as_boolean())
+                == Some(false),
+        }
+    }
+
+    /// `object` as it is to be delivered. One with anything left out is a
+    /// copy, so that other subscribers still get the shared one whole.
+    fn apply(&self, object: Arc<BusinessObject>) -> Arc<BusinessObject> {
+        let strips = self.strip.iter().any(|key| object.metadata.get(key).is_some());
+        let omits = self.omit_payload && object.payload.is_some();
+        if !strips && !omits {
+            return object;
+        }
+
+        let mut object = (*object).clone();
+        for key in &self.strip {
+            object.metadata.remove(key);
+        }
+        if omits {
+            if let Some(size) = object.size {
+                object.set_meta(PAYLOAD_SIZE_KEY, &size);
+            }
+            if let Some(id) = object.id().map(|id| id.to_string()) {
+                object.set_meta(PAYLOAD_TICKET_KEY, &id);
+            }
+            object.payload = None;
+            object.size = None;
+            // Nothing left to check it against
+            object.metadata.remove(metadata::SHA1);
+        }
+        Arc::new(object)
+    }
+}
+
+
+/// Whether `object` is on a channel `client` is on. Other routers are on
+/// all of them.
+fn is_on_channel(client: &BusinessClient, object: &BusinessObject) -> bool {
+    client.peer_router || metadata::is_on_channel(object.metadata.channel.as_deref(), client.channels.as_deref())
+}
+
+
+/// The payload compression a subscription asks for, if it is one we
+/// support. Anything else gets plain framing.
+fn requested_compression(subscription: &BusinessObject) -> Option<Compression> {
+    subscription.meta_str(COMPRESSION_KEY).and_then(|name| name.parse().ok())
+        .filter(|_| allows(subscription, Feature::Compression))
+}
+
+
+/// The header encoding a subscription asks for, if it is one we support.
+fn requested_encoding(subscription: &BusinessObject) -> Option<&'static dyn Encoding> {
+    subscription.meta_str(ENCODING_KEY).and_then(encoding)
+        .filter(|encoding| encoding.name() != CBOR.name() || allows(subscription, Feature::Cbor))
+}
+
+
+fn subscription_reply(subscriptions: &BusinessSubscription, request: &BusinessObject,
+                      routing_id: &str) -> Arc<BusinessObject> {
+    let mut reply = BusinessObject::reply_to(request);
+    reply.event = Some(Event::RoutingSubscribeReply.to_string());
+    reply.set_meta("subscriptions", subscriptions);
+    reply.set_meta("routing-id", routing_id);
+    if let Some(negotiated) = negotiated(request) {
+        negotiated.set_on(&mut reply);
+    }
+    if wants_no_echo(request) {
+        reply.set_meta("no-echo", &true);
+    }
+    if let Some(group) = requested_group(request) {
+        reply.set_meta("group", group);
+    }
+    if let Some(channels) = requested_channels(request) {
+        reply.set_meta("channels", &channels);
+    }
+    let transform = Transform::requested(request);
+    if !transform.strip.is_empty() {
+        reply.set_meta("strip", &transform.strip);
+    }
+    if transform.omit_payload {
+        reply.set_meta("payload", &false);
+    }
+    if let Some(compression) = requested_compression(request) {
+        reply.set_meta(COMPRESSION_KEY, &compression.to_string());
+    }
+    if let Some(encoding) = requested_encoding(request) {
+        reply.set_meta(ENCODING_KEY, encoding.name());
+    }
+
+    Arc::new(reply)
+}
+
+
+/// Subscribes to the objects an upstream router should send us. The
+/// `router-id` tells the upstream that we are a router too.
+fn upstream_subscription(subscription: &BusinessSubscription, router_id: &str) -> Arc<BusinessObject> {
+    let mut metadata = BTreeMap::new();
+    metadata.insert("subscriptions".to_string(), subscription.to_json());
+    metadata.insert("router-id".to_string(), router_id.to_json());
+
+    Arc::new(BusinessObject {
+        metadata: metadata.into(),
+        .. BusinessObject::event(Event::RoutingSubscribe)
+    }.with_new_id())
+}
+
+
+/// Whether `object` has been forwarded through the router `router_id`
+/// according to its `route` metadata.
+fn has_visited(object: &BusinessObject, router_id: &str) -> bool {
+    object.metadata.route.as_ref().is_some_and(|route| route.iter().any(|id| id == router_id))
+}
+
+
+/// Whether `client` is one of the `recipients` named by routing id or
+/// registered name.
+fn is_named(client: &BusinessClient, recipients: &[String]) -> bool {
+    recipients.iter().any(|recipient| *recipient == client.routing_id || client.name.as_ref() == Some(recipient))
+}
+
+
+/// Whether `client` is one of the `recipients`. Other routers may have the
+/// recipients as their clients, so they count as recipients too.
+fn is_recipient(client: &BusinessClient, recipients: &[String]) -> bool {
+    client.peer_router || is_named(client, recipients)
+}
+
+
+/// Tells a client why its rules were rejected. Its previous subscription,
+/// if any, stays in effect.
+fn subscription_error_reply(request: &BusinessObject, error: &BusinessSubscriptionError) -> Arc<BusinessObject> {
+    Arc::new(BusinessObject::error_reply(request, Event::RoutingSubscribeReply, ErrorCode::InvalidSubscription,
+                                         &error.to_string()))
+}
+
+
+fn may_publish(client: &BusinessClient, object: &BusinessObject) -> bool {
+    client.permissions.as_ref().is_none_or(|permissions| permissions.may_publish(&RoutingKey::of(object)))
+}
+
+
+fn may_subscribe(client: &BusinessClient) -> bool {
+    client.permissions.as_ref().is_none_or(|permissions| permissions.may_subscribe())
+}
+
+
+/// `subscription` without its `router-id` unless `client` logged in as one
+/// of the `peer_routers`, so that anyone else subscribes like any client.
+fn without_unauthorized_router_id(peer_routers: &[String], client: &BusinessClient,
+                                  subscription: Arc<BusinessObject>) -> Arc<BusinessObject> {
+    if !subscription.metadata.extra.contains_key("router-id") ||
+        client.identity.as_ref().is_some_and(|identity| peer_routers.contains(identity)) {
+        return subscription;
+    }
+
+    warn!("Ignoring router-id from {:?}, which hasn't logged in as a peer router", client.token);
+    let mut subscription = (*subscription).clone();
+    subscription.metadata.extra.remove("router-id");
+    Arc::new(subscription)
+}
+
+
+/// Compares tokens in time independent of where they differ.
+fn tokens_match(expected: &str, given: &str) -> bool {
+    expected.len() == given.len() &&
+        expected.bytes().zip(given.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+
+fn auth_login(token: &str) -> Arc<BusinessObject> {
+    let mut login = BusinessObject::event(Event::AuthLogin).with_new_id();
+    login.set_meta("token", token);
+
+    Arc::new(login)
+}
+
+
+fn auth_login_reply(request: &BusinessObject, error: Option<&str>) -> Arc<BusinessObject> {
+    let reply = match error {
+        Some(error) => BusinessObject::error_reply(request, Event::AuthLoginReply, ErrorCode::InvalidToken, error),
+        None => BusinessObject { event: Some(Event::AuthLoginReply.to_string()), .. BusinessObject::reply_to(request) }
+    };
+
+    Arc::new(reply)
+}
+
+
+/// Tells a client the access control rules don't allow what it asked for.
+fn access_denied_reply(request: &BusinessObject, error: &str) -> Arc<BusinessObject> {
+    Arc::new(BusinessObject::error_reply(request, Event::RoutingDenied, ErrorCode::AccessDenied, error))
+}
+
+
+/// Tells a client it is publishing faster than the router allows.
+fn rate_limit_warning(config: &Config) -> Arc<BusinessObject> {
+    let mut metadata = BTreeMap::new();
+    metadata.insert("reason".to_string(), "Rate limit exceeded".to_json());
+    if let Some(objects) = config.rate_limit_objects {
+        metadata.insert("objects-per-second".to_string(), objects.to_json());
+    }
+    if let Some(bytes) = config.rate_limit_bytes {
+        metadata.insert("bytes-per-second".to_string(), bytes.to_json());
+    }
+
+    Arc::new(BusinessObject { metadata: metadata.into(), .. BusinessObject::event(Event::RoutingRateLimit) })
+}
+
+
+fn history_replay_reply(request: &BusinessObject, count: usize, truncated: bool,
+                        error: Option<&str>) -> Arc<BusinessObject> {
+    let mut reply = BusinessObject::reply_to(request);
+    reply.event = Some(Event::HistoryReplayReply.to_string());
+    reply.set_meta("count", &count);
+    if truncated {
+        reply.set_meta("truncated", &true);
+    }
+    if let Some(error) = error {
+        reply.set_meta("error", error);
+    }
+
+    Arc::new(reply)
+}
+
+
+/// The reply to a `payload/fetch`, with the payload of `object`, or an
+/// error if it is no longer around.
+fn payload_fetch_reply(request: &BusinessObject, object: Option<&BusinessObject>) -> Arc<BusinessObject> {
+    let mut reply = BusinessObject::reply_to(request);
+    reply.event = Some(Event::PayloadFetchReply.to_string());
+    match object {
+        Some(object) => {
+            reply._type = object._type.clone();
+            reply.payload = object.payload.clone();
+            reply.size = object.size;
+            reply.metadata.sha1 = object.metadata.sha1.clone();
+        },
+        None => { reply.set_meta("error", "No such payload in the history"); }
+    }
+
+    Arc::new(reply)
+}
+
+
+fn idle_ping() -> Arc<BusinessObject> {
+    Arc::new(BusinessObject::event(Event::Ping).with_new_id())
+}
+
+
+fn shutdown_notification() -> Arc<BusinessObject> {
+    let mut metadata = BTreeMap::new();
+    metadata.insert("reason".to_string(), "Router shutting down".to_json());
+
+    Arc::new(BusinessObject {
+        metadata: metadata.into(),
+        .. BusinessObject::event(Event::RoutingDisconnect)
+    }.with_new_id())
+}
+
+
+/// What clients connecting to a router that won't take them are told
+/// before their connection is closed.
+fn refusal(code: ErrorCode, error: &str) -> Arc<BusinessObject> {
+    Arc::new(BusinessObject::error(Event::RoutingError, code, error).with_new_id())
+}
+
+
+fn service_reply(request_id: Option<&str>, name: Option<&str>, error: Option<&str>) -> Arc<BusinessObject> {
+    let mut metadata = BTreeMap::new();
+    if let Some(id) = request_id {
+        metadata.insert("in-reply-to".to_string(), id.to_json());
+    }
+    if let Some(name) = name {
+        metadata.insert("name".to_string(), name.to_json());
+    }
+    if let Some(error) = error {
+        metadata.insert("error".to_string(), error.to_json());
+    }
+
+    Arc::new(BusinessObject { metadata: metadata.into(), .. BusinessObject::event(Event::ServicesReply) })
+}
+
+
+/// What other clients get to know about a client: its routing id, address
+/// and whatever it has told about itself with `clients/register`.
+fn client_metadata(client: &BusinessClient) -> BTreeMap<String, Json> {
+    let mut metadata = BTreeMap::new();
+    metadata.insert("routing-id".to_string(), client.routing_id.to_json());
+    metadata.insert("peer".to_string(), client.peer_addr.to_string().to_json());
+    if let Some(ref name) = client.name {
+        metadata.insert("name".to_string(), name.to_json());
+    }
+    if let Some(ref user) = client.user {
+        metadata.insert("user".to_string(), user.to_json());
+    }
+    if let Some(ref identity) = client.identity {
+        metadata.insert("identity".to_string(), identity.to_json());
+    }
+    if let Some(ref group) = client.group {
+        metadata.insert("group".to_string(), group.to_json());
+    }
+
+    metadata
+}
+
+
+fn announcement(event: Event, client: &BusinessClient) -> Arc<BusinessObject> {
+    Arc::new(BusinessObject { metadata: client_metadata(client).into(), .. BusinessObject::event(event) })
+}
+
+
+/// A client's entry in `clients/list`. Its statistics change too often to
+/// keep here, and are added as it is listed.
+fn client_info(client: &BusinessClient) -> Json {
+    let mut metadata = client_metadata(client);
+    if let Some(ref subscription) = client.subscription {
+        metadata.insert("subscriptions".to_string(), subscription.to_json());
+    }
+
+    Json::Object(metadata)
+}
+
+
+/// Adds the current statistics of the client with `info` to it.
+fn with_stats(info: &Json, metrics: &Metrics) -> Json {
+    let mut info = info.clone();
+    let stats = info.find("routing-id").and_then(|routing_id| routing_id.as_string())
+        .and_then(|routing_id| metrics.connection_json(routing_id));
+    if let (Json::Object(ref mut fields), Some(stats)) = (&mut info, stats) {
+        fields.insert("stats".to_string(), stats);
+    }
+    info
+}
+
+
+fn client_list_reply(request: &BusinessObject, clients: &BTreeMap<ClientId, Json>,
+                     metrics: &Metrics) -> Arc<BusinessObject> {
+    let list: Vec<Json> = clients.values().map(|info| with_stats(info, metrics)).collect();
+
+    let mut reply = BusinessObject::reply_to(request);
+    reply.event = Some(Event::ClientsListReply.to_string());
+    reply.set_meta("clients", &Json::Array(list));
+
+    Arc::new(reply)
+}
+
+
+/// `admin/clients` reports, unlike `clients/list`, the worker and token
+/// each client can be disconnected by.
+fn admin_clients_list(clients: &BTreeMap<ClientId, Json>, metrics: &Metrics) -> Json {
+    Json::Array(clients.iter().map(|(id, info)| {
+        let mut info = with_stats(info, metrics);
+        if let Json::Object(ref mut fields) = info {
+            fields.insert("worker".to_string(), id.worker.to_json());
+            fields.insert("token".to_string(), id.token.as_usize().to_json());
+        }
+        info
+    }).collect())
+}
+
+
+fn service_name(object: &BusinessObject) -> Option<&str> {
+    object.meta_str("name")
+}
+
+
+/// Wraps an object no client got. Its metadata goes in `original`, and its
+/// payload is carried as is.
+fn dead_letter(object: &BusinessObject, reason: &str) -> Arc<BusinessObject> {
+    let mut letter = BusinessObject {
+        _type: object._type.clone(),
+        payload: object.payload.clone(),
+        size: object.size,
+        .. BusinessObject::event(Event::RoutingDeadLetter)
+    };
+    letter.set_meta("original", &object.to_json());
+    letter.set_meta("reason", reason);
+    letter.metadata.channel = object.metadata.channel.clone();
+
+    Arc::new(letter.with_new_id())
+}
+
+
+fn ping_reply(request: &BusinessObject) -> Arc<BusinessObject> {
+    let mut reply = BusinessObject::reply_to(request);
+    reply.event = Some(Event::Pong.to_string());
+
+    Arc::new(reply)
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ListenerKind {
+    Tcp,
+    WebSocket,
+    Tls,
+}
+
+
+struct Listener {
+    socket: TcpListener,
+    token: Token,
+    kind: ListenerKind,
+    // Index of the bus its clients are on
+    bus: usize,
+}
+
+
+/// Identifies a client among the clients of all workers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct ClientId {
+    worker: usize,
+    token: Token,
+}
+
+
+impl Ord for ClientId {
+    fn cmp(&self, other: &ClientId) -> Ordering {
+        (self.worker, self.token.as_usize()).cmp(&(other.worker, other.token.as_usize()))
+    }
+}
+
+impl PartialOrd for ClientId {
+    fn partial_cmp(&self, other: &ClientId) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+
+/// A `services/request` waiting for the provider's reply.
+struct PendingServiceRequest {
+    name: String,
+    requester: ClientId,
+    provider: ClientId,
+}
+
+
+/// Timeouts scheduled on a worker's event loop.
+enum Timer {
+    Periodical,
+    /// Start reading from a throttled client again.
+    Unthrottle(Token),
+}
+
+
+/// Passed between workers through their event loop channels, which are
+/// lock-free queues.
+enum Message {
+    /// Route an object to the worker's clients on a bus, except the given
+    /// one, counting the deliveries if the object may become a dead letter.
+    Route(usize, Arc<BusinessObject>, Option<ClientId>, Option<Arc<Deliveries>>),
+    /// Queue an object for one of the worker's clients.
+    Deliver(Token, Arc<BusinessObject>),
+    /// Stop accepting clients, tell the connected ones the router is going
+    /// away and stop once their queues are flushed or the deadline passes.
+    Shutdown(Instant),
+    /// Keep serving the connected clients but refuse new ones, stopping
+    /// once the clients are gone or shutting down at the deadline.
+    Drain(Instant),
+    /// Apply a reloaded configuration to the worker and its clients.
+    Reload(Arc<Config>),
+    /// Close the connection of one of the worker's clients.
+    Disconnect(Token),
+}
+
+
+/// How many clients a published object was routed to, summed up as the
+/// workers route it, so that the last one can tell whether it reached
+/// anybody.
+struct Deliveries {
+    workers_left: AtomicUsize,
+    matched: AtomicUsize,
+    delivered: AtomicUsize,
+}
+
+
+impl Deliveries {
+    fn new(workers: usize) -> Deliveries {
+        Deliveries { workers_left: AtomicUsize::new(workers), matched: AtomicUsize::new(0), delivered: AtomicUsize::new(0) }
+    }
+
+    /// Adds the clients a worker routed the object to, and queued it for
+    /// successfully. Once every worker has, returns why the object reached
+    /// nobody, if it didn't.
+    fn add(&self, matched: usize, delivered: usize) -> Option<&'static str> {
+        self.matched.fetch_add(matched, AtomicOrdering::SeqCst);
+        self.delivered.fetch_add(delivered, AtomicOrdering::SeqCst);
+        if self.workers_left.fetch_sub(1, AtomicOrdering::SeqCst) != 1 ||
+           self.delivered.load(AtomicOrdering::SeqCst) > 0 {
+            return None;
+        }
+        match self.matched.load(AtomicOrdering::SeqCst) {
+            0 => Some("no-subscribers"),
+            _ => Some("delivery-failed")
+        }
+    }
+}
+
+
+/// The registries of one bus, which its clients share with each other but
+/// not with those of other buses.
+struct BusRegistries {
+    // Service name to the client providing it
+    services: Mutex<HashMap<String, ClientId>>,
+    // What `clients/list` reports of each connected client
+    clients: Mutex<BTreeMap<ClientId, Json>>,
+    // Clients sharing the objects their subscriptions match
+    groups: Mutex<ConsumerGroups<ClientId>>,
+    history: Mutex<Box<dyn Storage>>,
+}
+
+
+impl BusRegistries {
+    fn new(history: Box<dyn Storage>) -> BusRegistries {
+        BusRegistries {
+            services: Mutex::new(HashMap::new()),
+            clients: Mutex::new(BTreeMap::new()),
+            groups: Mutex::new(ConsumerGroups::default()),
+            history: Mutex::new(history),
+        }
+    }
+}
+
+
+/// Registries shared by all workers. Routed objects never touch these, so
+/// the locks are only taken for service calls and client bookkeeping.
+#[derive(Default)]
+struct Shared {
+    // Those of the bus of the configured listeners first, then those of the
+    // configured buses in order
+    buses: Vec<BusRegistries>,
+    // Request id to the request being served
+    pending_service_requests: Mutex<HashMap<String, PendingServiceRequest>>,
+    metrics: Arc<Metrics>,
+    object_log: Option<ObjectLog>,
+    // Identifies this router in the `route` of forwarded objects
+    router_id: String,
+    // Records the objects routed on the first bus
+    journal: Option<Mutex<Box<dyn Storage>>>,
+    middlewares: Chain,
+    // Set by `admin/drain`, so that workers refuse new clients even before
+    // they are told to drain
+    draining: AtomicBool,
+    // Reads the time objects are stamped with
+    clock: Clock,
+}
+
+
+/// What the middlewares get to know about an object's sender or recipient.
+fn middleware_context<'a>(config: &'a Config, shared: &'a Shared, client: &'a BusinessClient) -> middleware::Context<'a> {
+    middleware::Context {
+        config,
+        router_id: &shared.router_id,
+        peer: middleware::Peer {
+            routing_id: &client.routing_id,
+            addr: client.peer_addr,
+            is_router: client.peer_router,
+        },
+    }
+}
+
+
+/// A connection to another router, owned by the first worker.
+struct UpstreamConnection {
+    upstream: Upstream,
+    subscription: BusinessSubscription,
+    token: Option<Token>,
+    next_attempt: Instant,
+}
+
+
+/// One worker of the router. Each worker runs its own event loop on its own
+/// thread, accepts connections from the shared listeners and owns the
+/// clients it accepted.
+struct Worker {
+    worker: usize,
+    workers: Vec<Sender<Message>>,
+    shared: Arc<Shared>,
+    listeners: Vec<Listener>,
+    clients: Slab<BusinessClient>,
+    config: Config,
+    tls_config: Option<Arc<rustls::ServerConfig>>,
+    upstreams: Vec<UpstreamConnection>,
+    shutdown_deadline: Option<Instant>,
+    drain_deadline: Option<Instant>,
+    // This worker's share of `max_clients`
+    max_clients: usize,
+    // Whether the listeners are registered, which they aren't while the
+    // worker is full and pauses accepting, or shuts down
+    accepting: bool,
+}
+
+
+/// The client of `token`, unless handling its events already disconnected
+/// it.
+fn client_for_token(server: &mut Worker, token: Token) -> Option<&mut BusinessClient> {
+    server.clients.get_mut(token)
+}
+
+
+impl Worker {
+    fn new(worker: usize, workers: Vec<Sender<Message>>, shared: Arc<Shared>,
+           sockets: Vec<(TcpListener, ListenerKind, usize)>, config: Config,
+           tls_config: Option<Arc<rustls::ServerConfig>>) -> Worker {
+        // As per
+        // <https://github.com/hjr3/mob/blob/multi-echo-blog-post/src/main.rs>
+        // something else but actually our registered events come in with
+        // Token(0) by default.
+        let listeners: Vec<Listener> = sockets.into_iter().enumerate().map(|(i, (socket, kind, bus))| {
+            Listener { socket, token: Token(i + 1), kind, bus }
+        }).collect();
+        let first_client_token = Token(listeners.len() + 1);
+        let max_clients = config.max_clients.div_ceil(workers.len());
+        let upstreams = if worker == 0 {
+            config.upstreams.iter().map(|upstream| UpstreamConnection {
+                upstream: upstream.clone(),
+                subscription: subscription::parse_subscription(&upstream.subscriptions.to_json())
+                    .expect("Upstream subscriptions were validated with the configuration"),
+                token: None,
+                next_attempt: Instant::now(),
+            }).collect()
+        } else {
+            Vec::new()
+        };
+
+        Worker {
+            worker,
+            workers,
+            shared,
+            listeners,
+            clients: Slab::new_starting_at(first_client_token, max_clients + upstreams.len() + REFUSAL_SLOTS),
+            config,
+            tls_config,
+            upstreams,
+            shutdown_deadline: None,
+            drain_deadline: None,
+            max_clients,
+            accepting: true,
+        }
+    }
+
+    fn client_id(&self, token: Token) -> ClientId {
+        ClientId { worker: self.worker, token }
+    }
+
+    fn listener_index(&self, token: Token) -> Option<usize> {
+        self.listeners.iter().position(|listener| listener.token == token)
+    }
+
+    fn upstream_index(&self, token: Token) -> Option<usize> {
+        self.upstreams.iter().position(|upstream| upstream.token == Some(token))
+    }
+
+    /// Whether the worker has as many clients as it takes. Upstream routers
+    /// and clients being refused don't count.
+    fn is_full(&self) -> bool {
+        let clients = self.clients.iter()
+            .filter(|client| !client.refused && self.upstream_index(client.token).is_none())
+            .count();
+        clients >= self.max_clients
+    }
+
+    fn register(&mut self, event_loop: &mut EventLoop<Worker>) -> io::Result<()> {
+        for listener in self.listeners.iter() {
+            event_loop.register_opt(&listener.socket, listener.token, EventSet::readable(),
+                                    PollOpt::edge() | PollOpt::oneshot()
+                                    ).map_err(|e| {
+                                        error!("Failed to register server {:?}, {:?}", listener.token, e);
+                                        e
+                                    })?;
+        }
+        self.connect_upstreams(event_loop);
+
+        Ok(())
+    }
+
+    fn reregister(&mut self, event_loop: &mut EventLoop<Worker>, index: usize) {
+        let token = self.listeners[index].token;
+        event_loop.reregister(&self.listeners[index].socket, token, EventSet::readable(),
+                              PollOpt::edge() | PollOpt::oneshot()
+                              ).unwrap_or_else(|e| {
+                                  error!("Failed to reregister server {:?}, {:?}", token, e);
+                                  self.reset_connection(event_loop, token);
+                              })
+    }
+
+    /// Stops accepting connections, which wait in the listen backlog or go
+    /// to other workers meanwhile.
+    fn pause_accepting(&mut self, event_loop: &mut EventLoop<Worker>) {
+        if !self.accepting {
+            return;
+        }
+        self.accepting = false;
+        for listener in self.listeners.iter() {
+            if let Err(e) = event_loop.deregister(&listener.socket) {
+                warn!("Failed to deregister server {:?}, {:?}", listener.token, e);
+            }
+        }
+    }
+
+    /// Accepts connections again once a full worker has room, unless it is
+    /// shutting down.
+    fn resume_accepting(&mut self, event_loop: &mut EventLoop<Worker>) {
+        if self.accepting || self.shutdown_deadline.is_some() || self.is_full() {
+            return;
+        }
+        info!("Worker {} has room for clients again", self.worker);
+        self.accepting = true;
+        for listener in self.listeners.iter() {
+            if let Err(e) = event_loop.register_opt(&listener.socket, listener.token, EventSet::readable(),
+                                                    PollOpt::edge() | PollOpt::oneshot()) {
+                error!("Failed to register server {:?}, {:?}", listener.token, e);
+                event_loop.shutdown();
+            }
+        }
+    }
+
+    fn new_client(&mut self, event_loop: &mut EventLoop<Worker>, index: usize) {
+        // Another listener may have had a connection waiting when this
+        // worker paused
+        if !self.accepting {
+            return;
+        }
+
+        // Log an error if there is no socket, but otherwise move on so we do not tear down the
+        // entire server.
+        let (sock, peer_addr) = match self.listeners[index].socket.accept() {
+            Ok(s) => {
+                match s {
+                    Some(sock) => {
+                        match sock.peer_addr() {
+                            Ok(addr) => {
+                                info!("Worker {} accepted connection from {:?}", self.worker, addr);
+                                (sock, addr)
+                            },
+                            Err(_) => {
+                                self.reregister(event_loop, index);
+                                return;
+                            }
+                        }
+                    },
+                    None => {
+                        // Another worker got to the connection first
+                        trace!("No connection to accept");
+                        self.reregister(event_loop, index);
+                        return;
+                    }
+                }
+            },
+            Err(e) => {
+                error!("Failed to accept new socket, {:?}", e);
+                self.reregister(event_loop, index);
+                return;
+            }
+        };
+
+        let options = match self.listeners[index].kind {
+            ListenerKind::Tcp => self.config.socket.clone(),
+            ListenerKind::WebSocket => self.config.websocket_socket.or(&self.config.socket),
+            ListenerKind::Tls => self.config.tls_socket.or(&self.config.socket),
+        };
+        if let Err(e) = options.apply(&sock) {
+            warn!("Failed to set socket options for {}: {}", peer_addr, e);
+        }
+
+        let transport = match self.listeners[index].kind {
+            ListenerKind::Tcp => Transport::Tcp(sock),
+            ListenerKind::WebSocket => Transport::WebSocket(WebSocketStream::new(sock)),
+            ListenerKind::Tls => {
+                let tls_config = self.tls_config.clone().expect("TLS listener without TLS configuration");
+                match rustls::ServerConnection::new(tls_config) {
+                    Ok(connection) => Transport::Tls(Box::new(rustls::StreamOwned::new(connection, sock))),
+                    Err(e) => {
+                        error!("Failed to set up TLS connection, {:?}", e);
+                        self.reregister(event_loop, index);
+                        return;
+                    }
+                }
+            },
+        };
+
+        let bus = self.listeners[index].bus;
+        let full = self.is_full();
+        let config = &self.config;
+        let metrics = self.shared.metrics.clone();
+        match self.clients.insert_with(|token| {
+            trace!("Registering {:?} with event loop", token);
+            let mut client = BusinessClient::new(transport, token, peer_addr, config, metrics);
+            client.bus = bus;
+            client
+        }) {
+            Some(token) => {
+                match self.clients[token].register(event_loop) {
+                    Ok(_) if self.shared.draining.load(AtomicOrdering::SeqCst) => {
+                        self.refuse(event_loop, token, ErrorCode::Draining,
+                                    "Router is draining for maintenance, connect to another one");
+                    },
+                    Ok(_) if full => {
+                        self.shared.metrics.refused_connections.inc();
+                        self.refuse(event_loop, token, ErrorCode::ServerFull,
+                                    "Router has as many clients as it takes, try again later");
+                    },
+                    Ok(_) => { self.update_directory(token); },
+                    Err(e) => {
+                        error!("Failed to register {:?} connection with event loop, {:?}", token, e);
+                        self.clients.remove(token);
+                    }
+                }
+            },
+            None => {
+                // The slots for refusing clients are taken too, so this one
+                // goes out of scope and is closed without being told why
+                warn!("Closing connection from {}: too many connections", peer_addr);
+                self.shared.metrics.refused_connections.inc();
+            }
+        };
+
+        if self.config.pause_accepting && self.is_full() {
+            info!("Worker {} is full, pausing accepting", self.worker);
+            self.pause_accepting(event_loop);
+        } else {
+            // Re-register server after received event
+            self.reregister(event_loop, index);
+        }
+    }
+
+    fn readable(&mut self, event_loop: &mut EventLoop<Worker>, token: Token) -> io::Result<()> {
+        trace!("Server conn readable, token: {:?}", token);
+        let objs_result = self.clients[token].read_objects();
+
+        match objs_result {
+            Ok(objs) => {
+                for obj in objs.into_iter() {
+                    // Handling an object may have disconnected the client
+                    if !self.clients.contains(token) {
+                        break;
+                    }
+                    debug!("IN({:?}): {}", self.clients[token].peer_addr, obj);
+                    self.log_object(token, &obj);
+                    self.handle_incoming_object(event_loop, token, Arc::new(obj));
+                }
+                // The frames that couldn't be read were skipped, but the
+                // client is told why
+                while let Some(e) = self.clients.get_mut(token).and_then(|client| client.stream.take_frame_error()) {
+                    warn!("Skipped a frame from {:?}: {}", token, e);
+                    if let Some(error) = RoutingError::from_read_error(&e) {
+                        self.queue_object(event_loop, token, Arc::new(error.to_object(Event::RoutingError)));
+                    }
+                }
+                if self.clients.contains(token) {
+                    self.enforce_rate_limit(event_loop, token)?;
+                }
+            },
+            Err(ReadBusinessObjectError::Protocol(e @ ProtocolError::HeaderTooLarge { .. }))
+            | Err(ReadBusinessObjectError::Protocol(e @ ProtocolError::PayloadTooLarge { .. })) => {
+                warn!("Disconnecting {:?}: {}", token, e);
+                // Tell why before going, as far as the socket takes it now
+                let client = &mut self.clients[token];
+                let error = RoutingError::new(e.code(), &e.to_string()).to_object(Event::RoutingError);
+                if client.send_object(Arc::new(error)).is_ok() {
+                    let _ = client.writable();
+                }
+                return Err(Error::new(ErrorKind::InvalidData, e));
+            },
+            Err(e) => {
+                warn!("Couldn't read objects from {:?}: {}", token, e);
+                if let Some(error) = RoutingError::from_read_error(&e) {
+                    self.queue_object(event_loop, token, Arc::new(error.to_object(Event::RoutingError)));
+                }
+            }
+        };
+
+
+        Ok(())
+    }
+
+    /// Applies the rate limit policy to a client that has sent more than its
+    /// limit allows.
+    fn enforce_rate_limit(&mut self, event_loop: &mut EventLoop<Worker>, token: Token) -> io::Result<()> {
+        let now = Instant::now();
+        let client = &mut self.clients[token];
+        let exceeded = !client.peer_router && client.rate_limiter.as_mut().is_some_and(|limiter| limiter.is_exceeded(now));
+        if !exceeded {
+            client.rate_limit_warned = false;
+            return Ok(());
+        }
+        self.shared.metrics.rate_limit_violations.inc();
+
+        match self.config.rate_limit_policy {
+            RateLimitPolicy::Throttle => {
+                let delay = client.rate_limiter.as_mut().unwrap().time_until_within(now);
+                debug!("Throttling {:?} for {} ms", token, delay.as_millis());
+                client.interest.remove(EventSet::readable());
+                event_loop.timeout_ms(Timer::Unthrottle(token), cmp::max(1, delay.as_millis() as u64))
+                    .map(|_| ())
+                    .map_err(|e| Error::other(format!("Failed to schedule unthrottling: {:?}", e)))
+            },
+            RateLimitPolicy::Warn => {
+                if !client.rate_limit_warned {
+                    warn!("{:?} exceeded its rate limit", client);
+                    client.rate_limit_warned = true;
+                    let warning = rate_limit_warning(&self.config);
+                    self.queue_object(event_loop, token, warning);
+                }
+                Ok(())
+            },
+            RateLimitPolicy::Disconnect => {
+                warn!("Disconnecting {:?}: rate limit exceeded", token);
+                Err(Error::other("Rate limit exceeded"))
+            }
+        }
+    }
+
+    /// Switches to a reloaded configuration. Connected clients get the new
+    /// limits and access control rules, but those already logged in stay
+    /// logged in.
+    fn reload(&mut self, config: &Config) {
+        if let Some(ref object_log) = self.shared.object_log {
+            object_log.set_sampling(config.object_log_sample_rate, config.object_log_payloads);
+        }
+
+        let limits_changed = config.rate_limit_objects != self.config.rate_limit_objects ||
+            config.rate_limit_bytes != self.config.rate_limit_bytes;
+        let now = Instant::now();
+        let upstream_tokens: Vec<Token> = self.upstreams.iter().filter_map(|upstream| upstream.token).collect();
+        for client in self.clients.iter_mut() {
+            client.max_queue_length = config.max_queue_length;
+            client.max_queue_age = config.max_queue_age.map(Duration::from_secs);
+            client.stream.set_max_header_size(Some(config.max_header_size));
+            client.stream.set_max_payload_size(Some(config.max_payload_size));
+            client.stream.socket.apply_limits(config);
+            client.stream.set_verify_checksums(config.verify_checksums);
+            client.stream.set_header_decoding(config.header_decoding);
+            if limits_changed {
+                client.rate_limiter = RateLimiter::new(config.rate_limit_objects, config.rate_limit_bytes, now);
+            }
+            if !config.requires_login() {
+                client.authenticated = true;
+            }
+            if !upstream_tokens.contains(&client.token) {
+                client.permissions = Permissions::new(&config.acl, client.identity.as_deref(), &client.peer_addr.ip());
+            }
+            if let Some(ref group) = client.group {
+                let id = ClientId { worker: self.worker, token: client.token };
+                self.shared.buses[client.bus].groups.lock().unwrap()
+                    .join(group, id, client.subscription.clone().unwrap(), client.permissions.clone(),
+                          client.channels.clone());
+            }
+        }
+
+        self.config = config.clone();
+        info!("Worker {} reloaded its configuration", self.worker);
+    }
+
+    /// Resumes reading from a client throttled for exceeding its rate limit.
+    fn unthrottle(&mut self, event_loop: &mut EventLoop<Worker>, token: Token) {
+        let result = match self.clients.get_mut(token) {
+            Some(client) => {
+                // It couldn't finish its frame while it wasn't read from
+                if client.partial_frame_since.is_some() {
+                    client.partial_frame_since = Some(Instant::now());
+                }
+                client.interest.insert(EventSet::readable());
+                client.reregister(event_loop)
+            },
+            None => { return; }
+        };
+
+        if let Err(e) = result {
+            warn!("Failed to resume reading from {:?}: {:?}", token, e);
+            self.reset_connection(event_loop, token);
+        }
+    }
+
+    fn log_object(&self, token: Token, object: &BusinessObject) {
+        if let Some(ref object_log) = self.shared.object_log {
+            object_log.log(&self.clients[token].peer_addr, object);
+        }
+    }
+
+    /// Handles the objects a client sent before hanging up.
+    fn read_remaining(&mut self, event_loop: &mut EventLoop<Worker>, token: Token) {
+        loop {
+            let objs_result = match self.clients.get_mut(token) {
+                Some(client) if !client.stream.at_eof() => client.read_objects(),
+                _ => { return; }
+            };
+
+            match objs_result {
+                Ok(objs) => {
+                    for obj in objs.into_iter() {
+                        self.log_object(token, &obj);
+                        self.handle_incoming_object(event_loop, token, Arc::new(obj));
+                    }
+                },
+                Err(_) => { return; }
+            }
+        }
+    }
+
+    fn periodical(&mut self, event_loop: &mut EventLoop<Worker>) {
+        let now = Instant::now();
+        let idle_timeout = Duration::from_secs(self.config.idle_timeout);
+        let pong_timeout = Duration::from_secs(self.config.pong_timeout);
+
+        let partial_frame_timeout = Duration::from_secs(self.config.partial_frame_timeout);
+
+        let upstream_tokens: Vec<Token> = self.upstreams.iter().filter_map(|upstream| upstream.token).collect();
+        let mut bad_tokens = Vec::new();
+        for client in self.clients.iter_mut() {
+            // Throttled clients aren't read from, so can't be blamed
+            let reading = client.interest.is_readable();
+            if reading && client.partial_frame_since.is_some_and(|since| now - since >= partial_frame_timeout) {
+                info!("{:?} took too long to finish a frame, disconnecting", client);
+                self.shared.metrics.partial_frame_timeouts.inc();
+                bad_tokens.push(client.token);
+                continue;
+            }
+
+            // Upstream routers answer pings only if our subscription lets
+            // pongs through, so rely on the connection failing instead
+            if upstream_tokens.contains(&client.token) {
+                continue;
+            }
+
+            match client.ping_sent {
+                Some(sent) => {
+                    if now - sent >= pong_timeout {
+                        info!("No pong from {:?}, disconnecting", client);
+                        bad_tokens.push(client.token);
+                    }
+                },
+                None => {
+                    if now - client.last_activity < idle_timeout {
+                        continue;
+                    }
+
+                    // Clients that never subscribed can't be expected to answer pings
+                    if client.subscription.is_none() {
+                        if now - client.last_activity >= idle_timeout + pong_timeout {
+                            info!("Unsubscribed client {:?} idle, disconnecting", client);
+                            bad_tokens.push(client.token);
+                        }
+                        continue;
+                    }
+
+                    trace!("Pinging idle client {:?}", client);
+                    client.ping_sent = Some(now);
+                    client.queue(event_loop, idle_ping())
+                        .unwrap_or_else(|e| {
+                            error!("Failed to queue ping for {:?}: {:?}", client.token, e);
+                            bad_tokens.push(client.token)
+                        });
+                }
+            }
+        }
+
+        for t in bad_tokens {
+            self.reset_connection(event_loop, t);
+        }
+
+        self.connect_upstreams(event_loop);
+    }
+
+    /// Connects to the upstream routers that aren't connected and are due
+    /// for another attempt.
+    fn connect_upstreams(&mut self, event_loop: &mut EventLoop<Worker>) {
+        if self.shutdown_deadline.is_some() {
+            return;
+        }
+
+        let now = Instant::now();
+        for index in 0 .. self.upstreams.len() {
+            if self.upstreams[index].token.is_none() && now >= self.upstreams[index].next_attempt {
+                self.connect_upstream(event_loop, index);
+            }
+        }
+    }
+
+    fn connect_upstream(&mut self, event_loop: &mut EventLoop<Worker>, index: usize) {
+        let address = self.upstreams[index].upstream.address;
+        self.upstreams[index].next_attempt = Instant::now() + Duration::from_secs(UPSTREAM_RETRY_INTERVAL);
+
+        let sock = match TcpStream::connect(&address) {
+            Ok(sock) => sock,
+            Err(e) => {
+                warn!("Failed to connect to upstream {}: {}", address, e);
+                return;
+            }
+        };
+        if let Err(e) = self.config.socket.apply(&sock) {
+            warn!("Failed to set socket options for upstream {}: {}", address, e);
+        }
+
+        let subscription = self.upstreams[index].subscription.clone();
+        let config = &self.config;
+        let metrics = self.shared.metrics.clone();
+        let token = match self.clients.insert_with(|token| {
+            let mut client = BusinessClient::new(Transport::Tcp(sock), token, address, config, metrics);
+            client.name = Some(format!("upstream {}", address));
+            client.subscription = Some(SubscriptionMatcher::new(subscription));
+            client.peer_router = true;
+            client.authenticated = true;
+            client.permissions = None;
+            client
+        }) {
+            Some(token) => token,
+            None => {
+                error!("Failed to insert upstream connection into slab");
+                return;
+            }
+        };
+
+        let login = self.upstreams[index].upstream.auth_token.as_ref().map(|auth_token| auth_login(auth_token));
+        let request = upstream_subscription(&self.upstreams[index].subscription, &self.shared.router_id);
+        let client = &mut self.clients[token];
+        let result = match login {
+            Some(login) => client.send_object(login),
+            None => Ok(())
+        };
+        if let Err(e) = result.and_then(|_| client.send_object(request))
+                .and_then(|_| client.register(event_loop).map_err(WriteBusinessObjectError::WriteError)) {
+            error!("Failed to register upstream {} with event loop, {:?}", address, e);
+            self.clients.remove(token);
+            return;
+        }
+
+        info!("Connecting to upstream {}", address);
+        self.upstreams[index].token = Some(token);
+        self.update_directory(token);
+    }
+
+    /// Handles an object from another router, upstream or downstream,
+    /// which is routed to our clients and other routers but not back to
+    /// where it came from.
+    fn handle_peer_object(&mut self, event_loop: &mut EventLoop<Worker>,
+                              token: Token, object: Arc<BusinessObject>) {
+        match object.typed_event() {
+            Some(Event::Ping) => {
+                self.queue_object(event_loop, token, ping_reply(&object));
+            },
+            Some(Event::RoutingSubscribeReply) => {
+                let address = self.clients[token].peer_addr;
+                match object.metadata.extra.get("error") {
+                    Some(error) => { error!("Upstream {} rejected our subscription: {}", address, error); },
+                    None => { info!("Subscribed to upstream {}", address); }
+                }
+            },
+            Some(Event::AuthLoginReply) => {
+                let address = self.clients[token].peer_addr;
+                match object.meta_str("error") {
+                    Some(error) => { error!("Upstream {} rejected our login: {}", address, error); },
+                    None => { info!("Logged in to upstream {}", address); }
+                }
+            },
+            _ if !may_publish(&self.clients[token], &object) => {
+                debug!("Dropping {:?} from router {:?}, which may not publish it", object, token);
+            },
+            _ => {
+                let verdict = self.shared.middlewares.inbound(
+                    &middleware_context(&self.config, &self.shared, &self.clients[token]), object);
+                match verdict {
+                    Verdict::Pass(object) => {
+                        let from = self.client_id(token);
+                        let bus = self.clients[token].bus;
+                        if let Some(object) = self.take_hop(event_loop, bus, object) {
+                            self.route(event_loop, bus, object, Some(from));
+                        }
+                    },
+                    _ => { debug!("Middleware stopped an object from router {:?}", token); }
+                }
+            }
+        }
+    }
+
+    fn shutdown(&mut self, event_loop: &mut EventLoop<Worker>, deadline: Instant) {
+        if self.shutdown_deadline.is_some() {
+            return;
+        }
+        info!("Worker {} shutting down", self.worker);
+        self.shutdown_deadline = Some(deadline);
+        self.drain_deadline = None;
+        self.pause_accepting(event_loop);
+
+        // Upstream routers would pass the notification on to their clients
+        let notification = shutdown_notification();
+        let tokens: Vec<Token> = self.clients.iter()
+            .map(|client| client.token)
+            .filter(|&token| self.upstream_index(token).is_none())
+            .collect();
+        for token in tokens {
+            self.queue_object(event_loop, token, notification.clone());
+        }
+    }
+
+    fn drain(&mut self, deadline: Instant) {
+        if self.shutdown_deadline.is_some() || self.drain_deadline.is_some() {
+            return;
+        }
+        info!("Worker {} draining", self.worker);
+        self.drain_deadline = Some(deadline);
+    }
+
+    /// Tells a client connecting while the router drains or is full to go
+    /// elsewhere. The connection is closed once it has been told.
+    fn refuse(&mut self, event_loop: &mut EventLoop<Worker>, token: Token, code: ErrorCode, error: &str) {
+        info!("Refusing {:?}: {}", self.clients[token].peer_addr, code);
+        self.clients[token].refused = true;
+        self.queue_object(event_loop, token, refusal(code, error));
+    }
+
+    /// Ends the event loop of a draining worker once its clients are gone,
+    /// shutting down the ones still connected when the deadline passes.
+    /// Ends that of a shutting down worker once every client has been sent
+    /// everything queued for it, or when the deadline passes.
+    fn check_shutdown(&mut self, event_loop: &mut EventLoop<Worker>) {
+        if let Some(deadline) = self.drain_deadline {
+            // Refused clients count too, until they have been told why
+            let remaining = self.clients.iter()
+                .filter(|client| self.upstream_index(client.token).is_none())
+                .count();
+            if remaining == 0 {
+                info!("Worker {} drained", self.worker);
+                event_loop.shutdown();
+                return;
+            }
+            let now = Instant::now();
+            if now < deadline {
+                return;
+            }
+            warn!("Worker {} still has {} clients at the end of draining", self.worker, remaining);
+            self.shutdown(event_loop, now + Duration::from_secs(self.config.shutdown_timeout));
+        }
+
+        if let Some(deadline) = self.shutdown_deadline {
+            let flushed = self.clients.iter().all(|client| client.is_flushed());
+            if !flushed && Instant::now() < deadline {
+                return;
+            }
+
+            if !flushed {
+                warn!("Worker {} shutting down with unsent objects", self.worker);
+            }
+            event_loop.shutdown();
+        }
+    }
+
+    fn reset_connection(&mut self, event_loop: &mut EventLoop<Worker>, token: Token) {
+        if self.listener_index(token).is_some() {
+            event_loop.shutdown();
+        } else {
+            trace!("Reset connection, token: {:?}", token);
+            let id = self.client_id(token);
+            if let Some(client) = self.clients.get(token) {
+                let bus = &self.shared.buses[client.bus];
+                bus.clients.lock().unwrap().remove(&id);
+                bus.groups.lock().unwrap().leave(id);
+            }
+            if let Some(index) = self.upstream_index(token) {
+                warn!("Lost connection to upstream {}", self.upstreams[index].upstream.address);
+                self.upstreams[index].token = None;
+                self.upstreams[index].next_attempt = Instant::now() + Duration::from_secs(UPSTREAM_RETRY_INTERVAL);
+                self.clients.remove(token);
+                return;
+            }
+            if let Some(client) = self.clients.remove(token) {
+                if client.subscription.is_some() {
+                    let announcement = announcement(Event::RoutingAnnouncementDisconnect, &client);
+                    self.announce(event_loop, client.bus, token, announcement);
+                }
+            }
+            self.forget_services(event_loop, token);
+            self.resume_accepting(event_loop);
+        }
+    }
+
+    /// Sends a routing announcement about `subject` to the other clients on
+    /// its bus subscribed to it.
+    fn announce(&mut self, event_loop: &mut EventLoop<Worker>, bus: usize, subject: Token,
+                announcement: Arc<BusinessObject>) {
+        let subject = self.client_id(subject);
+        self.route(event_loop, bus, announcement, Some(subject));
+    }
+
+    /// Routes an object a client published. If dead letters are asked for,
+    /// one reaching nobody is routed again wrapped in a
+    /// `routing/dead-letter`.
+    fn publish(&mut self, event_loop: &mut EventLoop<Worker>, bus: usize, object: Arc<BusinessObject>,
+               exclude: Option<ClientId>) {
+        let deliveries = if self.config.dead_letters && !object.is_event(Event::RoutingDeadLetter) {
+            Some(Arc::new(Deliveries::new(self.workers.len())))
+        } else {
+            None
+        };
+        self.dispatch(event_loop, bus, object, exclude, deliveries);
+    }
+
+    /// Routes `object` to the matching clients on `bus` of all workers,
+    /// except `exclude`.
+    fn route(&mut self, event_loop: &mut EventLoop<Worker>, bus: usize, object: Arc<BusinessObject>,
+             exclude: Option<ClientId>) {
+        self.dispatch(event_loop, bus, object, exclude, None);
+    }
+
+    fn dispatch(&mut self, event_loop: &mut EventLoop<Worker>, bus: usize, object: Arc<BusinessObject>,
+                exclude: Option<ClientId>, deliveries: Option<Arc<Deliveries>>) {
+        let object = if self.config.stamp_routed_at {
+            Arc::new(object.with_meta(clock::ROUTED_AT_KEY, &self.shared.clock.now()))
+        } else {
+            object
+        };
+        let now = self.shared.clock.now();
+        if let Err(e) = self.shared.buses[bus].history.lock().unwrap().append(object.clone(), now) {
+            warn!("Failed to record history: {}", e);
+        }
+        if let Some(journal) = self.shared.journal.as_ref().filter(|_| bus == 0) {
+            if let Err(e) = journal.lock().unwrap().append(object.clone(), now) {
+                warn!("Failed to write journal: {}", e);
+            }
+        }
+
+        for (worker, sender) in self.workers.iter().enumerate() {
+            if worker != self.worker {
+                if let Err(e) = sender.send(Message::Route(bus, object.clone(), exclude, deliveries.clone())) {
+                    warn!("Failed to pass object to worker {}: {:?}", worker, e);
+                }
+            }
+        }
+
+        let (matched, delivered) = self.route_locally(event_loop, bus, &object, exclude);
+        let picked = self.route_to_groups(event_loop, bus, &object, exclude);
+        if let Some(deliveries) = deliveries {
+            self.count_deliveries(event_loop, bus, &object, &deliveries, matched + picked, delivered + picked);
+        }
+    }
+
+    /// Adds this worker's share to the deliveries of `object`, routing it
+    /// as a dead letter if it turns out nobody got it.
+    fn count_deliveries(&mut self, event_loop: &mut EventLoop<Worker>, bus: usize, object: &BusinessObject,
+                        deliveries: &Deliveries, matched: usize, delivered: usize) {
+        if let Some(reason) = deliveries.add(matched, delivered) {
+            debug!("Routing {:?} as a dead letter: {}", object, reason);
+            self.shared.metrics.dead_letters.inc();
+            self.route(event_loop, bus, dead_letter(object, reason), None);
+        }
+    }
+
+    /// Queues `object` for one member of each shared subscription group that
+    /// wants it, on whichever worker it is. Returns how many got it.
+    fn route_to_groups(&mut self, event_loop: &mut EventLoop<Worker>, bus: usize, object: &Arc<BusinessObject>,
+                       exclude: Option<ClientId>) -> usize {
+        // Objects for named recipients reach group members like anyone else
+        if object.metadata.to.is_some() {
+            return 0;
+        }
+        let picked = self.shared.buses[bus].groups.lock().unwrap()
+            .pick(object, exclude);
+        self.shared.metrics.objects_routed.add(picked.len() as u64);
+        let count = picked.len();
+        for member in picked {
+            self.deliver(event_loop, member, object.clone());
+        }
+        count
+    }
+
+    /// Moves a client to the group its latest subscription names, if any.
+    fn update_group(&mut self, token: Token, group: Option<String>) {
+        let id = self.client_id(token);
+        let client = &mut self.clients[token];
+        let mut groups = self.shared.buses[client.bus].groups.lock().unwrap();
+        match group {
+            Some(ref group) => {
+                info!("{:?} joined group {}", token, group);
+                groups.join(group, id, client.subscription.clone().unwrap(), client.permissions.clone(),
+                            client.channels.clone());
+            },
+            None => groups.leave(id)
+        }
+        client.group = group;
+    }
+
+    /// Queues `object` for this worker's clients on `bus` whose subscription
+    /// matches it, except `exclude`, and only those it names in `to` if it
+    /// does. Returns how many it was routed to, and for how many of them it
+    /// was queued.
+    fn route_locally(&mut self, event_loop: &mut EventLoop<Worker>, bus: usize, object: &Arc<BusinessObject>,
+                     exclude: Option<ClientId>) -> (usize, usize) {
+        let key = RoutingKey::of(object);
+
+        let worker = self.worker;
+        let shared = &self.shared;
+        let config = &self.config;
+        let mut routed = 0;
+        let mut failed = Vec::new();
+        for client in self.clients.iter_mut() {
+            if client.bus != bus || exclude == Some(ClientId { worker, token: client.token }) {
+                continue;
+            }
+            let skip = match object.metadata.to {
+                Some(ref recipients) => !is_recipient(client, recipients),
+                None => client.group.is_some()
+            };
+            // Another router would only drop an object out of hops
+            if skip || !is_on_channel(client, object) || (client.peer_router && object.metadata.ttl == Some(0)) {
+                continue;
+            }
+            match client.subscription {
+                Some(ref subscription) => {
+                    if !(subscription.matches_object(&key, object) &&
+                         client.permissions.as_ref().is_none_or(|permissions| permissions.may_receive(&key))) {
+                        shared.metrics.routing_rejections.inc();
+                        continue;
+                    }
+                },
+                None => {
+                    trace!("Not subscribed; not routing {:?} to {:?}", object, client);
+                    continue;
+                }
+            }
+
+            let object = match shared.middlewares.outbound(&middleware_context(config, shared, client), object.clone()) {
+                Verdict::Pass(object) => object,
+                _ => { continue; }
+            };
+            routed += 1;
+            let object = client.transform.apply(object);
+            if let Err(e) = client.queue(event_loop, object) {
+                error!("Failed to queue message for {:?}: {:?}", client.token, e);
+                failed.push(client.token);
+            }
+        }
+
+        self.shared.metrics.objects_routed.add(routed as u64);
+        let delivered = routed - failed.len();
+        for token in failed {
+            self.reset_connection(event_loop, token);
+        }
+        (routed, delivered)
+    }
+
+    /// Queues `object` for a client of any worker.
+    fn deliver(&mut self, event_loop: &mut EventLoop<Worker>, to: ClientId, object: Arc<BusinessObject>) {
+        if to.worker == self.worker {
+            self.queue_outbound(event_loop, to.token, object);
+        } else if let Err(e) = self.workers[to.worker].send(Message::Deliver(to.token, object)) {
+            warn!("Failed to pass object to worker {}: {:?}", to.worker, e);
+        }
+    }
+
+    /// Publishes the current state of a client to `clients/list`.
+    fn update_directory(&mut self, token: Token) {
+        let id = self.client_id(token);
+        let client = &self.clients[token];
+        let info = client_info(client);
+        self.shared.buses[client.bus].clients.lock().unwrap().insert(id, info);
+    }
+
+    /// Queues an object passed on from another client, if the outbound
+    /// middlewares let it through.
+    fn queue_outbound(&mut self, event_loop: &mut EventLoop<Worker>, token: Token, object: Arc<BusinessObject>) {
+        let verdict = match self.clients.get(token) {
+            Some(client) => self.shared.middlewares.outbound(&middleware_context(&self.config, &self.shared, client), object),
+            None => { return; }
+        };
+        if let Verdict::Pass(object) = verdict {
+            let object = self.clients[token].transform.apply(object);
+            self.queue_object(event_loop, token, object);
+        }
+    }
+
+    /// Queues `object` for the client and resets the connection on failure.
+    fn queue_object(&mut self, event_loop: &mut EventLoop<Worker>, token: Token, object: Arc<BusinessObject>) {
+        let result = match self.clients.get_mut(token) {
+            Some(client) => client.queue(event_loop, object),
+            None => { return; }
+        };
+
+        if let Err(e) = result {
+            error!("Failed to queue message for {:?}: {:?}", token, e);
+            self.reset_connection(event_loop, token);
+        }
+    }
+
+    fn register_client(&mut self, event_loop: &mut EventLoop<Worker>,
+                       token: Token, object: Arc<BusinessObject>) {
+        let reply = {
+            let client = &mut self.clients[token];
+            client.name = object.meta_str("name").map(|name| name.to_string());
+            client.user = object.meta_str("user").map(|user| user.to_string());
+            info!("{:?} registered as {:?} (user {:?})", token, client.name, client.user);
+
+            let mut reply = BusinessObject::reply_to(&object);
+            reply.event = Some(Event::ClientsRegisterReply.to_string());
+            reply.set_meta("routing-id", &client.routing_id);
+            Arc::new(reply)
+        };
+
+        self.update_directory(token);
+        self.queue_object(event_loop, token, reply);
+    }
+
+    fn register_service(&mut self, event_loop: &mut EventLoop<Worker>,
+                        token: Token, object: Arc<BusinessObject>) {
+        let id = self.client_id(token);
+        let reply = match service_name(&object) {
+            Some(name) => {
+                let mut services = self.shared.buses[self.clients[token].bus].services.lock().unwrap();
+                match services.get(name) {
+                    Some(provider) if *provider != id => {
+                        warn!("{:?} tried to register service {} already provided by {:?}", id, name, provider);
+                        service_reply(object.id(), Some(name), Some("Service already registered"))
+                    },
+                    _ => {
+                        info!("{:?} registered service {}", id, name);
+                        services.insert(name.to_string(), id);
+                        service_reply(object.id(), Some(name), None)
+                    }
+                }
+            },
+            None => service_reply(object.id(), None, Some("No service name"))
+        };
+
+        let mut reply = (*reply).clone();
+        reply.event = Some(Event::ServicesRegisterReply.to_string());
+        self.queue_object(event_loop, token, Arc::new(reply));
+    }
+
+    fn route_service_request(&mut self, event_loop: &mut EventLoop<Worker>,
+                             token: Token, object: Arc<BusinessObject>) {
+        let name = service_name(&object).map(|name| name.to_string());
+        let services = &self.shared.buses[self.clients[token].bus].services;
+        let provider = name.as_ref().and_then(|name| services.lock().unwrap().get(name).cloned());
+
+        match (name, provider) {
+            (Some(name), Some(provider)) => {
+                // Replies are matched to requests by id, so make sure there is one
+                let request = match object.id() {
+                    Some(_) => object.clone(),
+                    None => Arc::new((*object).clone().with_new_id())
+                };
+                let id = request.id().unwrap().to_string();
+
+                let requester = self.client_id(token);
+                trace!("Routing request {} for {} from {:?} to {:?}", id, name, requester, provider);
+                self.shared.pending_service_requests.lock().unwrap().insert(id, PendingServiceRequest {
+                    name,
+                    requester,
+                    provider,
+                });
+                self.deliver(event_loop, provider, request);
+            },
+            (name, _) => {
+                debug!("No provider for service request {:?} from {:?}", name, token);
+                let reply = service_reply(object.id(), name.as_ref().map(|n| n.as_ref()), Some("No such service"));
+                self.queue_object(event_loop, token, reply);
+            }
+        }
+    }
+
+    /// Routes a reply to the client that made the request. Returns false if
+    /// the reply doesn't answer a pending request.
+    fn route_service_reply(&mut self, event_loop: &mut EventLoop<Worker>,
+                           token: Token, object: &Arc<BusinessObject>) -> bool {
+        let id = match object.in_reply_to() {
+            Some(id) => id.to_string(),
+            None => { return false; }
+        };
+
+        let pending = {
+            let mut pending_requests = self.shared.pending_service_requests.lock().unwrap();
+            let is_provider = match pending_requests.get(&id) {
+                Some(pending) => pending.provider == self.client_id(token),
+                None => false
+            };
+            if !is_provider {
+                return false;
+            }
+
+            pending_requests.remove(&id).unwrap()
+        };
+
+        trace!("Routing reply to {} for {} to {:?}", id, pending.name, pending.requester);
+        self.deliver(event_loop, pending.requester, object.clone());
+        true
+    }
+
+    /// Drops services provided by a disconnected client and fails requests
+    /// that were waiting on it.
+    fn forget_services(&mut self, event_loop: &mut EventLoop<Worker>, token: Token) {
+        let client = self.client_id(token);
+        for bus in &self.shared.buses {
+            bus.services.lock().unwrap().retain(|_, provider| *provider != client);
+        }
+
+        let orphaned: Vec<(String, PendingServiceRequest)> = {
+            let mut pending_requests = self.shared.pending_service_requests.lock().unwrap();
+            let ids: Vec<String> = pending_requests.iter()
+                .filter(|&(_, pending)| pending.requester == client || pending.provider == client)
+                .map(|(id, _)| id.clone())
+                .collect();
+
+            ids.into_iter().map(|id| {
+                let pending = pending_requests.remove(&id).unwrap();
+                (id, pending)
+            }).collect()
+        };
+
+        for (id, pending) in orphaned {
+            if pending.requester != client {
+                let reply = service_reply(Some(&id), Some(&pending.name), Some("Service provider disconnected"));
+                self.deliver(event_loop, pending.requester, reply);
+            }
+        }
+    }
+
+    /// Handles an object from a client that hasn't logged in, which may
+    /// only be an `auth/login` with one of the configured tokens. Logging in
+    /// with the token of an identity gives the client its permissions.
+    fn log_in(&mut self, event_loop: &mut EventLoop<Worker>, token: Token, object: Arc<BusinessObject>) {
+        if !object.is_event(Event::AuthLogin) {
+            debug!("Rejected {:?} from {:?}, which hasn't logged in", object, token);
+            let reply = BusinessObject::error_reply(&object, Event::RoutingError, ErrorCode::NotLoggedIn,
+                                                    "Log in with auth/login first");
+            self.queue_object(event_loop, token, Arc::new(reply));
+            return;
+        }
+
+        let given = object.meta_str("token").unwrap_or("");
+        let identity = self.config.identities.iter()
+            .find(|&(_, expected)| tokens_match(expected, given))
+            .map(|(identity, _)| identity.clone());
+        let valid = identity.is_some() || self.config.auth_tokens.iter().any(|expected| tokens_match(expected, given));
+        let reply = if valid {
+            info!("{:?} logged in as {}", self.clients[token], identity.as_deref().unwrap_or("anonymous"));
+            let acl = &self.config.acl;
+            let client = &mut self.clients[token];
+            client.permissions = Permissions::new(acl, identity.as_deref(), &client.peer_addr.ip());
+            client.identity = identity;
+            client.authenticated = true;
+            auth_login_reply(&object, None)
+        } else {
+            warn!("Rejected login from {:?}", self.clients[token]);
+            auth_login_reply(&object, Some("Invalid token"))
+        };
+        self.queue_object(event_loop, token, reply);
+    }
+
+    fn handle_incoming_object(&mut self, event_loop: &mut EventLoop<Worker>,
+                               token: Token, object: Arc<BusinessObject>) {
+        if self.clients[token].refused {
+            return;
+        }
+        if !self.clients[token].authenticated {
+            self.log_in(event_loop, token, object);
+            return;
+        }
+
+        let early_publish = self.config.publish_before_subscribing && !object.is_event(Event::RoutingSubscribe);
+        match self.clients[token].subscription {
+            Some(_) => {
+                trace!("Would handle {:?}", &object);
+                self.clients[token].last_activity = Instant::now();
+
+                if object.is_event(Event::Pong) && self.clients[token].ping_sent.is_some() {
+                    trace!("Got pong from {:?}", token);
+                    self.clients[token].ping_sent = None;
+                    return;
+                }
+
+                if has_visited(&object, &self.shared.router_id) {
+                    trace!("Dropping {:?}, it has already been routed here", object);
+                    return;
+                }
+                if self.clients[token].peer_router {
+                    self.handle_peer_object(event_loop, token, object);
+                    return;
+                }
+
+                match object.typed_event() {
+                    Some(Event::RoutingSubscribe) => {
+                        self.resubscribe(event_loop, token, object);
+                        return;
+                    },
+                    Some(Event::ClientsRegister) => {
+                        self.register_client(event_loop, token, object);
+                        return;
+                    },
+                    Some(Event::ClientsList) => {
+                        let clients = &self.shared.buses[self.clients[token].bus].clients;
+                        let reply = client_list_reply(&object, &clients.lock().unwrap(), &self.shared.metrics);
+                        self.queue_object(event_loop, token, reply);
+                        return;
+                    },
+                    Some(Event::ServicesRegister) => {
+                        self.register_service(event_loop, token, object);
+                        return;
+                    },
+                    Some(Event::HistoryReplay) => {
+                        self.replay_history(event_loop, token, object);
+                        return;
+                    },
+                    Some(Event::PayloadFetch) => {
+                        self.fetch_payload(event_loop, token, object);
+                        return;
+                    },
+                    Some(Event::ServicesRequest) => {
+                        self.route_service_request(event_loop, token, object);
+                        return;
+                    },
+                    Some(event @ Event::AdminStats) | Some(event @ Event::AdminClients) |
+                    Some(event @ Event::AdminDisconnect) | Some(event @ Event::AdminDrain) => {
+                        self.handle_admin(event_loop, token, object, event);
+                        return;
+                    },
+                    Some(Event::ServicesReply) if self.route_service_reply(event_loop, token, &object) => {
+                        return;
+                    },
+                    _ => {}
+                }
+
+                if object.event.as_ref().is_some_and(|event| event.starts_with("admin/")) {
+                    let reply = BusinessObject::error_reply(&object, Event::RoutingError, ErrorCode::UnknownEvent,
+                                                            "Unknown admin event");
+                    self.queue_object(event_loop, token, Arc::new(reply));
+                    return;
+                }
+
+                if object.is_event(Event::Ping) {
+                    let mut bad_tokens = Vec::new();
+                    let pong_key = RoutingKey::new(&[], Some(Event::Pong.as_str()), None);
+                    let decision = self.clients[token].subscription.as_ref()
+                        .is_some_and(|subscription| subscription.matches(&pong_key));
+
+                    let pong = ping_reply(&object);
+                    if decision {
+                        self.clients[token].queue(event_loop, pong)
+                            .unwrap_or_else(|e| {
+                                error!("Failed to queue message for {:?}: {:?}", token, e);
+                                bad_tokens.push(token)
+                            });
+                    }
+                    for t in bad_tokens {
+                        self.reset_connection(event_loop, t);
+                    }
+                } else {
+                    self.publish_from(event_loop, token, object);
+                }
+            },
+            None if early_publish => {
+                // There is no subscription a pong could be routed by
+                if !object.is_event(Event::Ping) {
+                    trace!("Routing {:?} from {:?}, which hasn't subscribed", &object, token);
+                    self.clients[token].last_activity = Instant::now();
+                    self.publish_from(event_loop, token, object);
+                }
+            },
+            None => {
+                trace!("Would subscribe {:?}", &object);
+                if !may_subscribe(&self.clients[token]) {
+                    debug!("Denied subscription from {:?}", token);
+                    let reply = access_denied_reply(&object, "Not allowed to subscribe");
+                    self.queue_object(event_loop, token, reply);
+                    return;
+                }
+                let object = without_unauthorized_router_id(&self.config.peer_routers, &self.clients[token], object);
+                match parse_subscription(&object, self.config.default_subscription.as_ref()) {
+                    Ok(subscription) => {
+                        info!("{:?} subscribed to {}", token, subscription);
+                        let announcement = {
+                            let client = &mut self.clients[token];
+                            let reply = subscription_reply(&subscription, &object, &client.routing_id);
+                            let _ = client.send_object(reply);
+                            client.stream.set_compression(requested_compression(&object));
+                            client.stream.set_encoding(requested_encoding(&object).unwrap_or(&JSON));
+                            client.subscription = Some(SubscriptionMatcher::new(subscription));
+                            client.peer_router = object.metadata.extra.contains_key("router-id");
+                            client.no_echo = wants_no_echo(&object);
+                            client.channels = requested_channels(&object);
+                            client.transform = Transform::requested(&object);
+                            client.last_activity = Instant::now();
+                            announcement(Event::RoutingAnnouncementConnect, client)
+                        };
+                        self.update_group(token, requested_group(&object).map(|group| group.to_string()));
+                        self.update_directory(token);
+                        let bus = self.clients[token].bus;
+                        self.announce(event_loop, bus, token, announcement);
+                    },
+                    Err(e) => {
+                        warn!("Rejected subscription from {:?}: {}", token, e);
+                        self.queue_object(event_loop, token, subscription_error_reply(&object, &e));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Publishes an object from the client, once the middlewares and its
+    /// permissions let it through.
+    fn publish_from(&mut self, event_loop: &mut EventLoop<Worker>, token: Token, object: Arc<BusinessObject>) {
+        let object = if self.config.stamp_received_at {
+            Arc::new(object.with_meta(clock::RECEIVED_AT_KEY, &self.shared.clock.now()))
+        } else {
+            object
+        };
+        let verdict = self.shared.middlewares.inbound(
+            &middleware_context(&self.config, &self.shared, &self.clients[token]), object);
+        match verdict {
+            Verdict::Reject(reply) => { self.queue_object(event_loop, token, reply); },
+            Verdict::Drop => { trace!("Middleware dropped an object from {:?}", token); },
+            Verdict::Pass(object) if !may_publish(&self.clients[token], &object) => {
+                debug!("Denied {:?} from {:?}", object, token);
+                let reply = access_denied_reply(&object, "Not allowed to publish");
+                self.queue_object(event_loop, token, reply);
+            },
+            Verdict::Pass(object) if !is_on_channel(&self.clients[token], &object) => {
+                debug!("Denied {:?} from {:?}, which isn't on its channel", object, token);
+                let reply = access_denied_reply(&object, "Not on the channel");
+                self.queue_object(event_loop, token, reply);
+            },
+            Verdict::Pass(object) => {
+                let exclude = if self.clients[token].no_echo {
+                    Some(self.client_id(token))
+                } else {
+                    None
+                };
+                let bus = self.clients[token].bus;
+                if let Some(object) = self.take_hop(event_loop, bus, object) {
+                    self.publish(event_loop, bus, object, exclude);
+                }
+            }
+        }
+    }
+
+    /// Takes a hop off the `ttl` of an object about to be routed on `bus`.
+    /// One already out of hops is dropped instead, or routed as a dead
+    /// letter if dead letters are on.
+    fn take_hop(&mut self, event_loop: &mut EventLoop<Worker>, bus: usize,
+                object: Arc<BusinessObject>) -> Option<Arc<BusinessObject>> {
+        match object.metadata.ttl {
+            None => Some(object),
+            Some(0) => {
+                debug!("Dropping {:?}: out of hops", object);
+                self.shared.metrics.hop_limit_drops.inc();
+                if self.config.dead_letters && !object.is_event(Event::RoutingDeadLetter) {
+                    self.shared.metrics.dead_letters.inc();
+                    self.route(event_loop, bus, dead_letter(&object, "Out of hops"), None);
+                }
+                None
+            },
+            Some(ttl) => {
+                let mut object = (*object).clone();
+                object.metadata.ttl = Some(ttl - 1);
+                Some(Arc::new(object))
+            }
+        }
+    }
+
+    /// Serves an `admin/*` request, which has to carry the configured admin
+    /// token in `admin-token`.
+    fn handle_admin(&mut self, event_loop: &mut EventLoop<Worker>,
+                    token: Token, request: Arc<BusinessObject>, event: Event) {
+        let mut reply = BusinessObject::reply_to(&request);
+        reply.event = Some(match event {
+            Event::AdminStats => Event::AdminStatsReply,
+            Event::AdminClients => Event::AdminClientsReply,
+            Event::AdminDrain => Event::AdminDrainReply,
+            _ => Event::AdminDisconnectReply
+        }.to_string());
+
+        let authorized = match (self.config.admin_token.as_ref(), request.meta_str("admin-token")) {
+            (Some(expected), Some(given)) => tokens_match(expected, given),
+            _ => false
+        };
+        if !authorized {
+            warn!("Refused {} from {:?}", event, self.clients[token]);
+            reply.set_meta("code", ErrorCode::InvalidToken.as_str());
+            reply.set_meta("error", "Not authorized");
+            self.queue_object(event_loop, token, Arc::new(reply));
+            return;
+        }
+
+        match event {
+            Event::AdminStats => {
+                reply.set_meta("router-id", &self.shared.router_id);
+                reply.set_meta("workers", &self.workers.len());
+                reply.set_meta("stats", &self.shared.metrics.to_json());
+            },
+            Event::AdminClients => {
+                let clients = &self.shared.buses[self.clients[token].bus].clients;
+                reply.set_meta("clients", &admin_clients_list(&clients.lock().unwrap(), &self.shared.metrics));
+            },
+            Event::AdminDrain => {
+                let timeout = request.meta_u64("timeout").unwrap_or(DEFAULT_DRAIN_TIMEOUT);
+                self.admin_drain(timeout);
+                reply.set_meta("timeout", &timeout);
+                let clients: usize = self.shared.buses.iter().map(|bus| bus.clients.lock().unwrap().len()).sum();
+                reply.set_meta("clients", &clients);
+            },
+            _ => {
+                if let Err(error) = self.admin_disconnect(event_loop, token, &request) {
+                    reply.set_meta("error", error);
+                }
+            }
+        }
+        self.queue_object(event_loop, token, Arc::new(reply));
+    }
+
+    /// Closes the connection of the client with the `worker` and `token` of
+    /// an `admin/disconnect` request, if it is on the requester's bus.
+    fn admin_disconnect(&mut self, event_loop: &mut EventLoop<Worker>, token: Token,
+                        request: &BusinessObject) -> Result<(), &'static str> {
+        let target = match (request.meta_u64("worker"), request.meta_u64("token")) {
+            (Some(worker), Some(token)) => ClientId { worker: worker as usize, token: Token(token as usize) },
+            _ => { return Err("worker and token are required"); }
+        };
+        if !self.shared.buses[self.clients[token].bus].clients.lock().unwrap().contains_key(&target) {
+            return Err("No such client");
+        }
+
+        info!("Disconnecting {:?} as requested by an admin", target);
+        if target.worker == self.worker {
+            self.reset_connection(event_loop, target.token);
+        } else if let Err(e) = self.workers[target.worker].send(Message::Disconnect(target.token)) {
+            warn!("Failed to pass disconnect to worker {}: {:?}", target.worker, e);
+            return Err("Failed to reach the client's worker");
+        }
+        Ok(())
+    }
+
+    /// Puts every worker into draining, giving clients `timeout` seconds
+    /// to leave, as asked by an `admin/drain` request.
+    fn admin_drain(&mut self, timeout: u64) {
+        info!("Draining within {} seconds as requested by an admin", timeout);
+        let deadline = Instant::now() + Duration::from_secs(timeout);
+        self.shared.draining.store(true, AtomicOrdering::SeqCst);
+        for (worker, sender) in self.workers.iter().enumerate() {
+            if worker == self.worker {
+                continue;
+            }
+            if let Err(e) = sender.send(Message::Drain(deadline)) {
+                warn!("Failed to tell worker {} to drain: {:?}", worker, e);
+            }
+        }
+        self.drain(deadline);
+    }
+
+    /// Sends a client the recent objects matching the `subscriptions` of the
+    /// request, or its own subscription, oldest first and followed by a
+    /// `history/replay/reply` with their `count`. `since` limits them to
+    /// that many seconds back, `filter` to those its expression matches and
+    /// `limit` to the most recent ones. Objects that wouldn't fit in the
+    /// send queue are left out and the reply is marked `truncated`.
+    fn replay_history(&mut self, event_loop: &mut EventLoop<Worker>,
+                      token: Token, request: Arc<BusinessObject>) {
+        let subscription = match request.metadata.extra.get("subscriptions") {
+            Some(rules) => match subscription::parse_subscription(rules) {
+                Ok(subscription) => SubscriptionMatcher::new(subscription),
+                Err(e) => {
+                    let reply = history_replay_reply(&request, 0, false, Some(&e.to_string()));
+                    self.queue_object(event_loop, token, reply);
+                    return;
+                }
+            },
+            None => self.clients[token].subscription.clone().unwrap()
+        };
+        let filter = match request.meta_str("filter").map(Filter::parse) {
+            Some(Ok(filter)) => Some(filter),
+            Some(Err(e)) => {
+                let reply = history_replay_reply(&request, 0, false, Some(&e.to_string()));
+                self.queue_object(event_loop, token, reply);
+                return;
+            },
+            None => None
+        };
+        let client = &self.clients[token];
+        let now = self.shared.clock.now();
+        let since = request.meta_u64("since")
+            .map(|seconds| now - Duration::from_secs(seconds));
+        // Objects naming their recipients are replayed to them alone, even
+        // to other routers
+        let filter = |object: &BusinessObject| {
+            if object.is_expired(now) {
+                return false;
+            }
+            let key = RoutingKey::of(object);
+            object.metadata.to.as_ref().is_none_or(|recipients| is_named(client, recipients)) &&
+                metadata::is_on_channel(object.metadata.channel.as_deref(), client.channels.as_deref()) &&
+                subscription.matches_object(&key, object) &&
+                client.permissions.as_ref().is_none_or(|permissions| permissions.may_receive(&key)) &&
+                filter.as_ref().is_none_or(|filter| filter.matches(object))
+        };
+        let history = &self.shared.buses[self.clients[token].bus].history;
+        let scanned = history.lock().unwrap().scan_matching(since, now, &filter);
+        let mut objects: Vec<Arc<BusinessObject>> = match scanned {
+            Ok(records) => records.into_iter().map(|(_, object)| object).collect(),
+            Err(e) => {
+                warn!("Failed to read history: {}", e);
+                let reply = history_replay_reply(&request, 0, false, Some(&e.to_string()));
+                self.queue_object(event_loop, token, reply);
+                return;
+            }
+        };
+
+        let wanted = match request.meta_u64("limit") {
+            Some(limit) => cmp::min(limit as usize, objects.len()),
+            None => objects.len()
+        };
+        let room = {
+            let client = &mut self.clients[token];
+            // Leave room for the reply
+            client.max_queue_length.saturating_sub(client.send_queue.len() + 1)
+        };
+        let count = cmp::min(wanted, room);
+
+        let skip = objects.len() - count;
+        for object in objects.drain(skip ..) {
+            self.queue_outbound(event_loop, token, object);
+        }
+        self.queue_object(event_loop, token, history_replay_reply(&request, count, count < wanted, None));
+    }
+
+    /// Replies to a `payload/fetch` with the payload of the object in the
+    /// history whose id is its `ticket`, if the client may still receive it
+    /// and is one of its recipients.
+    fn fetch_payload(&mut self, event_loop: &mut EventLoop<Worker>,
+                     token: Token, request: Arc<BusinessObject>) {
+        let ticket = match request.meta_str("ticket") {
+            Some(ticket) => ticket.to_string(),
+            None => {
+                let reply = BusinessObject::error_reply(&request, Event::RoutingError, ErrorCode::InvalidObject,
+                                                        "payload/fetch needs a ticket");
+                self.queue_object(event_loop, token, Arc::new(reply));
+                return;
+            }
+        };
+        let client = &self.clients[token];
+        let now = self.shared.clock.now();
+        let claimed = |object: &BusinessObject| {
+            object.id() == Some(ticket.as_str()) && object.payload.is_some() && !object.is_expired(now) &&
+                object.metadata.to.as_ref().is_none_or(|recipients| is_named(client, recipients)) &&
+                metadata::is_on_channel(object.metadata.channel.as_deref(), client.channels.as_deref()) &&
+                client.permissions.as_ref().is_none_or(|permissions| permissions.may_receive(&RoutingKey::of(object)))
+        };
+        let history = &self.shared.buses[self.clients[token].bus].history;
+        let found = match history.lock().unwrap().scan_matching(None, now, &claimed) {
+            Ok(mut records) => records.pop().map(|(_, object)| object),
+            Err(e) => {
+                warn!("Failed to read history: {}", e);
+                None
+            }
+        };
+        self.queue_object(event_loop, token, payload_fetch_reply(&request, found.as_deref()));
+    }
+
+    fn resubscribe(&mut self, event_loop: &mut EventLoop<Worker>,
+                   token: Token, object: Arc<BusinessObject>) {
+        if !may_subscribe(&self.clients[token]) {
+            debug!("Denied resubscription from {:?}", token);
+            self.queue_object(event_loop, token, access_denied_reply(&object, "Not allowed to subscribe"));
+            return;
+        }
+        let object = without_unauthorized_router_id(&self.config.peer_routers, &self.clients[token], object);
+        match parse_subscription(&object, self.config.default_subscription.as_ref()) {
+            Ok(subscription) => {
+                debug!("Replacing subscription of {:?} with {}", token, subscription);
+                let reply = {
+                    let client = &mut self.clients[token];
+                    let reply = subscription_reply(&subscription, &object, &client.routing_id);
+                    client.stream.set_compression(requested_compression(&object));
+                    client.stream.set_encoding(requested_encoding(&object).unwrap_or(&JSON));
+                    client.subscription = Some(SubscriptionMatcher::new(subscription));
+                    client.peer_router = object.metadata.extra.contains_key("router-id");
+                    client.no_echo = wants_no_echo(&object);
+                    client.channels = requested_channels(&object);
+                    client.transform = Transform::requested(&object);
+                    reply
+                };
+                self.update_group(token, requested_group(&object).map(|group| group.to_string()));
+                self.update_directory(token);
+                self.queue_object(event_loop, token, reply);
+            },
+            Err(e) => {
+                warn!("Rejected resubscription from {:?}: {}", token, e);
+                self.queue_object(event_loop, token, subscription_error_reply(&object, &e));
+            }
+        }
+    }
+}
+
+
+impl Handler for Worker {
+    type Timeout = Timer;
+    type Message = Message;
+
+    fn timeout(&mut self, event_loop: &mut EventLoop<Worker>, timer: Timer) {
+        match timer {
+            Timer::Periodical => {
+                self.periodical(event_loop);
+                self.check_shutdown(event_loop);
+
+                event_loop.timeout_ms(Timer::Periodical, PERIODICAL_INTERVAL_MS)
+                    .unwrap_or_else(|e| panic!("Failed to reschedule periodical timer: {:?}", e));
+            },
+            Timer::Unthrottle(token) => self.unthrottle(event_loop, token)
+        }
+    }
+
+    fn notify(&mut self, event_loop: &mut EventLoop<Worker>, message: Message) {
+        match message {
+            Message::Route(bus, object, exclude, deliveries) => {
+                let (matched, delivered) = self.route_locally(event_loop, bus, &object, exclude);
+                if let Some(deliveries) = deliveries {
+                    self.count_deliveries(event_loop, bus, &object, &deliveries, matched, delivered);
+                }
+            },
+            Message::Deliver(token, object) => self.queue_outbound(event_loop, token, object),
+            Message::Shutdown(deadline) => self.shutdown(event_loop, deadline),
+            Message::Drain(deadline) => self.drain(deadline),
+            Message::Reload(config) => self.reload(&config),
+            Message::Disconnect(token) => {
+                if self.clients.contains(token) {
+                    self.reset_connection(event_loop, token);
+                }
+            },
+        }
+        self.check_shutdown(event_loop);
+    }
+
+    fn ready(&mut self, event_loop: &mut EventLoop<Worker>, token: Token, events: EventSet) {
+        trace!("Events = {:?}", events);
+        assert!(token != Token(0), "[BUG]: Received event for Token(0)");
+
+        // The event disarmed the oneshot registration
+        if let Some(client) = self.clients.get_mut(token) {
+            client.armed = None;
+        }
+
+        if events.is_error() {
+            warn!("Error event for {:?}", token);
+            self.reset_connection(event_loop, token);
+            self.check_shutdown(event_loop);
+            return;
+        }
+
+        if events.is_hup() {
+            trace!("Hup event for {:?}", token);
+            if events.is_readable() && self.listener_index(token).is_none() {
+                self.read_remaining(event_loop, token);
+            }
+            self.reset_connection(event_loop, token);
+            self.check_shutdown(event_loop);
+            return;
+        }
+
+        // We never expect a write event for a listener token. A write event for any other token
+        // should be handed off to that connection.
+        if events.is_writable() {
+            trace!("Write event for {:?}", token);
+            assert!(self.listener_index(token).is_none(), "Received writable event for a listener");
+
+            let written = match client_for_token(self, token) {
+                Some(client) => client.writable()
+                    .and_then(|_| client.reregister(event_loop).map_err(WriteBusinessObjectError::WriteError)),
+                None => Ok(()),
+            };
+            if let Err(e) = written {
+                warn!("Write event failed for {:?}, {:?}", token, e);
+                self.reset_connection(event_loop, token);
+            }
+            if self.clients.get(token).is_some_and(|client| client.refused && client.is_flushed()) {
+                self.reset_connection(event_loop, token);
+                self.check_shutdown(event_loop);
+                return;
+            }
+        }
+
+        if events.is_readable() {
+            trace!("Read event for {:?}", token);
+            if let Some(index) = self.listener_index(token) {
+                self.new_client(event_loop, index);
+            } else if self.clients.contains(token) {
+                self.readable(event_loop, token)
+                    .and_then(|_| match client_for_token(self, token) {
+                        Some(client) => client.reregister(event_loop),
+                        // Handling the input disconnected the client
+                        None => Ok(()),
+                    })
+                    .unwrap_or_else(|e| {
+                        warn!("Read event failed for {:?}: {:?}", token, e);
+                        self.reset_connection(event_loop, token);
+                    });
+            }
+        }
+
+        self.check_shutdown(event_loop);
+    }
+}
+
+
+/// The connection underlying a `BusinessClient`.
+enum Transport {
+    Tcp(TcpStream),
+    WebSocket(WebSocketStream<TcpStream>),
+    Tls(Box<rustls::StreamOwned<rustls::ServerConnection, TcpStream>>),
+}
+
+
+impl Transport {
+    fn tcp_stream(&self) -> &TcpStream {
+        match *self {
+            Transport::Tcp(ref stream) => stream,
+            Transport::WebSocket(ref ws) => ws.get_ref(),
+            Transport::Tls(ref tls) => tls.get_ref(),
+        }
+    }
+
+    /// Limits WebSocket frames to what could carry one object of the
+    /// largest header and payload `config` allows.
+    fn apply_limits(&mut self, config: &Config) {
+        if let Transport::WebSocket(ref mut ws) = *self {
+            let max_frame_size = config.max_header_size.saturating_add(1).saturating_add(config.max_payload_size);
+            ws.set_max_frame_size(Some(max_frame_size));
+        }
+    }
+
+    /// Whether the transport holds part of a frame of its own, such as a
+    /// WebSocket frame, that the stream has yet to see anything of.
+    fn has_partial_frame(&self) -> bool {
+        match *self {
+            Transport::WebSocket(ref ws) => ws.has_partial_frame(),
+            Transport::Tcp(_) | Transport::Tls(_) => false,
+        }
+    }
+
+    /// Whether the transport holds input of its own, such as decoded
+    /// WebSocket messages, that the stream has yet to read.
+    fn has_buffered_input(&self) -> bool {
+        match *self {
+            Transport::WebSocket(ref ws) => ws.has_buffered_input(),
+            Transport::Tcp(_) | Transport::Tls(_) => false,
+        }
+    }
+
+    fn has_pending_output(&self) -> bool {
+        match *self {
+            Transport::Tcp(_) => false,
+            Transport::WebSocket(ref ws) => ws.has_pending_output(),
+            Transport::Tls(ref tls) => tls.conn.wants_write(),
+        }
+    }
+}
+
+
+impl Read for Transport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Transport::Tcp(ref mut stream) => stream.read(buf),
+            Transport::WebSocket(ref mut ws) => ws.read(buf),
+            Transport::Tls(ref mut tls) => tls.read(buf),
+        }
+    }
+}
+
+
+impl Write for Transport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Transport::Tcp(ref mut stream) => stream.write(buf),
+            Transport::WebSocket(ref mut ws) => ws.write(buf),
+            Transport::Tls(ref mut tls) => tls.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Transport::Tcp(ref mut stream) => stream.flush(),
+            Transport::WebSocket(ref mut ws) => ws.flush(),
+            Transport::Tls(ref mut tls) => tls.flush(),
+        }
+    }
+}
+
+
+/// An object being written to a client, which may take several writable
+/// events when the socket accepts only part of it at a time.
+struct WriteCursor {
+    bytes: Vec<u8>,
+    written: usize,
+}
+
+
+struct BusinessClient {
+    stream: BusinessObjectStream<Transport>,
+    token: Token,
+    interest: EventSet,
+    // The interest the socket's oneshot registration is armed with, `None`
+    // once an event has disarmed it
+    armed: Option<EventSet>,
+    send_queue: SendQueue,
+    // The object popped from the send queue that is partly written
+    write_cursor: Option<WriteCursor>,
+    max_queue_length: usize,
+    max_queue_age: Option<Duration>,
+
+    subscription: Option<SubscriptionMatcher>,
+    last_activity: Instant,
+    ping_sent: Option<Instant>,
+    // When it started the frame it has sent only part of, if any
+    partial_frame_since: Option<Instant>,
+
+    routing_id: String,
+    name: Option<String>,
+    user: Option<String>,
+    // Another router, which gets objects stamped with our `route`
+    peer_router: bool,
+    // Subscribed with `no-echo`, so its own objects aren't routed back to it
+    no_echo: bool,
+    // The shared subscription group it gets its share of objects through
+    group: Option<String>,
+    // The channels it declared, or None for the default channel
+    channels: Option<Vec<String>>,
+    // What to leave out of the objects delivered to it
+    transform: Transform,
+    // Logged in, or no login is required
+    authenticated: bool,
+    // The identity logged in as, if any
+    identity: Option<String>,
+    // What the access control rules allow, or None if there are none
+    permissions: Option<Permissions>,
+
+    rate_limiter: Option<RateLimiter>,
+    // Sent a warning since it last was within its rate limit
+    rate_limit_warned: bool,
+    // Connected while the router was draining or full, so closed once told
+    // that
+    refused: bool,
+    // Index of the bus it is on
+    bus: usize,
+
+    peer_addr: SocketAddr,
+    metrics: Arc<Metrics>,
+    stats: Arc<ConnectionStats>,
+}
+
+
+impl fmt::Debug for BusinessClient {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let subscription = match self.subscription {
+            Some(ref subscription) => subscription.subscription().to_string(),
+            None => "none".to_string()
+        };
+
+        write!(f, "BusinessClient(token: {}, idle: {} s, peer: {}, subscription: {}, \
+                   in: {} objects/{} bytes, out: {} objects/{} bytes, dropped: {}, last_error: {})",
+               self.token.as_usize(),
+               self.last_activity.elapsed().as_secs(),
+               self.peer_addr,
+               subscription,
+               self.stats.objects_received.get(),
+               self.stats.bytes_received.get(),
+               self.stats.objects_sent.get(),
+               self.stats.bytes_sent.get(),
+               self.stats.dropped.get(),
+               self.stats.last_error().unwrap_or_else(|| "none".to_string()))
+    }
+}
+
+
+impl BusinessClient {
+    fn new(mut socket: Transport, token: Token, peer_addr: SocketAddr, config: &Config,
+           metrics: Arc<Metrics>) -> BusinessClient {
+        metrics.connected_clients.inc();
+
+        socket.apply_limits(config);
+        let mut stream = BusinessObjectStream::new(socket);
+        stream.set_max_header_size(Some(config.max_header_size));
+        stream.set_max_payload_size(Some(config.max_payload_size));
+        stream.set_verify_checksums(config.verify_checksums);
+        stream.set_header_decoding(config.header_decoding);
+
+        let routing_id = Uuid::new_v4().hyphenated().to_string();
+        let stats = metrics.connection(&routing_id, &peer_addr.to_string());
+
+        BusinessClient {
+            peer_addr,
+
+            stream,
+            token: token,
+
+            interest: EventSet::hup(),
+            armed: None,
+
+            send_queue: SendQueue::new(),
+            write_cursor: None,
+            max_queue_length: config.max_queue_length,
+            max_queue_age: config.max_queue_age.map(Duration::from_secs),
+
+            subscription: Option::None,
+            last_activity: Instant::now(),
+            ping_sent: None,
+            partial_frame_since: None,
+
+            routing_id,
+            name: None,
+            user: None,
+            peer_router: false,
+            no_echo: false,
+            group: None,
+            channels: None,
+            transform: Transform::default(),
+            authenticated: !config.requires_login(),
+            identity: None,
+            permissions: Permissions::new(&config.acl, None, &peer_addr.ip()),
+
+            rate_limiter: RateLimiter::new(config.rate_limit_objects, config.rate_limit_bytes, Instant::now()),
+            rate_limit_warned: false,
+            refused: false,
+            bus: 0,
+
+            metrics,
+            stats,
+        }
+    }
+
+    fn read_objects(&mut self) -> Result<Vec<BusinessObject>, ReadBusinessObjectError> {
+        let bytes_read = self.stream.bytes_read();
+        let mut result = self.stream.read_business_objects();
+        // The socket won't become readable again for what the transport
+        // holds on to
+        while result.is_ok() && self.stream.socket.has_buffered_input() {
+            result = result.and_then(|mut objects| {
+                objects.extend(self.stream.read_business_objects()?);
+                Ok(objects)
+            });
+        }
+        let bytes_read = self.stream.bytes_read() - bytes_read;
+        self.metrics.bytes_received.add(bytes_read);
+        self.stats.bytes_received.add(bytes_read);
+        if let Ok(ref objects) = result {
+            self.metrics.objects_received.add(objects.len() as u64);
+            self.stats.objects_received.add(objects.len() as u64);
+            if let Some(ref mut limiter) = self.rate_limiter {
+                limiter.record(objects.len(), bytes_read as usize, Instant::now());
+            }
+            // A frame left over after others were finished is a new one
+            let partial = self.stream.has_partial_frame() || self.stream.socket.has_partial_frame();
+            self.partial_frame_since = match self.partial_frame_since {
+                _ if !partial => None,
+                Some(since) if objects.is_empty() => Some(since),
+                _ => Some(Instant::now()),
+            };
+        }
+
+        // Reading may produce protocol output of its own, e.g. handshakes
+        if self.stream.socket.has_pending_output() {
+            self.interest.insert(EventSet::writable());
+        }
+
+        result
+    }
+
+    fn writable(&mut self) -> Result<(), WriteBusinessObjectError> {
+        // Finish writing what the transport has buffered before handing it more
+        if self.stream.socket.has_pending_output() && !self.flush()? {
+            return Ok(());
+        }
+
+        let (now, wall_now) = (Instant::now(), Timestamp::now());
+        loop {
+            let mut cursor = match self.write_cursor.take() {
+                Some(cursor) => cursor,
+                None => match self.send_queue.pop() {
+                    Some((object, queued_at)) => {
+                        self.metrics.queued_objects.dec();
+                        if self.is_stale(&object, queued_at, now, wall_now) {
+                            debug!("Dropped stale object for {:?}", self.token);
+                            self.metrics.expired_objects.inc();
+                            self.stats.dropped.inc();
+                            continue;
+                        }
+                        WriteCursor { bytes: self.stream.encode(&object), written: 0 }
+                    },
+                    None => { break; }
+                }
+            };
+
+            let n = self.stream.write_partial(&cursor.bytes[cursor.written ..])?;
+            cursor.written += n;
+            self.metrics.bytes_sent.add(n as u64);
+            self.stats.bytes_sent.add(n as u64);
+            trace!("CONN : we wrote {} bytes", n);
+
+            if cursor.written < cursor.bytes.len() {
+                // Resume where we left off on the next writable event
+                trace!("Wrote {} of {} bytes to {:?}", cursor.written, cursor.bytes.len(), self.token);
+                self.write_cursor = Some(cursor);
+                return Ok(());
+            }
+            self.stats.objects_sent.inc();
+            debug!("Sent object to {:?}", self);
+            if !self.flush()? {
+                return Ok(());
+            }
+        }
+
+        if self.is_flushed() {
+            self.interest.remove(EventSet::writable());
+        }
+
+        Ok(())
+    }
+
+    /// Flushes the transport, returning whether it could do so without
+    /// blocking.
+    fn flush(&mut self) -> Result<bool, WriteBusinessObjectError> {
+        match self.stream.flush() {
+            Ok(()) => Ok(true),
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(WriteBusinessObjectError::WriteError(e))
+        }
+    }
+
+    /// Whether everything queued for the client has been written.
+    fn is_flushed(&self) -> bool {
+        self.send_queue.is_empty() && self.write_cursor.is_none() && !self.stream.socket.has_pending_output()
+    }
+
+    /// Whether `object` expired or has waited in the send queue for longer
+    /// than allowed.
+    fn is_stale(&self, object: &BusinessObject, queued_at: Instant, now: Instant, wall_now: Timestamp) -> bool {
+        object.is_expired(wall_now) || self.max_queue_age.is_some_and(|max_age| now - queued_at > max_age)
+    }
+
+    fn send_object(&mut self, object: Arc<BusinessObject>) -> Result<(), WriteBusinessObjectError> {
+        if object.is_expired(Timestamp::now()) {
+            debug!("Dropped expired object for {:?}", self.token);
+            self.metrics.expired_objects.inc();
+            self.stats.dropped.inc();
+            return Ok(());
+        }
+        if self.send_queue.len() >= self.max_queue_length {
+            let error = WriteBusinessObjectError::QueueFull(self.send_queue.len());
+            self.stats.dropped.inc();
+            self.stats.set_last_error(&error.to_string());
+            return Err(error);
+        }
+        // The router's own error replies tell what went wrong last
+        if let Some(code) = object.error_code() {
+            self.stats.set_last_error(&format!("{}: {}", code, object.meta_str("error").unwrap_or("")));
+        }
+
+        debug!("OUT({:?}): {}", self.peer_addr, object);
+        self.send_queue.push(object, Instant::now());
+        self.metrics.queued_objects.inc();
+        self.interest.insert(EventSet::writable());
+        Ok(())
+    }
+
+    fn register(&mut self, event_loop: &mut EventLoop<Worker>) -> io::Result<()> {
+        self.interest.insert(EventSet::readable());
+
+        event_loop.register_opt(self.stream.socket.tcp_stream(), self.token, self.interest, 
+                                PollOpt::edge() | PollOpt::oneshot()
+                                ).or_else(|e| {
+                                    error!("Failed to register {:?}, {:?}", self.token, e);
+                                    Err(e)
+                                })?;
+        self.armed = Some(self.interest);
+        Ok(())
+    }
+
+    /// Arms the registration with the current interest, unless it already
+    /// is, which spares a syscall per object routed to a busy client.
+    fn reregister(&mut self, event_loop: &mut EventLoop<Worker>) -> io::Result<()> {
+        if self.armed == Some(self.interest) {
+            return Ok(());
+        }
+
+        event_loop.reregister(self.stream.socket.tcp_stream(), self.token, self.interest,
+                              PollOpt::edge() | PollOpt::oneshot()
+                              ).or_else(|e| {
+                                  error!("Failed to reregister {:?}, {:?}", self.token, e);
+                                  Err(e)
+                              })?;
+        self.armed = Some(self.interest);
+        Ok(())
+    }
+
+    /// Queues `object` and makes sure the client is woken up to write it.
+    fn queue(&mut self, event_loop: &mut EventLoop<Worker>, object: Arc<BusinessObject>) -> Result<(), WriteBusinessObjectError> {
+        self.send_object(object)?;
+        self.reregister(event_loop).map_err(WriteBusinessObjectError::WriteError)
+    }
+}
+
+
+impl Drop for BusinessClient {
+    fn drop(&mut self) {
+        self.metrics.connected_clients.dec();
+        self.metrics.forget_connection(&self.routing_id);
+        self.metrics.queued_objects.add(-(self.send_queue.len() as i64));
+    }
+}
+
+
+/// The middleware checking published objects against the schemas of
+/// `schema`, if there are any.
+fn schema_validation(config: &Config) -> io::Result<Option<Box<dyn Middleware>>> {
+    if config.schemas.is_empty() {
+        return Ok(None);
+    }
+    let registry = SchemaRegistry::from_rules(&config.schemas).map_err(io::Error::other)?;
+    info!("Checking published objects against {} schema(s)", config.schemas.len());
+    Ok(Some(Box::new(SchemaValidation::new(registry))))
+}
+
+
+/// The middleware archiving published objects, if `archive` is set. It
+/// runs after all the others, so that it only sees what they let through.
+#[cfg(any(feature = "rusqlite", test))]
+fn archiver(config: &Config) -> io::Result<Option<Box<dyn Middleware>>> {
+    let path = match config.archive {
+        Some(ref path) => path,
+        None => { return Ok(None); }
+    };
+    let archive = Archive::open(path).map_err(|e| io::Error::other(format!("{}: {}", path.display(), e)))?;
+    info!("Archiving published objects to {}", path.display());
+    Ok(Some(Box::new(Archiver::new(archive))))
+}
+
+
+#[cfg(not(any(feature = "rusqlite", test)))]
+fn archiver(_config: &Config) -> io::Result<Option<Box<dyn Middleware>>> {
+    Ok(None)
+}
+
+
+/// Binds a listening socket. IPv6 sockets accept only IPv6, so that the
+/// same port can be bound for IPv4 separately.
+fn bind_listener(addr: &SocketAddr) -> io::Result<TcpListener> {
+    let socket = match *addr {
+        SocketAddr::V4(..) => TcpSocket::v4()?,
+        SocketAddr::V6(..) => {
+            let socket = TcpSocket::v6()?;
+            let only_v6: libc::c_int = 1;
+            let result = unsafe {
+                libc::setsockopt(socket.as_raw_fd(), libc::IPPROTO_IPV6, libc::IPV6_V6ONLY,
+                                 &only_v6 as *const libc::c_int as *const libc::c_void,
+                                 mem::size_of::<libc::c_int>() as libc::socklen_t)
+            };
+            if result != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            socket
+        }
+    };
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    socket.listen(1024)
+}
+
+
+/// What the signal handler of `rabboe`, or a test, uses to control a
+/// running server.
+#[derive(Clone)]
+pub struct ServerHandle {
+    workers: Vec<Sender<Message>>,
+    config: Arc<Mutex<Config>>,
+}
+
+
+impl ServerHandle {
+    /// Passes what can be changed in `new` without a restart to the workers,
+    /// warning about the rest.
+    pub fn reload(&self, new: Config) {
+        let mut config = self.config.lock().unwrap();
+        let (reloaded, needs_restart) = config.reload(new);
+        if !needs_restart.is_empty() {
+            warn!("Changes to {} take effect on restart", needs_restart.join(", "));
+        }
+
+        let for_workers = Arc::new(reloaded.clone());
+        for (worker, sender) in self.workers.iter().enumerate() {
+            if let Err(e) = sender.send(Message::Reload(for_workers.clone())) {
+                error!("Failed to pass configuration to worker {}: {:?}", worker, e);
+            }
+        }
+        *config = reloaded;
+    }
+
+    /// Asks the workers to stop accepting clients and to shut down once the
+    /// objects queued for their clients are sent, or the shutdown timeout
+    /// has passed.
+    pub fn shutdown(&self) {
+        let timeout = self.config.lock().unwrap().shutdown_timeout;
+        info!("Shutting down within {} seconds", timeout);
+        let deadline = Instant::now() + Duration::from_secs(timeout);
+        for (worker, sender) in self.workers.iter().enumerate() {
+            if let Err(e) = sender.send(Message::Shutdown(deadline)) {
+                error!("Failed to tell worker {} to shut down: {:?}", worker, e);
+            }
+        }
+    }
+}
+
+
+/// Sets up a `Server`. Anything not set here comes from the configuration
+/// it is created with.
+pub struct ServerBuilder {
+    config: Config,
+    router_id: Option<String>,
+    middlewares: Chain,
+    history_storage: Option<Box<dyn Fn() -> Box<dyn Storage>>>,
+    journal_storage: Option<Box<dyn Storage>>,
+}
+
+
+impl Default for ServerBuilder {
+    fn default() -> ServerBuilder {
+        ServerBuilder::new(Config::default())
+    }
+}
+
+
+impl ServerBuilder {
+    pub fn new(config: Config) -> ServerBuilder {
+        ServerBuilder {
+            config,
+            router_id: None,
+            middlewares: Chain::new(),
+            history_storage: None,
+            journal_storage: None,
+        }
+    }
+
+    /// Listens on `addrs` instead of the configured addresses. Port 0 binds
+    /// whichever port is free; `Server::local_addrs` tells which.
+    pub fn listen(mut self, addrs: &[SocketAddr]) -> ServerBuilder {
+        self.config.listen = addrs.to_vec();
+        self
+    }
+
+    pub fn workers(mut self, workers: usize) -> ServerBuilder {
+        self.config.workers = workers;
+        self
+    }
+
+    /// Identifies the router in the `route` of the objects it forwards.
+    /// By default a random id is made up on every start.
+    pub fn router_id(mut self, router_id: &str) -> ServerBuilder {
+        self.router_id = Some(router_id.to_string());
+        self
+    }
+
+    /// Adds a middleware to run after the router's own ones and those added
+    /// before it.
+    pub fn middleware(mut self, middleware: Box<dyn Middleware>) -> ServerBuilder {
+        self.middlewares.push(middleware);
+        self
+    }
+
+    /// Keeps the history of each bus in a storage `new_storage` makes
+    /// instead of in memory. The `history-*` limits are then up to the
+    /// storage.
+    pub fn history_storage<F: Fn() -> Box<dyn Storage> + 'static>(mut self, new_storage: F) -> ServerBuilder {
+        self.history_storage = Some(Box::new(new_storage));
+        self
+    }
+
+    /// Journals the objects routed on the first bus to `storage` instead of
+    /// to the configured `journal` directory. With `journal-replay` the
+    /// history is restored from it on start.
+    pub fn journal_storage(mut self, storage: Box<dyn Storage>) -> ServerBuilder {
+        self.journal_storage = Some(storage);
+        self
+    }
+
+    /// Binds the addresses and starts the workers, each on a thread of its
+    /// own. A configuration that doesn't validate fails with `InvalidInput`.
+    pub fn start(self) -> io::Result<Server> {
+        let config = self.config;
+        config.validate().map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        fn with_context<E: fmt::Display>(context: &dyn fmt::Display, e: E) -> io::Error {
+            io::Error::other(format!("{}: {}", context, e))
+        }
+
+        let mut sockets: Vec<(TcpListener, ListenerKind, usize)> = Vec::new();
+        for addr in &config.listen {
+            let socket = bind_listener(addr).map_err(|e| with_context(&format!("Failed to bind {}", addr), e))?;
+            sockets.push((socket, ListenerKind::Tcp, 0));
+        }
+        let local_addrs = sockets.iter().map(|(socket, _, _)| socket.local_addr()).collect::<io::Result<_>>()?;
+        let mut bus_addrs = Vec::new();
+        for (index, bus) in config.buses.iter().enumerate() {
+            let mut addrs = Vec::new();
+            for addr in &bus.listen {
+                let socket = bind_listener(addr).map_err(|e| with_context(&format!("Failed to bind {}", addr), e))?;
+                addrs.push(socket.local_addr()?);
+                sockets.push((socket, ListenerKind::Tcp, index + 1));
+            }
+            let listen: Vec<String> = addrs.iter().map(|addr| addr.to_string()).collect();
+            info!("Serving bus {} on {}", bus.name, listen.join(", "));
+            bus_addrs.push((bus.name.clone(), addrs));
+        }
+        let mut websocket_addr = None;
+        if let Some(ref addr) = config.websocket_listen {
+            let socket = bind_listener(addr).map_err(|e| with_context(&format!("Failed to bind {}", addr), e))?;
+            websocket_addr = Some(socket.local_addr()?);
+            info!("Accepting WebSocket clients on {}", socket.local_addr()?);
+            sockets.push((socket, ListenerKind::WebSocket, 0));
+        }
+
+        let mut tls_config = None;
+        if let Some(ref addr) = config.tls_listen {
+            let certificate = config.tls_certificate.as_ref().expect("TLS was validated with the configuration");
+            let private_key = config.tls_private_key.as_ref().expect("TLS was validated with the configuration");
+            tls_config = Some(tls::server_config(certificate, private_key).map_err(io::Error::other)?);
+
+            let socket = bind_listener(addr).map_err(|e| with_context(&format!("Failed to bind {}", addr), e))?;
+            info!("Accepting TLS clients on {}", socket.local_addr()?);
+            sockets.push((socket, ListenerKind::Tls, 0));
+        }
+
+        let event_loop_config = EventLoopConfig { notify_capacity: NOTIFY_CAPACITY, .. Default::default() };
+        let event_loops: Vec<EventLoop<Worker>> = (0 .. config.workers)
+            .map(|_| EventLoop::configured(event_loop_config))
+            .collect::<io::Result<_>>()?;
+        let senders: Vec<Sender<Message>> = event_loops.iter().map(|event_loop| event_loop.channel()).collect();
+        let object_log = match config.object_log {
+            Some(ref path) => Some(ObjectLog::open(path, config.object_log_sample_rate, config.object_log_payloads)
+                                   .map_err(|e| with_context(&path.display(), e))?),
+            None => None
+        };
+        let history_storage = self.history_storage;
+        let new_history = || match history_storage {
+            Some(ref new_storage) => new_storage(),
+            None => Box::new(History::new(config.history_max_objects, config.history_max_bytes,
+                                          config.history_max_age)) as Box<dyn Storage>
+        };
+        let mut journal = match (self.journal_storage, config.journal.as_ref()) {
+            (Some(storage), _) => Some(storage),
+            (None, Some(dir)) => {
+                let journal = Journal::open(dir, config.journal_segment_bytes, config.journal_segment_age)
+                    .map_err(|e| with_context(&dir.display(), e))?;
+                info!("Journaling routed objects to {}", journal.segment_path().display());
+                Some(Box::new(journal) as Box<dyn Storage>)
+            },
+            (None, None) => None
+        };
+        let mut history = new_history();
+        if let Some(journal) = journal.as_mut().filter(|_| config.journal_replay) {
+            let tail = journal.tail(config.history_max_objects, Timestamp::now())
+                .map_err(|e| with_context(&"Failed to read journal", e))?;
+            info!("Replaying {} object(s) from the journal into the history", tail.len());
+            for (routed, object) in tail {
+                history.append(object, routed)?;
+            }
+        }
+        let journal = journal.map(Mutex::new);
+        let mut middlewares = self.middlewares;
+        if let Some(validation) = schema_validation(&config)? {
+            middlewares.push(validation);
+        }
+        if let Some(archiver) = archiver(&config)? {
+            middlewares.push(archiver);
+        }
+        let mut buses = vec![BusRegistries::new(history)];
+        buses.extend(config.buses.iter().map(|_| BusRegistries::new(new_history())));
+        let shared = Arc::new(Shared {
+            buses,
+            object_log,
+            router_id: self.router_id.unwrap_or_else(|| Uuid::new_v4().hyphenated().to_string()),
+            journal,
+            middlewares,
+            .. Shared::default()
+        });
+
+        if let Some(ref addr) = config.metrics_listen {
+            info!("Serving metrics on http://{}/metrics", addr);
+            let listener = std::net::TcpListener::bind(addr).map_err(|e| with_context(&format!("Failed to bind {}", addr), e))?;
+            metrics::serve(listener, shared.metrics.clone())?;
+        }
+
+        let listen: Vec<String> = config.listen.iter().map(|addr| addr.to_string()).collect();
+        info!("Server starting on {} with {} worker(s)...", listen.join(", "), config.workers);
+        let mut threads = Vec::new();
+        for (index, mut event_loop) in event_loops.into_iter().enumerate() {
+            let sockets = sockets.iter()
+                .map(|&(ref socket, kind, bus)| Ok((socket.try_clone()?, kind, bus)))
+                .collect::<io::Result<_>>()?;
+            let mut worker = Worker::new(index, senders.clone(), shared.clone(), sockets,
+                                         config.clone(), tls_config.clone());
+            worker.register(&mut event_loop)?;
+            event_loop.timeout_ms(Timer::Periodical, PERIODICAL_INTERVAL_MS)
+                .map_err(|e| io::Error::other(format!("Failed to schedule periodical timer: {:?}", e)))?;
+
+            threads.push(thread::Builder::new().name(format!("worker-{}", index)).spawn(move || {
+                event_loop.run(&mut worker).expect("Failed to start event loop");
+            })?);
+        }
+
+        Ok(Server {
+            handle: ServerHandle { workers: senders, config: Arc::new(Mutex::new(config)) },
+            threads,
+            local_addrs,
+            bus_addrs,
+            websocket_addr,
+        })
+    }
+}
+
+
+/// A running router.
+pub struct Server {
+    handle: ServerHandle,
+    threads: Vec<thread::JoinHandle<()>>,
+    local_addrs: Vec<SocketAddr>,
+    bus_addrs: Vec<(String, Vec<SocketAddr>)>,
+    websocket_addr: Option<SocketAddr>,
+}
+
+
+impl Server {
+    /// The addresses the plain TCP listeners are bound to, in the order
+    /// they are in the configuration.
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    /// The addresses the listeners of the configured bus `name` are bound
+    /// to.
+    pub fn bus_addrs(&self, name: &str) -> Option<&[SocketAddr]> {
+        self.bus_addrs.iter().find(|(bus, _)| bus == name).map(|(_, addrs)| addrs.as_slice())
+    }
+
+    /// The address the WebSocket listener is bound to, if there is one.
+    pub fn websocket_addr(&self) -> Option<SocketAddr> {
+        self.websocket_addr
+    }
+
+    pub fn handle(&self) -> ServerHandle {
+        self.handle.clone()
+    }
+
+    /// Blocks until the workers have shut down.
+    pub fn run(self) {
+        for worker in self.threads {
+            if worker.join().is_err() {
+                error!("Worker panicked");
+            }
+        }
+        info!("Server shut down");
+    }
+}
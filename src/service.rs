@@ -0,0 +1,123 @@
+//! Providing a service on the bus from a function. `Service::serve`
+//! registers the service and answers each `services/request` for it with
+//! what the function makes of it:
+//!
+//! ```no_run
+//! use object_system::Client;
+//! use object_system::service::Service;
+//!
+//! let mut client = Client::connect("127.0.0.1:7890").unwrap();
+//! let error = Service::serve(&mut client, "echo", |request| request);
+//! eprintln!("Stopped serving: {}", error);
+//! ```
+//!
+//! The function returns the reply, or a `Result` whose error becomes an
+//! `error` reply. The reply is sent as a `services/reply` to the request,
+//! whatever event it had.
+
+use std::fmt;
+use std::time::Duration;
+
+use ::client::Client;
+use ::error::{ClientError, RoutingError};
+use ::events::Event;
+use ::object::BusinessObject;
+
+
+// How long to wait for the router to accept the registration
+const REGISTER_TIMEOUT: Duration = Duration::from_secs(10);
+
+
+/// What a service function may return.
+pub trait IntoReply {
+    fn into_reply(self) -> Result<BusinessObject, String>;
+}
+
+
+impl IntoReply for BusinessObject {
+    fn into_reply(self) -> Result<BusinessObject, String> {
+        Ok(self)
+    }
+}
+
+
+impl <E: fmt::Display> IntoReply for Result<BusinessObject, E> {
+    fn into_reply(self) -> Result<BusinessObject, String> {
+        self.map_err(|e| e.to_string())
+    }
+}
+
+
+pub struct Service<F> {
+    name: String,
+    handler: F,
+}
+
+
+impl <F, R> Service<F> where F: FnMut(BusinessObject) -> R, R: IntoReply {
+    /// Service `name`, answering requests with `handler`.
+    pub fn new(name: &str, handler: F) -> Service<F> {
+        Service { name: name.to_string(), handler }
+    }
+
+    /// Registers the service, and answers requests for it arriving at
+    /// `client` until receiving fails. Returns the error it stopped on.
+    pub fn serve(client: &mut Client, name: &str, handler: F) -> ClientError {
+        let mut service = Service::new(name, handler);
+        if let Err(e) = service.register(client) {
+            return e;
+        }
+        info!("Serving {}", name);
+
+        loop {
+            let object = match client.receive() {
+                Ok(object) => object,
+                Err(e) => { return e; }
+            };
+            if let Some(reply) = service.handle(object) {
+                if let Err(e) = client.send(&reply) {
+                    return e.into();
+                }
+            }
+        }
+    }
+
+    /// Asks the router to route requests for the service to `client`. One
+    /// already provided by another client fails with the
+    /// `ClientError::Routing` the router answered with.
+    pub fn register(&self, client: &mut Client) -> Result<(), ClientError> {
+        let mut register = BusinessObject::event(Event::ServicesRegister);
+        register.set_meta("name", &self.name);
+
+        let reply = client.request(&register, REGISTER_TIMEOUT)?;
+        match RoutingError::from_reply(&reply) {
+            Some(error) => Err(error.into()),
+            None => Ok(())
+        }
+    }
+
+    /// The reply to `object` if it is a request for the service.
+    pub fn handle(&mut self, object: BusinessObject) -> Option<BusinessObject> {
+        if !object.is_event(Event::ServicesRequest) || object.meta_str("name") != Some(self.name.as_str()) {
+            trace!("{} ignoring {:?}", self.name, object);
+            return None;
+        }
+
+        let in_reply_to = object.id().map(|id| id.to_string());
+        let mut reply = match (self.handler)(object).into_reply() {
+            Ok(reply) => reply,
+            Err(error) => {
+                debug!("{} failed: {}", self.name, error);
+                let mut reply = BusinessObject::event(Event::ServicesReply);
+                reply.set_meta("error", &error);
+                reply
+            }
+        };
+        reply.event = Some(Event::ServicesReply.to_string());
+        reply.metadata.in_reply_to = in_reply_to;
+        // An echoed request would keep its id otherwise
+        reply.metadata.id = None;
+        reply.set_meta("name", &self.name);
+        Some(reply)
+    }
+}
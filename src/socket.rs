@@ -0,0 +1,123 @@
+//! TCP socket options for the connections of routers and clients. Options
+//! left unset keep the system's defaults.
+
+use std::io;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+
+use libc;
+
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SocketOptions {
+    /// Whether small writes are sent right away instead of being delayed
+    /// to coalesce them (TCP_NODELAY, turning off Nagle's algorithm).
+    pub nodelay: Option<bool>,
+    /// Seconds a connection may be idle before TCP keepalive probes are
+    /// sent. Zero turns the probes off.
+    pub keepalive: Option<u32>,
+    /// Sizes of the kernel's send and receive buffers, in bytes.
+    pub send_buffer: Option<usize>,
+    pub recv_buffer: Option<usize>,
+}
+
+
+fn set_int<S: AsRawFd>(socket: &S, level: libc::c_int, name: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    let result = unsafe {
+        libc::setsockopt(socket.as_raw_fd(), level, name,
+                         &value as *const libc::c_int as *const libc::c_void,
+                         mem::size_of::<libc::c_int>() as libc::socklen_t)
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+const KEEPALIVE_IDLE: libc::c_int = libc::TCP_KEEPALIVE;
+#[cfg(not(any(target_os = "macos", target_os = "ios")))]
+const KEEPALIVE_IDLE: libc::c_int = libc::TCP_KEEPIDLE;
+
+
+impl SocketOptions {
+    /// These options, with those unset here taken from `defaults`.
+    pub fn or(&self, defaults: &SocketOptions) -> SocketOptions {
+        SocketOptions {
+            nodelay: self.nodelay.or(defaults.nodelay),
+            keepalive: self.keepalive.or(defaults.keepalive),
+            send_buffer: self.send_buffer.or(defaults.send_buffer),
+            recv_buffer: self.recv_buffer.or(defaults.recv_buffer),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == SocketOptions::default()
+    }
+
+    /// Sets the options on a connected socket.
+    pub fn apply<S: AsRawFd>(&self, socket: &S) -> io::Result<()> {
+        if let Some(nodelay) = self.nodelay {
+            set_int(socket, libc::IPPROTO_TCP, libc::TCP_NODELAY, nodelay as libc::c_int)?;
+        }
+        if let Some(seconds) = self.keepalive {
+            set_int(socket, libc::SOL_SOCKET, libc::SO_KEEPALIVE, (seconds > 0) as libc::c_int)?;
+            if seconds > 0 {
+                set_int(socket, libc::IPPROTO_TCP, KEEPALIVE_IDLE, seconds as libc::c_int)?;
+            }
+        }
+        if let Some(bytes) = self.send_buffer {
+            set_int(socket, libc::SOL_SOCKET, libc::SO_SNDBUF, bytes as libc::c_int)?;
+        }
+        if let Some(bytes) = self.recv_buffer {
+            set_int(socket, libc::SOL_SOCKET, libc::SO_RCVBUF, bytes as libc::c_int)?;
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+    use std::net::{TcpListener, TcpStream};
+    use std::os::unix::io::AsRawFd;
+
+    use libc;
+
+    use super::SocketOptions;
+
+
+    fn get_int(socket: &TcpStream, level: libc::c_int, name: libc::c_int) -> libc::c_int {
+        let mut value: libc::c_int = 0;
+        let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let result = unsafe {
+            libc::getsockopt(socket.as_raw_fd(), level, name,
+                             &mut value as *mut libc::c_int as *mut libc::c_void, &mut len)
+        };
+        assert_eq!(0, result);
+        value
+    }
+
+    #[test]
+    fn options_should_be_set_on_the_socket() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let socket = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+        let options = SocketOptions { nodelay: Some(true), keepalive: Some(30), .. SocketOptions::default() }
+            .or(&SocketOptions { nodelay: Some(false), recv_buffer: Some(64 * 1024), .. SocketOptions::default() });
+        assert_eq!(Some(true), options.nodelay);
+        options.apply(&socket).unwrap();
+
+        assert!(socket.nodelay().unwrap());
+        assert_eq!(1, get_int(&socket, libc::SOL_SOCKET, libc::SO_KEEPALIVE));
+        assert_eq!(30, get_int(&socket, libc::IPPROTO_TCP, super::KEEPALIVE_IDLE));
+        // The kernel may round the size up
+        assert!(get_int(&socket, libc::SOL_SOCKET, libc::SO_RCVBUF) >= 64 * 1024);
+
+        SocketOptions { nodelay: Some(false), keepalive: Some(0), .. SocketOptions::default() }.apply(&socket).unwrap();
+        assert!(!socket.nodelay().unwrap());
+        assert_eq!(0, get_int(&socket, libc::SOL_SOCKET, libc::SO_KEEPALIVE));
+    }
+}
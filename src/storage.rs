@@ -0,0 +1,73 @@
+//! Where the router keeps the objects it routes: the history clients catch
+//! up from and the journal kept for auditing. The router only appends
+//! objects to a storage and scans them back, so backends other than the
+//! in-memory `History` and the file-based `Journal`, a database say, can
+//! be plugged in with `ServerBuilder::history_storage` and
+//! `ServerBuilder::journal_storage`.
+
+use std::io;
+use std::sync::Arc;
+
+use ::object::BusinessObject;
+use ::timestamp::Timestamp;
+
+
+/// Objects with the times they were routed, oldest first.
+pub type Records = Vec<(Timestamp, Arc<BusinessObject>)>;
+
+
+pub trait Storage: Send {
+    /// Records `object` as routed at `routed`.
+    fn append(&mut self, object: Arc<BusinessObject>, routed: Timestamp) -> io::Result<()>;
+
+    /// The objects kept that were routed after `since`, or all of them.
+    /// Storages dropping objects as they age do so as of `now`.
+    fn scan(&mut self, since: Option<Timestamp>, now: Timestamp) -> io::Result<Records>;
+
+    /// The objects `scan` returns that `filter` accepts.
+    fn scan_matching(&mut self, since: Option<Timestamp>, now: Timestamp,
+                     filter: &dyn Fn(&BusinessObject) -> bool) -> io::Result<Records> {
+        let mut records = self.scan(since, now)?;
+        records.retain(|(_, object)| filter(object));
+        Ok(records)
+    }
+
+    /// The last `max_objects` objects kept, for restoring the history from
+    /// the journal on start.
+    fn tail(&mut self, max_objects: usize, now: Timestamp) -> io::Result<Records> {
+        let mut records = self.scan(None, now)?;
+        let skip = records.len().saturating_sub(max_objects);
+        records.drain(.. skip);
+        Ok(records)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{Records, Storage};
+    use ::history::History;
+    use ::object::BusinessObject;
+    use ::timestamp::Timestamp;
+
+
+    #[test]
+    fn scans_should_narrow_down_by_time_and_filter() {
+        let at = |seconds| Timestamp::from_secs(1_000_000 + seconds);
+        let mut storage: Box<dyn Storage> = Box::new(History::new(10, 1000, 60));
+        for (i, name) in ["chat/a", "news/b", "chat/c"].iter().enumerate() {
+            storage.append(Arc::new(BusinessObject::event(name)), at(i as i64)).unwrap();
+        }
+
+        let events = |records: Records| -> Vec<String> {
+            records.into_iter().map(|(_, object)| object.event.clone().unwrap()).collect()
+        };
+        assert_eq!(vec!["news/b", "chat/c"], events(storage.scan(Some(at(0)), at(2)).unwrap()));
+        let chat = |object: &BusinessObject| object.event.as_ref().unwrap().starts_with("chat/");
+        assert_eq!(vec!["chat/a", "chat/c"], events(storage.scan_matching(None, at(2), &chat).unwrap()));
+        assert_eq!(vec!["chat/c"], events(storage.tail(1, at(2)).unwrap()));
+        assert_eq!(vec![(at(2), Arc::new(BusinessObject::event("chat/c")))], storage.tail(1, at(2)).unwrap());
+    }
+}
@@ -1,22 +1,190 @@
+//! Subscriptions: the rules deciding which objects a client gets.
+//!
+//! A subscription is a list of rules, each optionally negated with `!`:
+//!
+//! * `*` matches every object.
+//! * `@pattern` matches the event, `#pattern` any of the natures and a bare
+//!   pattern the payload type, without its parameters.
+//! * `#a & #b` matches objects with every one of the listed natures.
+//! * `r:regex` matches the event or the type against a regex.
+//!
+//! Rules may also be JSON objects on a metadata field:
+//! `{"field": "user", "equals": "arkku"}` matches objects whose `user` is
+//! `"arkku"` and `{"field": "user", "exists": true}` those with a `user` at
+//! all. With `"negate": true` the rule excludes what it matches, like `!`.
+//! These see the metadata only where the router has the whole object at
+//! hand, routing to subscribers and replaying history; elsewhere, such as
+//! in ACL rules, they match nothing.
+//!
+//! Patterns are matched against `/`-separated names segment by segment. A
+//! literal segment matches only itself, `*` matches any one segment and `**`
+//! any number of segments, including none. A `*` ending the pattern matches
+//! like `**`, so `routing/*` covers `routing/announcement/connect` as well
+//! as `routing` itself, while `services/*/reply` covers
+//! `services/echo/reply` but not `services/reply` or
+//! `services/echo/reply/late`.
+//!
+//! Rules are evaluated in order and the last matching one wins, whether it
+//! is more or less specific than the others: `["@routing/*", "!@routing/**"]`
+//! matches no routing events, and an object no rule matches is not passed.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::error;
+use std::fmt;
+use std::slice;
+
+use regex::{Regex, RegexBuilder};
 use rustc_serialize::json::{Json, ToJson};
 
+use ::object::BusinessObject;
+
+
+/// Rules starting with this, optionally negated, are regexes matched against
+/// the event and the type of objects.
+pub const REGEX_RULE_PREFIX: &str = "r:";
+/// Longest regex accepted in a rule, in bytes.
+pub const MAX_REGEX_LENGTH: usize = 1024;
+// Bounds the memory a compiled regex rule may take
+const REGEX_SIZE_LIMIT: usize = 256 * 1024;
+
 
-#[derive(Eq, PartialEq, Debug, Clone)]
+#[derive(Debug, Clone)]
 pub enum BusinessSubscription {
     List(Vec<BusinessSubscription>),
-    String(String)
+    String(String),
+    /// A regex rule as given, with its regex compiled once per subscription.
+    Regex(String, Regex),
+    Field(FieldRule),
+}
+
+
+/// What a metadata field rule requires of the field.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldTest {
+    Equals(Json),
+    Exists(bool),
+}
+
+
+/// A rule on a metadata field, like `{"field": "user", "equals": "arkku"}`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldRule {
+    pub field: String,
+    pub test: FieldTest,
+    pub negated: bool,
+}
+
+
+impl FieldRule {
+    fn parse(rule: &Json) -> Result<FieldRule, BusinessSubscriptionError> {
+        let invalid = || BusinessSubscriptionError::InvalidRule(rule.to_string());
+        let fields = rule.as_object().ok_or_else(invalid)?;
+        if fields.keys().any(|key| !["field", "equals", "exists", "negate"].contains(&key.as_str())) {
+            return Err(invalid());
+        }
+
+        let field = fields.get("field").and_then(|field| field.as_string()).ok_or_else(invalid)?;
+        let test = match (fields.get("equals"), fields.get("exists")) {
+            (Some(value), None) => FieldTest::Equals(value.clone()),
+            (None, Some(&Json::Boolean(exists))) => FieldTest::Exists(exists),
+            _ => { return Err(invalid()); }
+        };
+        let negated = match fields.get("negate") {
+            Some(&Json::Boolean(negated)) => negated,
+            Some(_) => { return Err(invalid()); },
+            None => false
+        };
+        Ok(FieldRule { field: field.to_string(), test, negated })
+    }
+
+    /// Whether the field passes the test, whatever the negation.
+    pub fn matches(&self, object: &BusinessObject) -> bool {
+        let value = object.metadata.get(&self.field);
+        match self.test {
+            FieldTest::Equals(ref expected) => value.is_some_and(|value| match (value.as_f64(), expected.as_f64()) {
+                (Some(value), Some(expected)) => value == expected,
+                _ => value == *expected
+            }),
+            FieldTest::Exists(exists) => value.is_some() == exists,
+        }
+    }
 }
 
 
+impl ToJson for FieldRule {
+    fn to_json(&self) -> Json {
+        let mut fields = BTreeMap::new();
+        fields.insert("field".to_string(), self.field.to_json());
+        match self.test {
+            FieldTest::Equals(ref value) => { fields.insert("equals".to_string(), value.clone()); },
+            FieldTest::Exists(exists) => { fields.insert("exists".to_string(), exists.to_json()); }
+        }
+        if self.negated {
+            fields.insert("negate".to_string(), true.to_json());
+        }
+        Json::Object(fields)
+    }
+}
+
+
+impl PartialEq for BusinessSubscription {
+    fn eq(&self, other: &BusinessSubscription) -> bool {
+        match (self, other) {
+            (BusinessSubscription::List(a), BusinessSubscription::List(b)) => a == b,
+            (BusinessSubscription::String(a), BusinessSubscription::String(b)) => a == b,
+            (BusinessSubscription::Regex(a, _), BusinessSubscription::Regex(b, _)) => a == b,
+            (BusinessSubscription::Field(a), BusinessSubscription::Field(b)) => a == b,
+            _ => false
+        }
+    }
+}
+
+impl Eq for BusinessSubscription {}
+
+
 #[derive(Debug)]
 pub enum BusinessSubscriptionError {
     JsonTypeError(Json),
+    InvalidRule(String),
+    /// A regex rule and why its regex was rejected.
+    InvalidRegex(String, String),
     NoSubscriptionMetadataKey,
     SubscriptionNotEvent,
     UnknownSubscriptionEvent,
 }
 
 
+fn extract_reason(error: &BusinessSubscriptionError) -> &str {
+    match *error {
+        BusinessSubscriptionError::JsonTypeError(_) => "Subscriptions must be a rule or a list of rules",
+        BusinessSubscriptionError::InvalidRule(_) => "Invalid rule",
+        BusinessSubscriptionError::InvalidRegex(_, ref reason) => reason,
+        BusinessSubscriptionError::NoSubscriptionMetadataKey => "No subscriptions",
+        BusinessSubscriptionError::SubscriptionNotEvent => "Subscription has no event",
+        BusinessSubscriptionError::UnknownSubscriptionEvent => "Not a subscription event",
+    }
+}
+
+
+impl fmt::Display for BusinessSubscriptionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            BusinessSubscriptionError::InvalidRule(ref rule) => write!(f, "Invalid rule {:?}", rule),
+            BusinessSubscriptionError::InvalidRegex(ref rule, ref reason) =>
+                write!(f, "Invalid regex in rule {:?}: {}", rule, reason),
+            _ => write!(f, "{}", extract_reason(self))
+        }
+    }
+}
+
+impl error::Error for BusinessSubscriptionError {
+    fn description(&self) -> &str {
+        extract_reason(self)
+    }
+}
+
+
 impl ToJson for BusinessSubscription {
     fn to_json(&self) -> Json {
         match *self {
@@ -29,17 +197,83 @@ impl ToJson for BusinessSubscription {
 
                 result.to_json()
             },
-            BusinessSubscription::String(ref s) => {
+            BusinessSubscription::String(ref s) | BusinessSubscription::Regex(ref s, _) => {
                 s.to_json()
-            }
+            },
+            BusinessSubscription::Field(ref rule) => rule.to_json()
+        }
+    }
+}
+
+
+/// The rule as it is rendered canonically, with compound rules spaced
+/// evenly.
+fn canonical_rule(rule: &str) -> String {
+    if rule.contains('&') {
+        let (negation, rule) = match rule.strip_prefix('!') {
+            Some(rule) => ("!", rule),
+            None => ("", rule)
+        };
+        let terms: Vec<&str> = rule.split('&').map(|term| term.trim()).collect();
+        format!("{}{}", negation, terms.join(" & "))
+    } else {
+        rule.to_string()
+    }
+}
+
+
+impl fmt::Display for BusinessSubscription {
+    /// Renders the rules as a list of quoted strings, e.g.
+    /// `["*", "!#a & #b"]`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            BusinessSubscription::List(ref rules) => {
+                f.write_str("[")?;
+                for (i, rule) in rules.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{}", rule)?;
+                }
+                f.write_str("]")
+            },
+            BusinessSubscription::String(ref rule) => write!(f, "{}", Json::String(canonical_rule(rule))),
+            BusinessSubscription::Regex(ref rule, _) => write!(f, "{}", Json::String(rule.clone())),
+            BusinessSubscription::Field(ref rule) => write!(f, "{}", rule.to_json()),
         }
     }
 }
 
 
+impl BusinessSubscription {
+    /// The rules in the order they are evaluated.
+    pub fn rules(&self) -> &[BusinessSubscription] {
+        match *self {
+            BusinessSubscription::List(ref rules) => rules,
+            _ => slice::from_ref(self)
+        }
+    }
+
+    /// Whether an object with `event` and no natures or payload would be
+    /// routed to the subscriber.
+    pub fn matches_event(&self, event: &str) -> bool {
+        routing_decision(None, Some(event), None, self)
+    }
+}
+
+
 pub fn parse_subscription(subscription: &Json) -> Result<BusinessSubscription, BusinessSubscriptionError> {
     if subscription.is_string() {
-        Ok(BusinessSubscription::String(String::from(subscription.as_string().unwrap())))
+        let rule = subscription.as_string().unwrap();
+        if let Some(pattern) = rule.strip_prefix('!').unwrap_or(rule).strip_prefix(REGEX_RULE_PREFIX) {
+            return compile_regex_rule(rule, pattern).map(|regex| BusinessSubscription::Regex(rule.to_string(), regex));
+        }
+        if !is_valid_rule(rule) {
+            return Err(BusinessSubscriptionError::InvalidRule(rule.to_string()));
+        }
+        Ok(BusinessSubscription::String(String::from(rule)))
+    } else if subscription.is_object() {
+        FieldRule::parse(subscription).map(BusinessSubscription::Field)
     } else if subscription.as_array().is_some() {
         let array = subscription.as_array().unwrap();
 
@@ -65,26 +299,79 @@ pub fn parse_subscription(subscription: &Json) -> Result<BusinessSubscription, B
 }
 
 
-fn match_hierarchical(matcher: &str, matchable: &str) -> bool {
-    let matcher_parts: Vec<&str> = matcher.split('/').collect();
-    let matchable_parts: Vec<&str> = matchable.split('/').collect();
+fn compile_regex_rule(rule: &str, pattern: &str) -> Result<Regex, BusinessSubscriptionError> {
+    if pattern.len() > MAX_REGEX_LENGTH {
+        return Err(BusinessSubscriptionError::InvalidRegex(
+            rule.to_string(), format!("Regex longer than {} bytes", MAX_REGEX_LENGTH)));
+    }
 
-    for (index, matcher_part) in matcher_parts.iter().enumerate() {
-        if matcher_part == &"*" {
-            return true;
-        }
+    RegexBuilder::new(pattern)
+        .size_limit(REGEX_SIZE_LIMIT)
+        .build()
+        .map_err(|e| BusinessSubscriptionError::InvalidRegex(rule.to_string(), e.to_string()))
+}
 
-        if index >= matchable_parts.len() {
-            return false;
-        }
 
-        let matchable_part = matchable_parts[index];
-        if matcher_part != &matchable_part {
+/// A rule is an optional `!` negation followed by a non-empty pattern.
+/// Nature patterns can be joined with `&`, e.g. `#nature1 & #nature2`.
+fn is_valid_rule(rule: &str) -> bool {
+    let pattern = rule.strip_prefix('!').unwrap_or(rule);
+    if pattern.contains('&') {
+        return pattern.split('&').map(|term| term.trim()).all(|term| {
+            term.len() > 1 && term.starts_with('#') && !term.contains('!')
+        });
+    }
+
+    !pattern.is_empty() && !pattern.starts_with('!')
+}
+
+
+const ANY_SEGMENT: &str = "*";
+const ANY_SEGMENTS: &str = "**";
+
+
+/// Matches the segments of a name against the segments of a pattern, see
+/// the module documentation. Runs in time proportional to their product
+/// however many `**` there are, by only ever retrying from the latest one.
+fn match_segments<P: AsRef<str>>(pattern: &[P], parts: &[&str]) -> bool {
+    let is_any_segments = |p: usize| {
+        let segment = pattern[p].as_ref();
+        segment == ANY_SEGMENTS || (segment == ANY_SEGMENT && p == pattern.len() - 1)
+    };
+
+    let (mut p, mut m) = (0, 0);
+    // The latest `**` and the first part it hasn't yet tried to cover
+    let mut retry: Option<(usize, usize)> = None;
+    while m < parts.len() {
+        if p < pattern.len() && is_any_segments(p) {
+            retry = Some((p, m));
+            p += 1;
+        } else if p < pattern.len() && (pattern[p].as_ref() == ANY_SEGMENT || pattern[p].as_ref() == parts[m]) {
+            p += 1;
+            m += 1;
+        } else if let Some((any, covered)) = retry {
+            retry = Some((any, covered + 1));
+            p = any + 1;
+            m = covered + 1;
+        } else {
             return false;
         }
     }
 
-    true
+    while p < pattern.len() && is_any_segments(p) {
+        p += 1;
+    }
+    p == pattern.len()
+}
+
+
+/// Whether the hierarchical name `matchable`, such as an event, matches
+/// the pattern `matcher`, see the module documentation.
+pub fn match_hierarchical(matcher: &str, matchable: &str) -> bool {
+    let matcher_parts: Vec<&str> = matcher.split('/').collect();
+    let matchable_parts: Vec<&str> = matchable.split('/').collect();
+
+    match_segments(&matcher_parts, &matchable_parts)
 }
 
 
@@ -101,66 +388,58 @@ pub fn match_hierarchical_subscription(matcher: BusinessSubscription,
 }
 
 
-fn subscription_vec_to_str_vec<'a>(subscription_vec: &'a Vec<BusinessSubscription>) -> Option<Vec<&str>> {
-    let mut result: Vec<&str> = Vec::new();
-
-    for item in subscription_vec.iter() {
-        match *item {
-            BusinessSubscription::String(ref s) => {
-                result.push(s);
-            },
-            _ => {
-                return None;
-            }
+fn rule_matches(rule: &str, natures: &Option<Vec<&str>>, event: Option<&str>,
+                payload_type: Option<&str>) -> bool {
+    if rule.contains('&') {
+        // Compound nature rule; the object must carry every listed nature
+        rule.split('&').all(|term| rule_matches(term.trim(), natures, event, payload_type))
+    } else if let Some(rule) = rule.strip_prefix('#') {
+        match *natures {
+            Some(ref nature_list) => nature_list.iter().any(|nature| match_hierarchical(rule, nature)),
+            None => false
+        }
+    } else if let Some(rule) = rule.strip_prefix('@') {
+        match event {
+            Some(event) => match_hierarchical(rule, event),
+            None => false
+        }
+    } else if rule == "*" {
+        true
+    } else {
+        match payload_type {
+            Some(payload_type) => match_hierarchical(rule, payload_type),
+            None => false
         }
     }
-
-    Some(result)
 }
 
 
+/// Rules are evaluated in order and the last matching rule wins; a rule
+/// prefixed with `!` excludes the objects it matches.
 fn routing_decision_aux(natures: Option<Vec<&str>>, event: Option<&str>, payload_type: Option<&str>,
                         subscription_rules: &Vec<BusinessSubscription>) -> bool {
-    let rules_opt = subscription_vec_to_str_vec(&subscription_rules);
-    if rules_opt.is_none() {
-        return false;
-    }
-    let rules = rules_opt.unwrap();
-
     let mut pass = false;
 
-    for mut rule in rules {
-        let is_negative_rule = rule.starts_with("!");
-        if is_negative_rule {
-            rule = &rule[1..rule.len()];
-        }
-
-        if rule.starts_with("#") {
-            rule = &rule[1..rule.len()];
-            match natures {
-                Some(ref nature_list) => {
-                    for nature in nature_list {
-                        if match_hierarchical(rule, nature) {
-                            pass = ! is_negative_rule;
-                            break;
-                        }
-                    }
-                },
-                None => {}
-            }
-        } else if rule.starts_with("@") {
-            rule = &rule[1..rule.len()];
-            match event {
-                Some(event) => {
-                    if match_hierarchical(rule, event) {
-                        pass = ! is_negative_rule
-                    }
-                },
-                None => {}
-            }
-        } else if rule == "*" || match payload_type { Some(payload_type) => match_hierarchical(rule, payload_type),
-                                                      None => false } {
-            pass = ! is_negative_rule
+    for item in subscription_rules {
+        let (rule, regex) = match *item {
+            BusinessSubscription::String(ref rule) => (rule, None),
+            BusinessSubscription::Regex(ref rule, ref regex) => (rule, Some(regex)),
+            // There is no metadata to look at
+            BusinessSubscription::Field(_) => { continue; },
+            BusinessSubscription::List(_) => { return false; }
+        };
+        let (is_negative_rule, rule) = match rule.strip_prefix('!') {
+            Some(rule) => (true, rule),
+            None => (false, rule.as_str())
+        };
+
+        let matches = match regex {
+            Some(regex) => event.is_some_and(|event| regex.is_match(event)) ||
+                payload_type.is_some_and(|payload_type| regex.is_match(payload_type)),
+            None => rule_matches(rule, &natures, event, payload_type)
+        };
+        if matches {
+            pass = ! is_negative_rule;
         }
     }
 
@@ -192,9 +471,206 @@ pub fn routing_decision(natures: Option<Vec<&str>>, event: Option<&str>, payload
 }
 
 
+/// The fields of an object that routing decisions depend on, with the type
+/// stripped of its parameters and the natures in order.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RoutingKey {
+    natures: Vec<String>,
+    event: Option<String>,
+    payload_type: Option<String>,
+}
+
+
+impl RoutingKey {
+    pub fn new(natures: &[&str], event: Option<&str>, payload_type: Option<&str>) -> RoutingKey {
+        let mut natures: Vec<String> = natures.iter().map(|nature| nature.to_string()).collect();
+        natures.sort();
+        natures.dedup();
+
+        RoutingKey {
+            natures,
+            event: event.map(|event| event.to_string()),
+            payload_type: payload_type.map(|payload_type| match payload_type.split_once(';') {
+                Some((base, _)) => base.trim().to_string(),
+                None => payload_type.to_string()
+            }),
+        }
+    }
+
+    pub fn of(object: &BusinessObject) -> RoutingKey {
+        let natures = object.natures();
+        let natures: Vec<&str> = natures.iter().map(|nature| nature.as_str()).collect();
+        RoutingKey::new(&natures, object.event.as_ref().map(|e| e.as_ref()),
+                        object._type.as_ref().map(|t| t.as_ref()))
+    }
+}
+
+
+/// A hierarchical pattern split into its segments.
+#[derive(Debug, Clone)]
+struct Pattern(Vec<String>);
+
+
+impl Pattern {
+    fn new(pattern: &str) -> Pattern {
+        Pattern(pattern.split('/').map(|part| part.to_string()).collect())
+    }
+
+    /// Same as `match_hierarchical`.
+    fn matches(&self, matchable: &str) -> bool {
+        let matchable_parts: Vec<&str> = matchable.split('/').collect();
+        match_segments(&self.0, &matchable_parts)
+    }
+}
+
+
+#[derive(Debug, Clone)]
+enum Matcher {
+    Any,
+    Event(Pattern),
+    Nature(Pattern),
+    Type(Pattern),
+    /// Every one of the matchers, for compound nature rules.
+    All(Vec<Matcher>),
+    Regex(Regex),
+    Field(FieldRule),
+}
+
+
+impl Matcher {
+    /// Same as `rule_matches`.
+    fn new(rule: &str) -> Matcher {
+        if rule.contains('&') {
+            Matcher::All(rule.split('&').map(|term| Matcher::new(term.trim())).collect())
+        } else if let Some(rule) = rule.strip_prefix('#') {
+            Matcher::Nature(Pattern::new(rule))
+        } else if let Some(rule) = rule.strip_prefix('@') {
+            Matcher::Event(Pattern::new(rule))
+        } else if rule == "*" {
+            Matcher::Any
+        } else {
+            Matcher::Type(Pattern::new(rule))
+        }
+    }
+
+    fn matches(&self, key: &RoutingKey) -> bool {
+        match *self {
+            Matcher::Any => true,
+            Matcher::Event(ref pattern) => key.event.as_ref().is_some_and(|event| pattern.matches(event)),
+            Matcher::Nature(ref pattern) => key.natures.iter().any(|nature| pattern.matches(nature)),
+            Matcher::Type(ref pattern) => key.payload_type.as_ref().is_some_and(|t| pattern.matches(t)),
+            Matcher::All(ref matchers) => matchers.iter().all(|matcher| matcher.matches(key)),
+            Matcher::Regex(ref regex) => key.event.as_ref().is_some_and(|event| regex.is_match(event)) ||
+                key.payload_type.as_ref().is_some_and(|t| regex.is_match(t)),
+            Matcher::Field(_) => false,
+        }
+    }
+
+    fn matches_object(&self, key: &RoutingKey, object: &BusinessObject) -> bool {
+        match *self {
+            Matcher::Field(ref rule) => rule.matches(object),
+            _ => self.matches(key)
+        }
+    }
+}
+
+
+// Decisions cached per matcher before the cache is started over
+const MAX_CACHED_DECISIONS: usize = 1024;
+
+
+/// A subscription compiled for routing, deciding as `routing_decision`
+/// does. Decisions are cached by `RoutingKey`, as most traffic repeats a
+/// few kinds of objects.
+#[derive(Debug, Clone)]
+pub struct SubscriptionMatcher {
+    subscription: BusinessSubscription,
+    // Rules with whether they are negated; `None` if the subscription
+    // can't match anything
+    rules: Option<Vec<(bool, Matcher)>>,
+    // Whether any rule looks at more than the routing key
+    has_field_rules: bool,
+    cache: RefCell<HashMap<RoutingKey, bool>>,
+}
+
+
+impl SubscriptionMatcher {
+    pub fn new(subscription: BusinessSubscription) -> SubscriptionMatcher {
+        let rules = match subscription {
+            BusinessSubscription::List(ref rules) => rules.iter().map(|rule| match *rule {
+                BusinessSubscription::String(ref rule) => Some(match rule.strip_prefix('!') {
+                    Some(rule) => (true, Matcher::new(rule)),
+                    None => (false, Matcher::new(rule))
+                }),
+                BusinessSubscription::Regex(ref rule, ref regex) =>
+                    Some((rule.starts_with('!'), Matcher::Regex(regex.clone()))),
+                BusinessSubscription::Field(ref rule) => Some((rule.negated, Matcher::Field(rule.clone()))),
+                BusinessSubscription::List(_) => None
+            }).collect(),
+            _ => None
+        };
+        let has_field_rules = subscription.rules().iter().any(|rule| matches!(*rule, BusinessSubscription::Field(_)));
+
+        SubscriptionMatcher { subscription, rules, has_field_rules, cache: RefCell::new(HashMap::new()) }
+    }
+
+    pub fn subscription(&self) -> &BusinessSubscription {
+        &self.subscription
+    }
+
+    pub fn matches(&self, key: &RoutingKey) -> bool {
+        if let Some(&decision) = self.cache.borrow().get(key) {
+            return decision;
+        }
+
+        let rules = match self.rules {
+            Some(ref rules) => rules,
+            None => { return false; }
+        };
+        let decision = rules.iter().fold(false, |pass, &(negative, ref matcher)| {
+            if matcher.matches(key) { !negative } else { pass }
+        });
+
+        let mut cache = self.cache.borrow_mut();
+        if cache.len() >= MAX_CACHED_DECISIONS {
+            cache.clear();
+        }
+        cache.insert(key.clone(), decision);
+        decision
+    }
+
+    /// Same as `matches`, but with metadata field rules looking at the
+    /// object `key` is of. Those decisions aren't cached.
+    pub fn matches_object(&self, key: &RoutingKey, object: &BusinessObject) -> bool {
+        if !self.has_field_rules {
+            return self.matches(key);
+        }
+
+        match self.rules {
+            Some(ref rules) => rules.iter().fold(false, |pass, &(negative, ref matcher)| {
+                if matcher.matches_object(key, object) { !negative } else { pass }
+            }),
+            None => false
+        }
+    }
+}
+
+
+impl ToJson for SubscriptionMatcher {
+    fn to_json(&self) -> Json {
+        self.subscription.to_json()
+    }
+}
+
+
 #[cfg(test)]
 mod tests {
-    use super::{BusinessSubscription, match_hierarchical_subscription, routing_decision};
+    use rustc_serialize::json::{Json, ToJson};
+
+    use super::{BusinessSubscription, BusinessSubscriptionError, MAX_REGEX_LENGTH, RoutingKey,
+                SubscriptionMatcher, match_hierarchical, match_hierarchical_subscription, parse_subscription,
+                routing_decision};
+    use ::object::BusinessObject;
 
     fn bs(bs: &str) -> BusinessSubscription {
         BusinessSubscription::String(bs.to_string())
@@ -232,6 +708,41 @@ mod tests {
                                                 bs("")));
     }
 
+    #[test]
+    fn match_hierarchical_should_match_wildcards_per_segment() {
+        let names = ["", "routing", "routing/subscribe", "routing/subscribe/reply",
+                     "routing/announcement/connect", "services", "services/reply", "services/echo/reply",
+                     "services/echo/reply/late", "services/a/b/reply", "chat/reply"];
+        let cases: &[(&str, &[&str])] = &[
+            ("*", &names),
+            ("**", &names),
+            ("routing", &["routing"]),
+            ("routing/*", &["routing", "routing/subscribe", "routing/subscribe/reply",
+                            "routing/announcement/connect"]),
+            ("routing/**", &["routing", "routing/subscribe", "routing/subscribe/reply",
+                             "routing/announcement/connect"]),
+            ("routing/*/reply", &["routing/subscribe/reply"]),
+            ("*/reply", &["services/reply", "chat/reply"]),
+            ("**/reply", &["routing/subscribe/reply", "services/reply", "services/echo/reply",
+                           "services/a/b/reply", "chat/reply"]),
+            ("services/*/reply", &["services/echo/reply"]),
+            ("services/**/reply", &["services/reply", "services/echo/reply", "services/a/b/reply"]),
+            ("services/*/*", &["services/reply", "services/echo/reply", "services/echo/reply/late",
+                               "services/a/b/reply"]),
+            ("*/*/reply/*", &["routing/subscribe/reply", "services/echo/reply", "services/echo/reply/late"]),
+            ("**/echo/**", &["services/echo/reply", "services/echo/reply/late"]),
+            ("**/b/**/reply", &["services/a/b/reply"]),
+            ("services/x*", &[]),
+        ];
+
+        for &(pattern, matching) in cases {
+            for name in names.iter() {
+                assert_eq!(matching.contains(name), match_hierarchical(pattern, name),
+                           "{} against {:?}", pattern, name);
+            }
+        }
+    }
+
     #[test]
     fn routing_decision_should_work_with_events() {
         assert!(routing_decision(None,
@@ -279,4 +790,275 @@ mod tests {
                                  Some("text/plain"),
                                  &bs_list(vec!(bs("!text/*")))));
     }
+
+    #[test]
+    fn routing_decision_should_exclude_with_negative_rules() {
+        let everything_but_pings = bs_list(vec!(bs("*"), bs("!@ping")));
+
+        assert!(!
+                routing_decision(None,
+                                 Some("ping"),
+                                 None,
+                                 &everything_but_pings));
+
+        assert!(routing_decision(None,
+                                 Some("pong"),
+                                 None,
+                                 &everything_but_pings));
+
+        assert!(routing_decision(Some(vec!("hasselhoff")),
+                                 None,
+                                 Some("text/plain"),
+                                 &everything_but_pings));
+    }
+
+    #[test]
+    fn routing_decision_last_matching_rule_wins() {
+        assert!(routing_decision(None,
+                                 Some("routing/announcement"),
+                                 None,
+                                 &bs_list(vec!(bs("!@routing/*"), bs("@routing/announcement")))));
+
+        assert!(!
+                routing_decision(None,
+                                 Some("routing/announcement"),
+                                 None,
+                                 &bs_list(vec!(bs("@routing/announcement"), bs("!@routing/*")))));
+
+        assert!(!
+                routing_decision(None,
+                                 None,
+                                 Some("text/plain"),
+                                 &bs_list(vec!(bs("text/*"), bs("!*")))));
+
+        assert!(routing_decision(None,
+                                 None,
+                                 Some("text/plain"),
+                                 &bs_list(vec!(bs("!*"), bs("text/*")))));
+    }
+
+    #[test]
+    fn routing_decision_negative_rule_alone_passes_nothing() {
+        assert!(!
+                routing_decision(None,
+                                 Some("pong"),
+                                 Some("text/plain"),
+                                 &bs_list(vec!(bs("!@ping")))));
+    }
+
+    #[test]
+    fn routing_decision_non_matching_negative_rule_keeps_decision() {
+        assert!(routing_decision(Some(vec!("hasselhoff")),
+                                 None,
+                                 Some("image/png"),
+                                 &bs_list(vec!(bs("#hasselhoff"), bs("!text/*")))));
+
+        assert!(!
+                routing_decision(Some(vec!("hasselhoff", "knightrider")),
+                                 None,
+                                 None,
+                                 &bs_list(vec!(bs("*"), bs("!#knightrider")))));
+    }
+
+    #[test]
+    fn routing_decision_should_require_all_natures_of_compound_rules() {
+        let both = bs_list(vec!(bs("#hasselhoff & #knightrider")));
+        let cases: Vec<(Vec<&str>, bool)> = vec![
+            (vec!("hasselhoff", "knightrider"), true),
+            (vec!("knightrider", "baywatch", "hasselhoff"), true),
+            (vec!("hasselhoff"), false),
+            (vec!("knightrider"), false),
+            (vec!(), false),
+        ];
+
+        for (natures, expected) in cases {
+            assert_eq!(expected, routing_decision(Some(natures.clone()), None, None, &both),
+                       "natures {:?}", natures);
+        }
+        assert!(!routing_decision(None, None, None, &both));
+    }
+
+    #[test]
+    fn routing_decision_should_negate_compound_rules_as_a_whole() {
+        let not_both = bs_list(vec!(bs("*"), bs("!#hasselhoff&#knightrider")));
+
+        assert!(routing_decision(Some(vec!("hasselhoff")), None, None, &not_both));
+        assert!(!routing_decision(Some(vec!("hasselhoff", "knightrider")), None, None, &not_both));
+    }
+
+    #[test]
+    fn parse_subscription_should_accept_compound_nature_rules() {
+        let json = Json::from_str(r##"["#a & #b", "!#a&#b&#c/*"]"##).unwrap();
+
+        assert_eq!(bs_list(vec!(bs("#a & #b"), bs("!#a&#b&#c/*"))),
+                   parse_subscription(&json).unwrap());
+    }
+
+    #[test]
+    fn parse_subscription_should_reject_malformed_compound_rules() {
+        for rule in &["#a &", "& #a", "#a & & #b", "#a & @ping", "#a & text/*", "#a & !#b", "#a & #"] {
+            match parse_subscription(&Json::String(rule.to_string())) {
+                Err(BusinessSubscriptionError::InvalidRule(_)) => {},
+                other => panic!("Expected InvalidRule for {}, got {:?}", rule, other)
+            }
+        }
+    }
+
+    #[test]
+    fn routing_decision_should_match_regex_rules_against_events_and_types() {
+        let replies = parse_subscription(&Json::from_str(r#"["r:^services/.+/reply$"]"#).unwrap()).unwrap();
+
+        assert!(routing_decision(None, Some("services/echo/reply"), None, &replies));
+        assert!(!routing_decision(None, Some("services/reply"), None, &replies));
+        assert!(!routing_decision(None, Some("services/echo/reply/extra"), None, &replies));
+
+        let images = parse_subscription(&Json::from_str(r#"["r:^image/(png|gif)$"]"#).unwrap()).unwrap();
+        assert!(routing_decision(None, None, Some("image/png; name=hoff.png"), &images));
+        assert!(!routing_decision(None, None, Some("image/jpeg"), &images));
+        assert!(!routing_decision(Some(vec!("image/png")), None, None, &images));
+    }
+
+    #[test]
+    fn routing_decision_should_negate_regex_rules() {
+        let no_replies = parse_subscription(&Json::from_str(r#"["*", "!r:/reply$"]"#).unwrap()).unwrap();
+
+        assert!(!routing_decision(None, Some("services/echo/reply"), None, &no_replies));
+        assert!(routing_decision(None, Some("services/echo/request"), None, &no_replies));
+    }
+
+    #[test]
+    fn parse_subscription_should_keep_regex_rules_as_given() {
+        let json = Json::from_str(r#"["@ping", "!r:^a+$"]"#).unwrap();
+        let subscription = parse_subscription(&json).unwrap();
+
+        match subscription {
+            BusinessSubscription::List(ref rules) => match rules[1] {
+                BusinessSubscription::Regex(ref rule, _) => assert_eq!("!r:^a+$", rule),
+                ref other => panic!("Expected a regex rule, got {:?}", other)
+            },
+            ref other => panic!("Expected a list, got {:?}", other)
+        }
+        assert_eq!(json, subscription.to_json());
+    }
+
+    #[test]
+    fn parse_subscription_should_report_invalid_regexes() {
+        let too_long = format!("r:{}", "a".repeat(MAX_REGEX_LENGTH + 1));
+        for rule in &["r:(unclosed", "!r:*", too_long.as_str(), r"r:(?:\w{100}){100}"] {
+            match parse_subscription(&Json::String(rule.to_string())) {
+                Err(ref e @ BusinessSubscriptionError::InvalidRegex(_, _)) => {
+                    assert!(e.to_string().starts_with("Invalid regex in rule"));
+                },
+                other => panic!("Expected InvalidRegex for {}, got {:?}", rule, other)
+            }
+        }
+    }
+
+    #[test]
+    fn subscriptions_should_display_canonically() {
+        let json = Json::from_str(r##"["*", "!#a&#b", "#x &  #y/*", "!r:^a+$", "@say \"hi\""]"##).unwrap();
+        let subscription = parse_subscription(&json).unwrap();
+
+        assert_eq!(r##"["*", "!#a & #b", "#x & #y/*", "!r:^a+$", "@say \"hi\""]"##, subscription.to_string());
+        assert_eq!("[]", bs_list(vec!()).to_string());
+        assert_eq!(r#""@ping""#, bs("@ping").to_string());
+    }
+
+    #[test]
+    fn subscriptions_should_list_their_rules_and_match_events() {
+        let subscription = parse_subscription(&Json::from_str(r#"["@chat/*", "!@chat/private"]"#).unwrap()).unwrap();
+
+        assert_eq!(&[bs("@chat/*"), bs("!@chat/private")], subscription.rules());
+        assert_eq!(&[bs("@ping")], bs("@ping").rules());
+        assert!(subscription.matches_event("chat/message"));
+        assert!(!subscription.matches_event("chat/private"));
+        assert!(!subscription.matches_event("ping"));
+    }
+
+    #[test]
+    fn parse_subscription_should_accept_negative_rules() {
+        let json = Json::from_str(r#"["*", "!@ping"]"#).unwrap();
+
+        assert_eq!(bs_list(vec!(bs("*"), bs("!@ping"))),
+                   parse_subscription(&json).unwrap());
+    }
+
+    #[test]
+    fn parse_subscription_should_reject_empty_negations() {
+        for rules in &[r#"["*", "!"]"#, r#"["*", ""]"#, r#"["!!@ping"]"#] {
+            let json = Json::from_str(rules).unwrap();
+
+            match parse_subscription(&json) {
+                Err(BusinessSubscriptionError::InvalidRule(_)) => {},
+                other => panic!("Expected InvalidRule for {}, got {:?}", rules, other)
+            }
+        }
+    }
+
+    #[test]
+    fn matcher_should_decide_as_routing_decision() {
+        let subscriptions = [r#"["*"]"#, r#"["@chat/*", "!@chat/private"]"#, r##"["#a & #b/*", "text/*"]"##,
+                             r##"["r:^chat/.*$", "!#spam"]"##, r#"["routing", "@"]"#, r#"[]"#,
+                             r##"["@**/subscribe", "#*/c", "*/png"]"##, r#"["**", "!@*/*/**"]"#];
+        let objects: &[(&[&str], Option<&str>, Option<&str>)] = &[
+            (&[], Some("chat/message"), Some("text/plain; charset=utf-8")),
+            (&["spam"], Some("chat/private"), None),
+            (&["b/c", "a"], None, Some("image/png")),
+            (&["a"], Some("routing/subscribe"), Some("routing")),
+            (&[], Some(""), None),
+            (&[], None, None),
+        ];
+
+        for subscription in subscriptions.iter() {
+            let subscription = parse_subscription(&Json::from_str(subscription).unwrap()).unwrap();
+            let matcher = SubscriptionMatcher::new(subscription.clone());
+
+            // Twice, the second time from the cache
+            for _ in 0 .. 2 {
+                for &(natures, event, payload_type) in objects {
+                    let key = RoutingKey::new(natures, event, payload_type);
+                    assert_eq!(routing_decision(Some(natures.to_vec()), event, payload_type, &subscription),
+                               matcher.matches(&key), "{:?} with {:?}", subscription, key);
+                }
+            }
+        }
+
+        assert!(!SubscriptionMatcher::new(bs("*")).matches(&RoutingKey::new(&[], Some("a"), None)));
+    }
+
+    #[test]
+    fn field_rules_should_match_on_metadata() {
+        let json = Json::from_str(r#"["@chat/*", {"field": "user", "equals": "arkku"},
+                                      {"field": "ttl", "equals": 1.0, "negate": true}, {"field": "bot", "exists": false}]"#)
+            .unwrap();
+        let subscription = parse_subscription(&json).unwrap();
+        assert_eq!(json, subscription.to_json());
+        let matcher = SubscriptionMatcher::new(subscription);
+
+        let object = |user: Option<&str>, ttl: Option<u64>| {
+            let mut object = BusinessObject::event("news/story");
+            object.metadata.ttl = ttl;
+            if let Some(user) = user {
+                object.set_meta("user", user);
+                object.set_meta("bot", &true);
+            }
+            object
+        };
+        let matches = |object: &BusinessObject| matcher.matches_object(&RoutingKey::of(object), object);
+        assert!(matches(&object(Some("arkku"), Some(2))));
+        assert!(!matches(&object(Some("arkku"), Some(1))));
+        assert!(!matches(&object(Some("otto"), None)));
+        assert!(matches(&object(None, None)));
+        // Without the object only the other rules apply
+        assert!(!matcher.matches(&RoutingKey::of(&object(Some("arkku"), None))));
+
+        for rule in &[r#"{"equals": 1}"#, r#"{"field": "a"}"#, r#"{"field": "a", "equals": 1, "exists": true}"#,
+                      r#"{"field": "a", "exists": 1}"#, r#"{"field": "a", "equals": 1, "negate": "yes"}"#,
+                      r#"{"field": "a", "equals": 1, "like": 2}"#] {
+            match parse_subscription(&Json::from_str(rule).unwrap()) {
+                Err(BusinessSubscriptionError::InvalidRule(_)) => {},
+                other => panic!("Expected InvalidRule for {}, got {:?}", rule, other)
+            }
+        }
+    }
 }
@@ -0,0 +1,229 @@
+//! A router running in-process over in-memory streams, for testing clients
+//! and flows through a router without binding sockets. It routes like
+//! `rabboe` does but knows only the essentials: subscribing, `no-echo`,
+//! answering pings and routing everything else to matching subscribers.
+
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use ::client::Client;
+use ::events::Event;
+use ::io::{BusinessObjectStream, NextObject};
+use ::object::BusinessObject;
+use ::subscription::{self, RoutingKey, SubscriptionMatcher};
+use ::transport::mem::{self, MemStream};
+
+
+struct Connection {
+    id: usize,
+    // Writes to the client; its reads happen on the connection's thread
+    stream: BusinessObjectStream<MemStream>,
+    subscription: Option<SubscriptionMatcher>,
+    no_echo: bool,
+}
+
+
+#[derive(Default)]
+struct State {
+    connections: Vec<Connection>,
+    next_id: usize,
+    routed: usize,
+}
+
+
+#[derive(Clone, Default)]
+pub struct TestRouter {
+    state: Arc<Mutex<State>>,
+}
+
+
+fn subscription_reply(request: &BusinessObject, id: usize) -> BusinessObject {
+    let mut reply = BusinessObject::reply_to(request);
+    reply.event = Some(Event::RoutingSubscribeReply.to_string());
+    if let Some(rules) = request.metadata.extra.get("subscriptions") {
+        reply.metadata.insert("subscriptions".to_string(), rules.clone());
+    }
+    reply.set_meta("routing-id", &format!("test-{}", id));
+    reply
+}
+
+
+impl State {
+    fn connection(&mut self, id: usize) -> Option<&mut Connection> {
+        self.connections.iter_mut().find(|connection| connection.id == id)
+    }
+
+    /// Writes `object` to the connection, dropping it if that fails.
+    fn send(&mut self, id: usize, object: &BusinessObject) {
+        let failed = match self.connection(id) {
+            Some(connection) => connection.stream.write_object(object).is_err(),
+            None => false
+        };
+        if failed {
+            self.connections.retain(|connection| connection.id != id);
+        }
+    }
+
+    fn handle(&mut self, from: usize, object: BusinessObject) {
+        let subscribed = self.connection(from).is_some_and(|connection| connection.subscription.is_some());
+        if !subscribed || object.is_event(Event::RoutingSubscribe) {
+            let rules = object.metadata.extra.get("subscriptions").map(subscription::parse_subscription);
+            match rules {
+                Some(Ok(rules)) => {
+                    if let Some(connection) = self.connection(from) {
+                        connection.subscription = Some(SubscriptionMatcher::new(rules));
+                        connection.no_echo = object.metadata.extra.get("no-echo")
+                            .and_then(|no_echo| no_echo.as_boolean()).unwrap_or(false);
+                    }
+                    self.send(from, &subscription_reply(&object, from));
+                },
+                _ => debug!("Test router dropping {:?} from unsubscribed connection {}", object, from)
+            }
+            return;
+        }
+
+        if object.is_event(Event::Ping) {
+            let mut pong = BusinessObject::reply_to(&object);
+            pong.event = Some(Event::Pong.to_string());
+            let key = RoutingKey::of(&pong);
+            if self.connection(from).is_some_and(|connection| {
+                connection.subscription.as_ref().is_some_and(|subscription| subscription.matches(&key))
+            }) {
+                self.send(from, &pong);
+            }
+            return;
+        }
+
+        let key = RoutingKey::of(&object);
+        let recipients: Vec<usize> = self.connections.iter()
+            .filter(|connection| !(connection.id == from && connection.no_echo))
+            .filter(|connection| connection.subscription.as_ref()
+                    .is_some_and(|subscription| subscription.matches_object(&key, &object)))
+            .map(|connection| connection.id)
+            .collect();
+        self.routed += recipients.len();
+        for id in recipients {
+            self.send(id, &object);
+        }
+    }
+}
+
+
+impl TestRouter {
+    pub fn new() -> TestRouter {
+        TestRouter::default()
+    }
+
+    /// Connects a new client, returning its end of the connection. The
+    /// router serves the connection on a thread of its own until either end
+    /// closes it.
+    pub fn connect(&self) -> MemStream {
+        let (client_end, router_end) = mem::pair();
+
+        let id = {
+            let mut state = self.state.lock().unwrap();
+            let id = state.next_id;
+            state.next_id += 1;
+            let writer = router_end.try_clone().expect("In-memory streams clone");
+            state.connections.push(Connection {
+                id,
+                stream: BusinessObjectStream::new(writer),
+                subscription: None,
+                no_echo: false,
+            });
+            id
+        };
+
+        let state = self.state.clone();
+        thread::spawn(move || {
+            let mut stream = BusinessObjectStream::new(router_end);
+            loop {
+                let object = match stream.next_object() {
+                    Ok(NextObject::Object(object)) => object,
+                    Ok(NextObject::Streamed(_, _)) => unreachable!("Streaming isn't enabled"),
+                    Err(e) => {
+                        debug!("Test router closing connection {}: {}", id, e);
+                        break;
+                    }
+                };
+                state.lock().unwrap().handle(id, object);
+            }
+
+            let mut state = state.lock().unwrap();
+            if let Some(connection) = state.connection(id) {
+                let _ = connection.stream.flush();
+                connection.stream.socket.shutdown();
+            }
+            state.connections.retain(|connection| connection.id != id);
+        });
+
+        client_end
+    }
+
+    /// Connects a new `Client`.
+    pub fn client(&self) -> Client {
+        Client::connect_in_memory(self.connect())
+    }
+
+    /// Number of connections currently open.
+    pub fn connections(&self) -> usize {
+        self.state.lock().unwrap().connections.len()
+    }
+
+    /// Number of times an object has been routed to a subscriber.
+    pub fn routed(&self) -> usize {
+        self.state.lock().unwrap().routed
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::TestRouter;
+    use ::events::Event;
+    use ::object::BusinessObject;
+
+
+    #[test]
+    fn should_route_requests_and_replies_between_clients() {
+        let router = TestRouter::new();
+        let mut requester = router.client();
+        let mut responder = router.client();
+        requester.subscribe(&["@echo/reply"]).unwrap();
+        responder.subscribe(&["@echo/request"]).unwrap();
+
+        let replying = thread::spawn(move || {
+            let request = responder.receive().unwrap();
+            let mut reply = BusinessObject::reply_to(&request);
+            reply.event = Some("echo/reply".to_string());
+            reply.set_meta("text", request.meta_str("text").unwrap());
+            responder.send(&reply).unwrap();
+        });
+
+        let mut request = BusinessObject::event("echo/request");
+        request.set_meta("text", "hello");
+        let reply = requester.request(&request, Duration::from_secs(5)).unwrap();
+        assert_eq!(Some("hello"), reply.meta_str("text"));
+        replying.join().unwrap();
+        assert_eq!(2, router.routed());
+
+        // Pings are answered by the router, not routed
+        let pong = requester.request(&BusinessObject::event(Event::Ping), Duration::from_millis(100));
+        assert!(pong.is_err());
+        requester.subscribe(&["@echo/reply", "@pong"]).unwrap();
+        let pong = requester.request(&BusinessObject::event(Event::Ping), Duration::from_secs(5)).unwrap();
+        assert!(pong.is_event(Event::Pong));
+        assert_eq!(2, router.routed());
+
+        drop(requester);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while router.connections() > 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(0, router.connections());
+    }
+}
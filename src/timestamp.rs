@@ -0,0 +1,205 @@
+//! Points in wall clock time as they appear in the protocol: in metadata
+//! like `expires`, and in the journal and history of objects routed. They
+//! are kept as milliseconds since the epoch, and read and written as such
+//! or as RFC 3339.
+//!
+//! How long clients have been idle and other intervals the router measures
+//! are read off `Instant`s instead, so that setting the system time doesn't
+//! disturb them.
+
+use std::fmt;
+use std::ops::{Add, Sub};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rustc_serialize::json::{Json, ToJson};
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp {
+    millis: i64,
+}
+
+
+// Days from 1970-01-01 to the given date of the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+
+// The year, month and day `days` from 1970-01-01
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    (year_of_era + era * 400 + if month <= 2 { 1 } else { 0 }, month, day)
+}
+
+
+/// The number `digits` spells, if they are all ASCII digits.
+fn number(digits: &str) -> Option<i64> {
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    digits.parse().ok()
+}
+
+
+impl Timestamp {
+    pub fn now() -> Timestamp {
+        Timestamp::from(SystemTime::now())
+    }
+
+    pub fn from_millis(millis: i64) -> Timestamp {
+        Timestamp { millis }
+    }
+
+    pub fn from_secs(seconds: i64) -> Timestamp {
+        Timestamp { millis: seconds.saturating_mul(1000) }
+    }
+
+    /// Unix time in seconds, fractions of a millisecond dropped.
+    pub fn from_secs_f64(seconds: f64) -> Timestamp {
+        Timestamp { millis: (seconds * 1000.0).floor() as i64 }
+    }
+
+    pub fn as_millis(&self) -> i64 {
+        self.millis
+    }
+
+    /// Parses timestamps like `2024-05-01T12:00:00Z` or
+    /// `2024-05-01T14:00:00.250+02:00`.
+    pub fn parse_rfc3339(timestamp: &str) -> Option<Timestamp> {
+        let bytes = timestamp.as_bytes();
+        if !timestamp.is_ascii() || bytes.len() < 20 || bytes[4] != b'-' || bytes[7] != b'-' ||
+            !(bytes[10] == b'T' || bytes[10] == b't') || bytes[13] != b':' || bytes[16] != b':' {
+            return None;
+        }
+        let (year, month, day) = (number(&timestamp[0 .. 4])?, number(&timestamp[5 .. 7])?,
+                                  number(&timestamp[8 .. 10])?);
+        let (hour, minute, second) = (number(&timestamp[11 .. 13])?, number(&timestamp[14 .. 16])?,
+                                      number(&timestamp[17 .. 19])?);
+        if !(1 ..= 12).contains(&month) || !(1 ..= 31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+            return None;
+        }
+
+        let mut rest = &timestamp[19 ..];
+        let mut millis = 0;
+        if let Some(fraction) = rest.strip_prefix('.') {
+            let digits = fraction.bytes().take_while(|b| b.is_ascii_digit()).count();
+            if digits == 0 {
+                return None;
+            }
+            millis = number(&format!("{:0<3}", &fraction[.. digits.min(3)]))?;
+            rest = &fraction[digits ..];
+        }
+        let offset_minutes = match rest {
+            "Z" | "z" => 0,
+            _ if rest.len() == 6 && &rest[3 .. 4] == ":" => {
+                let minutes = number(&rest[1 .. 3])? * 60 + number(&rest[4 .. 6])?;
+                match &rest[.. 1] {
+                    "+" => minutes,
+                    "-" => -minutes,
+                    _ => { return None; }
+                }
+            },
+            _ => { return None; }
+        };
+
+        let seconds = days_from_civil(year, month, day) * 86_400 + hour * 3600 + minute * 60 + second
+            - offset_minutes * 60;
+        Some(Timestamp { millis: seconds * 1000 + millis })
+    }
+
+    /// The timestamp in UTC, like `2024-05-01T12:00:00.250Z`.
+    pub fn to_rfc3339(&self) -> String {
+        let seconds = self.millis.div_euclid(1000);
+        let (year, month, day) = civil_from_days(seconds.div_euclid(86_400));
+        let second_of_day = seconds.rem_euclid(86_400);
+        format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z", year, month, day,
+                second_of_day / 3600, second_of_day / 60 % 60, second_of_day % 60, self.millis.rem_euclid(1000))
+    }
+
+    /// How long after `earlier` this is, or zero if it isn't.
+    pub fn duration_since(&self, earlier: Timestamp) -> Duration {
+        Duration::from_millis(self.millis.saturating_sub(earlier.millis).max(0) as u64)
+    }
+}
+
+
+impl From<SystemTime> for Timestamp {
+    fn from(time: SystemTime) -> Timestamp {
+        match time.duration_since(UNIX_EPOCH) {
+            Ok(since) => Timestamp { millis: since.as_millis() as i64 },
+            Err(e) => Timestamp { millis: -(e.duration().as_millis() as i64) }
+        }
+    }
+}
+
+
+impl Add<Duration> for Timestamp {
+    type Output = Timestamp;
+
+    fn add(self, duration: Duration) -> Timestamp {
+        Timestamp { millis: self.millis.saturating_add(duration.as_millis() as i64) }
+    }
+}
+
+
+impl Sub<Duration> for Timestamp {
+    type Output = Timestamp;
+
+    fn sub(self, duration: Duration) -> Timestamp {
+        Timestamp { millis: self.millis.saturating_sub(duration.as_millis() as i64) }
+    }
+}
+
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.to_rfc3339())
+    }
+}
+
+
+/// Milliseconds since the epoch, as stamped in metadata.
+impl ToJson for Timestamp {
+    fn to_json(&self) -> Json {
+        Json::I64(self.millis)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Timestamp;
+
+
+    #[test]
+    fn rfc_3339_should_round_trip_through_millis() {
+        let noon = Timestamp::from_millis(1_714_564_800_000);
+        assert_eq!(Some(noon), Timestamp::parse_rfc3339("2024-05-01T12:00:00Z"));
+        assert_eq!(Some(noon + Duration::from_millis(250)),
+                   Timestamp::parse_rfc3339("2024-05-01T14:00:00.2509+02:00"));
+        assert_eq!(Some(noon), Timestamp::parse_rfc3339("2024-05-01T09:30:00-02:30"));
+        assert_eq!("2024-05-01T12:00:00.250Z", (noon + Duration::from_millis(250)).to_rfc3339());
+        assert_eq!("1969-12-31T23:59:59.999Z", Timestamp::from_millis(-1).to_rfc3339());
+        assert_eq!(Some(Timestamp::from_millis(-1)), Timestamp::parse_rfc3339("1969-12-31T23:59:59.999Z"));
+
+        for invalid in &["2024-05-01 12:00:00Z", "2024-05-01T12:00:00", "2024-13-01T12:00:00Z",
+                         "2024-05-01T12:00:00.Z", "2024-05-01T12:00:00+0200", "tomorrow"] {
+            assert_eq!(None, Timestamp::parse_rfc3339(invalid));
+        }
+    }
+}
@@ -0,0 +1,146 @@
+//! Loading of rustls configurations for encrypted connections between
+//! clients and the router.
+
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use rustls;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls_pemfile;
+
+
+#[derive(Debug)]
+pub enum TlsError {
+    ReadError(io::Error),
+
+    NoCertificates,
+    NoPrivateKey,
+    RustlsError(rustls::Error),
+}
+
+
+fn extract_reason(error: &TlsError) -> &str {
+    match *error {
+        TlsError::ReadError(_) => "Read error",
+        TlsError::NoCertificates => "No certificates found",
+        TlsError::NoPrivateKey => "No private key found",
+        TlsError::RustlsError(_) => "Invalid TLS configuration",
+    }
+}
+
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            TlsError::ReadError(ref e) => write!(f, "{}: {}", extract_reason(self), e),
+            TlsError::RustlsError(ref e) => write!(f, "{}: {}", extract_reason(self), e),
+            _ => write!(f, "{}", extract_reason(self))
+        }
+    }
+}
+
+impl error::Error for TlsError {
+    fn description(&self) -> &str {
+        extract_reason(self)
+    }
+}
+
+
+fn provider() -> Arc<rustls::crypto::CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+
+fn read_certificates<P: AsRef<Path>>(path: P) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let mut reader = BufReader::new(File::open(path).map_err(TlsError::ReadError)?);
+    let certs = rustls_pemfile::certs(&mut reader)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(TlsError::ReadError)?;
+
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificates);
+    }
+    Ok(certs)
+}
+
+
+fn read_private_key<P: AsRef<Path>>(path: P) -> Result<PrivateKeyDer<'static>, TlsError> {
+    let mut reader = BufReader::new(File::open(path).map_err(TlsError::ReadError)?);
+
+    match rustls_pemfile::private_key(&mut reader).map_err(TlsError::ReadError)? {
+        Some(key) => Ok(key),
+        None => Err(TlsError::NoPrivateKey)
+    }
+}
+
+
+/// Builds a server configuration from a PEM certificate chain and the PEM
+/// private key belonging to it.
+pub fn server_config<P: AsRef<Path>>(certificate: P, private_key: P)
+                                     -> Result<Arc<rustls::ServerConfig>, TlsError> {
+    let certs = read_certificates(certificate)?;
+    let key = read_private_key(private_key)?;
+
+    rustls::ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+        .map(Arc::new)
+        .map_err(TlsError::RustlsError)
+}
+
+
+/// Builds a client configuration trusting the certificate authorities in the
+/// given PEM file.
+pub fn client_config<P: AsRef<Path>>(ca_certificates: P) -> Result<Arc<rustls::ClientConfig>, TlsError> {
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in read_certificates(ca_certificates)? {
+        roots.add(cert).map_err(TlsError::RustlsError)?;
+    }
+
+    rustls::ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map(|builder| Arc::new(builder.with_root_certificates(roots).with_no_client_auth()))
+        .map_err(TlsError::RustlsError)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{client_config, server_config, TlsError};
+
+
+    fn testdata(name: &str) -> String {
+        format!("{}/testdata/tls/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    #[test]
+    fn should_load_server_config() {
+        assert!(server_config(testdata("server.pem"), testdata("server.key")).is_ok());
+    }
+
+    #[test]
+    fn should_load_client_config() {
+        assert!(client_config(testdata("ca.pem")).is_ok());
+    }
+
+    #[test]
+    fn should_report_missing_key() {
+        match server_config(testdata("server.pem"), testdata("ca.pem")) {
+            Err(TlsError::NoPrivateKey) => {},
+            other => panic!("Expected NoPrivateKey, got {:?}", other.err())
+        }
+    }
+
+    #[test]
+    fn should_report_missing_files() {
+        match client_config(testdata("nonexistent.pem")) {
+            Err(TlsError::ReadError(_)) => {},
+            other => panic!("Expected ReadError, got {:?}", other.err())
+        }
+    }
+}
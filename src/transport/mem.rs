@@ -0,0 +1,177 @@
+//! In-process duplex streams, for connecting clients and routers without
+//! binding sockets. Reads block like they do on a `TcpStream`, with an
+//! optional timeout, and find the end of the stream once the other end is
+//! dropped or shut down.
+
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+
+struct PipeState {
+    bytes: VecDeque<u8>,
+    closed: bool,
+}
+
+
+/// Bytes going one way.
+struct Pipe {
+    state: Mutex<PipeState>,
+    readable: Condvar,
+}
+
+
+impl Pipe {
+    fn new() -> Arc<Pipe> {
+        Arc::new(Pipe {
+            state: Mutex::new(PipeState { bytes: VecDeque::new(), closed: false }),
+            readable: Condvar::new(),
+        })
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.readable.notify_all();
+    }
+}
+
+
+/// One end of a pair, shared by the handles `try_clone` makes of it.
+struct End {
+    incoming: Arc<Pipe>,
+    outgoing: Arc<Pipe>,
+}
+
+
+impl Drop for End {
+    fn drop(&mut self) {
+        self.incoming.close();
+        self.outgoing.close();
+    }
+}
+
+
+pub struct MemStream {
+    end: Arc<End>,
+    read_timeout: Option<Duration>,
+}
+
+
+/// Two streams connected to each other: what is written to one is read
+/// from the other.
+pub fn pair() -> (MemStream, MemStream) {
+    let (a_to_b, b_to_a) = (Pipe::new(), Pipe::new());
+    let a = End { incoming: b_to_a.clone(), outgoing: a_to_b.clone() };
+    let b = End { incoming: a_to_b, outgoing: b_to_a };
+
+    (MemStream { end: Arc::new(a), read_timeout: None }, MemStream { end: Arc::new(b), read_timeout: None })
+}
+
+
+impl MemStream {
+    /// Another handle to the same end, e.g. for writing from one thread
+    /// while reading from another. The end is closed once every handle to
+    /// it is dropped.
+    pub fn try_clone(&self) -> io::Result<MemStream> {
+        Ok(MemStream { end: self.end.clone(), read_timeout: self.read_timeout })
+    }
+
+    /// Reads waiting longer than `timeout` fail with `WouldBlock`, as they
+    /// do on a `TcpStream`. `None` waits forever.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        if timeout == Some(Duration::from_secs(0)) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Zero timeout"));
+        }
+        self.read_timeout = timeout;
+        Ok(())
+    }
+
+    /// Closes both directions, for every handle to both ends.
+    pub fn shutdown(&self) {
+        self.end.incoming.close();
+        self.end.outgoing.close();
+    }
+}
+
+
+impl Read for MemStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let pipe = &self.end.incoming;
+        let deadline = self.read_timeout.map(|timeout| Instant::now() + timeout);
+
+        let mut state = pipe.state.lock().unwrap();
+        while state.bytes.is_empty() && !state.closed && !buf.is_empty() {
+            state = match deadline {
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining == Duration::from_secs(0) {
+                        return Err(io::ErrorKind::WouldBlock.into());
+                    }
+                    pipe.readable.wait_timeout(state, remaining).unwrap().0
+                },
+                None => pipe.readable.wait(state).unwrap()
+            };
+        }
+
+        let n = buf.len().min(state.bytes.len());
+        for (byte, slot) in state.bytes.drain(.. n).zip(buf.iter_mut()) {
+            *slot = byte;
+        }
+        Ok(n)
+    }
+}
+
+
+impl Write for MemStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let pipe = &self.end.outgoing;
+        let mut state = pipe.state.lock().unwrap();
+        if state.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+
+        state.bytes.extend(buf);
+        pipe.readable.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::io;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::pair;
+
+
+    #[test]
+    fn should_carry_bytes_both_ways_until_dropped() {
+        let (mut a, mut b) = pair();
+        a.write_all(b"ping").unwrap();
+        let mut buf = [0; 8];
+        assert_eq!(4, b.read(&mut buf).unwrap());
+        assert_eq!(b"ping", &buf[.. 4]);
+
+        let mut writer = b.try_clone().unwrap();
+        let echo = thread::spawn(move || writer.write_all(b"pong").unwrap());
+        a.read_exact(&mut buf[.. 4]).unwrap();
+        assert_eq!(b"pong", &buf[.. 4]);
+        echo.join().unwrap();
+
+        a.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+        assert_eq!(io::ErrorKind::WouldBlock, a.read(&mut buf).unwrap_err().kind());
+
+        drop(b);
+        assert_eq!(0, a.read(&mut buf).unwrap());
+        assert_eq!(io::ErrorKind::BrokenPipe, a.write(b"x").unwrap_err().kind());
+    }
+}
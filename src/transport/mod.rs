@@ -0,0 +1,3 @@
+//! Byte streams objects can be sent over besides sockets.
+
+pub mod mem;
@@ -0,0 +1,600 @@
+//! Server side of the WebSocket protocol (RFC 6455) for clients, such as web
+//! browsers, that can't open raw TCP connections.
+//!
+//! `WebSocketStream` is a byte stream like any other socket, so it can be
+//! wrapped in a `BusinessObjectStream`. Binary messages carry the regular wire
+//! format (NUL-terminated JSON followed by the payload) and may split or
+//! combine objects freely. A text message carries exactly one object without
+//! payload and needs no terminating NUL.
+
+use std::cmp;
+use std::convert::TryFrom;
+use std::error;
+use std::fmt;
+use std::io::{Read, Write};
+use std::io;
+use std::str;
+
+use rustc_serialize::base64::{ToBase64, STANDARD};
+use sha1::Sha1;
+
+
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_HANDSHAKE_SIZE: usize = 8 * 1024;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+/// Close status for a message too big to process.
+const STATUS_TOO_BIG: u16 = 1009;
+
+
+pub struct WebSocketStream<S: Read + Write> {
+    inner: S,
+    handshake_done: bool,
+    closed: bool,
+    max_frame_size: Option<usize>,
+    // Inside a fragmented text message
+    in_text_message: bool,
+
+    // Bytes read from `inner` but not yet decoded
+    raw: Vec<u8>,
+    // Decoded message contents waiting to be read
+    decoded: Vec<u8>,
+    // Encoded frames waiting to be written to `inner`
+    out: Vec<u8>,
+}
+
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+
+fn invalid_data(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_string())
+}
+
+
+/// The error of a frame longer than the stream accepts, with its length.
+#[derive(Debug)]
+struct FrameTooLarge(usize);
+
+impl fmt::Display for FrameTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Frame of {} bytes is too large", self.0)
+    }
+}
+
+impl error::Error for FrameTooLarge {}
+
+
+/// Computes the `Sec-WebSocket-Accept` header value for a client key.
+pub fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(ACCEPT_GUID.as_bytes());
+    sha1.digest().bytes().to_base64(STANDARD)
+}
+
+
+fn find_subsequence(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+
+/// Parses an HTTP upgrade request and returns the response to send.
+fn handshake_response(request: &[u8]) -> Result<String, String> {
+    let request = str::from_utf8(request).map_err(|_| "Handshake is not valid UTF-8".to_string())?;
+    let mut lines = request.split("\r\n");
+
+    match lines.next() {
+        Some(line) if line.starts_with("GET ") => {},
+        _ => { return Err("Handshake is not a GET request".to_string()); }
+    }
+
+    let mut key = None;
+    let mut upgrade = false;
+    for line in lines {
+        let mut parts = line.splitn(2, ':');
+        let (name, value) = match (parts.next(), parts.next()) {
+            (Some(name), Some(value)) => (name.trim().to_lowercase(), value.trim()),
+            _ => continue
+        };
+
+        if name == "upgrade" {
+            upgrade = value.to_lowercase().contains("websocket");
+        } else if name == "sec-websocket-key" {
+            key = Some(value);
+        }
+    }
+
+    match key {
+        Some(key) if upgrade => Ok(format!("HTTP/1.1 101 Switching Protocols\r\n\
+                                            Upgrade: websocket\r\n\
+                                            Connection: Upgrade\r\n\
+                                            Sec-WebSocket-Accept: {}\r\n\r\n", accept_key(key))),
+        _ => Err("Not a WebSocket upgrade request".to_string())
+    }
+}
+
+
+fn encode_frame(opcode: u8, payload: &[u8], out: &mut Vec<u8>) {
+    out.push(0x80 | opcode);
+
+    let len = payload.len();
+    if len < 126 {
+        out.push(len as u8);
+    } else if len <= 0xFFFF {
+        out.push(126);
+        out.push((len >> 8) as u8);
+        out.push(len as u8);
+    } else {
+        out.push(127);
+        for shift in (0 .. 8).rev() {
+            out.push((len as u64 >> (shift * 8)) as u8);
+        }
+    }
+
+    out.extend_from_slice(payload);
+}
+
+
+/// Decodes one client frame from the start of `buffer`, returning it and the
+/// number of bytes it took, or `None` if the frame is incomplete. Frames
+/// announcing more than `max_size` bytes of payload are rejected as soon as
+/// their length has arrived.
+fn decode_frame(buffer: &[u8], max_size: usize) -> io::Result<Option<(Frame, usize)>> {
+    if buffer.len() < 2 {
+        return Ok(None);
+    }
+
+    let fin = buffer[0] & 0x80 != 0;
+    let opcode = buffer[0] & 0x0F;
+    if buffer[1] & 0x80 == 0 {
+        return Err(invalid_data("Client frames must be masked"));
+    }
+
+    let (len, mut pos): (usize, usize) = match buffer[1] & 0x7F {
+        126 => {
+            if buffer.len() < 4 { return Ok(None); }
+            (((buffer[2] as usize) << 8) | buffer[3] as usize, 4)
+        },
+        127 => {
+            if buffer.len() < 10 { return Ok(None); }
+            let len = buffer[2 .. 10].iter().fold(0u64, |acc, b| (acc << 8) | *b as u64);
+            (usize::try_from(len).unwrap_or(usize::MAX), 10)
+        },
+        len => (len as usize, 2)
+    };
+
+    if len > max_size {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, FrameTooLarge(len)));
+    }
+
+    let end = match (pos + 4).checked_add(len) {
+        Some(end) => end,
+        None => { return Err(io::Error::new(io::ErrorKind::InvalidData, FrameTooLarge(len))); }
+    };
+    if buffer.len() < end {
+        return Ok(None);
+    }
+
+    let mask = [buffer[pos], buffer[pos + 1], buffer[pos + 2], buffer[pos + 3]];
+    pos += 4;
+
+    let payload = buffer[pos .. end].iter().enumerate()
+        .map(|(i, b)| b ^ mask[i % 4])
+        .collect();
+
+    Ok(Some((Frame { fin, opcode, payload }, end)))
+}
+
+
+impl <S: Read + Write> WebSocketStream<S> {
+    /// Wraps a freshly accepted connection; the opening handshake is answered
+    /// as part of reading from the stream.
+    pub fn new(inner: S) -> WebSocketStream<S> {
+        WebSocketStream {
+            inner,
+            handshake_done: false,
+            closed: false,
+            max_frame_size: None,
+            in_text_message: false,
+            raw: Vec::new(),
+            decoded: Vec::new(),
+            out: Vec::new(),
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Limits the payload of a single frame. A client announcing a longer
+    /// one is sent a close frame and the read fails with `InvalidData`.
+    pub fn set_max_frame_size(&mut self, limit: Option<usize>) {
+        self.max_frame_size = limit;
+    }
+
+    /// Whether part of the handshake or of a frame has been read but the
+    /// rest hasn't.
+    pub fn has_partial_frame(&self) -> bool {
+        !self.raw.is_empty()
+    }
+
+    /// Whether decoded message contents are waiting to be read. The
+    /// underlying stream won't become readable again for them.
+    pub fn has_buffered_input(&self) -> bool {
+        !self.decoded.is_empty()
+    }
+
+    /// Whether encoded frames are waiting for the underlying stream to become
+    /// writable.
+    pub fn has_pending_output(&self) -> bool {
+        !self.out.is_empty()
+    }
+
+    fn flush_out(&mut self) -> io::Result<()> {
+        while !self.out.is_empty() {
+            match self.inner.write(&self.out)? {
+                0 => { return Err(io::Error::new(io::ErrorKind::WriteZero, "Failed to write frame")); },
+                n => { self.out.drain(.. n); }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Like flush_out(), but leaves whatever doesn't fit for a later write
+    fn try_flush_out(&mut self) -> io::Result<()> {
+        match self.flush_out() {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            result => result
+        }
+    }
+
+    /// Decodes whatever is complete in the raw buffer. Returns whether any
+    /// progress was made.
+    fn process(&mut self) -> io::Result<bool> {
+        if !self.handshake_done {
+            let end = match find_subsequence(&self.raw, b"\r\n\r\n") {
+                Some(pos) => pos + 4,
+                None => {
+                    if self.raw.len() > MAX_HANDSHAKE_SIZE {
+                        return Err(invalid_data("Handshake too large"));
+                    }
+                    return Ok(false);
+                }
+            };
+
+            match handshake_response(&self.raw[.. end]) {
+                Ok(response) => {
+                    self.out.extend_from_slice(response.as_bytes());
+                    self.raw.drain(.. end);
+                    self.handshake_done = true;
+                    self.try_flush_out()?;
+                    return Ok(true);
+                },
+                Err(reason) => {
+                    let _ = self.inner.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n");
+                    return Err(invalid_data(&reason));
+                }
+            }
+        }
+
+        let max_size = self.max_frame_size.unwrap_or(usize::MAX);
+        let (frame, consumed) = match decode_frame(&self.raw, max_size) {
+            Ok(Some(result)) => result,
+            Ok(None) => { return Ok(false); },
+            Err(e) => {
+                if e.get_ref().is_some_and(|inner| inner.is::<FrameTooLarge>()) {
+                    encode_frame(OPCODE_CLOSE, &STATUS_TOO_BIG.to_be_bytes(), &mut self.out);
+                    let _ = self.try_flush_out();
+                    self.closed = true;
+                }
+                return Err(e);
+            }
+        };
+        self.raw.drain(.. consumed);
+
+        match frame.opcode {
+            OPCODE_BINARY => {
+                self.decoded.extend_from_slice(&frame.payload);
+            },
+            OPCODE_TEXT => {
+                self.decoded.extend_from_slice(&frame.payload);
+                self.in_text_message = !frame.fin;
+                if frame.fin {
+                    self.decoded.push(b'\0');
+                }
+            },
+            OPCODE_CONTINUATION => {
+                self.decoded.extend_from_slice(&frame.payload);
+                if frame.fin && self.in_text_message {
+                    self.decoded.push(b'\0');
+                    self.in_text_message = false;
+                }
+            },
+            OPCODE_PING => {
+                encode_frame(OPCODE_PONG, &frame.payload, &mut self.out);
+                self.try_flush_out()?;
+            },
+            OPCODE_PONG => {},
+            OPCODE_CLOSE => {
+                let status = &frame.payload[.. cmp::min(2, frame.payload.len())];
+                encode_frame(OPCODE_CLOSE, status, &mut self.out);
+                self.try_flush_out()?;
+                self.closed = true;
+            },
+            _ => { return Err(invalid_data("Unknown frame opcode")); }
+        }
+
+        Ok(true)
+    }
+}
+
+
+impl <S: Read + Write> Read for WebSocketStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            // Decode every complete frame read so far, so that none waits in
+            // the raw buffer for more bytes to arrive
+            while !self.closed && self.process()? {}
+
+            if !self.decoded.is_empty() {
+                let n = cmp::min(buf.len(), self.decoded.len());
+                buf[.. n].copy_from_slice(&self.decoded[.. n]);
+                self.decoded.drain(.. n);
+                return Ok(n);
+            }
+
+            if self.closed {
+                return Ok(0);
+            }
+
+            let mut read_buf = [0; 16 * 1024];
+            match self.inner.read(&mut read_buf)? {
+                0 => { return Ok(0); },
+                n => { self.raw.extend_from_slice(&read_buf[.. n]); }
+            }
+        }
+    }
+}
+
+
+impl <S: Read + Write> Write for WebSocketStream<S> {
+    /// Sends `buf` as one binary message. Fails with `WouldBlock` while
+    /// earlier messages are still waiting to be written.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.handshake_done || self.closed {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "WebSocket not open"));
+        }
+
+        self.flush_out()?;
+        encode_frame(OPCODE_BINARY, buf, &mut self.out);
+        self.try_flush_out()?;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_out()?;
+        self.inner.flush()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read, Write};
+    use std::io;
+
+    use super::{accept_key, encode_frame, WebSocketStream};
+    use ::io::{BusinessObjectStream, ReadBusinessObject};
+
+
+    struct Pipe {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    const HANDSHAKE: &str = "GET /chat HTTP/1.1\r\n\
+                             Host: server.example.com\r\n\
+                             Upgrade: websocket\r\n\
+                             Connection: Upgrade\r\n\
+                             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                             Sec-WebSocket-Version: 13\r\n\r\n";
+
+    fn masked_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut unmasked = Vec::new();
+        encode_frame(opcode, payload, &mut unmasked);
+
+        let header_len = unmasked.len() - payload.len();
+        let mut frame = unmasked[.. header_len].to_vec();
+        if !fin {
+            frame[0] &= 0x7F;
+        }
+        frame[1] |= 0x80;
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    fn websocket(frames: &[Vec<u8>]) -> WebSocketStream<Pipe> {
+        let mut input = HANDSHAKE.as_bytes().to_vec();
+        for frame in frames {
+            input.extend_from_slice(frame);
+        }
+        WebSocketStream::new(Pipe { input: Cursor::new(input), output: Vec::new() })
+    }
+
+    fn read_all(ws: &mut WebSocketStream<Pipe>) -> Vec<u8> {
+        let mut result = Vec::new();
+        ws.read_to_end(&mut result).unwrap();
+        result
+    }
+
+    #[test]
+    fn accept_key_should_match_rfc_example() {
+        assert_eq!("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=", accept_key("dGhlIHNhbXBsZSBub25jZQ=="));
+    }
+
+    #[test]
+    fn should_answer_handshake() {
+        let mut ws = websocket(&[]);
+        assert!(read_all(&mut ws).is_empty());
+
+        let response = String::from_utf8(ws.get_ref().output.clone()).unwrap();
+        assert!(response.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+    }
+
+    #[test]
+    fn should_reject_non_websocket_requests() {
+        let request = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n".to_vec();
+        let mut ws = WebSocketStream::new(Pipe { input: Cursor::new(request), output: Vec::new() });
+
+        assert!(ws.read(&mut [0; 16]).is_err());
+        assert!(ws.get_ref().output.starts_with(b"HTTP/1.1 400"));
+    }
+
+    #[test]
+    fn should_reject_unmasked_frames() {
+        let mut frame = Vec::new();
+        encode_frame(0x2, b"abc", &mut frame);
+        let mut ws = websocket(&[frame]);
+
+        assert!(ws.read(&mut [0; 16]).is_err());
+    }
+
+    #[test]
+    fn should_reject_frames_longer_than_the_limit() {
+        let mut frame = vec![0x82, 0xFF];
+        frame.extend_from_slice(&[0xFF; 8]);
+        frame.extend_from_slice(&[0x12, 0x34, 0x56, 0x78]);
+        let mut ws = websocket(&[frame]);
+
+        let error = ws.read(&mut [0; 16]).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+        assert!(ws.is_closed());
+        assert!(ws.get_ref().output.ends_with(&[0x88, 2, 0x03, 0xF1]));
+
+        let mut ws = websocket(&[masked_frame(true, 0x2, &[b'w'; 300])]);
+        ws.set_max_frame_size(Some(200));
+        assert_eq!(io::ErrorKind::InvalidData, ws.read(&mut [0; 16]).unwrap_err().kind());
+    }
+
+    #[test]
+    fn should_tell_when_a_frame_is_partly_read() {
+        let frame = masked_frame(true, 0x2, b"hello");
+        let mut ws = websocket(&[frame[.. 4].to_vec()]);
+
+        assert!(read_all(&mut ws).is_empty());
+        assert!(ws.has_partial_frame());
+
+        let mut ws = websocket(&[frame]);
+        read_all(&mut ws);
+        assert!(!ws.has_partial_frame());
+    }
+
+    #[test]
+    fn should_unwrap_binary_frames() {
+        let mut ws = websocket(&[masked_frame(true, 0x2, b"hello "),
+                                 masked_frame(true, 0x2, &[b'w'; 300])]);
+
+        let mut expected = b"hello ".to_vec();
+        expected.extend_from_slice(&[b'w'; 300]);
+        assert_eq!(expected, read_all(&mut ws));
+    }
+
+    #[test]
+    fn should_decode_every_frame_read_together() {
+        let mut ws = websocket(&[masked_frame(true, 0x2, b"hello "),
+                                 masked_frame(true, 0x2, b"world")]);
+
+        let mut buf = [0; 64];
+        let n = ws.read(&mut buf).unwrap();
+        assert_eq!(b"hello world", &buf[.. n]);
+        assert!(!ws.has_partial_frame());
+    }
+
+    #[test]
+    fn should_terminate_text_messages_with_nul() {
+        let mut ws = websocket(&[masked_frame(false, 0x1, b"{\"event\": "),
+                                 masked_frame(true, 0x0, b"\"ping\"}")]);
+
+        assert_eq!(b"{\"event\": \"ping\"}\0".to_vec(), read_all(&mut ws));
+    }
+
+    #[test]
+    fn should_answer_pings_and_closes() {
+        let mut ws = websocket(&[masked_frame(true, 0x9, b"hi"),
+                                 masked_frame(true, 0x8, &[0x03, 0xE8]),
+                                 masked_frame(true, 0x2, b"ignored")]);
+
+        assert!(read_all(&mut ws).is_empty());
+        assert!(ws.is_closed());
+
+        let output = &ws.get_ref().output;
+        assert!(output.ends_with(&[0x8A, 2, b'h', b'i', 0x88, 2, 0x03, 0xE8]));
+    }
+
+    #[test]
+    fn should_wrap_writes_in_binary_frames() {
+        let mut ws = websocket(&[]);
+        read_all(&mut ws);
+        ws.get_mut().output.clear();
+
+        ws.write_all(b"abc").unwrap();
+        assert_eq!(vec![0x82, 3, b'a', b'b', b'c'], ws.get_ref().output);
+    }
+
+    #[test]
+    fn should_carry_business_objects() {
+        let mut ws = websocket(&[masked_frame(true, 0x1, br#"{"event": "routing/subscribe"}"#),
+                                 masked_frame(true, 0x2, b"{\"event\": \"foo\", \"size\": 3}\0ABC")]);
+        let mut objects = Vec::new();
+        {
+            let mut stream = BusinessObjectStream::new(&mut ws);
+            while objects.len() < 2 {
+                objects.extend(stream.read_business_objects().unwrap());
+            }
+        }
+
+        assert_eq!(Some("routing/subscribe".to_string()), objects[0].event);
+        assert_eq!(Some("foo".to_string()), objects[1].event);
+        assert_eq!(Some(3), objects[1].size);
+    }
+}
@@ -0,0 +1,126 @@
+//! Reading and writing the frames in `testdata/interop`, as the other
+//! object-system implementations write them. See the README there.
+
+extern crate object_system;
+extern crate rustc_serialize;
+
+use std::fs;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+use rustc_serialize::base64::FromBase64;
+use rustc_serialize::json::Json;
+
+use object_system::{BusinessObject, Payload};
+use object_system::io::{BusinessObjectStream, ReadBusinessObject};
+
+
+fn vectors(implementation: &str) -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/interop").join(implementation);
+    let mut frames: Vec<PathBuf> = fs::read_dir(&dir).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "bin"))
+        .collect();
+    frames.sort();
+    assert!(!frames.is_empty(), "No test vectors in {}", dir.display());
+    frames
+}
+
+
+fn read_objects(bytes: Vec<u8>) -> Vec<BusinessObject> {
+    let mut stream = BusinessObjectStream::new(Cursor::new(bytes));
+    let mut objects = Vec::new();
+    while !stream.at_eof() {
+        objects.extend(stream.read_business_objects().unwrap());
+    }
+    objects
+}
+
+
+/// The object described by a line of a `.jsonl` file.
+fn expected_object(line: &str) -> BusinessObject {
+    let mut json = Json::from_str(line).unwrap();
+    let fields = json.as_object_mut().unwrap();
+    let payload = match (fields.remove("payload"), fields.remove("payload-base64")) {
+        (Some(Json::String(text)), None) => Some(Payload::Text(text, None)),
+        (Some(json), None) => Some(Payload::Json(json, None)),
+        (None, Some(Json::String(encoded))) => Some(Payload::Bytes(encoded.from_base64().unwrap().into())),
+        (None, None) => None,
+        _ => panic!("Bad payload in {}", line)
+    };
+
+    let mut object = BusinessObject::from_json(&Json::Object(fields.clone())).unwrap();
+    object.payload = payload;
+    object
+}
+
+
+/// Objects are equal, metadata included, but the `size` of JSON payloads
+/// built from a description is that of their canonical form.
+fn assert_equivalent(expected: &BusinessObject, actual: &BusinessObject, context: &str) {
+    assert_eq!(expected.event, actual.event, "{}", context);
+    assert_eq!(expected._type, actual._type, "{}", context);
+    assert_eq!(expected.metadata, actual.metadata, "{}", context);
+    assert_eq!(expected.payload, actual.payload, "{}", context);
+    if !matches!(expected.payload, Some(Payload::Json(..))) {
+        assert_eq!(expected.size, actual.size, "{}", context);
+    }
+}
+
+
+/// Reads the vectors of `implementation`, checking that they decode to the
+/// objects described and that those survive being written and read again.
+/// Returns the objects read from each, with what they were read from.
+fn check_vectors(implementation: &str) -> Vec<(Vec<u8>, Vec<BusinessObject>)> {
+    vectors(implementation).into_iter().map(|path| {
+        let context = path.display().to_string();
+        let bytes = fs::read(&path).unwrap();
+        let expected: Vec<BusinessObject> = fs::read_to_string(path.with_extension("jsonl")).unwrap()
+            .lines().map(expected_object).collect();
+
+        let objects = read_objects(bytes.clone());
+        assert_eq!(expected.len(), objects.len(), "{}", context);
+        for (expected, object) in expected.iter().zip(&objects) {
+            assert_equivalent(expected, object, &context);
+            let reread = read_objects(object.to_bytes());
+            assert_eq!(1, reread.len(), "{}", context);
+            assert_equivalent(object, &reread[0], &context);
+        }
+        (bytes, objects)
+    }).collect()
+}
+
+
+#[test]
+fn should_read_what_the_python_implementation_writes() {
+    check_vectors("python");
+}
+
+
+#[test]
+fn should_read_what_the_java_implementation_writes() {
+    check_vectors("java");
+}
+
+
+#[test]
+fn should_write_canonical_frames_byte_for_byte() {
+    for (bytes, objects) in check_vectors("canonical") {
+        let written: Vec<u8> = objects.iter().flat_map(|object| object.to_canonical_bytes()).collect();
+        assert_eq!(String::from_utf8_lossy(&bytes), String::from_utf8_lossy(&written));
+        assert_eq!(bytes, written);
+        for object in &objects {
+            assert_eq!(Ok(()), object.verify_checksum());
+        }
+    }
+}
+
+
+#[test]
+fn should_write_byte_identical_frames() {
+    for (bytes, objects) in check_vectors("rust") {
+        let written: Vec<u8> = objects.iter().flat_map(|object| object.to_bytes()).collect();
+        assert_eq!(String::from_utf8_lossy(&bytes), String::from_utf8_lossy(&written));
+        assert_eq!(bytes, written);
+    }
+}
@@ -0,0 +1,47 @@
+//! Structs deriving `BusinessObject` going to objects and back.
+
+extern crate object_system;
+#[macro_use] extern crate object_system_derive;
+extern crate rustc_serialize;
+
+use rustc_serialize::Decodable;
+use rustc_serialize::json::ToJson;
+
+use object_system::mapping::{FromBusinessObject, MappingError, ToBusinessObject, JSON_TYPE};
+
+
+#[derive(Debug, PartialEq, BusinessObject)]
+#[business_object(event = "sensors/reading")]
+struct Reading {
+    sensor: String,
+    celsius: f64,
+    tags: Vec<String>,
+    calibrated: Option<bool>,
+}
+
+
+#[derive(Debug, PartialEq, BusinessObject)]
+struct Note<T: ToJson + Decodable> {
+    body: T,
+}
+
+
+#[test]
+fn derived_structs_should_map_to_objects_of_their_event_and_back() {
+    let reading = Reading { sensor: "attic".to_string(), celsius: 21.5, tags: vec!["roof".to_string()],
+                            calibrated: None };
+    let object = reading.to_business_object();
+    assert_eq!(Some("sensors/reading".to_string()), object.event);
+    assert_eq!(Some(JSON_TYPE.to_string()), object._type);
+    assert_eq!(Ok(reading), Reading::from_business_object(&object));
+
+    let note = Note { body: vec![1, 2] };
+    let object = note.to_business_object();
+    assert_eq!(None, object.event);
+    assert_eq!(Ok(note), Note::from_business_object(&object));
+
+    match Reading::from_business_object(&object) {
+        Err(MappingError::WrongEvent { ref expected, found: None }) if expected == "sensors/reading" => {},
+        result => panic!("Expected the wrong event to be refused, got {:?}", result)
+    }
+}
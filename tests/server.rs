@@ -0,0 +1,1148 @@
+//! Routing through a router started on an ephemeral port, with clients
+//! connecting to it over TCP like they would to `rabboe`.
+
+extern crate object_system;
+
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use object_system::{BusinessObject, Client, Config, ErrorCode, Event, Payload};
+use object_system::clock;
+use object_system::config::{Bus, Upstream};
+use object_system::io::{BusinessObjectStream, NextObject};
+use object_system::middleware::{Context, Middleware, Verdict};
+use object_system::protocol::{Feature, Features, Negotiated, PROTOCOL_VERSION};
+use object_system::schema::{SchemaPolicy, SchemaRule, SCHEMA_VIOLATIONS_KEY};
+use object_system::server::{Server, ServerBuilder, PAYLOAD_SIZE_KEY, PAYLOAD_TICKET_KEY};
+use object_system::service::Service;
+use object_system::storage::{Records, Storage};
+use object_system::timestamp::Timestamp;
+
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+
+fn router_builder(config: Config) -> ServerBuilder {
+    ServerBuilder::new(Config { shutdown_timeout: 1, .. config })
+        .listen(&["127.0.0.1:0".parse().unwrap()])
+        // Clients of different workers exchange objects through channels
+        .workers(2)
+}
+
+
+fn start_router() -> Server {
+    router_builder(Config::default()).start().expect("Failed to start router")
+}
+
+
+fn stop_router(router: Server) {
+    router.handle().shutdown();
+    router.run();
+}
+
+
+fn connect(router: &Server, rules: &[&str]) -> Client {
+    let mut client = Client::connect(router.local_addrs()[0]).unwrap();
+    client.subscribe(rules).unwrap();
+    client
+}
+
+
+fn received_event(client: &mut Client) -> String {
+    client.receive().unwrap().event.unwrap()
+}
+
+
+#[test]
+fn should_route_only_what_subscriptions_match() {
+    let router = start_router();
+    let mut chat = connect(&router, &["@chat/*"]);
+    let mut everything = connect(&router, &["@chat/*", "@news/*"]);
+    let mut publisher = connect(&router, &[]);
+
+    // Objects from one client arrive in the order it sent them
+    for name in &["news/weather", "chat/hello", "sports/score", "news/sports", "chat/bye"] {
+        publisher.send(&BusinessObject::event(name)).unwrap();
+    }
+    assert_eq!("chat/hello", received_event(&mut chat));
+    assert_eq!("chat/bye", received_event(&mut chat));
+    for name in &["news/weather", "chat/hello", "news/sports", "chat/bye"] {
+        assert_eq!(*name, received_event(&mut everything));
+    }
+
+    drop((chat, everything, publisher));
+    stop_router(router);
+}
+
+
+#[test]
+fn should_answer_pings_with_pongs() {
+    let router = start_router();
+    let mut client = connect(&router, &["@pong"]);
+
+    let ping = BusinessObject::event(Event::Ping).with_new_id();
+    let pong = client.request(&ping, TIMEOUT).unwrap();
+    assert!(pong.is_event(Event::Pong));
+    assert_eq!(ping.id(), pong.in_reply_to());
+
+    drop(client);
+    stop_router(router);
+}
+
+
+#[test]
+fn should_keep_routing_after_dropping_a_client_while_reading_from_it() {
+    // A single worker, so that the second client is served by the same one
+    let router = ServerBuilder::new(Config { max_queue_length: 4, shutdown_timeout: 1, .. Config::default() })
+        .listen(&["127.0.0.1:0".parse().unwrap()])
+        .workers(1)
+        .start().unwrap();
+
+    // The pongs overflow the queue of a client that doesn't read them
+    let mut flooder = TcpStream::connect(router.local_addrs()[0]).unwrap();
+    flooder.write_all(b"{\"event\":\"routing/subscribe\",\"subscriptions\":[\"*\"]}\0").unwrap();
+    let pings: Vec<u8> = (0 .. 5000).flat_map(|_| b"{\"event\":\"ping\"}\0".to_vec()).collect();
+    flooder.write_all(&pings).unwrap();
+
+    let mut client = connect(&router, &["@pong"]);
+    let pong = client.request(&BusinessObject::event(Event::Ping).with_new_id(), TIMEOUT).unwrap();
+    assert!(pong.is_event(Event::Pong));
+
+    drop(flooder);
+    drop(client);
+    stop_router(router);
+}
+
+
+#[test]
+fn should_disconnect_a_client_whose_queue_fills_while_reading_from_it() {
+    let router = ServerBuilder::new(Config { max_queue_length: 4, shutdown_timeout: 1, .. Config::default() })
+        .listen(&["127.0.0.1:0".parse().unwrap()])
+        .workers(1)
+        .start().unwrap();
+
+    // Each malformed frame is answered with an error the client doesn't read
+    let mut flooder = TcpStream::connect(router.local_addrs()[0]).unwrap();
+    flooder.set_read_timeout(Some(TIMEOUT)).unwrap();
+    let frames: Vec<u8> = (0 .. 5000).flat_map(|_| b"{not json\0".to_vec()).collect();
+    flooder.write_all(&frames).unwrap();
+    let mut output = Vec::new();
+    match flooder.read_to_end(&mut output) {
+        Ok(_) => {},
+        Err(e) => assert_eq!(io::ErrorKind::ConnectionReset, e.kind()),
+    }
+
+    let mut client = connect(&router, &["@pong"]);
+    let pong = client.request(&BusinessObject::event(Event::Ping).with_new_id(), TIMEOUT).unwrap();
+    assert!(pong.is_event(Event::Pong));
+
+    drop(client);
+    stop_router(router);
+}
+
+
+#[test]
+fn should_not_echo_objects_to_clients_asking_not_to() {
+    let router = start_router();
+    let mut echoed = connect(&router, &["@chat/*"]);
+    let mut quiet = Client::connect(router.local_addrs()[0]).unwrap();
+    let mut subscribe = BusinessObject::event(Event::RoutingSubscribe).with_new_id();
+    subscribe.set_meta("subscriptions", &vec!["@chat/*".to_string()]);
+    subscribe.set_meta("no-echo", &true);
+    let reply = quiet.request(&subscribe, TIMEOUT).unwrap();
+    assert!(reply.is_event(Event::RoutingSubscribeReply));
+
+    quiet.send(&BusinessObject::event("chat/from-quiet")).unwrap();
+    assert_eq!("chat/from-quiet", received_event(&mut echoed));
+    // By now an echo would have been queued for the quiet client
+    echoed.send(&BusinessObject::event("chat/from-echoed")).unwrap();
+    assert_eq!("chat/from-echoed", received_event(&mut echoed));
+    assert_eq!("chat/from-echoed", received_event(&mut quiet));
+
+    drop((echoed, quiet));
+    stop_router(router);
+}
+
+
+#[test]
+fn should_use_only_the_features_negotiated_with_clients_stating_a_protocol_version() {
+    let router = start_router();
+    let mut negotiating = Client::connect(router.local_addrs()[0]).unwrap();
+    negotiating.set_no_echo(true);
+    negotiating.subscribe(&["@chat/*"]).unwrap();
+    assert_eq!(Some(&Negotiated { version: PROTOCOL_VERSION, features: Features::all() }), negotiating.negotiated());
+
+    // A client not knowing of no-echo doesn't get it, even if it asks
+    let mut unaware = Client::connect(router.local_addrs()[0]).unwrap();
+    let mut subscribe = BusinessObject::event(Event::RoutingSubscribe).with_new_id();
+    subscribe.set_meta("subscriptions", &vec!["@chat/*".to_string()]);
+    subscribe.set_meta("no-echo", &true);
+    let mut features = Features::all();
+    features.remove(Feature::NoEcho);
+    Negotiated { version: PROTOCOL_VERSION, features }.set_on(&mut subscribe);
+    let reply = unaware.request(&subscribe, TIMEOUT).unwrap();
+    assert_eq!(Some(Negotiated { version: PROTOCOL_VERSION, features }), Negotiated::from_object(&reply));
+    assert!(!reply.metadata.extra.contains_key("no-echo"));
+
+    negotiating.send(&BusinessObject::event("chat/from-negotiating")).unwrap();
+    assert_eq!("chat/from-negotiating", received_event(&mut unaware));
+    // By now an echo would have been queued for the negotiating client
+    unaware.send(&BusinessObject::event("chat/from-unaware")).unwrap();
+    assert_eq!("chat/from-unaware", received_event(&mut unaware));
+    assert_eq!("chat/from-unaware", received_event(&mut negotiating));
+
+    drop((negotiating, unaware));
+    stop_router(router);
+}
+
+
+#[test]
+fn should_announce_and_forget_disconnected_clients() {
+    let router = start_router();
+    let mut watcher = connect(&router, &["@routing/announcement/*", "@chat/*"]);
+
+    let leaving = connect(&router, &["@chat/*"]);
+    let connected = watcher.receive().unwrap();
+    assert!(connected.is_event(Event::RoutingAnnouncementConnect));
+    let routing_id = connected.meta_str("routing-id").unwrap().to_string();
+
+    drop(leaving);
+    let disconnected = watcher.receive().unwrap();
+    assert!(disconnected.is_event(Event::RoutingAnnouncementDisconnect));
+    assert_eq!(Some(routing_id.as_str()), disconnected.meta_str("routing-id"));
+
+    // Routing to the remaining clients carries on
+    let mut publisher = connect(&router, &[]);
+    assert!(watcher.receive().unwrap().is_event(Event::RoutingAnnouncementConnect));
+    publisher.send(&BusinessObject::event("chat/still-here")).unwrap();
+    assert_eq!("chat/still-here", received_event(&mut watcher));
+
+    drop((watcher, publisher));
+    stop_router(router);
+}
+
+
+/// Keeps `secret/*` objects in and tells recipients who sent the rest.
+struct Censor;
+
+
+impl Middleware for Censor {
+    fn inbound(&self, _context: &Context, object: Arc<BusinessObject>) -> Verdict {
+        match object.event {
+            Some(ref event) if event.starts_with("secret/") => Verdict::Drop,
+            _ => Verdict::Pass(object)
+        }
+    }
+
+    fn outbound(&self, context: &Context, object: Arc<BusinessObject>) -> Verdict {
+        let mut object = (*object).clone();
+        object.set_meta("delivered-to", context.peer.routing_id);
+        Verdict::Pass(Arc::new(object))
+    }
+}
+
+
+#[test]
+fn should_pass_objects_through_middlewares() {
+    let router = router_builder(Config::default()).middleware(Box::new(Censor)).start().unwrap();
+    let mut subscriber = Client::connect(router.local_addrs()[0]).unwrap();
+    let reply = subscriber.subscribe(&["@secret/*", "@chat/*"]).unwrap();
+    let routing_id = reply.meta_str("routing-id").unwrap().to_string();
+    let mut publisher = connect(&router, &[]);
+
+    publisher.send(&BusinessObject::event("secret/plans")).unwrap();
+    publisher.send(&BusinessObject::event("chat/hello")).unwrap();
+    let received = subscriber.receive().unwrap();
+    assert_eq!(Some("chat/hello"), received.event.as_deref());
+    assert_eq!(Some(routing_id.as_str()), received.meta_str("delivered-to"));
+
+    // Replays pass through them as well
+    let reply = subscriber.request(&BusinessObject::event("history/replay"), TIMEOUT).unwrap();
+    assert_eq!(Some(1), reply.meta_u64("count"));
+    let replayed = subscriber.receive().unwrap();
+    assert_eq!(Some("chat/hello"), replayed.event.as_deref());
+    assert_eq!(Some(routing_id.as_str()), replayed.meta_str("delivered-to"));
+
+    drop((subscriber, publisher));
+    stop_router(router);
+}
+
+
+#[test]
+fn should_route_objects_nobody_got_as_dead_letters() {
+    let router = router_builder(Config { dead_letters: true, .. Config::default() }).start().unwrap();
+    let mut publisher = connect(&router, &["@routing/dead-letter"]);
+    let mut chat = connect(&router, &["@chat/*"]);
+
+    publisher.send(&BusinessObject::event("chat/hello")).unwrap();
+    assert_eq!("chat/hello", received_event(&mut chat));
+    publisher.send(&BusinessObject::event("nobody/home")).unwrap();
+    let letter = publisher.receive().unwrap();
+    assert!(letter.is_event(Event::RoutingDeadLetter));
+    assert_eq!(Some("no-subscribers"), letter.meta_str("reason"));
+    let original = BusinessObject::from_json(&letter.metadata.extra["original"]).unwrap();
+    assert_eq!(Some("nobody/home"), original.event.as_deref());
+
+    drop((publisher, chat));
+    stop_router(router);
+}
+
+
+#[test]
+fn should_tell_clients_what_was_wrong_with_what_they_sent() {
+    let router = router_builder(Config { validate_objects: true, .. Config::default() }).start().unwrap();
+    let mut client = Client::connect(router.local_addrs()[0]).unwrap();
+
+    // A rejected subscription leaves the connection open for another try
+    let mut subscribe = BusinessObject::event(Event::RoutingSubscribe).with_new_id();
+    subscribe.set_meta("subscriptions", &vec![1]);
+    let reply = client.request(&subscribe, TIMEOUT).unwrap();
+    assert_eq!(Some(ErrorCode::InvalidSubscription), reply.error_code());
+    client.subscribe(&["@pong"]).unwrap();
+
+    let invalid = BusinessObject::event("").with_new_id();
+    let reply = client.request(&invalid, TIMEOUT).unwrap();
+    assert!(reply.is_event(Event::RoutingError));
+    assert_eq!(Some(ErrorCode::InvalidObject), reply.error_code());
+
+    // Unparseable bytes can't be replied to, but are reported all the same
+    let mut raw = TcpStream::connect(router.local_addrs()[0]).unwrap();
+    raw.write_all(b"{bad json\0").unwrap();
+    match BusinessObjectStream::new(raw).next_object().unwrap() {
+        NextObject::Object(error) => assert_eq!(Some(ErrorCode::MalformedObject), error.error_code()),
+        NextObject::Streamed(..) => panic!("Expected an error without payload")
+    }
+
+    drop(client);
+    stop_router(router);
+}
+
+
+#[test]
+fn should_read_on_past_a_malformed_frame_arriving_with_others() {
+    let router = start_router();
+    let raw = TcpStream::connect(router.local_addrs()[0]).unwrap();
+    raw.set_read_timeout(Some(TIMEOUT)).unwrap();
+    let mut stream = BusinessObjectStream::new(raw);
+    let next_event = |stream: &mut BusinessObjectStream<TcpStream>| match stream.next_object().unwrap() {
+        NextObject::Object(object) => object,
+        NextObject::Streamed(..) => panic!("Expected an object without payload")
+    };
+
+    stream.write_all(b"{\"event\":\"routing/subscribe\",\"subscriptions\":[\"@chat/*\"]}\0").unwrap();
+    assert!(next_event(&mut stream).is_event(Event::RoutingSubscribeReply));
+    stream.write_all(b"{\"event\":\"chat/one\"}\0{not json\0{\"event\":\"chat/two\"}\0").unwrap();
+    // The error may be sent ahead of the objects, which are of lower priority
+    let received: Vec<BusinessObject> = (0 .. 3).map(|_| next_event(&mut stream)).collect();
+    let chat: Vec<&str> = received.iter().filter_map(|object| object.event.as_deref())
+        .filter(|event| event.starts_with("chat/")).collect();
+    assert_eq!(vec!["chat/one", "chat/two"], chat);
+    assert!(received.iter().any(|object| object.error_code() == Some(ErrorCode::MalformedObject)));
+
+    drop(stream);
+    stop_router(router);
+}
+
+
+#[test]
+fn should_handle_websocket_frames_arriving_together() {
+    let config = Config { websocket_listen: Some("127.0.0.1:0".parse().unwrap()), .. Config::default() };
+    let router = router_builder(config).start().unwrap();
+
+    let mut client = TcpStream::connect(router.websocket_addr().unwrap()).unwrap();
+    client.set_read_timeout(Some(TIMEOUT)).unwrap();
+    let mut input = b"GET / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                      Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n".to_vec();
+    for payload in &[r#"{"event": "routing/subscribe", "subscriptions": ["@chat/*"]}"#,
+                     r#"{"event": "chat/one"}"#, r#"{"event": "chat/two"}"#] {
+        input.extend_from_slice(&[0x81, 0x80 | payload.len() as u8, 0, 0, 0, 0]);
+        input.extend_from_slice(payload.as_bytes());
+    }
+    client.write_all(&input).unwrap();
+
+    let mut output = Vec::new();
+    let contains = |output: &[u8], event: &str| output.windows(event.len()).any(|window| window == event.as_bytes());
+    while !contains(&output, "chat/two") {
+        let mut buf = [0; 1024];
+        let n = client.read(&mut buf).unwrap();
+        assert!(n > 0);
+        output.extend_from_slice(&buf[.. n]);
+    }
+    assert!(contains(&output, "routing/subscribe/reply"));
+    assert!(contains(&output, "chat/one"));
+
+    drop(client);
+    stop_router(router);
+}
+
+
+#[test]
+fn should_disconnect_clients_taking_too_long_to_finish_a_frame() {
+    let config = Config {
+        partial_frame_timeout: 1,
+        admin_token: Some("4dmin".to_string()),
+        websocket_listen: Some("127.0.0.1:0".parse().unwrap()),
+        .. Config::default()
+    };
+    let router = router_builder(config).start().unwrap();
+    let mut admin = connect(&router, &[]);
+
+    let mut slow = TcpStream::connect(router.local_addrs()[0]).unwrap();
+    slow.set_read_timeout(Some(TIMEOUT)).unwrap();
+    slow.write_all(br#"{"event": "chat/mes"#).unwrap();
+    thread::sleep(Duration::from_millis(500));
+    slow.write_all(b"sage").unwrap();
+    assert_eq!(0, slow.read(&mut [0; 16]).unwrap());
+
+    // Over WebSocket, a frame held back before any of it reaches the stream
+    let mut slow = TcpStream::connect(router.websocket_addr().unwrap()).unwrap();
+    slow.set_read_timeout(Some(TIMEOUT)).unwrap();
+    slow.write_all(b"GET / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                     Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n").unwrap();
+    let payload = br#"{"event": "chat/message"}"#;
+    let mut frame = vec![0x81, 0x80 | payload.len() as u8, 0, 0, 0, 0];
+    frame.extend_from_slice(payload);
+    slow.write_all(&frame[.. 10]).unwrap();
+    thread::sleep(Duration::from_millis(500));
+    slow.write_all(&frame[10 .. 20]).unwrap();
+    let mut response = Vec::new();
+    slow.read_to_end(&mut response).unwrap();
+    assert!(response.starts_with(b"HTTP/1.1 101 Switching Protocols\r\n"));
+
+    let mut stats = BusinessObject::event(Event::AdminStats).with_new_id();
+    stats.set_meta("admin-token", "4dmin");
+    let reply = admin.request(&stats, TIMEOUT).unwrap();
+    assert_eq!(Some(2), reply.metadata.extra["stats"].find("partial-frame-timeouts").and_then(|count| count.as_u64()));
+
+    drop(admin);
+    stop_router(router);
+}
+
+
+#[test]
+fn should_let_an_admin_disconnect_itself() {
+    let config = Config { admin_token: Some("4dmin".to_string()), .. Config::default() };
+    let router = router_builder(config).workers(1).start().unwrap();
+    let mut admin = connect(&router, &[]);
+
+    let mut clients = BusinessObject::event(Event::AdminClients).with_new_id();
+    clients.set_meta("admin-token", "4dmin");
+    let reply = admin.request(&clients, TIMEOUT).unwrap();
+    let itself = &reply.metadata.extra["clients"].as_array().unwrap()[0];
+    let mut disconnect = BusinessObject::event(Event::AdminDisconnect);
+    disconnect.set_meta("admin-token", "4dmin");
+    disconnect.metadata.extra.insert("worker".to_string(), itself.find("worker").unwrap().clone());
+    disconnect.metadata.extra.insert("token".to_string(), itself.find("token").unwrap().clone());
+    admin.send(&disconnect).unwrap();
+    assert!(admin.receive().is_err());
+
+    let mut client = connect(&router, &["@pong"]);
+    let pong = client.request(&BusinessObject::event(Event::Ping).with_new_id(), TIMEOUT).unwrap();
+    assert!(pong.is_event(Event::Pong));
+
+    drop(client);
+    stop_router(router);
+}
+
+
+#[test]
+fn should_refuse_to_start_with_an_invalid_configuration() {
+    let without_certificate = Config { tls_listen: Some("127.0.0.1:0".parse().unwrap()), .. Config::default() };
+    for builder in vec![router_builder(without_certificate), router_builder(Config::default()).workers(0)] {
+        match builder.start() {
+            Err(e) => assert_eq!(io::ErrorKind::InvalidInput, e.kind()),
+            Ok(_) => panic!("Expected the router not to start")
+        }
+    }
+}
+
+
+#[test]
+fn should_tell_clients_connecting_to_a_full_router_why_it_closes_them() {
+    let config = Config { max_clients: 2, admin_token: Some("4dmin".to_string()), .. Config::default() };
+    let router = router_builder(config).workers(1).start().unwrap();
+    let mut admin = connect(&router, &[]);
+    let other = connect(&router, &[]);
+
+    let mut excess = Client::connect(router.local_addrs()[0]).unwrap();
+    assert_eq!(Some(ErrorCode::ServerFull), excess.receive().unwrap().error_code());
+    assert!(excess.receive().is_err());
+
+    let mut stats = BusinessObject::event(Event::AdminStats).with_new_id();
+    stats.set_meta("admin-token", "4dmin");
+    let reply = admin.request(&stats, TIMEOUT).unwrap();
+    assert_eq!(Some(1), reply.metadata.extra["stats"].find("refused-connections").and_then(|count| count.as_u64()));
+
+    // A client leaving makes room for another
+    drop(other);
+    thread::sleep(Duration::from_millis(200));
+    connect(&router, &[]);
+
+    drop(admin);
+    stop_router(router);
+}
+
+
+#[test]
+fn should_let_clients_wait_for_room_when_pausing_accepting() {
+    let config = Config { max_clients: 1, pause_accepting: true, .. Config::default() };
+    let router = router_builder(config).workers(1).start().unwrap();
+    let first = connect(&router, &[]);
+
+    let mut waiting = Client::connect(router.local_addrs()[0]).unwrap();
+    thread::sleep(Duration::from_millis(200));
+    drop(first);
+    let reply = waiting.subscribe(&[]).unwrap();
+    assert!(reply.is_event(Event::RoutingSubscribeReply));
+
+    drop(waiting);
+    stop_router(router);
+}
+
+
+#[test]
+fn should_list_clients_with_what_they_have_sent_and_received() {
+    let router = start_router();
+    let mut subscriber = Client::connect(router.local_addrs()[0]).unwrap();
+    let routing_id = subscriber.subscribe(&["@chat/*"]).unwrap().meta_str("routing-id").unwrap().to_string();
+    let mut publisher = connect(&router, &[]);
+
+    publisher.send(&BusinessObject::event("chat/hello")).unwrap();
+    assert_eq!("chat/hello", received_event(&mut subscriber));
+
+    let list = publisher.request(&BusinessObject::event(Event::ClientsList).with_new_id(), TIMEOUT).unwrap();
+    let clients = list.metadata.extra["clients"].as_array().unwrap();
+    let listed = clients.iter().find(|client| client.find("routing-id").and_then(|id| id.as_string()) ==
+                                     Some(routing_id.as_str())).unwrap();
+    let stats = listed.find("stats").unwrap();
+    // It sent its subscription, and got the reply and the object routed to it
+    assert_eq!(Some(1), stats.find("objects-received").and_then(|count| count.as_u64()));
+    assert_eq!(Some(2), stats.find("objects-sent").and_then(|count| count.as_u64()));
+    assert_eq!(Some(0), stats.find("dropped").and_then(|count| count.as_u64()));
+
+    drop((subscriber, publisher));
+    stop_router(router);
+}
+
+
+#[test]
+fn should_subscribe_clients_giving_no_rules_to_the_default_subscription() {
+    let default = object_system::config::parse_rules("default-subscription", &["@chat/*".to_string()]).unwrap();
+    let router = router_builder(Config { default_subscription: Some(default), .. Config::default() }).start().unwrap();
+    let mut subscriber = Client::connect(router.local_addrs()[0]).unwrap();
+    let reply = subscriber.request(&BusinessObject::event(Event::RoutingSubscribe).with_new_id(), TIMEOUT).unwrap();
+    assert!(reply.is_event(Event::RoutingSubscribeReply));
+    assert_eq!(Some(vec!["@chat/*"]), reply.meta_array_of_str("subscriptions"));
+
+    let mut publisher = connect(&router, &[]);
+    publisher.send(&BusinessObject::event("news/weather")).unwrap();
+    publisher.send(&BusinessObject::event("chat/hello")).unwrap();
+    assert_eq!("chat/hello", received_event(&mut subscriber));
+
+    drop((subscriber, publisher));
+    stop_router(router);
+}
+
+
+#[test]
+fn should_route_objects_from_clients_yet_to_subscribe_when_configured_to() {
+    // On a single worker, an object is routed before it is echoed back to its sender
+    let router = router_builder(Config { publish_before_subscribing: true, .. Config::default() })
+        .workers(1).start().unwrap();
+    let mut subscriber = connect(&router, &["@chat/*"]);
+    let mut legacy = Client::connect(router.local_addrs()[0]).unwrap();
+
+    legacy.send(&BusinessObject::event("chat/early")).unwrap();
+    assert_eq!("chat/early", received_event(&mut subscriber));
+    // Nothing is routed to it before it subscribes
+    subscriber.send(&BusinessObject::event("chat/unheard")).unwrap();
+    assert_eq!("chat/unheard", received_event(&mut subscriber));
+    legacy.subscribe(&["@chat/*"]).unwrap();
+    subscriber.send(&BusinessObject::event("chat/heard")).unwrap();
+    assert_eq!("chat/heard", received_event(&mut legacy));
+
+    drop((subscriber, legacy));
+    stop_router(router);
+}
+
+
+#[test]
+fn should_deliver_objects_naming_recipients_only_to_them() {
+    let router = start_router();
+    let mut alice = Client::connect(router.local_addrs()[0]).unwrap();
+    let alice_id = alice.subscribe(&["@chat/*"]).unwrap().meta_str("routing-id").unwrap().to_string();
+    let mut bob = connect(&router, &["@chat/*"]);
+    let mut register = BusinessObject::event(Event::ClientsRegister).with_new_id();
+    register.set_meta("name", "bob");
+    bob.request(&register, TIMEOUT).unwrap();
+    let mut carol = connect(&router, &["@chat/*"]);
+
+    let mut whisper = BusinessObject::event("chat/whisper");
+    whisper.set_meta("to", &vec![alice_id, "bob".to_string()]);
+    carol.send(&whisper).unwrap();
+    let mut to_carol = BusinessObject::event("chat/whisper");
+    to_carol.set_meta("to", "nobody-by-that-name");
+    bob.send(&to_carol).unwrap();
+    carol.send(&BusinessObject::event("chat/everyone")).unwrap();
+
+    assert_eq!("chat/whisper", received_event(&mut alice));
+    assert_eq!("chat/whisper", received_event(&mut bob));
+    for client in &mut [&mut alice, &mut bob, &mut carol] {
+        assert_eq!("chat/everyone", received_event(client));
+    }
+
+    drop((alice, bob, carol));
+    stop_router(router);
+}
+
+
+#[test]
+fn should_replay_objects_naming_recipients_only_to_them() {
+    let router = start_router();
+    let mut alice = Client::connect(router.local_addrs()[0]).unwrap();
+    let alice_id = alice.subscribe(&["@chat/*"]).unwrap().meta_str("routing-id").unwrap().to_string();
+    let mut carol = connect(&router, &["@chat/*"]);
+
+    let mut whisper = BusinessObject::event("chat/whisper");
+    whisper.set_meta("to", &alice_id);
+    carol.send(&whisper).unwrap();
+    carol.send(&BusinessObject::event("chat/everyone")).unwrap();
+    assert_eq!("chat/whisper", received_event(&mut alice));
+    assert_eq!("chat/everyone", received_event(&mut alice));
+
+    let mut eve = connect(&router, &["@chat/*"]);
+    let reply = eve.request(&BusinessObject::event("history/replay"), TIMEOUT).unwrap();
+    assert_eq!(Some(1), reply.meta_u64("count"));
+    assert_eq!("chat/everyone", received_event(&mut eve));
+    let reply = alice.request(&BusinessObject::event("history/replay"), TIMEOUT).unwrap();
+    assert_eq!(Some(2), reply.meta_u64("count"));
+    assert_eq!("chat/whisper", received_event(&mut alice));
+
+    drop((alice, carol, eve));
+    stop_router(router);
+}
+
+
+#[test]
+fn should_strip_what_subscriptions_ask_to_be_left_out_only_for_them() {
+    let router = start_router();
+    let mut whole = connect(&router, &["@files/*"]);
+    let mut trimmed = Client::connect(router.local_addrs()[0]).unwrap();
+    trimmed.set_stripped_metadata(&["token", "thumbnail"]);
+    trimmed.set_omit_payloads(true);
+    let reply = trimmed.subscribe(&["@files/*"]).unwrap();
+    assert_eq!(Some(vec!["token", "thumbnail"]), reply.meta_array_of_str("strip"));
+    assert_eq!(Some(false), reply.metadata.extra.get("payload").and_then(|payload| payload.as_boolean()));
+
+    let mut upload = BusinessObject::event("files/upload").with_meta("token", "secret").with_meta("name", "cat.png");
+    upload._type = Some("image/png".to_string());
+    upload.payload = Some(Payload::Bytes(vec![1, 2, 3].into()));
+    whole.send(&upload).unwrap();
+
+    let received = trimmed.receive().unwrap();
+    assert_eq!(Some("cat.png"), received.meta_str("name"));
+    assert_eq!(None, received.meta_str("token"));
+    assert!(received.payload.is_none());
+    let received = whole.receive().unwrap();
+    assert_eq!(Some("secret"), received.meta_str("token"));
+    assert_eq!(Some(&[1, 2, 3][..]), received.payload.as_ref().map(|payload| payload.to_bytes(None)).as_deref());
+
+    drop((whole, trimmed));
+    stop_router(router);
+}
+
+
+#[test]
+fn should_hand_out_payloads_left_out_for_their_tickets() {
+    let router = start_router();
+    let mut publisher = connect(&router, &[]);
+    let mut indexer = Client::connect(router.local_addrs()[0]).unwrap();
+    indexer.set_omit_payloads(true);
+    let indexer_id = indexer.subscribe(&["@files/*"]).unwrap().meta_str("routing-id").unwrap().to_string();
+
+    let mut upload = BusinessObject::event("files/upload").with_new_id();
+    upload._type = Some("text/plain".to_string());
+    upload.payload = Some(Payload::Text("hello".to_string(), None));
+    publisher.send(&upload).unwrap();
+
+    let received = indexer.receive().unwrap();
+    assert!(received.payload.is_none());
+    assert_eq!(Some(5), received.meta_u64(PAYLOAD_SIZE_KEY));
+    let ticket = received.meta_str(PAYLOAD_TICKET_KEY).unwrap();
+    assert_eq!(upload.id(), Some(ticket));
+
+    let fetch = BusinessObject::event(Event::PayloadFetch).with_new_id().with_meta("ticket", ticket);
+    let reply = indexer.request(&fetch, TIMEOUT).unwrap();
+    assert!(reply.is_event(Event::PayloadFetchReply));
+    assert_eq!(Some("hello"), reply.payload_as_str());
+    let fetch = BusinessObject::event(Event::PayloadFetch).with_new_id().with_meta("ticket", "no-such-ticket");
+    let reply = indexer.request(&fetch, TIMEOUT).unwrap();
+    assert!(reply.payload.is_none());
+    assert_eq!(Some("No such payload in the history"), reply.meta_str("error"));
+
+    // Only the recipients of an object get its payload
+    let mut private = BusinessObject::event("files/upload").with_new_id().with_meta("to", &indexer_id);
+    private._type = Some("text/plain".to_string());
+    private.payload = Some(Payload::Text("secret".to_string(), None));
+    publisher.send(&private).unwrap();
+    let ticket = indexer.receive().unwrap().meta_str(PAYLOAD_TICKET_KEY).unwrap().to_string();
+    let fetch = BusinessObject::event(Event::PayloadFetch).with_new_id().with_meta("ticket", &ticket);
+    assert_eq!(Some("No such payload in the history"), publisher.request(&fetch, TIMEOUT).unwrap().meta_str("error"));
+    let fetch = fetch.with_new_id();
+    assert_eq!(Some("secret"), indexer.request(&fetch, TIMEOUT).unwrap().payload_as_str());
+
+    drop((publisher, indexer));
+    stop_router(router);
+}
+
+
+#[test]
+fn should_answer_requests_with_a_function_served_as_a_service() {
+    let router = start_router();
+    let mut provider = Client::connect(router.local_addrs()[0]).unwrap();
+    provider.subscribe(&[]).unwrap();
+    let serving = thread::spawn(move || {
+        Service::serve(&mut provider, "upper", |request: BusinessObject| match request.payload_as_str() {
+            Some(text) => Ok(BusinessObject::event("ignored").with_meta("text", &text.to_uppercase())),
+            None => Err("Nothing to uppercase")
+        })
+    });
+
+    let mut requester = connect(&router, &[]);
+    let mut request = BusinessObject::event(Event::ServicesRequest).with_meta("name", "upper");
+    request._type = Some("text/plain".to_string());
+    request.payload = Some(Payload::Text("shout".to_string(), None));
+    let reply = loop {
+        let reply = requester.request(&request, TIMEOUT).unwrap();
+        // Until the provider has registered
+        if reply.meta_str("error") != Some("No such service") {
+            break reply;
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+    assert!(reply.is_event(Event::ServicesReply));
+    assert_eq!(Some("SHOUT"), reply.meta_str("text"));
+    assert_eq!(Some("upper"), reply.meta_str("name"));
+
+    let empty = BusinessObject::event(Event::ServicesRequest).with_meta("name", "upper");
+    let reply = requester.request(&empty, TIMEOUT).unwrap();
+    assert_eq!(Some("Nothing to uppercase"), reply.meta_str("error"));
+
+    drop(requester);
+    stop_router(router);
+    serving.join().unwrap();
+}
+
+
+#[test]
+fn should_reject_objects_not_matching_their_checksum_when_configured_to() {
+    let router = router_builder(Config { verify_checksums: true, .. Config::default() }).start().unwrap();
+    let mut subscriber = connect(&router, &["@files/*"]);
+    let mut publisher = connect(&router, &[]);
+
+    let mut file = BusinessObject::event("files/upload");
+    file._type = Some("text/plain".to_string());
+    file.size = Some(5);
+    file.payload = Some(Payload::Text("hello".to_string(), None));
+    file.attach_checksum();
+    publisher.send(&file).unwrap();
+    assert_eq!("files/upload", received_event(&mut subscriber));
+
+    file.payload = Some(Payload::Text("jello".to_string(), None));
+    publisher.send(&file).unwrap();
+    let error = publisher.receive().unwrap();
+    assert_eq!(Some(ErrorCode::ChecksumMismatch), error.error_code());
+
+    drop((subscriber, publisher));
+    stop_router(router);
+}
+
+
+#[test]
+fn should_forward_payloads_as_they_were_sent() {
+    let router = start_router();
+    let mut subscriber = connect(&router, &["@files/*"]);
+    let mut publisher = connect(&router, &[]);
+
+    let payloads: [(&str, &[u8]); 2] = [("text/plain; charset=utf-16le", &[104, 0, 105, 0]),
+                                        ("application/json", br#"{ "b": 1.50, "a": [] }"#)];
+    for &(content_type, bytes) in &payloads {
+        let mut file = BusinessObject::event("files/upload");
+        file._type = Some(content_type.to_string());
+        file.size = Some(bytes.len());
+        file.payload = Some(Payload::decode(file.content_type().as_ref(), bytes.to_vec().into()));
+        file.attach_checksum();
+        publisher.send(&file).unwrap();
+
+        let received = subscriber.receive().unwrap();
+        let payload = received.payload.as_ref().unwrap();
+        assert_eq!(bytes, &payload.to_bytes(received.content_type().as_ref())[..]);
+        assert_eq!(Some(bytes.len()), received.size);
+        assert_eq!(Ok(()), received.verify_checksum());
+    }
+
+    drop((subscriber, publisher));
+    stop_router(router);
+}
+
+
+#[test]
+fn should_check_checksums_of_json_payloads_over_their_canonical_form() {
+    let router = router_builder(Config { verify_checksums: true, .. Config::default() }).start().unwrap();
+    let subscribe = |rules: &str| {
+        let mut raw = TcpStream::connect(router.local_addrs()[0]).unwrap();
+        raw.set_read_timeout(Some(TIMEOUT)).unwrap();
+        raw.write_all(format!(r#"{{"event": "routing/subscribe", "subscriptions": [{}]}}{}"#, rules, '\0').as_bytes()).unwrap();
+        let mut stream = BusinessObjectStream::new(raw);
+        stream.set_verify_checksums(true);
+        stream
+    };
+    let next_event = |stream: &mut BusinessObjectStream<TcpStream>, event: &str| loop {
+        match stream.next_object().unwrap() {
+            NextObject::Object(object) if object.event.as_deref() == Some(event) => { return object; },
+            NextObject::Object(_) => {},
+            NextObject::Streamed(..) => panic!("Expected a buffered payload")
+        }
+    };
+    let send = |stream: &mut BusinessObjectStream<TcpStream>, payload: &[u8], sha1: &str| {
+        let header = format!(r#"{{"event": "files/upload", "type": "application/json", "size": {}, "sha1": "{}"}}"#,
+                             payload.len(), sha1);
+        stream.socket.write_all(header.as_bytes()).unwrap();
+        stream.socket.write_all(b"\0").unwrap();
+        stream.socket.write_all(payload).unwrap();
+    };
+
+    let mut subscriber = subscribe(r#""@files/*""#);
+    next_event(&mut subscriber, Event::RoutingSubscribeReply.as_str());
+    let mut publisher = subscribe("");
+    next_event(&mut publisher, Event::RoutingSubscribeReply.as_str());
+
+    // The digest of the canonical form, not of the bytes sent, which are
+    // forwarded as they are
+    let payload = br#"{ "station": "Kumpula", "celsius": -2.50 }"#;
+    send(&mut publisher, payload, "fc21b37a501b59a8639060239623aeb99ee78afa");
+    let received = next_event(&mut subscriber, "files/upload");
+    assert_eq!(Ok(()), received.verify_checksum());
+    let sent = received.payload.as_ref().unwrap().to_bytes(received.content_type().as_ref());
+    assert_eq!(&payload[..], &sent[..]);
+
+    send(&mut publisher, payload, "c2a54e0a5df87daf1effb5e87d9b4f8203077207");
+    let error = next_event(&mut publisher, Event::RoutingError.as_str());
+    assert_eq!(Some(ErrorCode::ChecksumMismatch), error.error_code());
+
+    drop((subscriber, publisher));
+    stop_router(router);
+}
+
+
+#[test]
+fn should_reject_or_tag_objects_violating_their_schema() {
+    let file = env::temp_dir().join(format!("schema-test-{}.json", std::process::id()));
+    fs::write(&file, r#"{"type": "object", "required": ["celsius"]}"#).unwrap();
+    let schemas = vec![SchemaRule { event: Some("sensors/*".to_string()), _type: None, file: file.clone() }];
+    let reading = |payload: &str| {
+        let mut reading = BusinessObject::event("sensors/reading");
+        reading._type = Some("application/json".to_string());
+        reading.size = Some(payload.len());
+        reading.payload = Some(Payload::Text(payload.to_string(), None));
+        reading
+    };
+
+    let router = router_builder(Config { schemas: schemas.clone(), .. Config::default() }).start().unwrap();
+    let mut subscriber = connect(&router, &["@sensors/*"]);
+    let mut publisher = connect(&router, &[]);
+    publisher.send(&reading(r#"{"fahrenheit": 70}"#)).unwrap();
+    let error = publisher.receive().unwrap();
+    assert_eq!(Some(ErrorCode::SchemaViolation), error.error_code());
+    assert_eq!(Some(vec!["/: celsius is required"]), error.meta_array_of_str("errors"));
+    publisher.send(&reading(r#"{"celsius": 21}"#)).unwrap();
+    let received = subscriber.receive().unwrap();
+    assert_eq!(None, received.meta_array_of_str(SCHEMA_VIOLATIONS_KEY));
+    drop((subscriber, publisher));
+    stop_router(router);
+
+    let config = Config { schemas, schema_violations: SchemaPolicy::Tag, .. Config::default() };
+    let router = router_builder(config).start().unwrap();
+    let mut subscriber = connect(&router, &["@sensors/*"]);
+    let mut publisher = connect(&router, &[]);
+    publisher.send(&reading(r#"{"fahrenheit": 70}"#)).unwrap();
+    let received = subscriber.receive().unwrap();
+    assert_eq!(Some(vec!["/: celsius is required"]), received.meta_array_of_str(SCHEMA_VIOLATIONS_KEY));
+    drop((subscriber, publisher));
+    stop_router(router);
+    fs::remove_file(file).unwrap();
+}
+
+
+#[test]
+fn should_refuse_new_clients_while_draining_and_stop_once_the_rest_leave() {
+    let config = Config { admin_token: Some("4dmin".to_string()), .. Config::default() };
+    let router = router_builder(config).start().unwrap();
+    let mut admin = connect(&router, &["@chat/*"]);
+    let mut staying = connect(&router, &[]);
+
+    let mut drain = BusinessObject::event(Event::AdminDrain).with_new_id();
+    drain.set_meta("admin-token", "4dmin");
+    drain.set_meta("timeout", &60);
+    let reply = admin.request(&drain, TIMEOUT).unwrap();
+    assert!(reply.is_event(Event::AdminDrainReply));
+    assert_eq!(Some(2), reply.meta_u64("clients"));
+
+    let mut late = Client::connect(router.local_addrs()[0]).unwrap();
+    assert_eq!(Some(ErrorCode::Draining), late.receive().unwrap().error_code());
+    assert!(late.receive().is_err());
+
+    // Clients connected before keep being served
+    staying.send(&BusinessObject::event("chat/still-here")).unwrap();
+    assert_eq!("chat/still-here", received_event(&mut admin));
+
+    drop((admin, staying, late));
+    router.run();
+}
+
+
+#[test]
+fn should_keep_the_clients_of_each_bus_apart() {
+    let config = Config {
+        buses: vec![Bus { name: "tenant".to_string(), listen: vec!["127.0.0.1:0".parse().unwrap()] }],
+        .. Config::default()
+    };
+    // A single worker routes each object to every bus it might leak to
+    // before the next one
+    let router = router_builder(config).workers(1).start().unwrap();
+    let tenant_addr = router.bus_addrs("tenant").unwrap()[0];
+    let mut main = connect(&router, &["@chat/*"]);
+    let mut tenants = Vec::new();
+    for _ in 0 .. 2 {
+        let mut tenant = Client::connect(tenant_addr).unwrap();
+        tenant.subscribe(&["@chat/*"]).unwrap();
+        tenants.push(tenant);
+    }
+
+    main.send(&BusinessObject::event("chat/main")).unwrap();
+    tenants[0].send(&BusinessObject::event("chat/tenant")).unwrap();
+    main.send(&BusinessObject::event("chat/main-again")).unwrap();
+    assert_eq!("chat/main", received_event(&mut main));
+    assert_eq!("chat/main-again", received_event(&mut main));
+    for tenant in &mut tenants {
+        assert_eq!("chat/tenant", received_event(tenant));
+    }
+
+    let list = tenants[1].request(&BusinessObject::event(Event::ClientsList).with_new_id(), TIMEOUT).unwrap();
+    assert_eq!(Some(2), list.metadata.extra.get("clients").and_then(|clients| clients.as_array()).map(|clients| clients.len()));
+
+    drop((main, tenants));
+    stop_router(router);
+}
+
+
+#[test]
+fn should_route_objects_on_a_channel_only_to_clients_on_it() {
+    let router = router_builder(Config::default()).workers(1).start().unwrap();
+    let mut default = connect(&router, &["@chat/*"]);
+    let on_channel = |channels: &[&str]| {
+        let mut client = Client::connect(router.local_addrs()[0]).unwrap();
+        client.set_channels(channels);
+        let reply = client.subscribe(&["@chat/*"]).unwrap();
+        assert_eq!(Some(channels.to_vec()), reply.meta_array_of_str("channels"));
+        client
+    };
+    let mut lab = on_channel(&["lab"]);
+    let mut ops = on_channel(&["ops", "lab"]);
+    let on = |name: &str, channel: &str| BusinessObject::event(name).with_meta("channel", channel);
+
+    default.send(&BusinessObject::event("chat/default")).unwrap();
+    assert_eq!("chat/default", received_event(&mut default));
+
+    // Each client has received its own object once the router is done with it
+    lab.send(&on("chat/lab", "lab")).unwrap();
+    assert_eq!("chat/lab", received_event(&mut lab));
+    assert_eq!("chat/lab", received_event(&mut ops));
+    ops.send(&on("chat/ops", "ops")).unwrap();
+    assert_eq!("chat/ops", received_event(&mut ops));
+
+    let denied = lab.request(&on("chat/ops", "ops").with_new_id(), TIMEOUT).unwrap();
+    assert_eq!(Some(ErrorCode::AccessDenied), denied.error_code());
+    let denied = lab.request(&BusinessObject::event("chat/default").with_new_id(), TIMEOUT).unwrap();
+    assert_eq!(Some(ErrorCode::AccessDenied), denied.error_code());
+
+    default.send(&BusinessObject::event("chat/default-again")).unwrap();
+    assert_eq!("chat/default-again", received_event(&mut default));
+    lab.send(&on("chat/lab-again", "lab")).unwrap();
+    assert_eq!("chat/lab-again", received_event(&mut lab));
+    assert_eq!("chat/lab-again", received_event(&mut ops));
+
+    drop((default, lab, ops));
+    stop_router(router);
+}
+
+
+#[test]
+fn should_stamp_objects_with_when_they_were_received_and_routed() {
+    let router = router_builder(Config { stamp_received_at: true, .. Config::default() }).start().unwrap();
+    let mut sender = connect(&router, &[]);
+    let mut receiver = connect(&router, &["@chat/*"]);
+
+    let before = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+    sender.send(&BusinessObject::event("chat/message")).unwrap();
+    let object = receiver.receive().unwrap();
+    let received_at = object.meta_u64(clock::RECEIVED_AT_KEY).unwrap();
+    let routed_at = object.meta_u64(clock::ROUTED_AT_KEY).unwrap();
+    // The router's clock is read off the wall clock only once, so allow it
+    // to be a little behind
+    assert!(received_at + 1000 >= before);
+    assert!(routed_at >= received_at);
+
+    drop((sender, receiver));
+    stop_router(router);
+}
+
+
+#[test]
+fn should_stop_forwarding_objects_out_of_hops() {
+    let identities = [("near", "n3ar"), ("far", "f4r")].iter()
+        .map(|&(identity, token)| (identity.to_string(), token.to_string()))
+        .collect();
+    let config = Config { dead_letters: true, identities, peer_routers: vec!["near".to_string()], .. Config::default() };
+    let upstream = router_builder(config).workers(1).start().unwrap();
+    let mut far = Client::connect(upstream.local_addrs()[0]).unwrap();
+    far.authenticate("f4r", TIMEOUT).unwrap();
+    far.subscribe(&["@chat/*", "@routing/announcement/connect", "@routing/dead-letter"]).unwrap();
+    let to_upstream = Upstream { auth_token: Some("n3ar".to_string()), .. Upstream::new(upstream.local_addrs()[0]) };
+    let config = Config { upstreams: vec![to_upstream], .. Config::default() };
+    let router = router_builder(config).workers(1).start().unwrap();
+    // The downstream router has subscribed to the upstream
+    assert_eq!("routing/announcement/connect", received_event(&mut far));
+    let mut near = connect(&router, &["@chat/*"]);
+    // ... and passes on the announcement of its own client
+    assert_eq!("routing/announcement/connect", received_event(&mut far));
+    let with_ttl = |name: &str, ttl: u64| BusinessObject::event(name).with_meta("ttl", &ttl);
+
+    near.send(&with_ttl("chat/far", 2)).unwrap();
+    near.send(&with_ttl("chat/near", 1)).unwrap();
+    near.send(&BusinessObject::event("chat/anywhere")).unwrap();
+    let received = near.receive().unwrap();
+    assert_eq!((Some("chat/far"), Some(1)), (received.event.as_deref(), received.metadata.ttl));
+    let received = near.receive().unwrap();
+    assert_eq!((Some("chat/near"), Some(0)), (received.event.as_deref(), received.metadata.ttl));
+
+    let received = far.receive().unwrap();
+    assert_eq!((Some("chat/far"), Some(0)), (received.event.as_deref(), received.metadata.ttl));
+    assert_eq!("chat/anywhere", received_event(&mut far));
+
+    far.send(&with_ttl("chat/spent", 0)).unwrap();
+    let letter = far.receive().unwrap();
+    assert_eq!(Some("routing/dead-letter"), letter.event.as_deref());
+    assert_eq!(Some("Out of hops"), letter.meta_str("reason"));
+
+    drop((near, far));
+    stop_router(router);
+    stop_router(upstream);
+}
+
+
+#[test]
+fn should_take_only_configured_peer_routers_for_routers() {
+    let config = Config {
+        auth_tokens: vec!["s3cret".to_string()],
+        identities: [("east".to_string(), "e4st".to_string())].iter().cloned().collect(),
+        peer_routers: vec!["east".to_string()],
+        .. Config::default()
+    };
+    let router = router_builder(config).start().unwrap();
+    let log_in = || {
+        let mut client = Client::connect(router.local_addrs()[0]).unwrap();
+        client.authenticate("s3cret", TIMEOUT).unwrap();
+        client
+    };
+    let mut alice = log_in();
+    let alice_id = alice.subscribe(&["@chat/*"]).unwrap().meta_str("routing-id").unwrap().to_string();
+    let mut carol = log_in();
+    carol.subscribe(&[]).unwrap();
+    let mut eve = log_in();
+    let mut subscribe = BusinessObject::event(Event::RoutingSubscribe).with_new_id();
+    subscribe.set_meta("subscriptions", &vec!["@chat/*".to_string()]);
+    subscribe.set_meta("router-id", "eve");
+    assert!(eve.request(&subscribe, TIMEOUT).unwrap().is_event(Event::RoutingSubscribeReply));
+
+    let mut whisper = BusinessObject::event("chat/whisper");
+    whisper.set_meta("to", &alice_id);
+    carol.send(&whisper).unwrap();
+    carol.send(&BusinessObject::event("chat/everyone")).unwrap();
+
+    assert_eq!("chat/whisper", received_event(&mut alice));
+    assert_eq!("chat/everyone", received_event(&mut alice));
+    assert_eq!("chat/everyone", received_event(&mut eve));
+
+    drop((alice, carol, eve));
+    stop_router(router);
+}
+
+
+/// Keeps what is routed where the test can look.
+struct Recorder(Arc<Mutex<Records>>);
+
+
+impl Storage for Recorder {
+    fn append(&mut self, object: Arc<BusinessObject>, routed: Timestamp) -> io::Result<()> {
+        self.0.lock().unwrap().push((routed, object));
+        Ok(())
+    }
+
+    fn scan(&mut self, since: Option<Timestamp>, _now: Timestamp) -> io::Result<Records> {
+        Ok(self.0.lock().unwrap().iter()
+           .filter(|&&(routed, _)| since.is_none_or(|since| routed > since))
+           .cloned()
+           .collect())
+    }
+}
+
+
+#[test]
+fn should_keep_history_and_journal_in_the_storages_plugged_in() {
+    let history = Arc::new(Mutex::new(Vec::new()));
+    let journal = Arc::new(Mutex::new(Vec::new()));
+    let history_storage = history.clone();
+    let router = router_builder(Config::default())
+        .history_storage(move || Box::new(Recorder(history_storage.clone())))
+        .journal_storage(Box::new(Recorder(journal.clone())))
+        .start().unwrap();
+    let mut publisher = connect(&router, &[]);
+    let mut chat = connect(&router, &["@chat/*"]);
+
+    publisher.send(&BusinessObject::event("chat/hello")).unwrap();
+    publisher.send(&BusinessObject::event("chat/bye")).unwrap();
+    assert_eq!("chat/hello", received_event(&mut chat));
+    assert_eq!("chat/bye", received_event(&mut chat));
+    let chat_events = |records: &Arc<Mutex<Records>>| -> Vec<String> {
+        records.lock().unwrap().iter()
+            .filter_map(|(_, object)| object.event.clone().filter(|event| event.starts_with("chat/")))
+            .collect()
+    };
+    assert_eq!(vec!["chat/hello", "chat/bye"], chat_events(&history));
+    assert_eq!(vec!["chat/hello", "chat/bye"], chat_events(&journal));
+
+    let mut late = connect(&router, &["@chat/*"]);
+    late.send(&BusinessObject::event("history/replay")).unwrap();
+    assert_eq!("chat/hello", received_event(&mut late));
+    assert_eq!("chat/bye", received_event(&mut late));
+    let reply = late.receive().unwrap();
+    assert!(reply.is_event(Event::HistoryReplayReply));
+    assert_eq!(Some(2), reply.meta_u64("count"));
+
+    let mut request = BusinessObject::event("history/replay");
+    request.set_meta("filter", r#""bye" in event"#);
+    assert_eq!(Some(1), late.request(&request, TIMEOUT).unwrap().meta_u64("count"));
+    assert_eq!("chat/bye", received_event(&mut late));
+    request.set_meta("filter", "event ==");
+    assert_eq!(Some("Invalid filter at 8: expected a name or a value"),
+               late.request(&request, TIMEOUT).unwrap().meta_str("error"));
+
+    drop((publisher, chat, late));
+    stop_router(router);
+}